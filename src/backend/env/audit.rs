@@ -0,0 +1,182 @@
+use super::config::CONFIG;
+use super::post::Post;
+use super::user::UserId;
+use super::{Karma, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Audit {
+    // the next user id to be audited
+    pub cursor: UserId,
+    pub correct: bool,
+    // (user id, actual karma, karma recomputed from other karma and reactions)
+    pub discrepancies: Vec<(UserId, Karma, Karma)>,
+}
+
+// Computes the karma the author of the post received through its reactions.
+pub fn post_reaction_karma(post: &Post) -> Karma {
+    // reactions from cross-post references are rewarded once per user
+    CONFIG
+        .reactions
+        .iter()
+        .map(|(id, delta)| {
+            let users = post
+                .reactions
+                .get(id)
+                .into_iter()
+                .chain(post.cross_post_reactions.get(id))
+                .flatten()
+                .collect::<BTreeSet<_>>();
+            delta * users.len() as Karma
+        })
+        .sum()
+}
+
+// Recomputes the karma user received through reactions from the reactions stored on posts.
+pub fn reaction_karma(state: &State, user_id: UserId) -> Karma {
+    state
+        .users
+        .get(&user_id)
+        .map(|user| {
+            user.posts
+                .iter()
+                .filter_map(|id| state.posts.get(id))
+                .map(post_reaction_karma)
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+pub fn start(state: &mut State, correct: bool) {
+    state.audit = Some(Audit {
+        correct,
+        ..Default::default()
+    });
+    state.logger.info(format!(
+        "Karma audit started (corrections {}).",
+        if correct { "enabled" } else { "disabled" }
+    ));
+}
+
+// Audits the next batch of users and returns true once the audit is finished.
+pub fn step(state: &mut State, batch_size: usize) -> bool {
+    let mut audit = match state.audit.take() {
+        Some(audit) => audit,
+        None => return true,
+    };
+    let user_ids = state
+        .users
+        .keys()
+        .filter(|id| **id >= audit.cursor)
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .take(batch_size)
+        .collect::<Vec<_>>();
+    for user_id in &user_ids {
+        let reaction_karma = reaction_karma(state, *user_id);
+        let user = state.users.get_mut(user_id).expect("no user found");
+        let Some(other_karma) = user.other_karma else {
+            continue;
+        };
+        let (actual, expected) = (user.total_karma(), other_karma + reaction_karma);
+        if actual == expected {
+            continue;
+        }
+        audit.discrepancies.push((*user_id, actual, expected));
+        if audit.correct {
+            user.change_karma(expected - actual, "karma audit correction");
+            // the correction must not count as other karma
+            user.other_karma = Some(other_karma);
+        }
+    }
+    match user_ids.last() {
+        Some(last_id) if user_ids.len() == batch_size => {
+            audit.cursor = last_id + 1;
+            state.audit = Some(audit);
+            false
+        }
+        _ => {
+            state.logger.info(format!(
+                "Karma audit finished: found `{}` discrepancies{}: {}",
+                audit.discrepancies.len(),
+                if audit.correct { " (corrected)" } else { "" },
+                audit
+                    .discrepancies
+                    .iter()
+                    .map(|(id, stored, expected)| format!(
+                        "user `{}`: `{}` instead of `{}`",
+                        id, stored, expected
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+            state.last_audit = Some(audit);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::tests::{create_user, pr};
+    use crate::post::Post;
+
+    #[test]
+    fn test_karma_audit() {
+        let mut state = State::default();
+        for i in 0..5 {
+            create_user(&mut state, pr(i));
+        }
+        let mut post = Post::new(0, Default::default(), "Test".into(), 0, None, None, None);
+        post.id = state.new_post_id();
+        state.posts.insert(post.id, post);
+        state.users.get_mut(&0).unwrap().posts.push(0);
+        for i in 1..5 {
+            assert_eq!(state.react(pr(i), 0, 10, 0), Ok(()));
+        }
+        assert_eq!(reaction_karma(&state, 0), 4);
+        let user = state.users.get(&0).unwrap();
+        assert_eq!(user.total_karma(), user.other_karma.unwrap() + 4);
+
+        // no discrepancies found
+        start(&mut state, false);
+        while !step(&mut state, 2) {}
+        assert!(state.last_audit.as_ref().unwrap().discrepancies.is_empty());
+
+        // inject a discrepancy: karma credited bypassing the accounting
+        let user = state.users.get_mut(&0).unwrap();
+        let other_karma = user.other_karma;
+        user.change_karma(3, "bug");
+        user.other_karma = other_karma;
+        let karma = user.total_karma();
+
+        // the discrepancy is detected, but not corrected
+        start(&mut state, false);
+        assert!(!step(&mut state, 2));
+        assert!(!step(&mut state, 2));
+        assert!(step(&mut state, 2));
+        assert_eq!(
+            state.last_audit.as_ref().unwrap().discrepancies,
+            vec![(0, karma, karma - 3)]
+        );
+        assert_eq!(state.users.get(&0).unwrap().total_karma(), karma);
+
+        // the discrepancy is corrected
+        start(&mut state, true);
+        while !step(&mut state, 2) {}
+        assert_eq!(
+            state.last_audit.as_ref().unwrap().discrepancies,
+            vec![(0, karma, karma - 3)]
+        );
+        let user = state.users.get(&0).unwrap();
+        assert_eq!(user.total_karma(), karma - 3);
+        assert_eq!(user.other_karma, other_karma);
+
+        start(&mut state, false);
+        while !step(&mut state, 2) {}
+        assert!(state.last_audit.as_ref().unwrap().discrepancies.is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+use super::config::CONFIG;
+use super::post::PostId;
+use super::token::{self, Token};
+use super::{State, HOUR};
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Bounty {
+    pub amount: Token,
+    // the bounty gets refunded if no answer was accepted before this time
+    pub deadline: u64,
+    pub answer: Option<PostId>,
+    pub refunded: bool,
+}
+
+// Attaches a bounty to a root post of the caller, locking the tokens in the escrow.
+pub fn create(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    amount: Token,
+    duration_hours: u64,
+    now: u64,
+) -> Result<(), String> {
+    if amount < CONFIG.minimal_bounty {
+        return Err(format!(
+            "the minimal bounty is `{}` ${}",
+            token::display(CONFIG.minimal_bounty),
+            CONFIG.token_symbol
+        ));
+    }
+    if duration_hours == 0 || duration_hours > CONFIG.max_bounty_duration_hours {
+        return Err("invalid bounty duration".into());
+    }
+    let user = state.principal_to_user(principal).ok_or("no user found")?;
+    let (user_id, user_principal) = (user.id, user.principal);
+    let post = state.posts.get(&post_id).ok_or("post not found")?;
+    if post.deleted() {
+        return Err("post not found".into());
+    }
+    if post.user != user_id {
+        return Err("only the author can attach a bounty".into());
+    }
+    if post.parent.is_some() {
+        return Err("bounties can only be attached to root posts".into());
+    }
+    if post.bounty.is_some() {
+        return Err("post already has a bounty".into());
+    }
+    token::lock(now, state, user_principal, amount)?;
+    let deadline = now + duration_hours * HOUR;
+    state.posts.get_mut(&post_id).expect("no post found").bounty = Some(Bounty {
+        amount,
+        deadline,
+        answer: None,
+        refunded: false,
+    });
+    state.open_bounties.insert((deadline, post_id));
+    Ok(())
+}
+
+// Pays the bounty out to the author of a direct reply chosen by the author of the post.
+pub fn accept(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    answer_id: PostId,
+    now: u64,
+) -> Result<(), String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let post = state.posts.get(&post_id).ok_or("post not found")?;
+    let bounty = post.bounty.as_ref().ok_or("no bounty found")?;
+    if post.user != user_id {
+        return Err("only the author can accept an answer".into());
+    }
+    if bounty.answer.is_some() || bounty.refunded || now >= bounty.deadline {
+        return Err("bounty is closed".into());
+    }
+    let (amount, deadline) = (bounty.amount, bounty.deadline);
+    let answer = state
+        .posts
+        .get(&answer_id)
+        .filter(|answer| {
+            answer.parent == Some(post_id) && !answer.deleted() && answer.user != user_id
+        })
+        .ok_or("invalid answer")?;
+    let answerer = state.users.get(&answer.user).ok_or("no user found")?;
+    let answerer_id = answerer.id;
+    token::release(now, state, answerer.principal, amount)?;
+    if let Some(bounty) = state
+        .posts
+        .get_mut(&post_id)
+        .and_then(|post| post.bounty.as_mut())
+    {
+        bounty.answer = Some(answer_id);
+    }
+    state.open_bounties.remove(&(deadline, post_id));
+    let answerer = state.users.get_mut(&answerer_id).expect("no user found");
+    if answerer.notification_settings.rewards {
+        answerer.notify_about_post(
+            format!(
+                "Your answer was accepted and you received the bounty of `{}` ${}",
+                token::display(amount),
+                CONFIG.token_symbol
+            ),
+            answer_id,
+        );
+    }
+    Ok(())
+}
+
+// Refunds all bounties without an accepted answer past their deadline to the posters. Bounties
+// which couldn't be refunded stay open and get retried with the next run.
+pub fn refund_expired(state: &mut State, now: u64) {
+    let expired = state
+        .open_bounties
+        .range(..=(now, PostId::MAX))
+        .copied()
+        .collect::<Vec<_>>();
+    for key in expired {
+        let post_id = key.1;
+        let (author_id, amount) = match state.posts.get(&post_id).map(|post| (post, &post.bounty)) {
+            Some((post, Some(bounty))) if bounty.answer.is_none() && !bounty.refunded => {
+                (post.user, bounty.amount)
+            }
+            Some(_) => {
+                state.open_bounties.remove(&key);
+                continue;
+            }
+            None => {
+                state.logger.error(format!(
+                    "couldn't refund the bounty of post {}: post not found",
+                    post_id
+                ));
+                continue;
+            }
+        };
+        let result = state
+            .users
+            .get(&author_id)
+            .map(|user| user.principal)
+            .ok_or_else(|| "user not found".to_string())
+            .and_then(|principal| token::release(now, state, principal, amount));
+        if let Err(err) = result {
+            state.logger.error(format!(
+                "couldn't refund the bounty of post {}: {}",
+                post_id, err
+            ));
+            continue;
+        }
+        state.open_bounties.remove(&key);
+        if let Some(bounty) = state
+            .posts
+            .get_mut(&post_id)
+            .and_then(|post| post.bounty.as_mut())
+        {
+            bounty.refunded = true;
+        }
+        if let Some(user) = state.users.get_mut(&author_id) {
+            user.notify_about_post(
+                "No answer was accepted before the deadline, your bounty was refunded",
+                post_id,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post::add,
+        tests::{create_user, pr},
+    };
+    use crate::token::{account, escrow_account};
+
+    fn balance(state: &State, principal: Principal) -> Token {
+        state
+            .balances
+            .get(&account(principal))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn locked(state: &State) -> Token {
+        state
+            .balances
+            .get(&escrow_account())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    async fn question_with_answer(state: &mut State) -> (PostId, PostId) {
+        for i in 0..3 {
+            create_user(state, pr(i));
+        }
+        token::mint(state, account(pr(0)), 1000);
+        let question = add(
+            state,
+            "Question?".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let answer = add(
+            state,
+            "Answer".into(),
+            vec![],
+            pr(1),
+            0,
+            Some(question),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        (question, answer)
+    }
+
+    #[actix_rt::test]
+    async fn test_bounty_payout() {
+        let mut state = State::default();
+        let (question, answer) = question_with_answer(&mut state).await;
+        assert_eq!(
+            create(&mut state, pr(1), question, 500, 24, 0),
+            Err("only the author can attach a bounty".into())
+        );
+        assert_eq!(
+            create(&mut state, pr(0), question, 2000, 24, 0),
+            Err("not enough tokens".into())
+        );
+        assert_eq!(create(&mut state, pr(0), question, 500, 24, 0), Ok(()));
+        assert_eq!(
+            create(&mut state, pr(0), question, 100, 24, 0),
+            Err("post already has a bounty".into())
+        );
+
+        assert_eq!(
+            accept(&mut state, pr(1), question, answer, HOUR),
+            Err("only the author can accept an answer".into())
+        );
+        assert_eq!(
+            accept(&mut state, pr(0), question, question, HOUR),
+            Err("invalid answer".into())
+        );
+        assert_eq!(accept(&mut state, pr(0), question, answer, HOUR), Ok(()));
+        assert_eq!(balance(&state, pr(0)), 500);
+        assert_eq!(balance(&state, pr(1)), 500);
+        assert_eq!(locked(&state), 0);
+        assert!(state.open_bounties.is_empty());
+        assert_eq!(
+            state
+                .posts
+                .get(&question)
+                .unwrap()
+                .bounty
+                .as_ref()
+                .unwrap()
+                .answer,
+            Some(answer)
+        );
+
+        // the bounty can't be paid out twice
+        assert_eq!(
+            accept(&mut state, pr(0), question, answer, HOUR),
+            Err("bounty is closed".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_bounty_refund() {
+        let mut state = State::default();
+        let (question, answer) = question_with_answer(&mut state).await;
+        assert_eq!(
+            create(
+                &mut state,
+                pr(0),
+                question,
+                CONFIG.minimal_bounty - 1,
+                24,
+                0
+            ),
+            Err(format!(
+                "the minimal bounty is `{}` ${}",
+                token::display(CONFIG.minimal_bounty),
+                CONFIG.token_symbol
+            ))
+        );
+        create(&mut state, pr(0), question, 400, 24, 0).unwrap();
+
+        // nothing happens before the deadline
+        refund_expired(&mut state, 24 * HOUR - 1);
+        assert_eq!(locked(&state), 400);
+
+        // a failed refund keeps the bounty open for a retry
+        state.balances.insert(escrow_account(), 100);
+        refund_expired(&mut state, 24 * HOUR);
+        assert_eq!(state.open_bounties.len(), 1);
+        assert!(
+            !state
+                .posts
+                .get(&question)
+                .unwrap()
+                .bounty
+                .as_ref()
+                .unwrap()
+                .refunded
+        );
+        state.balances.insert(escrow_account(), 400);
+
+        refund_expired(&mut state, 24 * HOUR);
+        assert_eq!(balance(&state, pr(0)), 1000);
+        assert_eq!(locked(&state), 0);
+        assert!(state.open_bounties.is_empty());
+        assert!(
+            state
+                .posts
+                .get(&question)
+                .unwrap()
+                .bounty
+                .as_ref()
+                .unwrap()
+                .refunded
+        );
+        assert_eq!(
+            accept(&mut state, pr(0), question, answer, 24 * HOUR),
+            Err("bounty is closed".into())
+        );
+        assert_eq!(balance(&state, pr(1)), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_locked_balance() {
+        let mut state = State::default();
+        let (question, answer) = question_with_answer(&mut state).await;
+        token::mint(&mut state, account(pr(2)), 300);
+        let other = add(
+            &mut state,
+            "Another question?".into(),
+            vec![],
+            pr(2),
+            CONFIG.rate_limit_window,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        create(&mut state, pr(0), question, 600, 24, 0).unwrap();
+        create(&mut state, pr(2), other, 300, 48, 0).unwrap();
+        assert_eq!(locked(&state), 900);
+        assert_eq!(balance(&state, pr(0)), 400);
+        assert_eq!(balance(&state, pr(2)), 0);
+
+        // locked tokens can't be spent
+        assert_eq!(
+            token::transfer_tokens(0, &mut state, pr(0), pr(1), 500),
+            Err("not enough tokens".into())
+        );
+
+        accept(&mut state, pr(0), question, answer, HOUR).unwrap();
+        assert_eq!(locked(&state), 300);
+        refund_expired(&mut state, 48 * HOUR);
+        assert_eq!(locked(&state), 0);
+        // no tokens were created or lost
+        assert_eq!(
+            balance(&state, pr(0)) + balance(&state, pr(1)) + balance(&state, pr(2)),
+            1300
+        );
+    }
+}
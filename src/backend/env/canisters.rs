@@ -5,10 +5,7 @@ use ic_cdk::api::call::CallResult;
 use ic_cdk::export::candid::{CandidType, Principal};
 use ic_cdk::id;
 use ic_cdk::{
-    api::{
-        call::call_with_payment,
-        call::{call, call_raw},
-    },
+    api::{call::call, call::call_with_payment},
     notify,
 };
 use serde::Deserialize;
@@ -160,24 +157,25 @@ pub async fn topup_with_cycles(canister_id: Principal, cycles: u64) -> Result<()
     Ok(())
 }
 
-pub async fn top_up(canister_id: Principal, min_cycle_balance: u64) -> Result<bool, String> {
-    let bytes = call_raw(canister_id, "balance", Default::default(), 0)
-        .await
-        .map_err(|err| {
-            format!(
-                "couldn't get balance from canister {}: {:?}",
-                canister_id, err
-            )
-        })?;
-    let mut arr: [u8; 8] = Default::default();
-    arr.copy_from_slice(&bytes);
-    if u64::from_be_bytes(arr) < min_cycle_balance {
-        topup_with_cycles(canister_id, min_cycle_balance)
-            .await
-            .map_err(|err| format!("failed to top up canister {}: {:?}", canister_id, err))?;
-        return Ok(true);
-    }
-    Ok(false)
+pub async fn stop(canister_id: Principal) -> Result<(), String> {
+    call(
+        Principal::management_canister(),
+        "stop_canister",
+        (CanisterId { canister_id },),
+    )
+    .await
+    .map_err(|err| format!("couldn't stop the canister: {:?}", err))
+}
+
+// Deletes a stopped canister; its remaining cycles are burned.
+pub async fn delete(canister_id: Principal) -> Result<(), String> {
+    call(
+        Principal::management_canister(),
+        "delete_canister",
+        (CanisterId { canister_id },),
+    )
+    .await
+    .map_err(|err| format!("couldn't delete the canister: {:?}", err))
 }
 
 pub fn call_canister<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
@@ -187,3 +185,24 @@ pub fn call_canister<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
 ) -> impl Future<Output = CallResult<R>> {
     ic_cdk::call(id, method, args)
 }
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "ic0")]
+extern "C" {
+    #[link_name = "is_controller"]
+    fn ic0_is_controller(src: usize, size: usize) -> u32;
+}
+
+// Checks the principal against the controllers of this canister; works in queries too.
+pub fn is_controller(principal: &Principal) -> bool {
+    #[cfg(target_arch = "wasm32")]
+    {
+        let bytes = principal.as_slice();
+        unsafe { ic0_is_controller(bytes.as_ptr() as usize, bytes.len()) == 1 }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = principal;
+        false
+    }
+}
@@ -0,0 +1,477 @@
+use super::config::CONFIG;
+use super::invoices::principal_to_subaccount;
+use super::post::PostId;
+use super::storage::CallFn;
+use super::token::{account, Account, Subaccount};
+use super::user::UserId;
+use super::{id, State};
+use candid::CandidType;
+use ic_cdk::api::call::call_raw;
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+// A withdrawal whose ledger call failed; the ledger might have executed it, so it gets resent
+// with the same memo and creation time, which lets the ledger deduplicate it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Withdrawal {
+    user_id: UserId,
+    recipient: Principal,
+    amount: u64,
+    memo: Vec<u8>,
+    created_at_time: u64,
+}
+
+// ckBTC balances of users in satoshis, backed by the main ckBTC account of the canister.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Wallets {
+    pub balances: BTreeMap<UserId, u64>,
+    #[serde(default)]
+    pub pending: Vec<Withdrawal>,
+    #[serde(default)]
+    next_withdrawal: u64,
+    #[serde(skip)]
+    call: Option<CallFn>,
+}
+
+impl Wallets {
+    pub fn balance(&self, user_id: UserId) -> u64 {
+        self.balances.get(&user_id).copied().unwrap_or_default()
+    }
+
+    fn credit(&mut self, user_id: UserId, amount: u64) {
+        *self.balances.entry(user_id).or_default() += amount;
+    }
+
+    fn debit(&mut self, user_id: UserId, amount: u64) -> Result<(), String> {
+        let balance = self.balance(user_id);
+        if balance < amount {
+            return Err("insufficient ckBTC balance".into());
+        }
+        if balance == amount {
+            self.balances.remove(&user_id);
+        } else {
+            self.balances.insert(user_id, balance - amount);
+        }
+        Ok(())
+    }
+
+    async fn call<A: CandidType, R: CandidType + for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        arg: A,
+    ) -> Result<R, String> {
+        let ledger = Principal::from_text(CONFIG.ckbtc_ledger).map_err(|err| err.to_string())?;
+        let args = candid::encode_one(arg).map_err(|err| err.to_string())?;
+        let bytes = match &self.call {
+            Some(call) => call(ledger, method, args).await?,
+            None => call_raw(ledger, method, &args, 0).await.map_err(|err| {
+                format!("couldn't call {} on the ckBTC ledger: {:?}", method, err)
+            })?,
+        };
+        candid::decode_one(&bytes).map_err(|err| err.to_string())
+    }
+
+    async fn balance_of(&self, account: Account) -> Result<u64, String> {
+        let balance: u128 = self.call("icrc1_balance_of", account).await?;
+        Ok(balance as u64)
+    }
+
+    // Returns the error of the ledger in the inner result; an error of the outer result means
+    // that the outcome of the transfer is unknown.
+    async fn transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> Result<Result<u64, TransferError>, String> {
+        let result: Result<u128, TransferError> = self
+            .call(
+                "icrc1_transfer",
+                TransferArg {
+                    from_subaccount,
+                    to,
+                    amount: amount as u128,
+                    fee: Some(CONFIG.ckbtc_fee as u128),
+                    memo,
+                    created_at_time,
+                },
+            )
+            .await?;
+        Ok(result.map(|block| block as u64))
+    }
+
+    async fn send(&self, withdrawal: &Withdrawal) -> Result<Result<u64, TransferError>, String> {
+        let result = self
+            .transfer(
+                None,
+                account(withdrawal.recipient),
+                withdrawal.amount,
+                Some(withdrawal.memo.clone()),
+                Some(withdrawal.created_at_time),
+            )
+            .await?;
+        Ok(match result {
+            // the withdrawal was executed by a previous call
+            Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of as u64),
+            result => result,
+        })
+    }
+}
+
+// Every user deposits ckBTC to an own subaccount of the canister.
+pub fn deposit_subaccount(principal: Principal) -> Subaccount {
+    principal_to_subaccount(&principal).0.to_vec()
+}
+
+// Moves the funds from the deposit subaccount of the user to the main account and credits
+// the user after the ledger confirmed the transfer. Returns the credited amount.
+pub async fn deposit(state: &mut State, principal: Principal) -> Result<u64, String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let subaccount = deposit_subaccount(principal);
+    let balance = state
+        .ckbtc
+        .balance_of(Account {
+            owner: id(),
+            subaccount: Some(subaccount.clone()),
+        })
+        .await?;
+    if balance <= CONFIG.ckbtc_fee {
+        return Err("no deposit found".into());
+    }
+    let amount = balance - CONFIG.ckbtc_fee;
+    // concurrent deposits of the same funds fail on the ledger, so nothing is credited twice
+    state
+        .ckbtc
+        .transfer(Some(subaccount), account(id()), amount, None, None)
+        .await?
+        .map_err(|err| format!("ckBTC transfer failed: {:?}", err))?;
+    state.ckbtc.credit(user_id, amount);
+    state.logger.info(format!(
+        "User `{}` deposited `{}` ckBTC satoshis",
+        user_id, amount
+    ));
+    Ok(amount)
+}
+
+// Debits the amount and the ledger fee before the transfer, so that concurrent withdrawals
+// can't spend the same funds; the debit is reverted if the ledger rejects the transfer. If the
+// call fails, the withdrawal stays pending and gets resent later.
+pub async fn withdraw(
+    state: &mut State,
+    principal: Principal,
+    recipient: String,
+    amount: u64,
+    now: u64,
+) -> Result<u64, String> {
+    let recipient = Principal::from_text(recipient).map_err(|err| err.to_string())?;
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    if amount == 0 {
+        return Err("nothing to withdraw".into());
+    }
+    let total = amount
+        .checked_add(CONFIG.ckbtc_fee)
+        .ok_or("invalid amount")?;
+    state.ckbtc.debit(user_id, total)?;
+    // every withdrawal gets a unique memo, so that the ledger never deduplicates distinct ones
+    let withdrawal = Withdrawal {
+        user_id,
+        recipient,
+        amount,
+        memo: state.ckbtc.next_withdrawal.to_be_bytes().to_vec(),
+        created_at_time: now,
+    };
+    state.ckbtc.next_withdrawal += 1;
+    match state.ckbtc.send(&withdrawal).await {
+        Ok(Ok(block)) => Ok(block),
+        Ok(Err(err)) => {
+            state.ckbtc.credit(user_id, total);
+            Err(format!("ckBTC transfer failed: {:?}", err))
+        }
+        Err(err) => {
+            state.ckbtc.pending.push(withdrawal);
+            Err(format!("the withdrawal is pending: {}", err))
+        }
+    }
+}
+
+// Resends the pending withdrawals; the debit is reverted only if the ledger rejects one.
+pub async fn retry_withdrawals(state: &mut State) {
+    for withdrawal in std::mem::take(&mut state.ckbtc.pending) {
+        match state.ckbtc.send(&withdrawal).await {
+            Ok(Ok(block)) => state.logger.info(format!(
+                "Pending ckBTC withdrawal of user `{}` was executed in block `{}`",
+                withdrawal.user_id, block
+            )),
+            // the deduplication window has passed, so the outcome can't be determined anymore
+            Ok(Err(TransferError::TooOld)) => state.logger.error(format!(
+                "Pending ckBTC withdrawal of user `{}` expired and needs a manual resolution: {:?}",
+                withdrawal.user_id, withdrawal
+            )),
+            Ok(Err(err)) => {
+                state
+                    .ckbtc
+                    .credit(withdrawal.user_id, withdrawal.amount + CONFIG.ckbtc_fee);
+                state.logger.info(format!(
+                    "Pending ckBTC withdrawal of user `{}` was rejected: {:?}",
+                    withdrawal.user_id, err
+                ));
+            }
+            Err(_) => state.ckbtc.pending.push(withdrawal),
+        }
+    }
+}
+
+pub fn tip(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    amount: u64,
+) -> Result<(), String> {
+    if amount < CONFIG.minimal_ckbtc_tip {
+        return Err(format!(
+            "the minimal tip is `{}` satoshis",
+            CONFIG.minimal_ckbtc_tip
+        ));
+    }
+    let tipper = state.principal_to_user(principal).ok_or("no user found")?;
+    let (tipper_id, tipper_name) = (tipper.id, tipper.name.clone());
+    let post = state.posts.get(&post_id).ok_or("post not found")?;
+    if post.deleted() {
+        return Err("post not found".into());
+    }
+    let author_id = post.user;
+    if author_id == tipper_id {
+        return Err("you can't tip yourself".into());
+    }
+    state.ckbtc.debit(tipper_id, amount)?;
+    state.ckbtc.credit(author_id, amount);
+    let post = state.posts.get_mut(&post_id).expect("post not found");
+    post.ckbtc_tips.push((tipper_id, amount));
+    let author = state.users.get_mut(&author_id).expect("user not found");
+    if author.notification_settings.rewards {
+        author.notify_about_post(
+            format!(
+                "@{} tipped you with `{}` ckBTC satoshis for your post",
+                tipper_name, amount
+            ),
+            post_id,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post::add,
+        tests::{create_user, pr},
+    };
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    type Ledger = Rc<RefCell<HashMap<Account, u64>>>;
+
+    // Mocks the ckBTC ledger by the balances of accounts; if the returned flag is set, calls
+    // fail after the ledger executed the transfer.
+    fn mock_ledger(state: &mut State) -> (Ledger, Rc<Cell<bool>>) {
+        let ledger: Ledger = Default::default();
+        let failing = Rc::new(Cell::new(false));
+        let (mock, fail) = (ledger.clone(), failing.clone());
+        let executed = Rc::new(RefCell::new(HashMap::new()));
+        state.ckbtc.call = Some(Box::new(move |_, method, args| {
+            let mut balances = mock.borrow_mut();
+            let result = match method {
+                "icrc1_balance_of" => {
+                    let account: Account = candid::decode_one(&args).unwrap();
+                    let balance = balances.get(&account).copied().unwrap_or_default() as u128;
+                    candid::encode_one(balance).unwrap()
+                }
+                "icrc1_transfer" => {
+                    let arg: TransferArg = candid::decode_one(&args).unwrap();
+                    let key = (arg.memo.clone(), arg.created_at_time);
+                    if key.1.is_some() {
+                        if let Some(block) = executed.borrow().get(&key) {
+                            if fail.get() {
+                                return Box::pin(async { Err("call timed out".into()) });
+                            }
+                            let result: Result<u128, TransferError> =
+                                Err(TransferError::Duplicate {
+                                    duplicate_of: *block,
+                                });
+                            let result = candid::encode_one(result).unwrap();
+                            return Box::pin(async move { Ok(result) });
+                        }
+                    }
+                    let from = Account {
+                        owner: id(),
+                        subaccount: arg.from_subaccount,
+                    };
+                    let balance = balances.get(&from).copied().unwrap_or_default();
+                    let total = arg.amount as u64 + CONFIG.ckbtc_fee;
+                    let result: Result<u128, TransferError> = if balance < total {
+                        Err(TransferError::InsufficientFunds {
+                            balance: balance as u128,
+                        })
+                    } else {
+                        balances.insert(from, balance - total);
+                        *balances.entry(arg.to).or_default() += arg.amount as u64;
+                        executed.borrow_mut().insert(key, 7);
+                        Ok(7)
+                    };
+                    if fail.get() {
+                        return Box::pin(async { Err("call timed out".into()) });
+                    }
+                    candid::encode_one(result).unwrap()
+                }
+                _ => return Box::pin(async { Err("unknown method".into()) }),
+            };
+            Box::pin(async move { Ok(result) })
+        }));
+        (ledger, failing)
+    }
+
+    fn ledger_balance(ledger: &Ledger, account: &Account) -> u64 {
+        ledger.borrow().get(account).copied().unwrap_or_default()
+    }
+
+    #[actix_rt::test]
+    async fn test_ckbtc_deposit_and_withdrawal() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let (ledger, failing) = mock_ledger(&mut state);
+        let deposit_account = Account {
+            owner: id(),
+            subaccount: Some(deposit_subaccount(pr(0))),
+        };
+
+        assert_eq!(
+            deposit(&mut state, pr(0)).await,
+            Err("no deposit found".into())
+        );
+
+        // the deposit is swept to the main account and credited without the fee
+        ledger.borrow_mut().insert(deposit_account.clone(), 1000);
+        assert_eq!(deposit(&mut state, pr(0)).await, Ok(990));
+        assert_eq!(state.ckbtc.balance(0), 990);
+        assert_eq!(ledger_balance(&ledger, &deposit_account), 0);
+        assert_eq!(ledger_balance(&ledger, &account(id())), 990);
+        assert_eq!(
+            deposit(&mut state, pr(0)).await,
+            Err("no deposit found".into())
+        );
+
+        // withdrawals debit the amount and the fee
+        assert_eq!(
+            withdraw(&mut state, pr(0), pr(5).to_text(), 990, 0).await,
+            Err("insufficient ckBTC balance".into())
+        );
+        assert_eq!(
+            withdraw(&mut state, pr(0), pr(5).to_text(), 500, 0).await,
+            Ok(7)
+        );
+        assert_eq!(state.ckbtc.balance(0), 480);
+        assert_eq!(ledger_balance(&ledger, &account(pr(5))), 500);
+        assert_eq!(ledger_balance(&ledger, &account(id())), 480);
+
+        // a failed transfer reverts the debit
+        ledger.borrow_mut().insert(account(id()), 0);
+        assert!(withdraw(&mut state, pr(0), pr(5).to_text(), 400, 0)
+            .await
+            .unwrap_err()
+            .contains("InsufficientFunds"));
+        assert_eq!(state.ckbtc.balance(0), 480);
+
+        // a failed call keeps the debit and the withdrawal pending
+        ledger.borrow_mut().insert(account(id()), 480);
+        failing.set(true);
+        assert_eq!(
+            withdraw(&mut state, pr(0), pr(5).to_text(), 100, 1).await,
+            Err("the withdrawal is pending: call timed out".into())
+        );
+        assert_eq!(state.ckbtc.balance(0), 370);
+        assert_eq!(state.ckbtc.pending.len(), 1);
+        retry_withdrawals(&mut state).await;
+        assert_eq!(state.ckbtc.pending.len(), 1);
+
+        // the resent withdrawal gets deduplicated by the ledger and is executed only once
+        failing.set(false);
+        retry_withdrawals(&mut state).await;
+        assert!(state.ckbtc.pending.is_empty());
+        assert_eq!(state.ckbtc.balance(0), 370);
+        assert_eq!(ledger_balance(&ledger, &account(pr(5))), 600);
+        assert_eq!(ledger_balance(&ledger, &account(id())), 370);
+    }
+
+    #[actix_rt::test]
+    async fn test_ckbtc_tips() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        let post_id = add(
+            &mut state,
+            "hello".into(),
+            vec![],
+            pr(1),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        state.ckbtc.credit(0, 150);
+
+        assert_eq!(
+            tip(&mut state, pr(0), post_id, 10),
+            Err("the minimal tip is `100` satoshis".into())
+        );
+        assert_eq!(
+            tip(&mut state, pr(1), post_id, 100),
+            Err("you can't tip yourself".into())
+        );
+        assert_eq!(tip(&mut state, pr(0), post_id, 100), Ok(()));
+        assert_eq!(
+            tip(&mut state, pr(0), post_id, 100),
+            Err("insufficient ckBTC balance".into())
+        );
+        assert_eq!(state.ckbtc.balance(0), 50);
+        assert_eq!(state.ckbtc.balance(1), 100);
+        assert_eq!(
+            state.posts.get(&post_id).unwrap().ckbtc_tips,
+            vec![(0, 100)]
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use crate::token::Token;
 
+use super::user::OnboardingStep;
 use super::{Cycles, Karma, HOUR, WEEK};
 use ic_cdk::export::candid::CandidType;
 use serde::Serialize;
@@ -19,23 +20,68 @@ pub struct Config {
     pub total_supply: Token,
 
     pub proposal_approval_threshold: u16,
+    // percentage of voting power that has to participate by the end of the voting period
+    pub proposal_quorum: u16,
+    pub proposal_voting_period: u64,
     pub proposal_controversy_threashold: u16,
     pub proposal_rejection_penalty: u32,
+    // percentage of voting power needed to veto an adopted proposal during the timelock
+    pub proposal_veto_threshold: u16,
+    pub proposal_timelock_release: u64,
+    pub proposal_timelock_controller: u64,
+    pub proposal_timelock_funding: u64,
+    pub max_timelocked_executions: usize,
+
+    pub karma_audit_batch_size: usize,
 
     pub min_cycle_balance_main: u64,
+    pub critical_cycle_balance_main: u64,
+    pub degraded_cycle_balance: u64,
+    pub degraded_stable_memory_size: u64,
+    pub bucket_top_up_interval: u64,
+    pub bucket_min_cycle_balance: u64,
+    pub bucket_top_up_cycles: u64,
 
     pub max_bucket_size: u64,
-
-    pub max_posts_per_hour: u8,
-    pub max_comments_per_hour: u8,
+    pub bucket_write_attempts: usize,
+    pub bucket_failure_backoff: u64,
+    pub blob_compression_threshold: usize,
+    pub storage_metrics_interval: u64,
+
+    pub max_posts_per_window: u8,
+    pub max_comments_per_window: u8,
+    pub rate_limit_window: u64,
+    pub max_scheduled_posts: usize,
+    pub max_schedule_ahead: u64,
+    pub max_post_edits: usize,
+    pub post_edit_grace_period: u64,
 
     pub feed_page_size: usize,
 
+    pub max_search_term_length: usize,
+    pub search_recency_period: u64,
+    pub atom_feed_size: usize,
+    pub sitemap_max_urls: usize,
+
+    pub max_user_interests: usize,
+    pub recommendation_window: usize,
+    pub recommendation_interest_weight: i64,
+    pub recommendation_following_weight: i64,
+    pub recommendation_engagement_weight: i64,
+    pub recommendation_max_posts_per_author: usize,
+
     pub min_cycles_minted: Cycles,
     pub reporting_penalty: Cycles,
 
     pub minimal_tip: Cycles,
     pub tipping_fee: Cycles,
+    pub minimal_token_tip: u64,
+    pub minimal_bounty: u64,
+    pub max_bounty_duration_hours: u64,
+
+    pub ckbtc_ledger: &'static str,
+    pub ckbtc_fee: u64,
+    pub minimal_ckbtc_tip: u64,
 
     pub trusted_user_min_karma: i64,
     pub trusted_user_min_age_weeks: u64,
@@ -48,11 +94,35 @@ pub struct Config {
 
     pub max_realm_name: usize,
     pub max_realm_logo_len: usize,
+    pub max_realm_tiers: usize,
+    pub realm_tiers_batch_size: usize,
+    pub max_realm_invites: usize,
+    pub min_realm_rewards_period_hours: u64,
+    pub max_pinned_posts: usize,
+    pub max_realm_blocked_patterns: usize,
+    pub max_blocked_pattern_length: usize,
+    pub max_blocked_pattern_nesting: u32,
+    // bytes of the compiled program and of the lazy DFA cache of all blocked patterns
+    pub max_blocked_patterns_size: usize,
 
     pub response_reward: Karma,
+    pub quote_reward: Karma,
+    pub referral_reward: Karma,
+    // activity required from an invited user before the inviter gets the referral reward
+    pub referral_min_posts: usize,
+    pub referral_min_karma: Karma,
 
     pub inactivity_penalty: i64,
     pub inactivity_duration_weeks: u64,
+    // percentage of karma inactive users lose every decay period; 0 disables the decay
+    pub karma_decay_percentage: i64,
+    pub karma_decay_period: u64,
+
+    pub badge_upvotes: u64,
+    pub badge_streak_days: u32,
+
+    // token base units claimable once per completed onboarding step; unlisted steps are disabled
+    pub onboarding_rewards: &'static [(OnboardingStep, Token)],
 
     pub voting_reward: i64,
 
@@ -65,17 +135,73 @@ pub struct Config {
     // percentage of stalwarts needed to confirm a report
     pub report_confirmation_percentage: u16,
 
+    pub coordination_min_post_length: usize,
+    pub coordination_window_hours: u64,
+    pub coordination_window_size: usize,
+    // max hamming distance between fingerprints of near-duplicate posts
+    pub coordination_max_distance: u32,
+    pub coordination_min_accounts: usize,
+    pub coordination_min_linked_accounts: usize,
+    pub coordination_auto_hold: bool,
+
+    // posts with a spam score at or above the threshold are held for review
+    pub spam_hold_threshold: u32,
+    // score of a post consisting of links only; scaled down by the share of other words
+    pub spam_link_density_weight: u32,
+    // score of a post identical to one of the recent posts of the same author
+    pub spam_duplicate_weight: u32,
+    pub spam_duplicate_lookback: usize,
+    pub spam_new_account_weight: u32,
+    pub spam_new_account_age: u64,
+    // score of posts from authors with less karma than trusted users have
+    pub spam_low_karma_weight: u32,
+
     pub hot_post_reactions_percentage: f32,
     pub hot_post_comments_percentage: f32,
+    // the number of recent posts ranked for the trending feed
+    pub trending_window: usize,
+    // how quickly the trending score of a post decays with its age
+    pub trending_gravity: f32,
+    // the age of posts eligible as the featured post of the day
+    pub featured_post_window: u64,
 
     pub max_post_length: usize,
+    pub max_post_attachments: usize,
+    // bounds of the post limits set by realms
+    pub max_realm_post_length: usize,
+    pub max_realm_post_attachments: usize,
     pub max_tag_length: usize,
     pub max_user_info_length: usize,
+    pub max_collections: usize,
+    pub max_guardians: usize,
+    // time during which the user can contest a recovery approved by the guardians
+    pub recovery_timelock: u64,
+    pub max_collection_size: usize,
+    pub max_collection_title_length: usize,
+    pub max_drafts: usize,
+    pub max_drafts_size_bytes: usize,
     pub max_blob_size_bytes: usize,
+    pub max_export_page_bytes: usize,
+    pub max_user_search_scan: usize,
+    pub max_user_search_results: usize,
+    pub follow_suggestions_max_followees: usize,
+    pub follow_suggestions_window: usize,
+    pub max_follow_suggestions: usize,
+    pub max_canister_previews: usize,
+    pub canister_preview_ttl: u64,
+
+    pub max_webhook_url_length: usize,
+    pub webhook_batch_size: usize,
+    pub webhook_max_queue: usize,
+    pub webhook_max_failures: u32,
+    pub webhook_backoff: u64,
+    pub webhook_max_response_bytes: u64,
+    pub webhook_delivery_cost: Cycles,
 
     pub min_cycles_for_inviting: Cycles,
 
     pub chores_interval_hours: u64,
+    pub chores_instruction_budget: u64,
     pub online_activity_minutes: u64,
 
     pub revenue_share_activity_weeks: u64,
@@ -85,12 +211,19 @@ pub struct Config {
     pub reactions: &'static [(u16, Cycles)],
 
     pub min_positive_reaction_id: u16,
+    pub max_reactions_per_batch: usize,
 
     pub reaction_fee: i64,
 
     pub max_funding_amount: u64,
+    pub max_vesting_duration: u64,
 
     pub post_deletion_penalty_factor: u32,
+    pub post_deletion_grace_period: u64,
+
+    pub realm_reward_multiplier_range: (u32, u32),
+
+    pub incremental_heap_dumps: bool,
 }
 
 pub const CONFIG: &Config = &Config {
@@ -107,12 +240,30 @@ pub const CONFIG: &Config = &Config {
     proposal_approval_threshold: 1,
     #[cfg(not(feature = "dev"))]
     proposal_approval_threshold: 66,
+    proposal_quorum: 30,
+    proposal_voting_period: 6 * WEEK,
     proposal_controversy_threashold: 10,
     proposal_rejection_penalty: 1000,
+    proposal_veto_threshold: 50,
+    // releases are executed right away, because the upgrade is triggered by the last voter
+    proposal_timelock_release: 0,
+    proposal_timelock_controller: 3 * 24 * HOUR,
+    proposal_timelock_funding: 2 * 24 * HOUR,
+    max_timelocked_executions: 5,
+
+    karma_audit_batch_size: 500,
 
     total_supply: 100_000_000,
 
     min_cycle_balance_main: 2 * ICP_CYCLES_PER_XDR,
+    // buckets aren't topped up if the main canister would fall below this balance
+    critical_cycle_balance_main: ICP_CYCLES_PER_XDR / 2,
+    // expensive writes are rejected below this balance or above this stable memory size
+    degraded_cycle_balance: ICP_CYCLES_PER_XDR,
+    degraded_stable_memory_size: 380 << 30,
+    bucket_top_up_interval: 6 * HOUR,
+    bucket_min_cycle_balance: ICP_CYCLES_PER_XDR,
+    bucket_top_up_cycles: ICP_CYCLES_PER_XDR,
 
     #[cfg(feature = "dev")]
     report_confirmation_percentage: 10,
@@ -126,14 +277,47 @@ pub const CONFIG: &Config = &Config {
 
     minimal_tip: 1,
     tipping_fee: 1,
+    minimal_token_tip: 10,
+    minimal_bounty: 100,
+    max_bounty_duration_hours: 30 * 24,
 
-    max_bucket_size: 1024 * 1024 * 1024 * 31, // 31Gb
+    ckbtc_ledger: "mxzaz-hqaaa-aaaar-qaada-cai",
+    // in satoshis
+    ckbtc_fee: 10,
+    minimal_ckbtc_tip: 100,
 
-    max_posts_per_hour: 3,
-    max_comments_per_hour: 15,
+    max_bucket_size: 1024 * 1024 * 1024 * 31, // 31Gb
+    bucket_write_attempts: 3,
+    // failed buckets are skipped for new writes within this time
+    bucket_failure_backoff: 24 * HOUR,
+    blob_compression_threshold: 1024,
+    storage_metrics_interval: 6 * HOUR,
+
+    max_posts_per_window: 3,
+    max_comments_per_window: 15,
+    rate_limit_window: HOUR,
+    max_scheduled_posts: 10,
+    max_schedule_ahead: 4 * WEEK,
+    max_post_edits: 5,
+    // edits within this time after the last revision aren't recorded
+    post_edit_grace_period: HOUR / 12,
 
     feed_page_size: 30,
 
+    max_search_term_length: 32,
+    // search relevance of posts halves after this period
+    search_recency_period: 4 * WEEK,
+    atom_feed_size: 20,
+    // at ~120 bytes per URL, a sitemap part stays well below the response size limit
+    sitemap_max_urls: 10000,
+
+    max_user_interests: 50,
+    recommendation_window: 1000,
+    recommendation_interest_weight: 3,
+    recommendation_following_weight: 5,
+    recommendation_engagement_weight: 1,
+    recommendation_max_posts_per_author: 2,
+
     min_cycles_minted: 1000,
 
     reporting_penalty: 200,
@@ -148,15 +332,49 @@ pub const CONFIG: &Config = &Config {
 
     max_realm_name: 12,
     max_realm_logo_len: 16 * 1024,
+    max_realm_tiers: 5,
+    realm_tiers_batch_size: 20,
+    max_realm_invites: 20,
+    min_realm_rewards_period_hours: 24,
+    max_pinned_posts: 5,
+    max_realm_blocked_patterns: 50,
+    max_blocked_pattern_length: 100,
+    max_blocked_pattern_nesting: 10,
+    max_blocked_patterns_size: 1 << 20,
 
     post_deletion_penalty_factor: 10,
+    // deleted posts can be restored by their authors within this period
+    post_deletion_grace_period: WEEK,
+
+    // the bounds of realm reward multipliers in percent
+    realm_reward_multiplier_range: (50, 200),
+
+    // serialize only the posts, users and realms changed since the last upgrade; releases
+    // without incremental dumps can't load such heaps, so a rollback needs a full dump via the
+    // `heap_to_stable` endpoint first (see memory::SECTIONS_FLAG)
+    incremental_heap_dumps: true,
 
     voting_reward: 5,
 
     response_reward: 1,
+    quote_reward: 1,
+    referral_reward: 100,
+    referral_min_posts: 5,
+    referral_min_karma: 25,
 
     inactivity_penalty: 45,
     inactivity_duration_weeks: 4,
+    karma_decay_percentage: 2,
+    karma_decay_period: WEEK,
+
+    badge_upvotes: 100,
+    badge_streak_days: 30,
+
+    onboarding_rewards: &[
+        (OnboardingStep::Profile, 100),
+        (OnboardingStep::FirstPost, 200),
+        (OnboardingStep::FirstFollow, 100),
+    ],
     revenue_share_activity_weeks: 2,
 
     stalwart_percentage: 3,
@@ -164,24 +382,77 @@ pub const CONFIG: &Config = &Config {
     min_stalwart_account_age_weeks: 26,
     stalwart_moderation_reward: 20,
 
+    coordination_min_post_length: 40,
+    coordination_window_hours: 6,
+    coordination_window_size: 1000,
+    coordination_max_distance: 3,
+    coordination_min_accounts: 3,
+    coordination_min_linked_accounts: 2,
+    coordination_auto_hold: false,
+
+    spam_hold_threshold: 80,
+    spam_link_density_weight: 60,
+    spam_duplicate_weight: 40,
+    spam_duplicate_lookback: 10,
+    spam_new_account_weight: 20,
+    spam_new_account_age: WEEK,
+    spam_low_karma_weight: 20,
+
     hot_post_reactions_percentage: 0.01,
     hot_post_comments_percentage: 0.006,
+    trending_window: 1000,
+    trending_gravity: 1.8,
+    featured_post_window: 2 * 24 * HOUR,
 
     max_post_length: 15000,
+    max_post_attachments: 16,
+    max_realm_post_length: 50000,
+    max_realm_post_attachments: 32,
     max_tag_length: 20,
     max_user_info_length: 500,
+    max_collections: 20,
+    max_guardians: 7,
+    recovery_timelock: WEEK,
+    max_collection_size: 200,
+    max_collection_title_length: 64,
+    max_drafts: 10,
+    max_drafts_size_bytes: 64 * 1024,
     max_blob_size_bytes: 460800,
+    // stays well below the 2MB limit of a response
+    max_export_page_bytes: 1024 * 1024,
+    max_user_search_scan: 200,
+    max_user_search_results: 10,
+    follow_suggestions_max_followees: 100,
+    follow_suggestions_window: 1000,
+    max_follow_suggestions: 20,
+    max_canister_previews: 1000,
+    canister_preview_ttl: 24 * HOUR,
+
+    max_webhook_url_length: 256,
+    webhook_batch_size: 25,
+    webhook_max_queue: 200,
+    // webhooks get disabled after this many consecutive failed deliveries
+    webhook_max_failures: 5,
+    // doubles with every consecutive failure
+    webhook_backoff: HOUR / 4,
+    webhook_max_response_bytes: 2048,
+    // charged for every delivered batch, covering the cycles of the HTTP outcall
+    webhook_delivery_cost: 1,
 
     online_activity_minutes: 10 * 60000000000_u64,
     chores_interval_hours: 24 * HOUR,
+    // scheduled tasks beyond this many instructions per tick get deferred to the next tick
+    chores_instruction_budget: 5_000_000_000,
 
     distribution_interval_hours: WEEK,
 
     reactions: &[(1, -3), (100, 10), (50, 5), (51, 5), (10, 1)],
 
     min_positive_reaction_id: 10,
+    max_reactions_per_batch: 50,
 
     reaction_fee: 1,
 
     max_funding_amount: 10000,
+    max_vesting_duration: 4 * 365 * 24 * HOUR,
 };
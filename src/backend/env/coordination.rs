@@ -0,0 +1,268 @@
+use super::config::CONFIG;
+use super::post::PostId;
+use super::user::UserId;
+use super::{State, HOUR};
+use ic_cdk::export::candid::Principal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    post_id: PostId,
+    user_id: UserId,
+    timestamp: u64,
+    fingerprint: u64,
+}
+
+// Detector parameters, changeable via proposals.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub min_post_length: usize,
+    pub window_hours: u64,
+    pub window_size: usize,
+    // max hamming distance between fingerprints of near-duplicate posts
+    pub max_distance: u32,
+    pub min_accounts: usize,
+    pub min_linked_accounts: usize,
+    pub auto_hold: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            min_post_length: CONFIG.coordination_min_post_length,
+            window_hours: CONFIG.coordination_window_hours,
+            window_size: CONFIG.coordination_window_size,
+            max_distance: CONFIG.coordination_max_distance,
+            min_accounts: CONFIG.coordination_min_accounts,
+            min_linked_accounts: CONFIG.coordination_min_linked_accounts,
+            auto_hold: CONFIG.coordination_auto_hold,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window_hours == 0 || self.window_size == 0 {
+            return Err("the window must be non-empty".into());
+        }
+        if self.max_distance >= 32 {
+            return Err("the max distance must be below 32 bits".into());
+        }
+        if self.min_accounts < 2 || self.min_linked_accounts < 2 {
+            return Err("a burst requires at least 2 accounts".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Detector {
+    recent: VecDeque<Entry>,
+    // flagged post -> near-duplicate posts from other accounts
+    pub flagged: BTreeMap<PostId, Vec<PostId>>,
+    pub held: BTreeSet<PostId>,
+    #[serde(default)]
+    pub settings: Settings,
+}
+
+// Computes a 64-bit simhash over the words of the normalized text, so that
+// near-identical texts have fingerprints with a small hamming distance.
+pub fn fingerprint(body: &str) -> u64 {
+    let mut weights = [0i32; 64];
+    for word in body
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let mut hasher = Sha256::new();
+        hasher.update(word.to_lowercase().as_bytes());
+        let mut bytes: [u8; 8] = Default::default();
+        bytes.copy_from_slice(&hasher.finalize()[..8]);
+        let hash = u64::from_be_bytes(bytes);
+        for (i, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash & (1 << i) > 0 { 1 } else { -1 };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |acc, (i, _)| acc | (1 << i))
+}
+
+// Two accounts are considered linked if one invited the other, they were invited by the
+// same user or one follows the other.
+fn linked(state: &State, a: UserId, b: UserId) -> bool {
+    match (state.users.get(&a), state.users.get(&b)) {
+        (Some(a), Some(b)) => {
+            a.invited_by == Some(b.id)
+                || b.invited_by == Some(a.id)
+                || a.invited_by.is_some() && a.invited_by == b.invited_by
+                || a.followees.contains(&b.id)
+                || b.followees.contains(&a.id)
+        }
+        _ => false,
+    }
+}
+
+// Records the post fingerprint and flags the post if a burst of near-duplicate posts from
+// other accounts was observed recently. Returns true if the post was flagged.
+pub fn check(state: &mut State, post_id: PostId) -> bool {
+    let (user_id, timestamp, body) = match state.posts.get(&post_id) {
+        Some(post) => (post.user, post.timestamp, post.body.clone()),
+        None => return false,
+    };
+    let settings = state.detector.settings.clone();
+    if body.chars().count() < settings.min_post_length {
+        return false;
+    }
+    let fingerprint = fingerprint(&body);
+    let window_start = timestamp.saturating_sub(settings.window_hours * HOUR);
+    let detector = &mut state.detector;
+    detector
+        .recent
+        .retain(|entry| entry.timestamp >= window_start);
+    let matches = detector
+        .recent
+        .iter()
+        .filter(|entry| {
+            entry.user_id != user_id
+                && (entry.fingerprint ^ fingerprint).count_ones() <= settings.max_distance
+        })
+        .map(|entry| (entry.post_id, entry.user_id))
+        .collect::<Vec<_>>();
+    detector.recent.push_back(Entry {
+        post_id,
+        user_id,
+        timestamp,
+        fingerprint,
+    });
+    while detector.recent.len() > settings.window_size {
+        detector.recent.pop_front();
+    }
+
+    let authors = matches
+        .iter()
+        .map(|(_, author)| *author)
+        .collect::<BTreeSet<_>>();
+    let linked_authors = authors
+        .iter()
+        .filter(|author| linked(state, user_id, **author))
+        .count();
+    if authors.len() + 1 < settings.min_accounts
+        && linked_authors + 1 < settings.min_linked_accounts
+    {
+        return false;
+    }
+
+    let detector = &mut state.detector;
+    detector
+        .flagged
+        .insert(post_id, matches.iter().map(|(id, _)| *id).collect());
+    while detector.flagged.len() > settings.window_size {
+        detector.flagged.pop_first();
+    }
+    if settings.auto_hold {
+        detector.held.insert(post_id);
+    }
+    let message = format!(
+        "Post [{0}](#/post/{0}) was flagged as a part of a coordinated burst of `{1}` similar posts from `{2}` accounts",
+        post_id,
+        matches.len() + 1,
+        authors.len() + 1
+    );
+    state.logger.info(&message);
+    state.notify_users(&|user| user.stalwart, message);
+    true
+}
+
+pub fn release(state: &mut State, principal: Principal, post_id: PostId) -> Result<(), String> {
+    if !state
+        .principal_to_user(principal)
+        .map(|user| user.stalwart)
+        .unwrap_or_default()
+    {
+        return Err("only stalwarts can release posts".into());
+    }
+    state.detector.flagged.remove(&post_id);
+    if !state.detector.held.remove(&post_id) {
+        return Err("post is not held".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::tests::{create_user, pr};
+    use crate::post::Post;
+
+    fn post(state: &mut State, user_id: UserId, body: &str, timestamp: u64) -> PostId {
+        let id = state.next_post_id;
+        state.next_post_id += 1;
+        let mut post = Post::new(
+            user_id,
+            Default::default(),
+            body.to_string(),
+            timestamp,
+            None,
+            None,
+            None,
+        );
+        post.id = id;
+        state.posts.insert(id, post);
+        id
+    }
+
+    #[test]
+    fn test_coordinated_burst() {
+        let mut state = State::default();
+        let users = (0..5)
+            .map(|i| create_user(&mut state, pr(i)))
+            .collect::<Vec<_>>();
+
+        let spam = "Buy the new amazing token right now, it will go to the moon very soon!";
+        let variation = "Buy the new amazing token right now, it will go to the moon very soon!!!";
+
+        // unrelated similar posts from two unlinked accounts aren't flagged
+        let id = post(&mut state, users[0], spam, 0);
+        assert!(!check(&mut state, id));
+        let id = post(&mut state, users[1], variation, HOUR);
+        assert!(!check(&mut state, id));
+
+        // posts outside of the window are forgotten
+        let later = (state.detector.settings.window_hours + 2) * HOUR;
+        let id = post(&mut state, users[2], spam, later);
+        assert!(!check(&mut state, id));
+
+        // a burst from multiple accounts is flagged
+        let id = post(&mut state, users[3], variation, later + 1);
+        assert!(!check(&mut state, id));
+        let id = post(&mut state, users[4], spam, later + 2);
+        assert!(check(&mut state, id));
+        assert_eq!(state.detector.flagged.get(&id).unwrap().len(), 2);
+
+        // a different post isn't flagged
+        let id = post(
+            &mut state,
+            users[0],
+            "Just finished reading a great book about the history of computing.",
+            later + 3,
+        );
+        assert!(!check(&mut state, id));
+
+        // with adjusted settings, the burst is held automatically
+        state.detector.settings.auto_hold = true;
+        let id = post(&mut state, users[1], variation, later + 4);
+        assert!(check(&mut state, id));
+        assert!(state.detector.held.contains(&id));
+
+        // and posts of two accounts suffice
+        state.detector.settings.min_accounts = 2;
+        let id = post(&mut state, users[0], spam, later * 3);
+        assert!(!check(&mut state, id));
+        let id = post(&mut state, users[1], spam, later * 3 + 1);
+        assert!(check(&mut state, id));
+    }
+}
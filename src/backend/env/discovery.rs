@@ -0,0 +1,199 @@
+use super::post::{mentioned_users, Post, PostId};
+use super::user::UserId;
+use super::State;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// Increase to rebuild the index on the next upgrade.
+const INDEX_VERSION: u32 = 1;
+
+// Posts by the users mentioned in them and by their lower-cased tags.
+#[derive(Default, Serialize, Deserialize)]
+pub struct DiscoveryIndex {
+    version: u32,
+    mentions: BTreeMap<UserId, BTreeSet<PostId>>,
+    tags: BTreeMap<String, BTreeSet<PostId>>,
+}
+
+impl DiscoveryIndex {
+    // Indexes all posts which are not deleted; mentions are resolved with the current users.
+    pub fn build(state: &State) -> Self {
+        let mut index = Self {
+            version: INDEX_VERSION,
+            ..Default::default()
+        };
+        for post in state.posts.values().filter(|post| !post.deleted()) {
+            index.insert(state, post);
+        }
+        index
+    }
+
+    pub fn outdated(&self) -> bool {
+        self.version != INDEX_VERSION
+    }
+
+    fn insert(&mut self, state: &State, post: &Post) {
+        for user_id in mentioned_users(state, post.user, &post.text()) {
+            self.mentions.entry(user_id).or_default().insert(post.id);
+        }
+        for tag in &post.tags {
+            self.tags
+                .entry(tag.to_lowercase())
+                .or_default()
+                .insert(post.id);
+        }
+    }
+
+    fn delete(&mut self, state: &State, post: &Post) {
+        for user_id in mentioned_users(state, post.user, &post.text()) {
+            if let Some(posts) = self.mentions.get_mut(&user_id) {
+                posts.remove(&post.id);
+                if posts.is_empty() {
+                    self.mentions.remove(&user_id);
+                }
+            }
+        }
+        for tag in &post.tags {
+            let tag = tag.to_lowercase();
+            if let Some(posts) = self.tags.get_mut(&tag) {
+                posts.remove(&post.id);
+                if posts.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+
+    // Returns the posts mentioning the user, newest first.
+    pub fn mentions(&self, user_id: UserId) -> impl Iterator<Item = PostId> + '_ {
+        self.mentions
+            .get(&user_id)
+            .into_iter()
+            .flat_map(|posts| posts.iter().rev().copied())
+    }
+
+    // Returns the posts with all of the lower-cased tags, newest first.
+    pub fn tagged<'a>(&'a self, tags: &'a HashSet<String>) -> impl Iterator<Item = PostId> + 'a {
+        let mut lists = tags
+            .iter()
+            .map(|tag| self.tags.get(tag))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        lists.sort_unstable_by_key(|posts| posts.len());
+        let (shortest, rest) = match lists.split_first() {
+            Some((shortest, rest)) => (Some(*shortest), rest.to_vec()),
+            None => (None, Vec::new()),
+        };
+        shortest
+            .into_iter()
+            .flat_map(|posts| posts.iter().rev().copied())
+            .filter(move |id| rest.iter().all(|posts| posts.contains(id)))
+    }
+}
+
+// Adds the post to the index; called after the post was stored.
+pub fn index(state: &mut State, post_id: PostId) {
+    let mut index = std::mem::take(&mut state.discovery_index);
+    if let Some(post) = state.posts.get(&post_id) {
+        index.insert(state, post);
+    }
+    state.discovery_index = index;
+}
+
+// Removes the post from the index; called before the post gets changed or deleted.
+pub fn unindex(state: &mut State, post_id: PostId) {
+    let mut index = std::mem::take(&mut state.discovery_index);
+    if let Some(post) = state.posts.get(&post_id) {
+        index.delete(state, post);
+    }
+    state.discovery_index = index;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post::{add, edit},
+        tests::{create_user, pr},
+        CONFIG,
+    };
+
+    #[actix_rt::test]
+    async fn test_incremental_maintenance() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(1000, "")
+            .unwrap();
+        let name = |state: &State, id: UserId| state.users.get(&id).unwrap().name.clone();
+        let (one, two) = (name(&state, 1), name(&state, 2));
+        let mut posts = Vec::new();
+        for (i, body) in [
+            format!("Hi @{} about #Rust", one),
+            format!("More #rust and #wasm for @{}", two),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let time = i as u64 * CONFIG.rate_limit_window;
+            posts.push(
+                add(
+                    &mut state,
+                    body.clone(),
+                    vec![],
+                    pr(0),
+                    time,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let (first, second) = (posts[0], posts[1]);
+        let tagged = |state: &State, tags: &[&str]| {
+            let tags = tags.iter().map(|tag| tag.to_string()).collect();
+            state.discovery_index.tagged(&tags).collect::<Vec<_>>()
+        };
+        let mentions = |state: &State, id| state.discovery_index.mentions(id).collect::<Vec<_>>();
+        assert_eq!(tagged(&state, &["rust"]), vec![second, first]);
+        assert_eq!(tagged(&state, &["rust", "wasm"]), vec![second]);
+        assert_eq!(mentions(&state, 1), vec![first]);
+
+        // edits move the post between the entries
+        edit(
+            &mut state,
+            first,
+            format!("Hi @{} about #wasm", two),
+            vec![],
+            "".into(),
+            None,
+            pr(0),
+            2 * CONFIG.rate_limit_window,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tagged(&state, &["rust"]), vec![second]);
+        assert_eq!(tagged(&state, &["wasm"]), vec![second, first]);
+        assert!(mentions(&state, 1).is_empty());
+        assert_eq!(mentions(&state, 2), vec![second, first]);
+
+        // deleted posts are removed and restored posts come back
+        state.delete_post(pr(0), second, vec![], 0).unwrap();
+        assert!(tagged(&state, &["rust"]).is_empty());
+        assert_eq!(mentions(&state, 2), vec![first]);
+        state.restore_post(pr(0), second, 0).unwrap();
+        assert_eq!(mentions(&state, 2), vec![second, first]);
+
+        // a rebuilt index matches the incrementally maintained one
+        let index = DiscoveryIndex::build(&state);
+        assert!(!index.outdated());
+        assert_eq!(index.tags, state.discovery_index.tags);
+        assert_eq!(index.mentions, state.discovery_index.mentions);
+    }
+}
@@ -0,0 +1,165 @@
+use super::post::{Post, PostId};
+use super::user::User;
+use super::{decode_cursor, encode_cursor, State};
+use ic_cdk::export::Principal;
+use serde::Serialize;
+
+// A page of the user's data export; the profile (including bookmarks, drafts and
+// collections) comes with the first page, posts and comments follow newest first.
+#[derive(Default, Serialize)]
+pub struct Export {
+    pub profile: Option<User>,
+    pub posts: Vec<Post>,
+    pub comments: Vec<Post>,
+    pub cursor: Option<String>,
+}
+
+// Assembles the export page following the cursor, limited by the serialized size of the posts.
+pub fn export(
+    state: &State,
+    principal: Principal,
+    cursor: Option<String>,
+    max_bytes: usize,
+) -> Result<Export, String> {
+    let user = state.principal_to_user(principal).ok_or("no user found")?;
+    let last_seen = decode_cursor(cursor)?;
+    let mut export = Export::default();
+    if last_seen.is_none() {
+        let mut profile = user.clone();
+        profile.balance = state
+            .balances
+            .get(&super::account(user.principal))
+            .copied()
+            .unwrap_or_default();
+        export.profile = Some(profile);
+    }
+
+    let mut bytes = 0;
+    let mut last_id: Option<PostId> = None;
+    for post in user
+        .posts
+        .iter()
+        .rev()
+        .filter(|id| last_seen.map(|last_id| **id < last_id).unwrap_or(true))
+        .filter_map(|id| state.posts.get(id))
+        // deleted posts have their content removed
+        .filter(|post| !post.deleted())
+    {
+        // archived bodies are read back from the cold storage
+        let post = post.restored();
+        let size = serde_json::to_vec(&post)
+            .map_err(|err| err.to_string())?
+            .len();
+        // a page contains at least one post
+        if last_id.is_some() && bytes + size > max_bytes {
+            export.cursor = last_id.map(encode_cursor);
+            break;
+        }
+        bytes += size;
+        last_id = Some(post.id);
+        if post.parent.is_some() {
+            export.comments.push(post)
+        } else {
+            export.posts.push(post)
+        }
+    }
+    Ok(export)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        config::CONFIG,
+        post::add,
+        tests::{create_user, pr},
+    };
+
+    async fn new_post(
+        state: &mut State,
+        principal: Principal,
+        body: &str,
+        parent: Option<PostId>,
+    ) -> PostId {
+        let time = state.posts.len() as u64 * CONFIG.rate_limit_window;
+        add(
+            state,
+            body.into(),
+            vec![],
+            principal,
+            time,
+            parent,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_export() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        let other = new_post(&mut state, pr(1), "other user's post", None).await;
+        let post = new_post(&mut state, pr(0), "first post", None).await;
+        let comment = new_post(&mut state, pr(0), "a comment", Some(other)).await;
+        let deleted = new_post(&mut state, pr(0), "deleted post", None).await;
+        let last = new_post(&mut state, pr(0), "last post", None).await;
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(1000, "")
+            .unwrap();
+        state
+            .delete_post(pr(0), deleted, vec!["deleted post".into()], 0)
+            .unwrap();
+        let user = state.principal_to_user_mut(pr(0)).unwrap();
+        user.toggle_bookmark(other);
+        user.save_draft(None, "my draft".into(), None, 0).unwrap();
+        state
+            .principal_to_user_mut(pr(1))
+            .unwrap()
+            .save_draft(None, "secret draft".into(), None, 0)
+            .unwrap();
+
+        assert_eq!(
+            export(&state, pr(2), None, usize::MAX).err(),
+            Some("no user found".into())
+        );
+
+        // everything fits into one page
+        let page = export(&state, pr(0), None, usize::MAX).unwrap();
+        assert_eq!(page.cursor, None);
+        let ids = |posts: &[Post]| posts.iter().map(|post| post.id).collect::<Vec<_>>();
+        assert_eq!(ids(&page.posts), vec![last, post]);
+        assert_eq!(ids(&page.comments), vec![comment]);
+        let profile = page.profile.unwrap();
+        assert_eq!(profile.id, 0);
+        assert_eq!(profile.drafts.len(), 1);
+        // the bookmarked post of another user is only referenced
+        let json = serde_json::to_string(&profile).unwrap();
+        assert!(json.contains(&format!("\"bookmarks\":[{}]", other)));
+        assert!(!json.contains("secret draft"));
+        assert!(!json.contains("other user's post"));
+
+        // pagination by size
+        let mut cursor = None;
+        let mut exported = Vec::new();
+        loop {
+            let page = export(&state, pr(0), cursor.clone(), 1).unwrap();
+            assert_eq!(page.profile.is_some(), cursor.is_none());
+            assert_eq!(page.posts.len() + page.comments.len(), 1);
+            exported.extend(ids(&page.posts).into_iter().chain(ids(&page.comments)));
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(exported, vec![last, comment, post]);
+        assert_eq!(
+            export(&state, pr(0), Some("-".into()), 1).err(),
+            Some("invalid cursor".into())
+        );
+    }
+}
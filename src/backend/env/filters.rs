@@ -0,0 +1,95 @@
+use super::config::CONFIG;
+use regex::{RegexSet, RegexSetBuilder};
+
+// Compiles the blocked patterns of a realm into one case-insensitive set. The regex engine
+// matches in linear time without backtracking and the size limits bound the compiled
+// program, so neither compiling nor matching gets expensive.
+fn compile(patterns: &[String]) -> Result<RegexSet, String> {
+    RegexSetBuilder::new(patterns)
+        .case_insensitive(true)
+        .size_limit(CONFIG.max_blocked_patterns_size)
+        .dfa_size_limit(CONFIG.max_blocked_patterns_size)
+        .nest_limit(CONFIG.max_blocked_pattern_nesting)
+        .build()
+        .map_err(|err| format!("invalid pattern: {}", err))
+}
+
+// Checks the patterns before they get stored in a realm.
+pub fn validate(patterns: &[String]) -> Result<(), String> {
+    if patterns.len() > CONFIG.max_realm_blocked_patterns {
+        return Err(format!(
+            "not more than {} patterns are allowed",
+            CONFIG.max_realm_blocked_patterns
+        ));
+    }
+    if patterns.iter().any(|pattern| {
+        pattern.trim().is_empty() || pattern.chars().count() > CONFIG.max_blocked_pattern_length
+    }) {
+        return Err(format!(
+            "patterns must be non-empty and not longer than {} characters",
+            CONFIG.max_blocked_pattern_length
+        ));
+    }
+    compile(patterns).map(|_| ())
+}
+
+// Compiles the patterns once, so that the set can be cached by the realm.
+pub fn blocklist(patterns: &[String]) -> Option<RegexSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+    compile(patterns).ok()
+}
+
+// Returns true if any pattern matches the text. Patterns without a compiled set block all
+// content, so that a failed compilation doesn't let blocked content through.
+pub fn blocked(patterns: &[String], blocklist: Option<&RegexSet>, text: &str) -> bool {
+    !patterns.is_empty() && blocklist.map(|set| set.is_match(text)).unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_validation() {
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            validate(&patterns(&["spam", r"buy\s+now", "(a+)+$"])),
+            Ok(())
+        );
+        assert!(validate(&patterns(&["(unclosed"]))
+            .unwrap_err()
+            .starts_with("invalid pattern"));
+        assert!(validate(&patterns(&[""])).is_err());
+        assert!(validate(&patterns(&[
+            &"a".repeat(CONFIG.max_blocked_pattern_length + 1)
+        ]))
+        .is_err());
+        // patterns compiling into huge programs are rejected
+        assert!(validate(&patterns(&["(\\w{100}){100}"]))
+            .unwrap_err()
+            .starts_with("invalid pattern"));
+        let too_many = vec!["x".to_string(); CONFIG.max_realm_blocked_patterns + 1];
+        assert!(validate(&too_many).is_err());
+        // nested groups beyond the limit
+        let nested = format!(
+            "{}a{}",
+            "(".repeat(CONFIG.max_blocked_pattern_nesting as usize + 1),
+            ")".repeat(CONFIG.max_blocked_pattern_nesting as usize + 1)
+        );
+        assert!(validate(&patterns(&[&nested])).is_err());
+
+        // matching ignores the case
+        let spam = patterns(&["spam"]);
+        let set = blocklist(&spam);
+        assert!(blocked(&spam, set.as_ref(), "Buy SPAM here"));
+        assert!(!blocked(&spam, set.as_ref(), "Buy eggs here"));
+        assert!(!blocked(&[], None, "anything"));
+
+        // patterns which couldn't be compiled block everything
+        let invalid = patterns(&["(unclosed"]);
+        assert!(blocklist(&invalid).is_none());
+        assert!(blocked(&invalid, None, "anything"));
+    }
+}
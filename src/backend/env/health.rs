@@ -0,0 +1,104 @@
+use super::config::CONFIG;
+
+// Resource probes of the canister checked before expensive writes.
+#[derive(Default)]
+pub struct Health {
+    // replace the cycle balance and the stable memory size if set
+    cycles: Option<Box<dyn Fn() -> u64>>,
+    memory: Option<Box<dyn Fn() -> u64>>,
+}
+
+impl Health {
+    fn cycles(&self) -> u64 {
+        match &self.cycles {
+            Some(cycles) => cycles(),
+            None => cycle_balance(),
+        }
+    }
+
+    fn memory(&self) -> u64 {
+        match &self.memory {
+            Some(memory) => memory(),
+            None => stable_memory_size(),
+        }
+    }
+
+    // Returns the reason of the degraded mode if the canister runs low on resources.
+    pub fn degraded(&self) -> Option<String> {
+        if self.cycles() < CONFIG.degraded_cycle_balance {
+            return Some("the cycle balance is low".into());
+        }
+        if self.memory() > CONFIG.degraded_stable_memory_size {
+            return Some("the stable memory is almost full".into());
+        }
+        None
+    }
+
+    // Rejects non-critical writes in the degraded mode. Reads and governance actions
+    // don't check the health, so they keep working.
+    pub fn check(&self) -> Result<(), String> {
+        match self.degraded() {
+            Some(reason) => Err(format!("service degraded: {}", reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn cycle_balance() -> u64 {
+    ic_cdk::api::canister_balance()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn cycle_balance() -> u64 {
+    u64::MAX
+}
+
+#[cfg(target_arch = "wasm32")]
+fn stable_memory_size() -> u64 {
+    ic_cdk::api::stable::stable64_size() << 16
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn stable_memory_size() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        proposals::{propose, Payload},
+        tests::{create_user, pr},
+        State,
+    };
+
+    #[actix_rt::test]
+    async fn test_degraded_mode() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(1));
+        state.users.get_mut(&id).unwrap().stalwart = true;
+        assert_eq!(state.health.check(), Ok(()));
+
+        // low cycles degrade the service
+        state.health.cycles = Some(Box::new(|| CONFIG.degraded_cycle_balance - 1));
+        assert_eq!(
+            state.health.check(),
+            Err("service degraded: the cycle balance is low".into())
+        );
+        state.health.cycles = Some(Box::new(|| CONFIG.degraded_cycle_balance));
+        assert_eq!(state.health.check(), Ok(()));
+
+        // an almost full stable memory too
+        state.health.memory = Some(Box::new(|| CONFIG.degraded_stable_memory_size + 1));
+        assert_eq!(
+            state.health.check(),
+            Err("service degraded: the stable memory is almost full".into())
+        );
+
+        // governance stays available
+        assert!(propose(&mut state, pr(1), "test".into(), Payload::Noop, 0)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::ops::Range;
+
+const JPEG_SIGNATURE: &[u8] = &[0xFF, 0xD8];
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Ancillary PNG chunks carrying metadata instead of rendering information.
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+// Removes EXIF and other metadata from JPEG and PNG images. Other blobs and images which
+// can't be parsed are returned unchanged.
+pub fn strip_metadata(blob: &[u8]) -> Cow<'_, [u8]> {
+    let kept = if blob.starts_with(JPEG_SIGNATURE) {
+        jpeg_segments(blob).map(|segments| {
+            segments
+                .into_iter()
+                .filter(|(marker, _)| !jpeg_metadata(*marker))
+                .map(|(_, range)| range)
+                .collect::<Vec<_>>()
+        })
+    } else if blob.starts_with(PNG_SIGNATURE) {
+        png_chunks(blob).map(|chunks| {
+            std::iter::once(0..PNG_SIGNATURE.len())
+                .chain(
+                    chunks
+                        .into_iter()
+                        .filter(|(chunk_type, _)| !PNG_METADATA_CHUNKS.contains(chunk_type))
+                        .map(|(_, range)| range),
+                )
+                .collect::<Vec<_>>()
+        })
+    } else {
+        None
+    };
+    match kept {
+        Some(ranges) if ranges.iter().map(|range| range.len()).sum::<usize>() < blob.len() => {
+            Cow::Owned(
+                ranges
+                    .into_iter()
+                    .flat_map(|range| blob[range].iter().copied())
+                    .collect(),
+            )
+        }
+        _ => Cow::Borrowed(blob),
+    }
+}
+
+// APP1 (EXIF, XMP) to APP13 and APP15 segments and comments; APP0 (JFIF) and APP14 (Adobe)
+// are kept, because decoders rely on them for the color space.
+fn jpeg_metadata(marker: u8) -> bool {
+    matches!(marker, 0xE1..=0xED | 0xEF | 0xFE)
+}
+
+// Splits a JPEG into segments with their markers. Everything starting with the first scan
+// is returned as one segment, because the entropy-coded data has no length prefix.
+fn jpeg_segments(data: &[u8]) -> Option<Vec<(u8, Range<usize>)>> {
+    let mut segments = vec![(0xD8, 0..2)];
+    let mut pos = 2;
+    loop {
+        let start = pos;
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // markers may be preceded by fill bytes
+        while *data.get(pos)? == 0xFF {
+            pos += 1;
+        }
+        let marker = data[pos];
+        pos += 1;
+        match marker {
+            0xD9 | 0xDA => {
+                segments.push((marker, start..data.len()));
+                return Some(segments);
+            }
+            0x01 | 0xD0..=0xD7 => segments.push((marker, start..pos)),
+            _ => {
+                let len = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+                if len < 2 || pos + len > data.len() {
+                    return None;
+                }
+                pos += len;
+                segments.push((marker, start..pos));
+            }
+        }
+    }
+}
+
+// Splits a PNG after the signature into chunks with their types up to the last chunk.
+fn png_chunks(data: &[u8]) -> Option<Vec<(&[u8], Range<usize>)>> {
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk_type = data.get(pos + 4..pos + 8)?;
+        // length, type, data and checksum
+        let end = pos.checked_add(len)?.checked_add(12)?;
+        if end > data.len() {
+            return None;
+        }
+        chunks.push((chunk_type, pos..end));
+        pos = end;
+        if chunk_type == b"IEND" {
+            return Some(chunks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+    use std::io::{Read, Write};
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut crc = Crc::new();
+        crc.update(chunk_type);
+        crc.update(data);
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc.sum().to_be_bytes());
+        chunk
+    }
+
+    // Decodes the pixels of an 8-bit grayscale PNG without interlacing after verifying all
+    // checksums.
+    fn decode_png(png: &[u8]) -> Vec<u8> {
+        assert!(png.starts_with(PNG_SIGNATURE));
+        let mut compressed = Vec::new();
+        for (chunk_type, range) in png_chunks(png).unwrap() {
+            let chunk = &png[range];
+            let data = &chunk[8..chunk.len() - 4];
+            let mut crc = Crc::new();
+            crc.update(chunk_type);
+            crc.update(data);
+            assert_eq!(crc.sum().to_be_bytes(), chunk[chunk.len() - 4..]);
+            if chunk_type == b"IDAT" {
+                compressed.extend_from_slice(data);
+            }
+        }
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut pixels)
+            .unwrap();
+        pixels
+    }
+
+    #[test]
+    fn test_png_metadata_stripping() {
+        // a 2x2 grayscale image, every row starts with a filter byte
+        let pixels = vec![0, 10, 20, 0, 30, 40];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pixels).unwrap();
+        let mut header = Vec::new();
+        header.extend_from_slice(&2_u32.to_be_bytes());
+        header.extend_from_slice(&2_u32.to_be_bytes());
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+        let exif = b"Exif\0\0GPSLatitude 47.37";
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend(png_chunk(b"IHDR", &header));
+        png.extend(png_chunk(b"gAMA", &45455_u32.to_be_bytes()));
+        png.extend(png_chunk(b"eXIf", exif));
+        png.extend(png_chunk(b"tEXt", b"Author\0Alice"));
+        png.extend(png_chunk(b"IDAT", &encoder.finish().unwrap()));
+        png.extend(png_chunk(b"tIME", &[7, 230, 1, 1, 0, 0, 0]));
+        png.extend(png_chunk(b"IEND", &[]));
+
+        let stripped = strip_metadata(&png);
+        assert!(!stripped.windows(exif.len()).any(|w| w == exif));
+        assert!(!stripped.windows(5).any(|w| w == b"Alice"));
+        let types = png_chunks(&stripped)
+            .unwrap()
+            .into_iter()
+            .map(|(chunk_type, _)| chunk_type.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![b"IHDR", b"gAMA", b"IDAT", b"IEND"]);
+        assert_eq!(decode_png(&stripped), pixels);
+
+        // images without metadata stay untouched
+        assert!(matches!(strip_metadata(&stripped), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_jpeg_metadata_stripping() {
+        let segment = |marker: u8, data: &[u8]| {
+            let mut segment = vec![0xFF, marker];
+            segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+            segment.extend_from_slice(data);
+            segment
+        };
+        let exif = b"Exif\0\0Model: Phone";
+        let mut jpeg = JPEG_SIGNATURE.to_vec();
+        jpeg.extend(segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"));
+        jpeg.extend(segment(0xE1, exif));
+        jpeg.extend(segment(0xFE, b"a comment"));
+        jpeg.extend(segment(0xDB, &[0; 65]));
+        jpeg.extend(segment(0xC0, &[8, 0, 1, 0, 1, 1, 1, 0x11, 0]));
+        jpeg.extend(segment(0xC4, &[0; 17]));
+        // the scan header, the entropy-coded data with a stuffed byte and the end marker
+        let scan = [
+            segment(0xDA, &[1, 1, 0, 0, 63, 0]),
+            vec![0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9],
+        ]
+        .concat();
+        jpeg.extend_from_slice(&scan);
+
+        let stripped = strip_metadata(&jpeg);
+        assert!(!stripped.windows(exif.len()).any(|w| w == exif));
+        let markers = jpeg_segments(&stripped)
+            .unwrap()
+            .into_iter()
+            .map(|(marker, _)| marker)
+            .collect::<Vec<_>>();
+        assert_eq!(markers, vec![0xD8, 0xE0, 0xDB, 0xC0, 0xC4, 0xDA]);
+        // only the metadata segments were removed
+        assert_eq!(stripped.len(), jpeg.len() - exif.len() - 4 - 9 - 4);
+        assert!(stripped.ends_with(&scan));
+
+        // other blobs and broken images pass through unchanged
+        for blob in [&b"plain text"[..], &jpeg[..20]].iter() {
+            assert!(matches!(strip_metadata(blob), Cow::Borrowed(_)));
+        }
+    }
+}
@@ -2,6 +2,7 @@ use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet};
 
+use super::config::CONFIG;
 use super::post::{Post, PostId};
 
 pub trait Storable {
@@ -9,11 +10,113 @@ pub trait Storable {
     fn from_bytes(bytes: Vec<u8>) -> Self;
 }
 
+impl Storable for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        String::from_utf8(bytes).expect("couldn't deserialize")
+    }
+}
+
+// Wraps a top-level section of the state and marks it dirty on every mutable access, so that
+// incremental heap dumps can reuse the stored bytes of clean sections. Loaded sections are clean.
+pub struct Tracked<T> {
+    value: T,
+    dirty: bool,
+}
+
+impl<T: Default> Default for Tracked<T> {
+    fn default() -> Self {
+        Self {
+            value: Default::default(),
+            dirty: true,
+        }
+    }
+}
+
+impl<T> Tracked<T> {
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn clean(&mut self) {
+        self.dirty = false;
+    }
+}
+
+impl<T> std::ops::Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> std::ops::DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.dirty = true;
+        &mut self.value
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Tracked<T>
+where
+    &'a T: IntoIterator,
+{
+    type Item = <&'a T as IntoIterator>::Item;
+    type IntoIter = <&'a T as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.value.into_iter()
+    }
+}
+
+impl<T: Serialize> Serialize for Tracked<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Tracked<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(|value| Self {
+            value,
+            dirty: false,
+        })
+    }
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Memory {
     allocator: Allocator,
     #[serde(skip)]
     posts: ObjectManager<PostId, Post>,
+    // segments of the last heap dump allocated by the allocator
+    #[serde(default)]
+    heap: Vec<(u64, u64)>,
+    // the block with both heap root slots
+    #[serde(default)]
+    root_block: Option<u64>,
+    #[serde(skip)]
+    restore: Option<Restore>,
+    // segments of every section of the last incremental heap dump
+    #[serde(skip)]
+    sections: BTreeMap<String, Vec<(u64, u64)>>,
+    // set by a full heap dump, so that the upgrade dump stays readable by older releases
+    #[serde(skip)]
+    full_dumps: bool,
+}
+
+// A heap restore from an off-chain backup in progress. The chunks are staged in a region
+// reserved by the allocator, so that they never overwrite live data.
+struct Restore {
+    len: u64,
+    chunk_size: u64,
+    chunks: BTreeSet<u64>,
+    offset: u64,
+    // bodies of the cold-stored posts of the backup
+    bodies: BTreeMap<PostId, String>,
 }
 
 const INITIAL_OFFSET: u64 = 16;
@@ -27,78 +130,967 @@ impl Memory {
         Ok((offset, buffer.len() as u64))
     }
 
+    // Releases a region returned by `write` once the stored value is deleted; regions
+    // overlapping free space are rejected, so nothing gets freed twice.
+    pub fn free(&mut self, offset: u64, len: u64) -> Result<(), String> {
+        self.allocator.free(offset, len)
+    }
+
     pub fn size(&self) -> u64 {
         self.allocator.boundary
     }
 
-    pub fn read<T: Storable>(offset: u64, len: u64) -> T {
-        let mut bytes = Vec::with_capacity(len as usize);
-        bytes.spare_capacity_mut();
-        unsafe {
-            bytes.set_len(len as usize);
+    // Returns the total free bytes, the size of the largest free segment and the number of
+    // free segments below the boundary.
+    pub fn fragmentation(&self) -> (u64, u64, usize) {
+        (
+            self.allocator.segments.values().sum(),
+            self.allocator.largest_free_segment(),
+            self.allocator.segments.len(),
+        )
+    }
+
+    pub fn read<T: Storable>(offset: u64, len: u64) -> Result<T, String> {
+        checked_read(offset, len, stable64_size() << 16, &mut |offset, buf| {
+            stable64_read(offset, buf)
+        })
+        .map(T::from_bytes)
+    }
+
+    // Moves the given live regions towards the beginning of the memory and returns the
+    // remapping of their offsets. The heap segments and the root block stay in place.
+    pub fn defragment(&mut self, live: &[(u64, u64)]) -> Result<Vec<(u64, u64)>, String> {
+        let mut heap = self.heap.clone();
+        heap.extend(self.root_block.map(|block| (block, ROOT_BLOCK_LEN)));
+        self.allocator
+            .defragment(live, &heap, &mut |from, to, len| {
+                // live regions are within the boundary; a failed read traps and reverts the moves
+                stable64_write(
+                    to,
+                    &read_bytes(from, len).expect("couldn't read a live region"),
+                )
+            })
+    }
+
+    // Returns the allocator for the next heap dump, in which the previous heap segments stay
+    // allocated until the new heap coordinates are committed. The persisted allocator releases
+    // the previous heap segments right away.
+    fn heap_allocator(&mut self) -> (Allocator, Vec<(u64, u64)>) {
+        let allocator = Allocator {
+            segments: self.allocator.segments.clone(),
+            boundary: self.allocator.boundary,
+            ..Default::default()
+        };
+        let previous_heap = std::mem::take(&mut self.heap);
+        self.allocator.release(&previous_heap);
+        (allocator, previous_heap)
+    }
+
+    // Writes a chunk of a heap backup into the staging area after verifying its checksum.
+    pub fn restore_chunk<W: FnMut(u64, &[u8])>(
+        &mut self,
+        index: u64,
+        bytes: &[u8],
+        checksum: u32,
+        len: u64,
+        chunk_size: u64,
+        write: &mut W,
+    ) -> Result<(), String> {
+        if crc32(bytes) != checksum {
+            return Err(format!("checksum mismatch in chunk {}", index));
+        }
+        if self.restore.is_none() {
+            self.restore = Some(Restore {
+                len,
+                chunk_size,
+                chunks: Default::default(),
+                offset: self.allocator.alloc(len)?,
+                bodies: Default::default(),
+            });
+        }
+        let restore = self.restore.as_mut().expect("no restore found");
+        if (restore.len, restore.chunk_size) != (len, chunk_size) {
+            return Err("the chunk belongs to a different backup".into());
+        }
+        let start = index.saturating_mul(chunk_size);
+        if start >= len || bytes.len() as u64 != chunk_size.min(len - start) {
+            return Err(format!("invalid chunk {}", index));
+        }
+        write(restore.offset + start, bytes);
+        restore.chunks.insert(index);
+        Ok(())
+    }
+
+    // Adds bodies of cold-stored posts from the backup to the restore in progress.
+    pub fn restore_cold_bodies(&mut self, bodies: Vec<(PostId, String)>) -> Result<(), String> {
+        let restore = self.restore.as_mut().ok_or("no restore in progress")?;
+        restore.bodies.extend(bodies);
+        Ok(())
+    }
+
+    // Verifies the checksum of the restored heap and returns the restored state with the bodies
+    // of its cold-stored posts moved back into the posts, because the stable memory they pointed
+    // to belongs to the backed up canister. The staging region gets released.
+    pub fn commit_restore<R: Fn(u64, u64) -> Result<Vec<u8>, String>>(
+        &mut self,
+        read: &R,
+    ) -> Result<super::State, String> {
+        let restore = self.restore.as_mut().ok_or("no restore in progress")?;
+        let chunks = restore.len.div_ceil(restore.chunk_size);
+        if restore.chunks.len() as u64 != chunks {
+            let missing = chunks - restore.chunks.len() as u64;
+            return Err(format!("{} chunks are missing", missing));
+        }
+        let mut state = decode_heap(read(restore.offset, restore.len)?, true)?;
+        if let Some(post) = state
+            .posts
+            .values()
+            .find(|post| post.cold_body.is_some() && !restore.bodies.contains_key(&post.id))
+        {
+            return Err(format!(
+                "the cold-stored body of post {} is missing",
+                post.id
+            ));
+        }
+        for post in state.posts.values_mut() {
+            if post.cold_body.take().is_some() {
+                post.body = restore.bodies.remove(&post.id).unwrap_or_default();
+            }
+        }
+        let restore = self.restore.take().expect("no restore found");
+        self.allocator.free(restore.offset, restore.len)?;
+        Ok(state)
+    }
+
+    pub fn metrics(&self) -> MemoryMetrics {
+        let (free_bytes, largest_free_segment, segments) = self.fragmentation();
+        MemoryMetrics {
+            stable_size: self
+                .allocator
+                .mem_size
+                .as_ref()
+                .map(|f| f())
+                .unwrap_or_default(),
+            boundary: self.allocator.boundary,
+            free_bytes,
+            largest_free_segment,
+            segments,
+            allocator_ops: self.allocator.ops,
+            cached_objects: self.posts.cache.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MemoryMetrics {
+    pub stable_size: u64,
+    pub boundary: u64,
+    pub free_bytes: u64,
+    pub largest_free_segment: u64,
+    pub segments: usize,
+    // allocations and deallocations since the last upgrade
+    pub allocator_ops: u64,
+    pub cached_objects: usize,
+}
+
+// The highest bit of the stored heap length signals that the heap is followed by its checksum.
+const CHECKSUM_FLAG: u64 = 1 << 63;
+// The second highest bit signals that the root points to a heap index.
+const INDEX_FLAG: u64 = 1 << 62;
+// The third highest bit signals that the root points to a root block, which consists of the
+// active slot byte and two slots with heap coordinates. A new heap gets committed by writing
+// its coordinates into the inactive slot and flipping the active slot byte afterwards.
+const ROOT_BLOCK_FLAG: u64 = 1 << 61;
+const ROOT_BLOCK_LEN: u64 = 1 + 2 * 16;
+// The fourth highest bit signals that the root points to a section index of an incremental
+// heap dump. Releases older than this flag can't load such heaps, so enabling incremental dumps
+// is a one-way migration. To roll back to such a release, dump the full heap via the
+// `heap_to_stable` endpoint first: all dumps of the running release are full dumps afterwards,
+// including the one before the upgrade.
+const SECTIONS_FLAG: u64 = 1 << 60;
+const CHECKSUM_LEN: u64 = 4;
+const HEAP_SEGMENT_SIZE: u64 = 1 << 26;
+const HEAP_INDEX_VERSION: u32 = 1;
+
+// Lists the segments of a heap dump. New fields must be optional, so that older releases can
+// still read the index; the version only gets increased on incompatible changes.
+#[derive(Serialize, Deserialize)]
+struct HeapIndex {
+    version: u32,
+    segments: Vec<(u64, u64)>,
+}
+
+// Lists the segments of every separately serialized section of an incremental heap dump.
+#[derive(Serialize, Deserialize)]
+struct SectionIndex {
+    version: u32,
+    sections: BTreeMap<String, Vec<(u64, u64)>>,
+}
+
+fn segment_sizes(len: u64, segment_size: u64) -> Vec<u64> {
+    (0..len)
+        .step_by(segment_size as usize)
+        .map(|start| segment_size.min(len - start))
+        .collect()
+}
+
+// Writes bytes at the given offset of a heap split into the segments.
+fn write_segmented<F: FnMut(u64, &[u8])>(
+    segments: &[(u64, u64)],
+    mut offset: u64,
+    mut bytes: &[u8],
+    write: &mut F,
+) {
+    for (start, size) in segments {
+        if bytes.is_empty() {
+            break;
+        }
+        if offset >= *size {
+            offset -= size;
+            continue;
+        }
+        let n = (size - offset).min(bytes.len() as u64) as usize;
+        write(start + offset, &bytes[..n]);
+        bytes = &bytes[n..];
+        offset = 0;
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+const HEAP_CHUNK_SIZE: usize = 1 << 20;
+
+// Counts the serialized bytes without storing them.
+#[derive(Default)]
+struct Counter(u64);
+
+impl std::io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Buffers the serialized bytes and passes them in chunks to the sink at increasing offsets,
+// so that the serialized state never gets materialized on the heap.
+struct ChunkedWriter<F: FnMut(u64, &[u8])> {
+    offset: u64,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    crc: u32,
+    sink: F,
+}
+
+impl<F: FnMut(u64, &[u8])> ChunkedWriter<F> {
+    fn new(offset: u64, chunk_size: usize, sink: F) -> Self {
+        Self {
+            offset,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+            crc: !0,
+            sink,
         }
-        stable64_read(offset, &mut bytes);
-        T::from_bytes(bytes)
     }
 
-    pub fn report_health(&self, logger: &mut super::Logger) {
-        let cache_size = self.posts.cache.len();
-        logger.info(format!(
-            "Memory health: {}, cached_objects=`{}`",
-            self.allocator.health(),
-            cache_size
+    fn flush_buffer(&mut self) {
+        self.crc = crc32_update(self.crc, &self.buffer);
+        (self.sink)(self.offset, &self.buffer);
+        self.offset += self.buffer.len() as u64;
+        self.buffer.clear();
+    }
+
+    // Flushes the remaining bytes and appends the checksum.
+    fn finish(mut self) {
+        self.flush_buffer();
+        let checksum = !self.crc;
+        (self.sink)(self.offset, &checksum.to_be_bytes());
+    }
+}
+
+impl<F: FnMut(u64, &[u8])> std::io::Write for ChunkedWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.chunk_size {
+            self.flush_buffer();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Returns the length of the serialized state including the checksum.
+fn encoded_heap_len<T: Serialize>(state: &T) -> u64 {
+    let mut counter = Counter::default();
+    serde_cbor::to_writer(&mut counter, state).expect("couldn't serialize the state");
+    counter.0 + CHECKSUM_LEN
+}
+
+// Serializes the state with the checksum chunk-wise into the sink starting at the given offset.
+fn stream_heap<T: Serialize, F: FnMut(u64, &[u8])>(
+    state: &T,
+    offset: u64,
+    chunk_size: usize,
+    sink: F,
+) {
+    let mut writer = ChunkedWriter::new(offset, chunk_size, sink);
+    serde_cbor::to_writer(&mut writer, state).expect("couldn't serialize the state");
+    writer.finish();
+}
+
+// Serializes the state and appends the checksum of the serialized buffer.
+fn encode_heap(state: &super::State) -> Vec<u8> {
+    let mut bytes = state.to_bytes();
+    let checksum = crc32(&bytes);
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    bytes
+}
+
+// Verifies the checksum (if present) before deserializing the state.
+fn decode_heap(bytes: Vec<u8>, checksummed: bool) -> Result<super::State, String> {
+    let bytes = if checksummed {
+        verify_checksum(bytes)?
+    } else {
+        bytes
+    };
+    serde_cbor::from_slice(&bytes).map_err(|err| format!("couldn't deserialize the heap: {}", err))
+}
+
+// Strips the checksum from the bytes after verifying it.
+fn verify_checksum(mut bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if (bytes.len() as u64) < CHECKSUM_LEN {
+        return Err("heap is too short to contain a checksum".into());
+    }
+    let mut checksum_bytes: [u8; 4] = Default::default();
+    checksum_bytes.copy_from_slice(&bytes[bytes.len() - CHECKSUM_LEN as usize..]);
+    bytes.truncate(bytes.len() - CHECKSUM_LEN as usize);
+    let expected = u32::from_be_bytes(checksum_bytes);
+    let actual = crc32(&bytes);
+    if expected != actual {
+        return Err(format!(
+            "heap checksum mismatch: expected {:08x}, actual {:08x}",
+            expected, actual
         ));
     }
+    Ok(bytes)
 }
 
-pub fn heap_to_stable(state: &mut super::State) {
-    let mut memory: Memory = Default::default();
-    memory.allocator.segments = state.memory.allocator.segments.clone();
-    memory.allocator.boundary = state.memory.allocator.boundary;
-    let (offset, len) = match memory.write(state) {
-        Ok(values) => values,
+pub fn heap_to_stable(state: &mut super::State) -> Result<(), String> {
+    // the segments of the sections get released with the previous heap
+    state.memory.sections.clear();
+    state.memory.full_dumps = true;
+    // without a root block, the heap coordinates are written to the legacy root
+    if state.memory.root_block.is_none() {
+        state.memory.root_block = state.memory.allocator.alloc(ROOT_BLOCK_LEN).ok();
+    }
+    let root_block = state.memory.root_block;
+    let mut write_stable = |offset, bytes: &[u8]| stable64_write(offset, bytes);
+    let (mut allocator, previous_heap) = state.memory.heap_allocator();
+    let len = encoded_heap_len(state);
+    match write_heap(
+        &mut allocator,
+        state,
+        len,
+        HEAP_SEGMENT_SIZE,
+        &mut |offset, bytes| stable64_write(offset, bytes),
+    ) {
+        Ok(((offset, index_len), segments)) => {
+            commit_root(
+                root_block,
+                offset,
+                index_len | INDEX_FLAG,
+                &read_bytes,
+                &mut write_stable,
+            )?;
+            // the new heap is committed, so the previous one can be overwritten
+            allocator.release(&previous_heap);
+            state.memory.allocator.segments = allocator.segments;
+            state.memory.allocator.boundary = allocator.boundary;
+            state.memory.heap = segments;
+        }
         // Plan B: if the allocator ever fails, just dump the heap at the end of stable memory
         Err(err) => {
             state.logger.log(
                 format!("Allocator failed when dumping the heap: {:?}", err),
                 "CRITICAL".into(),
             );
-            let bytes = state.to_bytes();
-            let offset = stable64_size() >> 16;
-            stable64_grow(1 + (bytes.len() as u64 >> 16)).expect("couldn't grow memory");
+            let bytes = encode_heap(state);
+            let offset = stable64_size() << 16;
+            stable64_grow(1 + (bytes.len() as u64 >> 16))
+                .map_err(|err| format!("out of stable memory: {:?}", err))?;
             stable64_write(offset, &bytes);
-            (offset, bytes.len() as u64)
+            commit_root(
+                root_block,
+                offset,
+                bytes.len() as u64 | CHECKSUM_FLAG,
+                &read_bytes,
+                &mut write_stable,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Dumps only the sections changed since the last load or dump if incremental dumps are
+// enabled and no full dump happened since the last load; the full dump is the fallback.
+pub fn dump_heap(state: &mut super::State) -> Result<(), String> {
+    if !CONFIG.incremental_heap_dumps || state.memory.full_dumps {
+        return heap_to_stable(state);
+    }
+    if state.memory.root_block.is_none() {
+        state.memory.root_block = state.memory.allocator.alloc(ROOT_BLOCK_LEN).ok();
+    }
+    let root_block = state.memory.root_block;
+    let stored = std::mem::take(&mut state.memory.sections);
+    let (mut allocator, previous_heap) = state.memory.heap_allocator();
+    match write_sections(
+        &mut allocator,
+        state,
+        &stored,
+        HEAP_SEGMENT_SIZE,
+        &mut |offset, bytes| stable64_write(offset, bytes),
+    ) {
+        Ok(((offset, index_len), new_segments, sections)) => {
+            commit_root(
+                root_block,
+                offset,
+                index_len | SECTIONS_FLAG,
+                &read_bytes,
+                &mut |offset, bytes| stable64_write(offset, bytes),
+            )?;
+            // the segments of reused sections stay allocated
+            let reused = sections
+                .values()
+                .flatten()
+                .filter(|segment| !new_segments.contains(segment))
+                .copied()
+                .collect::<Vec<_>>();
+            allocator.release(
+                &previous_heap
+                    .into_iter()
+                    .filter(|segment| !reused.contains(segment))
+                    .collect::<Vec<_>>(),
+            );
+            state.memory.allocator.segments = allocator.segments;
+            state.memory.allocator.boundary = allocator.boundary;
+            state.memory.heap = reused.into_iter().chain(new_segments).collect();
+            state.memory.sections = sections;
+            state.posts.clean();
+            state.users.clean();
+            state.realms.clean();
+            Ok(())
+        }
+        Err(err) => {
+            state.logger.error(format!(
+                "Incremental heap dump failed, falling back to a full dump: {}",
+                err
+            ));
+            // the previous heap stays allocated until the full dump commits
+            for (offset, len) in &previous_heap {
+                state.memory.allocator.reserve(*offset, *len)?;
+            }
+            state.memory.heap = previous_heap;
+            heap_to_stable(state)
+        }
+    }
+}
+
+// Writes sections of the state into newly allocated segments.
+struct SectionWriter<'a, F: FnMut(u64, &[u8])> {
+    allocator: &'a mut Allocator,
+    segment_size: u64,
+    write: &'a mut F,
+    sections: BTreeMap<String, Vec<(u64, u64)>>,
+    new_segments: Vec<(u64, u64)>,
+}
+
+impl<'a, F: FnMut(u64, &[u8])> SectionWriter<'a, F> {
+    // Serializes the section with a checksum unless the stored segments can be reused.
+    fn section<T: Serialize>(
+        &mut self,
+        name: &str,
+        value: &T,
+        stored: Option<&Vec<(u64, u64)>>,
+    ) -> Result<(), String> {
+        let segments = match stored {
+            Some(segments) => segments.clone(),
+            None => {
+                let len = encoded_heap_len(value);
+                let segments = self
+                    .allocator
+                    .alloc_segments(&segment_sizes(len, self.segment_size))?;
+                self.new_segments.extend_from_slice(&segments);
+                let write = &mut self.write;
+                stream_heap(value, 0, HEAP_CHUNK_SIZE, |offset, chunk| {
+                    write_segmented(&segments, offset, chunk, write)
+                });
+                segments
+            }
+        };
+        self.sections.insert(name.into(), segments);
+        Ok(())
+    }
+
+    // Writes the section index and returns its coordinates.
+    fn index(&mut self) -> Result<(u64, u64), String> {
+        let index = serde_cbor::to_vec(&SectionIndex {
+            version: HEAP_INDEX_VERSION,
+            sections: self.sections.clone(),
+        })
+        .expect("couldn't serialize the section index");
+        let len = index.len() as u64;
+        let offset = self.allocator.alloc(len)?;
+        (self.write)(offset, &index);
+        self.new_segments.push((offset, len));
+        Ok((offset, len))
+    }
+}
+
+// Writes the dirty tracked sections and the rest of the state, reusing the stored segments of
+// clean sections. Returns the index coordinates, the new segments and the segments of all
+// sections.
+#[allow(clippy::type_complexity)]
+fn write_sections<F: FnMut(u64, &[u8])>(
+    allocator: &mut Allocator,
+    state: &mut super::State,
+    stored: &BTreeMap<String, Vec<(u64, u64)>>,
+    segment_size: u64,
+    write: &mut F,
+) -> Result<
+    (
+        (u64, u64),
+        Vec<(u64, u64)>,
+        BTreeMap<String, Vec<(u64, u64)>>,
+    ),
+    String,
+> {
+    let reusable = |name: &str, dirty: bool| stored.get(name).filter(|_| !dirty);
+    let (posts, users, realms) = (
+        std::mem::take(&mut state.posts),
+        std::mem::take(&mut state.users),
+        std::mem::take(&mut state.realms),
+    );
+    let mut writer = SectionWriter {
+        allocator,
+        segment_size,
+        write,
+        sections: Default::default(),
+        new_segments: Default::default(),
+    };
+    let result = writer
+        .section("posts", &*posts, reusable("posts", posts.dirty()))
+        .and_then(|_| writer.section("users", &*users, reusable("users", users.dirty())))
+        .and_then(|_| writer.section("realms", &*realms, reusable("realms", realms.dirty())))
+        .and_then(|_| writer.section("state", state, None))
+        .and_then(|_| writer.index());
+    state.posts = posts;
+    state.users = users;
+    state.realms = realms;
+    match result {
+        Ok(index) => Ok((index, writer.new_segments, writer.sections)),
+        Err(err) => {
+            writer.allocator.release(&writer.new_segments);
+            Err(err)
+        }
+    }
+}
+
+// Reads and verifies all sections of an incremental heap dump and assembles the state.
+#[allow(clippy::type_complexity)]
+fn read_sections<R: Fn(u64, u64) -> Result<Vec<u8>, String>>(
+    offset: u64,
+    len: u64,
+    read: &R,
+) -> Result<(super::State, BTreeMap<String, Vec<(u64, u64)>>), String> {
+    let index: SectionIndex = serde_cbor::from_slice(&read(offset, len)?)
+        .map_err(|err| format!("couldn't deserialize the section index: {}", err))?;
+    if index.version > HEAP_INDEX_VERSION {
+        return Err(format!("unsupported heap index version {}", index.version));
+    }
+    let section = |name: &str| -> Result<Vec<u8>, String> {
+        let segments = index
+            .sections
+            .get(name)
+            .ok_or(format!("section {} is missing", name))?;
+        let mut bytes = Vec::with_capacity(segments.iter().map(|(_, len)| *len as usize).sum());
+        for (offset, len) in segments {
+            bytes.extend_from_slice(&read(*offset, *len)?);
+        }
+        verify_checksum(bytes)
+    };
+    let parse_err = |err: serde_cbor::Error| format!("couldn't deserialize the heap: {}", err);
+    let mut state: super::State = serde_cbor::from_slice(&section("state")?).map_err(parse_err)?;
+    state.posts = serde_cbor::from_slice(&section("posts")?).map_err(parse_err)?;
+    state.users = serde_cbor::from_slice(&section("users")?).map_err(parse_err)?;
+    state.realms = serde_cbor::from_slice(&section("realms")?).map_err(parse_err)?;
+    Ok((state, index.sections))
+}
+
+// Allocates the heap segments and the index, writes the serialized state into the segments
+// and returns the index coordinates together with all allocated segments.
+#[allow(clippy::type_complexity)]
+fn write_heap<F: FnMut(u64, &[u8])>(
+    allocator: &mut Allocator,
+    state: &super::State,
+    len: u64,
+    segment_size: u64,
+    write: &mut F,
+) -> Result<((u64, u64), Vec<(u64, u64)>), String> {
+    let mut segments = allocator.alloc_segments(&segment_sizes(len, segment_size))?;
+    stream_heap(state, 0, HEAP_CHUNK_SIZE, |offset, chunk| {
+        write_segmented(&segments, offset, chunk, write)
+    });
+    let index = serde_cbor::to_vec(&HeapIndex {
+        version: HEAP_INDEX_VERSION,
+        segments: segments.clone(),
+    })
+    .expect("couldn't serialize the heap index");
+    let index_len = index.len() as u64;
+    let offset = match allocator.alloc(index_len) {
+        Ok(offset) => offset,
+        Err(err) => {
+            allocator.release(&segments);
+            return Err(err);
         }
     };
-    stable64_write(0, &offset.to_be_bytes());
-    stable64_write(8, &len.to_be_bytes());
+    write(offset, &index);
+    segments.push((offset, index_len));
+    Ok(((offset, index_len), segments))
 }
 
-pub fn heap_address() -> (u64, u64) {
+fn coordinates(offset: u64, len: u64) -> Vec<u8> {
+    let mut bytes = offset.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes
+}
+
+fn parse_coordinates(bytes: &[u8]) -> (u64, u64) {
     let mut offset_bytes: [u8; 8] = Default::default();
-    stable64_read(0, &mut offset_bytes);
-    let offset = u64::from_be_bytes(offset_bytes);
+    offset_bytes.copy_from_slice(&bytes[..8]);
     let mut len_bytes: [u8; 8] = Default::default();
-    stable64_read(8, &mut len_bytes);
-    let len = u64::from_be_bytes(len_bytes);
-    (offset, len)
+    len_bytes.copy_from_slice(&bytes[8..16]);
+    (
+        u64::from_be_bytes(offset_bytes),
+        u64::from_be_bytes(len_bytes),
+    )
+}
+
+// Makes the given heap coordinates the root, so that an interrupted commit leaves the
+// previous root intact.
+fn commit_root<R: Fn(u64, u64) -> Result<Vec<u8>, String>, W: FnMut(u64, &[u8])>(
+    root_block: Option<u64>,
+    offset: u64,
+    len: u64,
+    read: &R,
+    write: &mut W,
+) -> Result<(), String> {
+    let block = match root_block {
+        Some(block) => block,
+        None => {
+            write(0, &coordinates(offset, len));
+            return Ok(());
+        }
+    };
+    let (root_offset, root_len) = parse_coordinates(&read(0, 16)?);
+    if root_len & ROOT_BLOCK_FLAG > 0 && root_offset == block {
+        let inactive = 1 - (read(block, 1)?[0] & 1);
+        write(block + 1 + 16 * inactive as u64, &coordinates(offset, len));
+        write(block, &[inactive]);
+    } else {
+        // the root block is initialized before the root points to it
+        write(block + 1, &coordinates(offset, len));
+        write(block, &[0]);
+        write(0, &coordinates(block, ROOT_BLOCK_FLAG));
+    }
+    Ok(())
+}
+
+// Returns the heap coordinates from the active root slot with the length including flags.
+fn read_root<R: Fn(u64, u64) -> Result<Vec<u8>, String>>(read: &R) -> Result<(u64, u64), String> {
+    let (offset, len) = parse_coordinates(&read(0, 16)?);
+    if len & ROOT_BLOCK_FLAG == 0 {
+        return Ok((offset, len));
+    }
+    let active = read(offset, 1)?[0] & 1;
+    let slot = read(offset + 1 + 16 * active as u64, 16)?;
+    Ok(parse_coordinates(&slot))
+}
+
+fn heap_root() -> Result<(u64, u64), String> {
+    read_root(&read_bytes)
+}
+
+// Reads len bytes at the offset into a zeroed buffer, if they are within the memory size.
+fn checked_read<F: FnMut(u64, &mut [u8])>(
+    offset: u64,
+    len: u64,
+    mem_size: u64,
+    read: &mut F,
+) -> Result<Vec<u8>, String> {
+    match offset.checked_add(len) {
+        Some(end) if end <= mem_size => {
+            let mut bytes = vec![0; len as usize];
+            read(offset, &mut bytes);
+            Ok(bytes)
+        }
+        _ => Err(format!(
+            "reading {} bytes at offset {} exceeds the memory size {}",
+            len, offset, mem_size
+        )),
+    }
+}
+
+fn read_bytes(offset: u64, len: u64) -> Result<Vec<u8>, String> {
+    checked_read(offset, len, stable64_size() << 16, &mut |offset, buf| {
+        stable64_read(offset, buf)
+    })
+}
+
+fn unflagged(len: u64) -> u64 {
+    len & !CHECKSUM_FLAG & !INDEX_FLAG & !SECTIONS_FLAG
+}
+
+// Returns the data segments of the heap and whether the heap is checksummed.
+fn heap_segments<F: Fn(u64, u64) -> Result<Vec<u8>, String>>(
+    offset: u64,
+    len: u64,
+    read: &F,
+) -> Result<(Vec<(u64, u64)>, bool), String> {
+    let checksummed = len & CHECKSUM_FLAG > 0;
+    let indexed = len & INDEX_FLAG > 0;
+    let sectioned = len & SECTIONS_FLAG > 0;
+    let len = unflagged(len);
+    if sectioned {
+        // every section is checksummed separately
+        let index: SectionIndex = serde_cbor::from_slice(&read(offset, len)?)
+            .map_err(|err| format!("couldn't deserialize the section index: {}", err))?;
+        return Ok((index.sections.into_values().flatten().collect(), false));
+    }
+    if !indexed {
+        return Ok((vec![(offset, len)], checksummed));
+    }
+    let index: HeapIndex = serde_cbor::from_slice(&read(offset, len)?)
+        .map_err(|err| format!("couldn't deserialize the heap index: {}", err))?;
+    if index.version > HEAP_INDEX_VERSION {
+        return Err(format!("unsupported heap index version {}", index.version));
+    }
+    Ok((index.segments, true))
+}
+
+// Returns the chunk of the serialized heap at the given index, the total heap length and the
+// checksum of the chunk. Only checksummed heaps can be backed up, so that restores are verified.
+fn backup_chunk<R: Fn(u64, u64) -> Result<Vec<u8>, String>>(
+    (offset, len): (u64, u64),
+    read: &R,
+    index: u64,
+    chunk_size: u64,
+) -> Result<(Vec<u8>, u64, u32), String> {
+    if len & SECTIONS_FLAG > 0 {
+        return Err("dump the full heap first".into());
+    }
+    let (segments, checksummed) = heap_segments(offset, len, read)?;
+    if !checksummed {
+        return Err("the heap has no checksum".into());
+    }
+    let total = segments.iter().map(|(_, len)| len).sum::<u64>();
+    let start = index.saturating_mul(chunk_size);
+    if start >= total {
+        return Err(format!("chunk {} is out of range", index));
+    }
+    let end = (start + chunk_size).min(total);
+    let mut bytes = Vec::with_capacity((end - start) as usize);
+    let mut segment_start = 0;
+    for (offset, len) in segments {
+        let (from, to) = (start.max(segment_start), end.min(segment_start + len));
+        if from < to {
+            bytes.extend_from_slice(&read(offset + from - segment_start, to - from)?);
+        }
+        segment_start += len;
+    }
+    let checksum = crc32(&bytes);
+    Ok((bytes, total, checksum))
+}
+
+pub fn heap_backup_chunk(index: u64, chunk_size: u64) -> Result<(Vec<u8>, u64, u32), String> {
+    backup_chunk(heap_root()?, &read_bytes, index, chunk_size)
+}
+
+pub fn restore_heap_chunk(
+    memory: &mut Memory,
+    index: u64,
+    bytes: &[u8],
+    checksum: u32,
+    len: u64,
+    chunk_size: u64,
+) -> Result<(), String> {
+    memory.restore_chunk(
+        index,
+        bytes,
+        checksum,
+        len,
+        chunk_size,
+        &mut |offset, bytes| stable64_write(offset, bytes),
+    )
+}
+
+// Replaces the state with the restored one, which takes over the memory layout of the
+// replaced state, and dumps it to the stable memory.
+pub fn commit_heap_restore(state: &mut super::State) -> Result<(), String> {
+    let mut restored = state.memory.commit_restore(&read_bytes)?;
+    // the cold-stored bodies of the replaced state aren't referenced anymore
+    for (offset, len) in state.posts.values().filter_map(|post| post.cold_body) {
+        state.memory.free(offset, len)?;
+    }
+    restored.memory = std::mem::take(&mut state.memory);
+    *state = restored;
+    heap_to_stable(state)
+}
+
+// Returns a page of the bodies of cold-stored posts, which aren't included in heap backups.
+pub fn cold_bodies_backup(
+    state: &super::State,
+    page: usize,
+    page_size: usize,
+) -> Vec<(PostId, String)> {
+    let mut ids = state
+        .posts
+        .values()
+        .filter(|post| post.cold_body.is_some())
+        .map(|post| post.id)
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.into_iter()
+        .skip(page * page_size)
+        .take(page_size)
+        .filter_map(|id| state.posts.get(&id))
+        .map(|post| (post.id, post.text()))
+        .collect()
+}
+
+// Returns the end of the last heap segment or the root block.
+pub fn heap_end() -> Result<u64, String> {
+    let (offset, len) = heap_root()?;
+    let root = (offset, unflagged(len));
+    let (block, block_len) = parse_coordinates(&read_bytes(0, 16)?);
+    let root_block = (block_len & ROOT_BLOCK_FLAG > 0).then_some((block, ROOT_BLOCK_LEN));
+    Ok(heap_segments(offset, len, &read_bytes)?
+        .0
+        .into_iter()
+        .chain(std::iter::once(root))
+        .chain(root_block)
+        .map(|(offset, len)| offset + len)
+        .max()
+        .unwrap_or_default())
+}
+
+fn read_heap<F: Fn(u64, u64) -> Result<Vec<u8>, String>>(
+    offset: u64,
+    len: u64,
+    read: &F,
+) -> Result<(Vec<u8>, bool), String> {
+    let (segments, checksummed) = heap_segments(offset, len, read)?;
+    let mut bytes = Vec::with_capacity(segments.iter().map(|(_, len)| *len as usize).sum());
+    for (offset, len) in segments {
+        bytes.extend_from_slice(&read(offset, len)?);
+    }
+    Ok((bytes, checksummed))
 }
 
 pub fn stable_to_heap() -> super::State {
-    let (offset, len) = heap_address();
+    let (offset, len) = heap_root().expect("couldn't read the heap root");
     ic_cdk::println!("Reading heap from coordinates: {:?}", (offset, len),);
-    Memory::read(offset, len)
+    let (mut state, sections) = if len & SECTIONS_FLAG > 0 {
+        read_sections(offset, unflagged(len), &read_bytes).expect("couldn't restore the heap")
+    } else {
+        let (bytes, checksummed) =
+            read_heap(offset, len, &read_bytes).expect("couldn't read the heap");
+        let state = decode_heap(bytes, checksummed).expect("couldn't restore the heap");
+        (state, Default::default())
+    };
+    // the heap segments stay allocated until the next heap dump is committed
+    let (mut segments, _) = heap_segments(offset, len, &read_bytes).unwrap_or_default();
+    if len & (INDEX_FLAG | SECTIONS_FLAG) > 0 {
+        segments.push((offset, unflagged(len)));
+    }
+    state.memory.sections = sections;
+    for (offset, len) in segments {
+        match state.memory.allocator.reserve(offset, len) {
+            Ok(()) => state.memory.heap.push((offset, len)),
+            Err(err) => ic_cdk::println!("Couldn't reserve the heap segment: {}", err),
+        }
+    }
+    state
 }
 
 #[derive(Serialize, Deserialize)]
 struct Allocator {
     segments: BTreeMap<u64, u64>,
     boundary: u64,
-    #[serde(skip)]
+    #[serde(skip, default = "stable_mem_grow")]
     #[allow(clippy::type_complexity)]
     mem_grow: Option<Box<dyn FnMut(u64) -> Result<u64, String>>>,
-    #[serde(skip)]
+    #[serde(skip, default = "stable_mem_size")]
     mem_size: Option<Box<dyn Fn() -> u64>>,
+    // the memory grows by multiples of this many bytes
+    #[serde(skip, default = "growth_chunk")]
+    growth_chunk: u64,
+    #[serde(skip)]
+    ops: u64,
+}
+
+// Stable memory grows in chunks of 64 pages to amortize the costs of growing.
+const GROWTH_CHUNK_PAGES: u64 = 64;
+
+#[allow(clippy::type_complexity)]
+fn stable_mem_grow() -> Option<Box<dyn FnMut(u64) -> Result<u64, String>>> {
+    Some(Box::new(|n| {
+        stable64_grow((n + 0xffff) >> 16).map_err(|err| format!("out of stable memory: {:?}", err))
+    }))
+}
+
+fn stable_mem_size() -> Option<Box<dyn Fn() -> u64>> {
+    Some(Box::new(|| stable64_size() << 16))
+}
+
+fn growth_chunk() -> u64 {
+    GROWTH_CHUNK_PAGES << 16
 }
 
 impl Default for Allocator {
@@ -106,53 +1098,138 @@ impl Default for Allocator {
         Self {
             segments: Default::default(),
             boundary: INITIAL_OFFSET,
-            mem_size: Some(Box::new(|| stable64_size() << 16)),
-            mem_grow: Some(Box::new(|n| {
-                stable64_grow((n >> 16) + 1)
-                    .map_err(|err| format!("couldn't grow memory: {:?}", err))
-            })),
+            mem_size: stable_mem_size(),
+            mem_grow: stable_mem_grow(),
+            growth_chunk: growth_chunk(),
+            ops: 0,
         }
     }
 }
 
 impl Allocator {
     fn alloc(&mut self, n: u64) -> Result<u64, String> {
-        // find all segments that are big enough
-        let mut candidates = BTreeMap::new();
+        self.alloc_aligned(n, 1)
+    }
+
+    // Allocates n bytes at an offset that is a multiple of align; the gap before it stays free.
+    fn alloc_aligned(&mut self, n: u64, align: u64) -> Result<u64, String> {
+        if align == 0 {
+            return Err("alignment must be positive".into());
+        }
+        let align_up = |offset: u64| offset + (align - offset % align) % align;
+        // find the smallest segment that fits the aligned allocation
+        let mut candidate: Option<(u64, u64)> = None;
         for (start, size) in self.segments.iter() {
-            if size >= &n {
-                candidates.insert(size, start);
+            let needed = align_up(*start) - start + n;
+            if size >= &needed && candidate.map(|(_, c_size)| size <= &c_size).unwrap_or(true) {
+                candidate = Some((*start, *size));
             }
-            if size == &n {
+            if size == &needed {
                 break;
             }
         }
-        let (start, new_segment) = match candidates.first_key_value() {
-            // get the smallest segment from the candidates
-            Some((size, start)) => (
-                **start,
+        let offset = match candidate {
+            Some((start, size)) => {
+                self.segments.remove(&start);
+                let offset = align_up(start);
+                if offset > start {
+                    self.segments.insert(start, offset - start);
+                }
                 // if the segment is larger, create a new rest segment
-                (n < **size).then_some((**start + n, **size - n)),
-            ),
+                if start + size > offset + n {
+                    self.segments.insert(offset + n, start + size - offset - n);
+                }
+                offset
+            }
             // if no large enough segments exist, grow the memory
             _ => {
                 let boundary = self.boundary;
-                self.boundary += n;
-                if self.boundary >= (self.mem_size.as_ref().unwrap())() {
-                    (self.mem_grow.as_mut().unwrap())(n)?;
+                let offset = align_up(boundary);
+                // grow first, so that a failed growth leaves the allocator unchanged
+                let mem_size = (self.mem_size.as_ref().unwrap())();
+                if offset + n > mem_size {
+                    let missing = offset + n - mem_size;
+                    let chunk = self.growth_chunk.max(1);
+                    (self.mem_grow.as_mut().unwrap())(missing + (chunk - missing % chunk) % chunk)?;
                 }
-                (boundary, None)
+                if offset > boundary {
+                    self.segments.insert(boundary, offset - boundary);
+                }
+                self.boundary = offset + n;
+                offset
             }
         };
+        self.ops += 1;
+        Ok(offset)
+    }
+
+    // Allocates segments of the given sizes; either all or none of them get allocated.
+    fn alloc_segments(&mut self, sizes: &[u64]) -> Result<Vec<(u64, u64)>, String> {
+        let mut segments = Vec::new();
+        for size in sizes {
+            match self.alloc(*size) {
+                Ok(offset) => segments.push((offset, *size)),
+                Err(err) => {
+                    self.release(&segments);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(segments)
+    }
+
+    fn release(&mut self, segments: &[(u64, u64)]) {
+        for (offset, size) in segments {
+            if let Err(err) = self.free(*offset, *size) {
+                ic_cdk::println!("Couldn't release segment {:?}: {}", (offset, size), err);
+            }
+        }
+    }
+
+    // Marks the given segment as allocated.
+    fn reserve(&mut self, offset: u64, size: u64) -> Result<(), String> {
+        if offset >= self.boundary {
+            let boundary = self.boundary;
+            self.boundary = offset + size;
+            if offset > boundary {
+                self.free(boundary, offset - boundary)?;
+            }
+            return Ok(());
+        }
+        let (start, segment_size) = self
+            .segments
+            .range(..=offset)
+            .last()
+            .map(|(start, size)| (*start, *size))
+            .filter(|(start, segment_size)| start + segment_size >= offset + size)
+            .ok_or(format!("segment {:?} is not free", (offset, size)))?;
         self.segments.remove(&start);
-        if let Some((start, size)) = new_segment {
-            self.segments.insert(start, size);
+        if offset > start {
+            self.segments.insert(start, offset - start);
         }
-        ic_cdk::println!("Allocated {} bytes, {}", n, self.health());
-        Ok(start)
+        if start + segment_size > offset + size {
+            self.segments
+                .insert(offset + size, start + segment_size - offset - size);
+        }
+        Ok(())
     }
 
     fn free(&mut self, offset: u64, size: u64) -> Result<(), String> {
+        if offset + size > self.boundary {
+            return Err(format!(
+                "deallocating {:?} beyond the boundary {}",
+                (offset, size),
+                self.boundary
+            ));
+        }
+        // a free segment starting within the region means it was already (partially) freed
+        if let Some((start, len)) = self.segments.range(offset..offset + size).next() {
+            return Err(format!(
+                "free segment {:?} overlaps with deallocating {:?}",
+                (start, len),
+                (offset, size)
+            ));
+        }
         let left_segment = self.segments.range(..offset).last().map(|(a, b)| (*a, *b));
         let right_segment = self
             .segments
@@ -191,31 +1268,93 @@ impl Allocator {
                     .expect("no right segment found");
                 self.segments.insert(offset, size + r_size);
             }
+            // the freed segment is the last one, so the boundary shrinks
+            (Some((l_start, l_size)), _)
+                if l_start + l_size == offset && offset + size == self.boundary =>
+            {
+                self.segments
+                    .remove(&l_start)
+                    .expect("no left segment found");
+                self.boundary = l_start;
+            }
             (Some((l_start, l_size)), _) if l_start + l_size == offset => {
                 self.segments
                     .insert(l_start, l_size + size)
                     .expect("no left segment found");
             }
+            _ if offset + size == self.boundary => {
+                self.boundary = offset;
+            }
             _ => {
                 self.segments.insert(offset, size);
             }
         }
-        ic_cdk::println!(
-            "Deallocated segment={:?}, {}",
-            (offset, size),
-            self.health()
-        );
+        self.ops += 1;
         Ok(())
     }
 
-    fn health(&self) -> String {
-        let megabyte = 1024 * 1024;
-        format!(
-            "boundary=`{}Mb`, mem_size=`{}Mb`, segments=`{:?}`",
-            self.boundary / megabyte,
-            self.mem_size.as_ref().map(|f| f()).unwrap_or_default() / megabyte,
-            &self.segments.len(),
-        )
+    // Moves the live regions towards the initial offset around the pinned regions and returns
+    // the new offsets as `(old_offset, new_offset)`. All other space gets freed, so only gaps
+    // in front of pinned regions remain as free segments.
+    fn defragment<F: FnMut(u64, u64, u64)>(
+        &mut self,
+        live: &[(u64, u64)],
+        pinned: &[(u64, u64)],
+        copy: &mut F,
+    ) -> Result<Vec<(u64, u64)>, String> {
+        let mut live = live.to_vec();
+        live.sort_unstable();
+        let mut pinned = pinned.to_vec();
+        pinned.sort_unstable();
+        let mut regions = live.iter().chain(pinned.iter()).collect::<Vec<_>>();
+        regions.sort_unstable();
+        let mut end = INITIAL_OFFSET;
+        for (offset, len) in regions {
+            if *offset < end {
+                return Err(format!("region {:?} overlaps with others", (offset, len)));
+            }
+            end = offset + len;
+        }
+        if end > self.boundary {
+            return Err(format!("regions exceed the boundary {}", self.boundary));
+        }
+
+        let mut segments = BTreeMap::new();
+        let mut remapping = Vec::new();
+        let mut cursor = INITIAL_OFFSET;
+        let mut pinned = pinned.into_iter().peekable();
+        for (offset, len) in live {
+            // skip all pinned regions the live region doesn't fit in front of
+            while let Some((pinned_offset, pinned_len)) = pinned.peek().copied() {
+                if cursor + len <= pinned_offset {
+                    break;
+                }
+                if pinned_offset > cursor {
+                    segments.insert(cursor, pinned_offset - cursor);
+                }
+                cursor = pinned_offset + pinned_len;
+                pinned.next();
+            }
+            // regions only move towards the beginning, so they're copied in ascending order
+            if cursor < offset {
+                copy(offset, cursor, len);
+            }
+            remapping.push((offset, cursor));
+            cursor += len;
+        }
+        for (pinned_offset, pinned_len) in pinned {
+            if pinned_offset > cursor {
+                segments.insert(cursor, pinned_offset - cursor);
+            }
+            cursor = pinned_offset + pinned_len;
+        }
+        self.segments = segments;
+        self.boundary = cursor;
+        Ok(remapping)
+    }
+
+    fn largest_free_segment(&self) -> u64 {
+        self.segments.values().max().copied().unwrap_or_default()
     }
 
     #[cfg(test)]
@@ -248,7 +1387,7 @@ impl<K: Eq + Ord + Copy, T: Storable> ObjectManager<K, T> {
             self.cache.entry(*id).or_insert(
                 self.index
                     .get(id)
-                    .map(|(offset, len)| Memory::read(*offset, *len))?,
+                    .and_then(|(offset, len)| Memory::read(*offset, *len).ok())?,
             ),
         )
     }
@@ -259,7 +1398,7 @@ impl<K: Eq + Ord + Copy, T: Storable> ObjectManager<K, T> {
             self.cache.entry(*id).or_insert(
                 self.index
                     .get(id)
-                    .map(|(offset, len)| Memory::read(*offset, *len))?,
+                    .and_then(|(offset, len)| Memory::read(*offset, *len).ok())?,
             ),
         )
     }
@@ -288,6 +1427,8 @@ impl<K: Eq + Ord + Copy, T: Storable> ObjectManager<K, T> {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
 
     #[test]
     fn test_allocator() {
@@ -304,6 +1445,8 @@ pub(crate) mod tests {
             mem_grow: Some(Box::new(mem_grow)),
             mem_size: Some(Box::new(mem_end)),
             boundary: 16,
+            growth_chunk: 1,
+            ops: 0,
         };
 
         // |oooooooooooooooo|...
@@ -354,131 +1497,925 @@ pub(crate) mod tests {
         assert_eq!(a.seg(16 + 10), 2);
 
         a.free(32, 32).unwrap();
-        // |oooooooooooooooo|xxxxxxxxxx|..|xxxx|...
-        assert_eq!(a.segs(), 2);
+        // the last block was freed, so the boundary shrinks
+        // |oooooooooooooooo|xxxxxxxxxx|..|xxxx| 32
+        assert_eq!(a.segs(), 1);
         assert_eq!(a.seg(16 + 10), 2);
-        assert_eq!(a.seg(32), 32);
+        assert_eq!(a.boundary, 32);
 
         assert_eq!(a.alloc(16).unwrap(), 32);
-        // |oooooooooooooooo|xxxxxxxxxx|..|xxxx|xxxxxxxxxxxxxxxx|... 64
-        assert_eq!(a.segs(), 2);
-        assert_eq!(a.seg(16 + 10), 2);
-        assert_eq!(a.seg(32 + 16), 16);
+        // |oooooooooooooooo|xxxxxxxxxx|..|xxxx|xxxxxxxxxxxxxxxx| 48
+        assert_eq!(a.segs(), 1);
+        assert_eq!(a.boundary, 48);
 
         a.free(16 + 10 + 2, 4).unwrap();
-        // |oooooooooooooooo|xxxxxxxxxx|......|xxxxxxxxxxxxxxxx|... 64
-        assert_eq!(a.segs(), 2);
+        // |oooooooooooooooo|xxxxxxxxxx|......|xxxxxxxxxxxxxxxx| 48
+        assert_eq!(a.segs(), 1);
         assert_eq!(a.seg(16 + 10), 6);
-        assert_eq!(a.seg(32 + 16), 16);
 
         a.free(16, 10).unwrap();
-        // |oooooooooooooooo|................|xxxxxxxxxxxxxxxx|... 64
-        assert_eq!(a.segs(), 2);
+        // |oooooooooooooooo|................|xxxxxxxxxxxxxxxx| 48
+        assert_eq!(a.segs(), 1);
         assert_eq!(a.seg(16), 16);
-        assert_eq!(a.seg(32 + 16), 16);
 
         a.free(32, 16).unwrap();
-        // |oooooooooooooooo|... 64
-        assert_eq!(a.segs(), 1);
-        assert_eq!(a.seg(16), 48);
+        // the last block is coalesced with the left segment and the boundary shrinks
+        // |oooooooooooooooo| 16
+        assert_eq!(a.segs(), 0);
+        assert_eq!(a.boundary, 16);
 
         assert_eq!(a.alloc(8).unwrap(), 16);
-        // |oooooooooooooooo|xxxxxxxx|... 64
-
         assert_eq!(a.alloc(4).unwrap(), 16 + 8);
-        // |oooooooooooooooo|xxxxxxxx|xxxx|... 64
-
         assert_eq!(a.alloc(4).unwrap(), 16 + 8 + 4);
-        // |oooooooooooooooo|xxxxxxxx|xxxx|xxxx|... 64
-        assert_eq!(a.segs(), 1);
-        assert_eq!(a.seg(32), 32);
-
         assert_eq!(a.alloc(4).unwrap(), 16 + 8 + 4 + 4);
         assert_eq!(a.alloc(4).unwrap(), 16 + 8 + 4 + 4 + 4);
-        // |oooooooooooooooo|xxxxxxxx|xxxx|xxxx|xxxx|xxxx|... 64
-        assert_eq!(a.segs(), 1);
-        assert_eq!(a.seg(40), 24);
+        // |oooooooooooooooo|xxxxxxxx|xxxx|xxxx|xxxx|xxxx| 40
+        assert_eq!(a.segs(), 0);
+        assert_eq!(a.boundary, 40);
         assert_eq!(mem_end(), 64);
 
         a.free(16, 8).unwrap();
-        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx|... 64
+        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx| 40
         a.free(16 + 8 + 4, 4).unwrap();
-        // |oooooooooooooooo|........|xxxx|....|xxxx|xxxx|... 64
-        assert_eq!(a.segs(), 3);
+        // |oooooooooooooooo|........|xxxx|....|xxxx|xxxx| 40
+        assert_eq!(a.segs(), 2);
         assert_eq!(a.seg(16), 8);
         assert_eq!(a.seg(16 + 8 + 4), 4);
-        assert_eq!(a.seg(40), 24);
 
         assert_eq!(a.alloc(4).unwrap(), 28);
-        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx|... 64
-        assert_eq!(a.segs(), 2);
+        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx| 40
+        assert_eq!(a.segs(), 1);
         assert_eq!(a.seg(16), 8);
-        assert_eq!(a.seg(40), 24);
 
         assert_eq!(a.alloc(20).unwrap(), 40);
-        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|...
-        assert_eq!(a.segs(), 2);
-        assert_eq!(a.seg(16), 8);
-        assert_eq!(a.seg(60), 4);
-
-        assert_eq!(a.alloc(4).unwrap(), 60);
-        assert_eq!(a.alloc(4).unwrap(), 16);
-        // |oooooooooooooooo|xxxx|....|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|
+        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx| 60
         assert_eq!(a.segs(), 1);
-        assert_eq!(a.seg(20), 4);
+        assert_eq!(a.boundary, 60);
 
+        assert_eq!(a.alloc(4).unwrap(), 16);
         assert_eq!(a.alloc(4).unwrap(), 20);
-        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|
+        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx| 60
         assert_eq!(a.segs(), 0);
 
+        assert_eq!(a.alloc(4).unwrap(), 60);
         assert_eq!(a.alloc(4).unwrap(), 64);
-        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|xxxx
+        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|xxxx| 68
         assert_eq!(a.segs(), 0);
+        assert_eq!(a.boundary, 68);
 
         a.free(64, 4).unwrap();
-        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 1);
-        assert_eq!(a.seg(64), 4);
+        // |oooooooooooooooo|xxxx|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx| 64
+        assert_eq!(a.segs(), 0);
+        assert_eq!(a.boundary, 64);
 
         a.free(16, 4).unwrap();
-        // |oooooooooooooooo|....|xxxx|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 2);
-        assert_eq!(a.seg(16), 4);
-        assert_eq!(a.seg(64), 4);
-
         a.free(20, 4).unwrap();
-        // |oooooooooooooooo|........|xxxx|xxxx|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 2);
-        assert_eq!(a.seg(16), 8);
-        assert_eq!(a.seg(64), 4);
-
         a.free(16 + 8 + 4, 4).unwrap();
-        // |oooooooooooooooo|........|xxxx|....|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 3);
+        // |oooooooooooooooo|........|xxxx|....|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx| 64
+        assert_eq!(a.segs(), 2);
         assert_eq!(a.seg(16), 8);
         assert_eq!(a.seg(16 + 8 + 4), 4);
-        assert_eq!(a.seg(64), 4);
 
         a.free(16 + 8, 4).unwrap();
-        // |oooooooooooooooo|................|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 2);
+        // |oooooooooooooooo|................|xxxx|xxxx|xxxxxxxxxxxxxxxxxxxx|xxxx| 64
+        assert_eq!(a.segs(), 1);
         assert_eq!(a.seg(16), 16);
-        assert_eq!(a.seg(64), 4);
 
-        a.free(32 + 8, 4).unwrap();
-        // |oooooooooooooooo|................|xxxx|xxxx|....|xxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 3);
-        assert_eq!(a.seg(16), 16);
-        assert_eq!(a.seg(32 + 8), 4);
-        assert_eq!(a.seg(64), 4);
+        a.free(60, 4).unwrap();
+        a.free(40, 20).unwrap();
+        a.free(36, 4).unwrap();
+        // |oooooooooooooooo|................|xxxx| 36
+        assert_eq!(a.segs(), 1);
+        assert_eq!(a.boundary, 36);
 
-        a.free(32 + 4, 4).unwrap();
-        // |oooooooooooooooo|................|xxxx|........|xxxxxxxxxxxxxxx|xxxx|....
-        assert_eq!(a.segs(), 3);
-        assert_eq!(a.seg(16), 16);
-        assert_eq!(a.seg(32 + 4), 8);
-        assert_eq!(a.seg(64), 4);
+        a.free(32, 4).unwrap();
+        // |oooooooooooooooo| 16
+        assert_eq!(a.segs(), 0);
+        assert_eq!(a.boundary, 16);
+
+        // freeing beyond the boundary fails
+        assert!(a.free(16, 4).is_err());
 
         assert!(a.boundary <= mem_end());
     }
+
+    // A deterministic xorshift generator, so that failures can be reproduced by the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn check_allocator(
+        a: &Allocator,
+        live: &BTreeMap<u64, u64>,
+        mem_size: u64,
+    ) -> Result<(), String> {
+        if a.boundary > mem_size {
+            return Err("boundary beyond the memory".into());
+        }
+        // live allocations and free segments tile the memory without overlaps
+        let mut regions = live
+            .iter()
+            .map(|(offset, size)| (*offset, *size, true))
+            .chain(
+                a.segments
+                    .iter()
+                    .map(|(offset, size)| (*offset, *size, false)),
+            )
+            .collect::<Vec<_>>();
+        regions.sort_unstable();
+        let mut end = INITIAL_OFFSET;
+        let mut last_free = false;
+        for (offset, size, allocated) in regions {
+            if size == 0 {
+                return Err(format!("empty region at {}", offset));
+            }
+            if offset != end {
+                return Err(format!("overlap or gap at {}", offset));
+            }
+            // free segments are fully coalesced
+            if !allocated && last_free {
+                return Err(format!("adjacent free segments at {}", offset));
+            }
+            end = offset + size;
+            last_free = !allocated;
+        }
+        if end != a.boundary || last_free {
+            return Err("the last region doesn't end at the boundary".into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocator_fuzzing() {
+        for seed in 1..=50 {
+            let mem_size = Rc::new(Cell::new(INITIAL_OFFSET));
+            let (size, grow) = (mem_size.clone(), mem_size.clone());
+            let mut a = Allocator {
+                segments: Default::default(),
+                boundary: INITIAL_OFFSET,
+                mem_size: Some(Box::new(move || size.get())),
+                mem_grow: Some(Box::new(move |n| {
+                    grow.set(grow.get() + n);
+                    Ok(0)
+                })),
+                growth_chunk: 1,
+                ops: 0,
+            };
+            let mut rng = Rng(seed);
+            let mut live = BTreeMap::new();
+            for step in 0..500 {
+                if live.is_empty() || rng.next(5) < 3 {
+                    let n = 1 + rng.next(64);
+                    let align = [1, 1, 8, 64][rng.next(4) as usize];
+                    let offset = a.alloc_aligned(n, align).unwrap();
+                    assert_eq!(offset % align, 0);
+                    live.insert(offset, n);
+                } else {
+                    let i = rng.next(live.len() as u64) as usize;
+                    let (offset, n) = live.iter().nth(i).map(|(o, n)| (*o, *n)).unwrap();
+                    live.remove(&offset);
+                    a.free(offset, n).unwrap();
+                }
+                if let Err(err) = check_allocator(&a, &live, mem_size.get()) {
+                    panic!("seed {}, step {}: {}", seed, step, err);
+                }
+            }
+            // freeing everything shrinks the memory back
+            for (offset, n) in std::mem::take(&mut live) {
+                a.free(offset, n).unwrap();
+            }
+            assert_eq!(check_allocator(&a, &live, mem_size.get()), Ok(()));
+            assert_eq!(a.boundary, INITIAL_OFFSET);
+        }
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+        let a = memory.allocator.alloc(100).unwrap();
+        let b = memory.allocator.alloc(100).unwrap();
+        memory.allocator.alloc(8).unwrap();
+        assert_eq!(memory.free(a, 100), Ok(()));
+        assert_eq!(memory.free(b, 100), Ok(()));
+        let segments = memory.allocator.segments.clone();
+        assert_eq!(segments.len(), 1);
+
+        // freeing the same region again or any region overlapping the free space fails
+        assert!(memory.free(a, 100).is_err());
+        assert!(memory.free(b, 100).is_err());
+        assert!(memory.free(a + 50, 10).is_err());
+        assert!(memory.free(a - 8, 16).is_err());
+        assert!(memory.free(b + 50, 108).is_err());
+        assert_eq!(memory.allocator.segments, segments);
+
+        // released regions can be allocated again
+        assert_eq!(memory.allocator.alloc(200), Ok(a));
+        assert_eq!(memory.free(a, 200), Ok(()));
+    }
+
+    #[test]
+    fn test_aligned_alloc() {
+        let mut a = Allocator::default();
+        mock_memory(&mut a);
+        assert_eq!(
+            a.alloc_aligned(8, 0),
+            Err("alignment must be positive".into())
+        );
+
+        // alignment at the boundary leaves a free gap
+        assert_eq!(a.alloc(3).unwrap(), 16);
+        assert_eq!(a.alloc_aligned(8, 8).unwrap(), 24);
+        assert_eq!((a.seg(19), a.boundary), (5, 32));
+        // the gap gets reused
+        assert_eq!(a.alloc(5).unwrap(), 19);
+        assert_eq!(a.segs(), 0);
+
+        // alignment inside of larger free segments
+        assert_eq!(a.alloc(40).unwrap(), 32);
+        assert_eq!(a.alloc(20).unwrap(), 72);
+        assert_eq!(a.alloc(1).unwrap(), 92);
+        a.free(32, 40).unwrap();
+        a.free(72, 20).unwrap();
+        assert_eq!(a.seg(32), 60);
+        assert_eq!(a.alloc_aligned(4, 16).unwrap(), 32);
+        assert_eq!(a.seg(36), 56);
+        assert_eq!(a.alloc_aligned(4, 16).unwrap(), 48);
+        assert_eq!((a.seg(36), a.seg(52)), (12, 40));
+
+        // the smallest fitting segment is used
+        assert_eq!(a.alloc_aligned(8, 8).unwrap(), 40);
+        assert_eq!((a.seg(36), a.seg(52)), (4, 40));
+        assert_eq!(a.alloc_aligned(8, 8).unwrap(), 56);
+        assert_eq!((a.seg(36), a.seg(52), a.seg(64)), (4, 4, 28));
+        assert_eq!(a.alloc_aligned(4, 4).unwrap(), 36);
+        assert_eq!((a.seg(52), a.seg(64)), (4, 28));
+
+        // freeing coalesces the gaps again
+        for (offset, n) in [(32, 4), (36, 4), (40, 8), (48, 4), (56, 8)].iter() {
+            a.free(*offset, *n).unwrap();
+        }
+        assert_eq!(a.segs(), 1);
+        assert_eq!(a.seg(32), 60);
+    }
+
+    #[test]
+    fn test_failed_growth() {
+        let mem_size = Rc::new(Cell::new(64));
+        let (size, grow) = (mem_size.clone(), mem_size.clone());
+        let mut a = Allocator {
+            mem_size: Some(Box::new(move || size.get())),
+            // memory can grow up to 128 bytes
+            mem_grow: Some(Box::new(move |n| {
+                if grow.get() + n > 128 {
+                    return Err("out of stable memory".into());
+                }
+                grow.set(grow.get() + n);
+                Ok(0)
+            })),
+            growth_chunk: 1,
+            ..Default::default()
+        };
+        assert_eq!(a.alloc(40).unwrap(), 16);
+        assert_eq!(a.alloc(3).unwrap(), 56);
+
+        // a failed growth leaves the allocator unchanged
+        assert_eq!(a.alloc_aligned(100, 8), Err("out of stable memory".into()));
+        assert_eq!((a.boundary, a.segs()), (59, 0));
+
+        // the allocator keeps working after the failure
+        a.free(16, 40).unwrap();
+        assert_eq!(a.alloc(30).unwrap(), 16);
+        assert_eq!(a.alloc(20).unwrap(), 59);
+        assert_eq!((a.boundary, mem_size.get()), (79, 79));
+    }
+
+    #[test]
+    fn test_growth_chunks() {
+        let grow_calls = |chunk| {
+            let mem_size = Rc::new(Cell::new(INITIAL_OFFSET));
+            let calls = Rc::new(Cell::new(0));
+            let (size, grow, counter) = (mem_size.clone(), mem_size.clone(), calls.clone());
+            let mut a = Allocator {
+                mem_size: Some(Box::new(move || size.get())),
+                mem_grow: Some(Box::new(move |n| {
+                    counter.set(counter.get() + 1);
+                    grow.set(grow.get() + n);
+                    Ok(0)
+                })),
+                growth_chunk: chunk,
+                ..Default::default()
+            };
+            for _ in 0..100 {
+                a.alloc(10).unwrap();
+            }
+            // the grown memory always covers the boundary
+            assert!(a.boundary <= mem_size.get());
+            assert_eq!(mem_size.get() % chunk, INITIAL_OFFSET % chunk);
+            calls.get()
+        };
+        assert_eq!(grow_calls(1), 100);
+        assert_eq!(grow_calls(64), 16);
+        assert_eq!(grow_calls(growth_chunk()), 1);
+
+        // the memory functions are restored after an upgrade
+        let a: Allocator =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&Allocator::default()).unwrap()).unwrap();
+        assert!(a.mem_grow.is_some() && a.mem_size.is_some());
+        assert_eq!(a.growth_chunk, 64 << 16);
+    }
+
+    #[test]
+    fn test_checked_read() {
+        let memory = (0..64).collect::<Vec<u8>>();
+        let mut read = |offset: u64, buf: &mut [u8]| {
+            buf.copy_from_slice(&memory[offset as usize..offset as usize + buf.len()])
+        };
+        assert_eq!(checked_read(60, 4, 64, &mut read), Ok(vec![60, 61, 62, 63]));
+        assert_eq!(checked_read(64, 0, 64, &mut read), Ok(vec![]));
+        assert_eq!(
+            checked_read(60, 5, 64, &mut read),
+            Err("reading 5 bytes at offset 60 exceeds the memory size 64".into())
+        );
+        assert!(checked_read(u64::MAX, 2, 64, &mut read).is_err());
+    }
+
+    #[test]
+    fn test_root_commit() {
+        let stable = RefCell::new(vec![0; 128]);
+        let read = |offset: u64, len: u64| {
+            Ok(stable.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        // writes after the first n ones get lost, as if the canister trapped
+        let commit = |offset, len, n| {
+            let mut writes = 0;
+            commit_root(Some(40), offset, len, &read, &mut |offset, bytes: &[u8]| {
+                writes += 1;
+                if writes <= n {
+                    stable.borrow_mut()[offset as usize..offset as usize + bytes.len()]
+                        .copy_from_slice(bytes);
+                }
+            })
+            .unwrap();
+        };
+
+        // the legacy root
+        commit_root(None, 100, 10, &read, &mut |offset, bytes: &[u8]| {
+            stable.borrow_mut()[offset as usize..offset as usize + bytes.len()]
+                .copy_from_slice(bytes)
+        })
+        .unwrap();
+        assert_eq!(read_root(&read), Ok((100, 10)));
+
+        // the migration to the root block stays invisible until the root points to it
+        for n in 0..3 {
+            commit(200, 20, n);
+            assert_eq!(read_root(&read), Ok((100, 10)));
+        }
+        commit(200, 20, 3);
+        assert_eq!(read_root(&read), Ok((200, 20)));
+        commit(300, 30 | INDEX_FLAG, 2);
+        assert_eq!(read_root(&read), Ok((300, 30 | INDEX_FLAG)));
+
+        // an interrupted commit keeps the previous root readable
+        for n in 0..2 {
+            commit(400, 40, n);
+            assert_eq!(read_root(&read), Ok((300, 30 | INDEX_FLAG)));
+        }
+        commit(400, 40, 2);
+        assert_eq!(read_root(&read), Ok((400, 40)));
+    }
+
+    #[test]
+    fn test_defragment() {
+        let mut a = Allocator::default();
+        mock_memory(&mut a);
+        let mut memory = vec![0_u8; 1024];
+        let mut live = Vec::new();
+        for i in 0..40_u64 {
+            let len = 1 + i % 7;
+            let offset = a.alloc(len).unwrap();
+            for byte in &mut memory[offset as usize..(offset + len) as usize] {
+                *byte = i as u8;
+            }
+            live.push((offset, len, i as u8));
+        }
+        // free every third region
+        for (offset, len, _) in live.iter().step_by(3) {
+            a.free(*offset, *len).unwrap();
+        }
+        let live = live
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, region)| (i % 3 != 0).then_some(region))
+            .collect::<Vec<_>>();
+        assert!(a.segs() > 10);
+        let live_bytes = live.iter().map(|(_, len, _)| len).sum::<u64>();
+
+        // overlapping regions are rejected
+        let (offset, len, _) = live[0];
+        assert!(a
+            .defragment(&[(offset, len), (offset + 1, len)], &[], &mut |_, _, _| ())
+            .is_err());
+
+        // pin the last live region
+        let (pinned, live) = live.split_last().unwrap();
+        let regions = live
+            .iter()
+            .map(|(offset, len, _)| (*offset, *len))
+            .collect::<Vec<_>>();
+        let remapping = a
+            .defragment(&regions, &[(pinned.0, pinned.1)], &mut |from, to, len| {
+                let (from, to, len) = (from as usize, to as usize, len as usize);
+                memory.copy_within(from..from + len, to);
+            })
+            .unwrap();
+
+        // the holes collapsed and all live bytes were preserved
+        assert_eq!(a.segs(), 1);
+        assert_eq!(a.boundary, pinned.0 + pinned.1);
+        assert_eq!(
+            a.segments.iter().next(),
+            Some((
+                &(INITIAL_OFFSET + live_bytes - pinned.1),
+                &(pinned.0 - INITIAL_OFFSET - live_bytes + pinned.1)
+            ))
+        );
+        for ((old_offset, len, value), (mapped_offset, new_offset)) in
+            live.iter().zip(remapping.iter())
+        {
+            assert_eq!(old_offset, mapped_offset);
+            assert!(memory[*new_offset as usize..(new_offset + len) as usize]
+                .iter()
+                .all(|byte| byte == value));
+        }
+        assert!(memory[pinned.0 as usize..(pinned.0 + pinned.1) as usize]
+            .iter()
+            .all(|byte| *byte == pinned.2));
+
+        // without pinned regions, all free space is beyond the boundary
+        let (offset, len) = remapping.last().map(|(_, offset)| (*offset, 1)).unwrap();
+        a.defragment(&[(offset, len)], &[], &mut |_, _, _| ())
+            .unwrap();
+        assert_eq!((a.segs(), a.boundary), (0, INITIAL_OFFSET + 1));
+    }
+
+    #[test]
+    fn test_heap_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let mut state = crate::State::default();
+        state.logger.info("test");
+        let bytes = encode_heap(&state);
+        assert!(decode_heap(bytes.clone(), true).is_ok());
+        // heaps written without checksums are still readable
+        assert!(decode_heap(state.to_bytes(), false).is_ok());
+
+        // a flipped byte is detected before deserialization
+        let mut corrupted = bytes.clone();
+        corrupted[10] ^= 1;
+        let expected = crc32(&bytes[..bytes.len() - 4]);
+        let actual = crc32(&corrupted[..corrupted.len() - 4]);
+        assert_eq!(
+            decode_heap(corrupted, true).err(),
+            Some(format!(
+                "heap checksum mismatch: expected {:08x}, actual {:08x}",
+                expected, actual
+            ))
+        );
+    }
+
+    #[test]
+    fn test_streamed_heap() {
+        let mut state = crate::State::default();
+        for i in 0..100 {
+            state.logger.info(format!("log entry {}", i));
+        }
+        let bytes = encode_heap(&state);
+        assert_eq!(encoded_heap_len(&state), bytes.len() as u64);
+
+        let offset = 16;
+        let mut memory = vec![0; offset];
+        let mut chunks = 0;
+        stream_heap(&state, offset as u64, 64, |offset, chunk| {
+            assert!(chunk.len() <= 64);
+            assert_eq!(offset as usize, memory.len());
+            memory.extend_from_slice(chunk);
+            chunks += 1;
+        });
+        assert!(chunks > 2);
+        assert_eq!(&memory[offset..], bytes.as_slice());
+
+        let streamed = decode_heap(memory[offset..].to_vec(), true).unwrap();
+        let materialized = decode_heap(bytes, true).unwrap();
+        assert_eq!(streamed.to_bytes(), materialized.to_bytes());
+        assert_eq!(streamed.to_bytes(), state.to_bytes());
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+        assert_eq!(memory.fragmentation(), (0, 0, 0));
+
+        let offsets = (1..=6)
+            .map(|i| memory.allocator.alloc(i * 10).unwrap())
+            .collect::<Vec<_>>();
+        // free every second block, keeping the last one allocated
+        for (i, offset) in offsets.iter().enumerate().step_by(2) {
+            memory.allocator.free(*offset, (i as u64 + 1) * 10).unwrap();
+        }
+        assert_eq!(memory.fragmentation(), (10 + 30 + 50, 50, 3));
+
+        // adjacent free blocks are coalesced
+        memory.allocator.free(offsets[1], 20).unwrap();
+        assert_eq!(memory.fragmentation(), (10 + 20 + 30 + 50, 60, 2));
+        assert_eq!(memory.size(), INITIAL_OFFSET + 210);
+
+        let metrics = memory.metrics();
+        assert_eq!(
+            (metrics.boundary, metrics.free_bytes, metrics.segments),
+            (INITIAL_OFFSET + 210, 110, 2)
+        );
+        assert_eq!(metrics.allocator_ops, 6 + 3 + 1);
+    }
+
+    fn mock_memory(allocator: &mut Allocator) {
+        allocator.mem_size = Some(Box::new(|| u64::MAX));
+        allocator.mem_grow = Some(Box::new(|_| Ok(0)));
+    }
+
+    #[test]
+    fn test_heap_segment_reuse() {
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+        for i in 0..20 {
+            // some objects get allocated between upgrades
+            memory.allocator.alloc(8).unwrap();
+
+            // heap dump
+            let (mut allocator, previous_heap) = memory.heap_allocator();
+            mock_memory(&mut allocator);
+            let len = 100 + (i % 3) * 10;
+            let segments = allocator.alloc_segments(&segment_sizes(len, 64)).unwrap();
+            assert_eq!(segments.len(), 2);
+            // the new heap never overwrites the previous one
+            for (offset, len) in &segments {
+                for (previous_offset, previous_len) in &previous_heap {
+                    assert!(
+                        offset + len <= *previous_offset
+                            || previous_offset + previous_len <= *offset
+                    );
+                }
+            }
+            allocator.release(&previous_heap);
+
+            // upgrade: the loaded allocator reserves the new heap segments
+            for (offset, len) in &segments {
+                memory.allocator.reserve(*offset, *len).unwrap();
+            }
+            memory.heap = segments;
+            assert!(memory.allocator.segs() <= 8);
+        }
+        // without releasing previous heaps, the boundary would grow by a heap size per upgrade
+        assert!(memory.allocator.boundary <= INITIAL_OFFSET + 20 * 8 + 4 * 120);
+    }
+
+    #[test]
+    fn test_segmented_heap() {
+        let mut state = crate::State::default();
+        for i in 0..100 {
+            state.logger.info(format!("log entry {}", i));
+        }
+        let len = encoded_heap_len(&state);
+        let segment_size = len / 3 + 1;
+
+        // fragment the memory, so that the heap can't be stored contiguously
+        let mut allocator = Allocator::default();
+        mock_memory(&mut allocator);
+        let hole1 = allocator.alloc(segment_size).unwrap();
+        allocator.alloc(8).unwrap();
+        let hole2 = allocator.alloc(segment_size).unwrap();
+        allocator.alloc(8).unwrap();
+        allocator.free(hole1, segment_size).unwrap();
+        allocator.free(hole2, segment_size).unwrap();
+
+        let mut stable = Vec::new();
+        let ((offset, index_len), segments) = write_heap(
+            &mut allocator,
+            &state,
+            len,
+            segment_size,
+            &mut |offset, bytes| {
+                let end = offset as usize + bytes.len();
+                if stable.len() < end {
+                    stable.resize(end, 0);
+                }
+                stable[offset as usize..end].copy_from_slice(bytes);
+            },
+        )
+        .unwrap();
+        // three heap segments and the index
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0].0, hole1);
+        assert_eq!(segments[1].0, hole2);
+        assert_eq!(segments[3], (offset, index_len));
+
+        let read =
+            |offset: u64, len: u64| Ok(stable[offset as usize..(offset + len) as usize].to_vec());
+        let (bytes, checksummed) = read_heap(offset, index_len | INDEX_FLAG, &read).unwrap();
+        assert!(checksummed);
+        assert_eq!(bytes, encode_heap(&state));
+        assert_eq!(
+            decode_heap(bytes, checksummed).unwrap().to_bytes(),
+            state.to_bytes()
+        );
+
+        // unknown fields of the index are ignored
+        #[derive(Serialize)]
+        struct FutureIndex {
+            version: u32,
+            segments: Vec<(u64, u64)>,
+            compression: String,
+        }
+        let future_index = serde_cbor::to_vec(&FutureIndex {
+            version: HEAP_INDEX_VERSION,
+            segments: segments[..3].to_vec(),
+            compression: "none".into(),
+        })
+        .unwrap();
+        let index: HeapIndex = serde_cbor::from_slice(&future_index).unwrap();
+        assert_eq!(index.segments, segments[..3].to_vec());
+    }
+
+    // Hash maps serialize in arbitrary order, so states are compared as sorted CBOR values.
+    fn canonical(state: &crate::State) -> serde_cbor::Value {
+        serde_cbor::value::to_value(state).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_incremental_heap_dump() {
+        let mut state = crate::State::default();
+        for i in 0..3 {
+            crate::env::tests::create_user(&mut state, crate::env::tests::pr(i));
+        }
+        crate::env::post::add(
+            &mut state,
+            "hello".into(),
+            vec![],
+            crate::env::tests::pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut allocator = Allocator::default();
+        mock_memory(&mut allocator);
+        let stable = std::cell::RefCell::new(Vec::new());
+        let mut write = |offset: u64, bytes: &[u8]| {
+            let mut stable = stable.borrow_mut();
+            let end = offset as usize + bytes.len();
+            if stable.len() < end {
+                stable.resize(end, 0);
+            }
+            stable[offset as usize..end].copy_from_slice(bytes);
+        };
+
+        // a full round-trip and an incremental one restore the same state
+        let ((offset, len), new_segments, sections) = write_sections(
+            &mut allocator,
+            &mut state,
+            &Default::default(),
+            64,
+            &mut write,
+        )
+        .unwrap();
+        assert!(state.posts.dirty());
+        let read = |offset: u64, len: u64| {
+            Ok(stable.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        let (restored, restored_sections) = read_sections(offset, len, &read).unwrap();
+        assert_eq!(canonical(&restored), canonical(&state));
+        assert_eq!(restored_sections, sections);
+        assert!(!restored.posts.dirty() && !restored.users.dirty() && !restored.realms.dirty());
+        assert_eq!(
+            canonical(&decode_heap(encode_heap(&state), true).unwrap()),
+            canonical(&state)
+        );
+
+        // only the changed sections get written again
+        let mut state = restored;
+        state.users.get_mut(&1).unwrap().name = "changed".into();
+        let ((offset, len), segments, next_sections) =
+            write_sections(&mut allocator, &mut state, &sections, 64, &mut write).unwrap();
+        assert_eq!(next_sections.get("posts"), sections.get("posts"));
+        assert_eq!(next_sections.get("realms"), sections.get("realms"));
+        assert_ne!(next_sections.get("users"), sections.get("users"));
+        for segment in sections["posts"].iter().chain(sections["realms"].iter()) {
+            assert!(!segments.contains(segment));
+        }
+        assert!(new_segments.len() > segments.len());
+        let read = |offset: u64, len: u64| {
+            Ok(stable.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        let (restored, _) = read_sections(offset, len, &read).unwrap();
+        assert_eq!(canonical(&restored), canonical(&state));
+        assert_eq!(restored.users.get(&1).unwrap().name, "changed");
+
+        // sectioned heaps can't be backed up
+        assert_eq!(
+            backup_chunk((offset, len | SECTIONS_FLAG), &read, 0, 64),
+            Err("dump the full heap first".into())
+        );
+    }
+
+    #[test]
+    fn test_heap_backup_round_trip() {
+        let mut state = crate::State::default();
+        for i in 0..100 {
+            state.logger.info(format!("log entry {}", i));
+        }
+        let len = encoded_heap_len(&state);
+        let mut allocator = Allocator::default();
+        mock_memory(&mut allocator);
+        let mut stable = Vec::new();
+        let ((offset, index_len), _) = write_heap(
+            &mut allocator,
+            &state,
+            len,
+            len / 3 + 1,
+            &mut |offset, bytes| {
+                let end = offset as usize + bytes.len();
+                if stable.len() < end {
+                    stable.resize(end, 0);
+                }
+                stable[offset as usize..end].copy_from_slice(bytes);
+            },
+        )
+        .unwrap();
+        let read =
+            |offset: u64, len: u64| Ok(stable[offset as usize..(offset + len) as usize].to_vec());
+        let root = (offset, index_len | INDEX_FLAG);
+
+        // the chunks don't align with the heap segments
+        let chunk_size = len / 5 + 7;
+        let mut chunks = Vec::new();
+        while let Ok(chunk) = backup_chunk(root, &read, chunks.len() as u64, chunk_size) {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|(_, total, _)| *total == len));
+
+        // restore the chunks in a random order into a memory with live data
+        let restored = RefCell::new(vec![0; 16]);
+        let read_restored = |offset: u64, len: u64| {
+            Ok(restored.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        let mut write_restored = |offset: u64, bytes: &[u8]| {
+            let mut memory = restored.borrow_mut();
+            let end = offset as usize + bytes.len();
+            if memory.len() < end {
+                memory.resize(end, 0);
+            }
+            memory[offset as usize..end].copy_from_slice(bytes);
+        };
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+        let live = memory.allocator.alloc(8).unwrap();
+        write_restored(live, &[7; 8]);
+        for index in [3, 0, 4, 1].iter() {
+            let (bytes, total, checksum) = &chunks[*index];
+            memory
+                .restore_chunk(
+                    *index as u64,
+                    bytes,
+                    *checksum,
+                    *total,
+                    chunk_size,
+                    &mut write_restored,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            memory.commit_restore(&read_restored).map(|_| ()),
+            Err("1 chunks are missing".into())
+        );
+        let (bytes, total, checksum) = &chunks[2];
+        memory
+            .restore_chunk(2, bytes, *checksum, *total, chunk_size, &mut write_restored)
+            .unwrap();
+        // failed reads are reported and keep the restore in progress
+        assert_eq!(
+            memory
+                .commit_restore(&|_, _| Err("out of range".to_string()))
+                .map(|_| ()),
+            Err("out of range".into())
+        );
+        let restored_state = memory.commit_restore(&read_restored).unwrap();
+        assert_eq!(restored_state.to_bytes(), state.to_bytes());
+
+        // the live data wasn't overwritten and the staging region was released
+        assert_eq!(read_restored(live, 8), Ok(vec![7; 8]));
+        assert_eq!(memory.size(), live + 8);
+        assert_eq!(
+            memory.commit_restore(&read_restored).map(|_| ()),
+            Err("no restore in progress".into())
+        );
+    }
+
+    #[test]
+    fn test_heap_restore_cold_bodies() {
+        let mut state = crate::State::default();
+        let mut post = Post::new(0, Default::default(), String::new(), 0, None, None, None);
+        post.id = 5;
+        post.cold_body = Some((1 << 20, 4));
+        state.posts.insert(post.id, post);
+        let bytes = encode_heap(&state);
+        let len = bytes.len() as u64;
+        let stable = RefCell::new(Vec::new());
+        let read = |offset: u64, len: u64| {
+            Ok(stable.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        let mut write = |offset: u64, bytes: &[u8]| {
+            let mut memory = stable.borrow_mut();
+            let end = offset as usize + bytes.len();
+            if memory.len() < end {
+                memory.resize(end, 0);
+            }
+            memory[offset as usize..end].copy_from_slice(bytes);
+        };
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+        assert_eq!(
+            memory.restore_cold_bodies(vec![(5, "Body".into())]),
+            Err("no restore in progress".into())
+        );
+        memory
+            .restore_chunk(0, &bytes, crc32(&bytes), len, len, &mut write)
+            .unwrap();
+
+        // the restored state must not point to the stable memory of the backed up canister
+        assert_eq!(
+            memory.commit_restore(&read).map(|_| ()),
+            Err("the cold-stored body of post 5 is missing".into())
+        );
+        memory
+            .restore_cold_bodies(vec![(5, "Body".into())])
+            .unwrap();
+        let restored = memory.commit_restore(&read).unwrap();
+        let post = restored.posts.get(&5).unwrap();
+        assert_eq!(post.body, "Body");
+        assert_eq!(post.cold_body, None);
+    }
+
+    #[test]
+    fn test_heap_restore_checksum_mismatch() {
+        let mut state = crate::State::default();
+        state.logger.info("test");
+        let bytes = encode_heap(&state);
+        let len = bytes.len() as u64;
+        let stable = RefCell::new(Vec::new());
+        let read = |offset: u64, len: u64| {
+            Ok(stable.borrow()[offset as usize..(offset + len) as usize].to_vec())
+        };
+        let mut write = |offset: u64, bytes: &[u8]| {
+            let mut memory = stable.borrow_mut();
+            let end = offset as usize + bytes.len();
+            if memory.len() < end {
+                memory.resize(end, 0);
+            }
+            memory[offset as usize..end].copy_from_slice(bytes);
+        };
+        let mut memory = Memory::default();
+        mock_memory(&mut memory.allocator);
+
+        // a chunk corrupted in transit is rejected
+        let mut corrupted = bytes.clone();
+        corrupted[5] ^= 1;
+        assert_eq!(
+            memory.restore_chunk(0, &corrupted, crc32(&bytes), len, len, &mut write),
+            Err("checksum mismatch in chunk 0".into())
+        );
+        assert!(stable.borrow().is_empty());
+
+        // a corrupted backup is rejected on commit
+        memory
+            .restore_chunk(0, &corrupted, crc32(&corrupted), len, len, &mut write)
+            .unwrap();
+        assert!(memory
+            .commit_restore(&read)
+            .map(|_| ())
+            .unwrap_err()
+            .starts_with("heap checksum mismatch"));
+    }
 }
@@ -12,29 +12,46 @@ use ic_cdk::export::candid::Principal;
 use ic_ledger_types::{Memo, Tokens};
 use invoices::e8s_to_icp;
 use invoices::Invoices;
-use memory::Storable;
-use post::{Post, PostId};
+use memory::{Storable, Tracked};
+use post::{CommentSort, Post, PostId, ScheduledPost};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
-use user::{User, UserId};
+use std::convert::TryInto;
+use user::{FeedMode, NotificationSettings, OnboardingStep, User, UserId};
 
+pub mod audit;
+pub mod bounties;
 pub mod canisters;
+pub mod ckbtc;
 pub mod config;
+pub mod coordination;
+pub mod discovery;
+pub mod export;
+pub mod filters;
+pub mod health;
+pub mod images;
 pub mod invoices;
 pub mod memory;
 pub mod post;
+pub mod previews;
 pub mod proposals;
 pub mod reports;
+pub mod scheduler;
+pub mod search;
+pub mod spam;
 pub mod storage;
 pub mod token;
 pub mod user;
+pub mod webhooks;
 
 pub type Cycles = i64;
 pub type Karma = i64;
 pub type Blob = ByteBuf;
 
+const SECOND: u64 = 1000000000_u64;
+const MINUTE: u64 = 60 * SECOND;
 const HOUR: u64 = 3600000000000_u64;
 const WEEK: u64 = 7 * 24 * HOUR;
 
@@ -72,9 +89,11 @@ pub struct Stats {
     stalwarts: Vec<UserId>,
     bots: Vec<UserId>,
     state_size: u64,
+    // total free bytes, largest free segment and number of free segments
+    stable_memory_fragmentation: (u64, u64, usize),
     active_users: usize,
     invited_users: usize,
-    buckets: Vec<(String, u64)>,
+    buckets: Vec<(String, u64, u64)>,
     users_online: usize,
     last_upgrade: u64,
     module_hash: String,
@@ -83,7 +102,59 @@ pub struct Stats {
     meta: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Tier {
+    pub name: String,
+    // requirements
+    pub min_karma: Karma,
+    pub min_tips: Cycles,
+    // perks
+    pub flair: String,
+    pub extra_posts_per_hour: u8,
+    pub extra_comments_per_hour: u8,
+    pub gated_tag: Option<String>,
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum RealmVisibility {
+    #[default]
+    Public,
+    // joining requires an approval by a controller
+    Restricted,
+    // like restricted, but hidden from discovery and global feeds for non-members
+    Private,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RealmInvite {
+    pub creator: UserId,
+    pub expires_at: Option<u64>,
+    pub uses_left: u32,
+    pub redeemed_by: BTreeSet<UserId>,
+}
+
+impl RealmInvite {
+    fn valid(&self, now: u64) -> Result<(), String> {
+        if self.expires_at.map(|time| now >= time).unwrap_or_default() {
+            return Err("invite expired".into());
+        }
+        if self.uses_left == 0 {
+            return Err("invite is fully used".into());
+        }
+        Ok(())
+    }
+}
+
+// A recurring distribution of treasury tokens to the realm members contributing to the realm.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RealmRewards {
+    // token base units distributed per period, capped by the treasury
+    pub amount: Token,
+    pub period: u64,
+    pub last_distribution: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Realm {
     logo: String,
     pub description: String,
@@ -91,14 +162,120 @@ pub struct Realm {
     controllers: Vec<UserId>,
     pub members: BTreeSet<UserId>,
     pub label_color: String,
+    // tiers are ordered from the lowest to the highest
+    #[serde(default)]
+    pub tiers: Vec<Tier>,
+    #[serde(default)]
+    pub member_tiers: BTreeMap<UserId, usize>,
+    // delay after which posts in a terminal status are archived
+    #[serde(default)]
+    pub archive_after: Option<u64>,
+    #[serde(default)]
+    pub archive_cold_storage: bool,
+    #[serde(default)]
+    pub archived: Vec<PostId>,
+    // posts with open reports, triaged by the controllers
+    #[serde(default)]
+    pub reports: BTreeSet<PostId>,
+    // tokens donated to the realm, spendable via realm proposals
+    #[serde(default)]
+    pub treasury: Token,
+    #[serde(default)]
+    pub visibility: RealmVisibility,
+    // users waiting for a controller to approve their membership
+    #[serde(default)]
+    pub join_requests: BTreeSet<UserId>,
+    // invite codes joining the realm without an approval
+    #[serde(default)]
+    pub invites: BTreeMap<String, RealmInvite>,
+    // posts shown above the chronological feed, the latest pin first
+    #[serde(default)]
+    pub pinned: Vec<PostId>,
+    #[serde(default)]
+    pub rewards: Option<RealmRewards>,
+    // the percentage of karma rewards earned by activity in the realm
+    #[serde(default)]
+    pub reward_multiplier: Option<u32>,
+    // overrides of the global limits for the body length and attachments of posts
+    #[serde(default)]
+    pub max_post_length: Option<usize>,
+    #[serde(default)]
+    pub max_post_attachments: Option<usize>,
+    // case-insensitive patterns of content rejected in the realm
+    #[serde(default)]
+    pub blocked_patterns: Vec<String>,
+    // the compiled blocked patterns, rebuilt when they're set and when the state is loaded
+    #[serde(skip)]
+    pub blocklist: Option<regex::RegexSet>,
+}
+
+impl Realm {
+    // Returns a copy of the realm without invite codes, unless the user is a controller.
+    pub fn view(&self, user_id: Option<UserId>) -> Realm {
+        let mut realm = self.clone();
+        if !user_id
+            .map(|id| self.controllers.contains(&id))
+            .unwrap_or_default()
+        {
+            realm.invites.clear();
+        }
+        realm
+    }
+
+    pub fn tier(&self, user_id: UserId) -> Option<&Tier> {
+        self.member_tiers
+            .get(&user_id)
+            .and_then(|index| self.tiers.get(*index))
+    }
+
+    // Returns the flairs of members whose tier has one, to be shown next to their names.
+    pub fn flairs(&self) -> BTreeMap<UserId, &String> {
+        self.member_tiers
+            .keys()
+            .filter_map(|user_id| {
+                self.tier(*user_id)
+                    .filter(|tier| !tier.flair.is_empty())
+                    .map(|tier| (*user_id, &tier.flair))
+            })
+            .collect()
+    }
+
+    // Checks if the post is not gated by a tier above the user's one and, for private realms,
+    // that the user is a member.
+    pub fn visible(&self, user_id: Option<UserId>, post: &Post) -> bool {
+        if self.visibility == RealmVisibility::Private
+            && !user_id
+                .map(|id| self.members.contains(&id))
+                .unwrap_or_default()
+        {
+            return false;
+        }
+        let user_tier = user_id.and_then(|id| self.member_tiers.get(&id));
+        self.tiers
+            .iter()
+            .enumerate()
+            .all(|(index, tier)| match &tier.gated_tag {
+                Some(tag)
+                    if post
+                        .tags
+                        .iter()
+                        .any(|t| t.to_lowercase() == tag.to_lowercase()) =>
+                {
+                    user_tier
+                        .map(|user_tier| *user_tier >= index)
+                        .unwrap_or_default()
+                }
+                _ => true,
+            })
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct State {
     pub burned_cycles: Cycles,
     pub burned_cycles_total: Cycles,
-    pub posts: HashMap<PostId, Post>,
-    pub users: HashMap<UserId, User>,
+    pub posts: Tracked<HashMap<PostId, Post>>,
+    pub users: Tracked<HashMap<UserId, User>>,
     pub principals: HashMap<Principal, UserId>,
     pub next_post_id: PostId,
     pub next_user_id: UserId,
@@ -106,23 +283,91 @@ pub struct State {
     pub last_distribution: u64,
     pub storage: storage::Storage,
     pub last_chores: u64,
+    // the last karma decay period (time / decay period) that was applied
+    #[serde(default)]
+    pub last_karma_decay: u64,
     pub logger: Logger,
     pub hot: VecDeque<PostId>,
     pub invites: BTreeMap<String, (UserId, Cycles)>,
-    pub realms: BTreeMap<String, Realm>,
+    pub realms: Tracked<BTreeMap<String, Realm>>,
+    // names of merged realms pointing to the realms they were merged into
+    #[serde(default)]
+    pub realm_redirects: BTreeMap<String, String>,
     pub balances: HashMap<Account, Token>,
 
     total_revenue_shared: u64,
     total_rewards_shared: u64,
 
     pub proposals: Vec<Proposal>,
+    #[serde(default)]
+    pub proposal_thresholds: proposals::Thresholds,
     pub ledger: Vec<Transaction>,
 
     pub team_tokens: HashMap<UserId, Token>,
+    // token grants vesting over time, identified by their index
+    #[serde(default)]
+    pub vestings: Vec<token::Vesting>,
 
     #[serde(default)]
     pub memory: memory::Memory,
 
+    #[serde(default)]
+    pub detector: coordination::Detector,
+
+    // the id of the next webhook event
+    #[serde(default)]
+    pub next_webhook_event: u64,
+
+    // the name of the last realm whose tiers were recomputed
+    #[serde(default)]
+    pub realm_tiers_cursor: String,
+
+    #[serde(default)]
+    pub audit: Option<audit::Audit>,
+    #[serde(default)]
+    pub last_audit: Option<audit::Audit>,
+
+    // posts waiting for publication keyed by the publication time and the schedule id
+    #[serde(default)]
+    pub scheduled_posts: BTreeMap<(u64, u64), ScheduledPost>,
+    #[serde(default)]
+    pub next_scheduled_post_id: u64,
+    // the featured post along with the day of its selection
+    #[serde(default)]
+    pub featured_post: Option<(u64, PostId)>,
+    // polls to be closed keyed by the closing time and the post id
+    #[serde(default)]
+    pub open_polls: BTreeSet<(u64, PostId)>,
+    // bounties to be refunded keyed by the deadline and the post id
+    #[serde(default)]
+    pub open_bounties: BTreeSet<(u64, PostId)>,
+
+    // soft-deleted posts keyed by the end of their grace period
+    #[serde(default)]
+    pub pending_deletions: BTreeSet<(u64, PostId)>,
+
+    #[serde(default)]
+    pub search_index: search::SearchIndex,
+    #[serde(default)]
+    pub discovery_index: discovery::DiscoveryIndex,
+
+    #[serde(default)]
+    pub ckbtc: ckbtc::Wallets,
+
+    #[serde(default)]
+    pub scheduler: scheduler::Scheduler,
+
+    // lower-cased user names, rebuilt on upgrades
+    #[serde(skip)]
+    pub user_names: BTreeMap<String, UserId>,
+
+    // resolved canister references of posts
+    #[serde(skip)]
+    pub previews: previews::Previews,
+
+    #[serde(skip)]
+    pub health: health::Health,
+
     #[serde(skip)]
     pub module_hash: String,
     #[serde(skip)]
@@ -152,6 +397,13 @@ impl Logger {
         self.log(message, "INFO".to_string());
     }
 
+    pub fn metrics<T: Serialize>(&mut self, metrics: &T) {
+        self.log(
+            serde_json::to_string(metrics).expect("couldn't serialize metrics"),
+            "METRICS".to_string(),
+        );
+    }
+
     fn log<T: ToString>(&mut self, message: T, level: String) {
         self.events.push(Event {
             timestamp: time(),
@@ -235,19 +487,140 @@ impl State {
     pub fn load(&mut self) {
         crate::assets::load();
         self.last_upgrade = time();
+        if self.search_index.outdated() {
+            self.search_index = search::SearchIndex::build(&self.posts);
+        }
+        self.user_names = self
+            .users
+            .values()
+            .map(|user| (user.name.to_lowercase(), user.id))
+            .collect();
+        if self.discovery_index.outdated() {
+            self.discovery_index = discovery::DiscoveryIndex::build(self);
+        }
+        for realm in self.realms.values_mut() {
+            realm.blocklist = filters::blocklist(&realm.blocked_patterns);
+        }
     }
 
     pub fn hot_posts(&self, principal: Principal, page: usize) -> Vec<Post> {
         let current_realm = self
             .principal_to_user(principal)
             .and_then(|u| u.current_realm.clone());
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
         self.hot
             .iter()
             .filter_map(|post_id| self.posts.get(post_id))
             .filter(|post| current_realm.is_none() || post.realm == current_realm)
+            .filter(|post| self.visible(user_id, post))
             .skip(page * CONFIG.feed_page_size)
             .take(CONFIG.feed_page_size)
-            .cloned()
+            .map(Post::restored)
+            .collect()
+    }
+
+    // Returns recent root posts ranked by positive reactions decayed by the age in hours:
+    // reactions / (age + 2)^gravity.
+    pub fn trending_posts(&self, principal: Principal, page: usize, now: u64) -> Vec<Post> {
+        let mut posts = self
+            .last_posts(principal, false)
+            .take(CONFIG.trending_window)
+            .filter(|post| !post.deleted() && !self.detector.held.contains(&post.id))
+            .map(|post| (trending_score(post, now), post))
+            .filter(|(score, _)| *score > 0.0)
+            .collect::<Vec<_>>();
+        sort_by_score(&mut posts);
+        posts
+            .into_iter()
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .map(|(_, post)| post.restored())
+            .collect()
+    }
+
+    // Selects the featured post once per day among recent public root posts. The selection
+    // is seeded by the day and the candidates, so it's reproducible from the state.
+    pub fn select_featured_post(&mut self, now: u64) {
+        let day = now / (24 * HOUR);
+        if self.featured_post.map(|(selected, _)| selected) == Some(day) {
+            return;
+        }
+        let candidates = (0..self.next_post_id)
+            .rev()
+            .filter_map(|id| self.posts.get(&id))
+            .take_while(|post| post.creation_timestamp() + CONFIG.featured_post_window > now)
+            .filter(|post| post.parent.is_none() && self.visible(None, post))
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        let mut hasher = Sha256::new();
+        hasher.update(day.to_be_bytes());
+        for id in &candidates {
+            hasher.update(id.to_be_bytes());
+        }
+        let previous = self.featured_post.map(|(_, id)| id);
+        if let Some(id) = pick_featured_post(&candidates, previous, &hasher.finalize()) {
+            self.featured_post = Some((day, id));
+        }
+    }
+
+    pub fn featured_post(&self, now: u64) -> Option<&Post> {
+        self.featured_post
+            .filter(|(day, _)| *day == now / (24 * HOUR))
+            .and_then(|(_, id)| self.posts.get(&id))
+            .filter(|post| self.visible(None, post))
+    }
+
+    // Returns posts ranked by the topic overlap with user's interests, followed users and
+    // tags, and the engagement, with a limited number of posts per author.
+    pub fn recommended_posts(&self, principal: Principal, page: usize) -> Vec<Post> {
+        let user = match self.principal_to_user(principal) {
+            Some(user) => user,
+            None => return self.hot_posts(principal, page),
+        };
+        let mut candidates = self
+            .last_posts(principal, false)
+            .take(CONFIG.recommendation_window)
+            .filter(|post| post.user != user.id && !user.ignores(post.user))
+            .filter(|post| {
+                post.reactions
+                    .values()
+                    .all(|users| !users.contains(&user.id))
+            })
+            .map(|post| {
+                let lc_tags: BTreeSet<_> = post.tags.iter().map(|t| t.to_lowercase()).collect();
+                let interest: i64 = lc_tags
+                    .iter()
+                    .filter_map(|tag| user.interests.get(tag))
+                    .sum();
+                let following = (user.followees.contains(&post.user)
+                    || covered_by_feeds(&user.feeds, &lc_tags, false).is_some())
+                    as i64;
+                let engagement = post
+                    .reactions
+                    .iter()
+                    .filter(|(id, _)| **id >= CONFIG.min_positive_reaction_id)
+                    .map(|(_, users)| users.len() as i64)
+                    .sum::<i64>()
+                    + post.tree_size as i64;
+                let score = CONFIG.recommendation_interest_weight * interest
+                    + CONFIG.recommendation_following_weight * following
+                    + CONFIG.recommendation_engagement_weight * engagement;
+                (score, post)
+            })
+            .filter(|(score, _)| *score > 0)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(score, post)| std::cmp::Reverse((*score, post.id)));
+        let mut authors: HashMap<UserId, usize> = Default::default();
+        candidates
+            .into_iter()
+            .filter(|(_, post)| {
+                let count = authors.entry(post.user).or_default();
+                *count += 1;
+                *count <= CONFIG.recommendation_max_posts_per_author
+            })
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .map(|(_, post)| post.restored())
             .collect()
     }
 
@@ -277,18 +650,258 @@ impl State {
             if user.current_realm == Some(name.clone()) {
                 user.current_realm = None
             }
-            self.realms
-                .get_mut(&name)
-                .map(|realm| realm.members.remove(&user_id));
+            if let Some(realm) = self.realms.get_mut(&name) {
+                realm.members.remove(&user_id);
+                realm.member_tiers.remove(&user_id);
+            }
             return false;
         }
-        user.realms.push(name.clone());
-        self.realms
-            .get_mut(&name)
-            .map(|realm| realm.members.insert(user_id));
+        let realm = self.realms.get_mut(&name).expect("no realm found");
+        if realm.visibility != RealmVisibility::Public && !realm.controllers.contains(&user_id) {
+            // restricted realms are joined via a request approved by a controller
+            if realm.join_requests.insert(user_id) {
+                let user_name = self
+                    .users
+                    .get(&user_id)
+                    .expect("no user found")
+                    .name
+                    .clone();
+                for controller in realm.controllers.clone() {
+                    if let Some(user) = self
+                        .users
+                        .get_mut(&controller)
+                        .filter(|user| user.notification_settings.realms)
+                    {
+                        user.notify(format!(
+                            "@{} requested to join realm [{1}](/#/realm/{1})",
+                            user_name, name
+                        ));
+                    }
+                }
+            }
+            return false;
+        }
+        self.add_realm_member(user_id, &name);
         true
     }
 
+    fn add_realm_member(&mut self, user_id: UserId, name: &str) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.realms.push(name.to_string());
+        }
+        if let Some(realm) = self.realms.get_mut(name) {
+            realm.members.insert(user_id);
+            realm.join_requests.remove(&user_id);
+        }
+        self.recompute_realm_tiers(name);
+    }
+
+    pub fn resolve_join_request(
+        &mut self,
+        principal: Principal,
+        name: String,
+        user_id: UserId,
+        approve: bool,
+    ) -> Result<(), String> {
+        let controller_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&controller_id) {
+            return Err("not authorized".into());
+        }
+        if !realm.join_requests.remove(&user_id) {
+            return Err("no join request found".into());
+        }
+        if approve {
+            self.add_realm_member(user_id, &name);
+        }
+        if let Some(user) = self
+            .users
+            .get_mut(&user_id)
+            .filter(|user| user.notification_settings.realms)
+        {
+            user.notify(format!(
+                "Your request to join realm [{0}](/#/realm/{0}) was {1}",
+                name,
+                if approve { "approved" } else { "rejected" }
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn create_realm_invite(
+        &mut self,
+        principal: Principal,
+        name: String,
+        max_uses: u32,
+        expires_in_hours: Option<u64>,
+        seed: &[u8],
+        now: u64,
+    ) -> Result<String, String> {
+        if max_uses == 0 {
+            return Err("invite needs at least one use".into());
+        }
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        realm.invites.retain(|_, invite| invite.valid(now).is_ok());
+        if realm.invites.len() >= CONFIG.max_realm_invites {
+            return Err(format!(
+                "not more than {} active invites are allowed",
+                CONFIG.max_realm_invites
+            ));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        let code = format!("{:x}", hasher.finalize())[..16].to_string();
+        if realm.invites.contains_key(&code) {
+            return Err("invite code collision, please retry".into());
+        }
+        realm.invites.insert(
+            code.clone(),
+            RealmInvite {
+                creator: user_id,
+                expires_at: expires_in_hours.map(|hours| now + hours * HOUR),
+                uses_left: max_uses,
+                redeemed_by: Default::default(),
+            },
+        );
+        Ok(code)
+    }
+
+    // Joins the caller to the realm of the invite and returns the realm name.
+    pub fn redeem_realm_invite(
+        &mut self,
+        principal: Principal,
+        code: String,
+        now: u64,
+    ) -> Result<String, String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let (name, realm) = self
+            .realms
+            .iter_mut()
+            .find(|(_, realm)| realm.invites.contains_key(&code))
+            .ok_or("invite not found")?;
+        let invite = realm.invites.get_mut(&code).expect("no invite found");
+        if invite.redeemed_by.contains(&user_id) {
+            return Err("invite already redeemed".into());
+        }
+        invite.valid(now)?;
+        if realm.members.contains(&user_id) {
+            return Err("already a member of the realm".into());
+        }
+        invite.uses_left -= 1;
+        invite.redeemed_by.insert(user_id);
+        let name = name.clone();
+        self.add_realm_member(user_id, &name);
+        Ok(name)
+    }
+
+    pub fn pin_post(
+        &mut self,
+        principal: Principal,
+        name: String,
+        post_id: PostId,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let post = self.posts.get(&post_id).ok_or("post not found")?;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if post.deleted() {
+            return Err("deleted posts can't be pinned".into());
+        }
+        if post.realm.as_ref() != Some(&name) {
+            return Err("post is not in the realm".into());
+        }
+        if realm.pinned.contains(&post_id) {
+            return Err("post is already pinned".into());
+        }
+        if realm.pinned.len() >= CONFIG.max_pinned_posts {
+            return Err(format!(
+                "not more than {} pinned posts are allowed",
+                CONFIG.max_pinned_posts
+            ));
+        }
+        realm.pinned.insert(0, post_id);
+        Ok(())
+    }
+
+    pub fn unpin_post(
+        &mut self,
+        principal: Principal,
+        name: String,
+        post_id: PostId,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        let len = realm.pinned.len();
+        realm.pinned.retain(|id| *id != post_id);
+        if realm.pinned.len() == len {
+            return Err("post is not pinned".into());
+        }
+        Ok(())
+    }
+
+    // Returns the realm feed with the pinned posts on top of the first page.
+    pub fn realm_posts(
+        &self,
+        principal: Principal,
+        name: &str,
+        cursor: Option<String>,
+        with_comments: bool,
+    ) -> Result<FeedPage, String> {
+        let realm = self.realms.get(name).ok_or("no realm found")?;
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
+        let visible = |post: &&Post| self.visible(user_id, post);
+        let pinned = realm
+            .pinned
+            .iter()
+            .filter_map(|id| self.posts.get(id))
+            .filter(|post| !post.deleted())
+            .filter(visible)
+            .collect::<Vec<_>>();
+        let pinned_ids = pinned.iter().map(|post| post.id).collect::<HashSet<_>>();
+        let first_page = cursor.is_none();
+        let mut page = FeedPage::new(
+            realm
+                .posts
+                .iter()
+                .rev()
+                .filter(|id| !pinned_ids.contains(id))
+                .filter_map(|id| self.posts.get(id))
+                .filter(|post| with_comments || post.parent.is_none())
+                .filter(visible),
+            cursor,
+            CONFIG.feed_page_size - if first_page { pinned.len() } else { 0 },
+        )?;
+        if first_page {
+            page.posts
+                .splice(..0, pinned.into_iter().map(Post::restored));
+        }
+        Ok(page)
+    }
+
+    pub fn set_realm_visibility(
+        &mut self,
+        principal: Principal,
+        name: String,
+        visibility: RealmVisibility,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        realm.visibility = visibility;
+        Ok(())
+    }
+
     pub fn edit_realm(
         &mut self,
         principal: Principal,
@@ -346,7 +959,7 @@ impl State {
             return Err("realm name should have at least on character".into());
         }
 
-        if self.realms.contains_key(&name) {
+        if self.realms.contains_key(&name) || self.realm_redirects.contains_key(&name) {
             return Err("realm name taken".into());
         }
 
@@ -372,6 +985,23 @@ impl State {
                 label_color,
                 posts: Default::default(),
                 members: vec![user.id].into_iter().collect(),
+                tiers: Default::default(),
+                member_tiers: Default::default(),
+                archive_after: None,
+                archive_cold_storage: false,
+                archived: Default::default(),
+                reports: Default::default(),
+                treasury: 0,
+                rewards: None,
+                visibility: Default::default(),
+                join_requests: Default::default(),
+                invites: Default::default(),
+                pinned: Default::default(),
+                reward_multiplier: None,
+                max_post_length: None,
+                max_post_attachments: None,
+                blocked_patterns: Default::default(),
+                blocklist: None,
             },
         );
 
@@ -383,18 +1013,519 @@ impl State {
         Ok(())
     }
 
-    pub fn tip(
+    // Follows the redirects of merged realms.
+    pub fn resolve_realm_name(&self, name: &str) -> String {
+        match self.realm_redirects.get(name) {
+            Some(target) => target.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    // Moves the posts, members and the treasury of the source realm into the target realm and
+    // leaves a redirect behind. Users controlling only one of both realms while being a member
+    // of the other one lose the controller role.
+    pub fn merge_realms(
         &mut self,
         principal: Principal,
-        post_id: PostId,
-        tip: Cycles,
+        source: String,
+        target: String,
     ) -> Result<(), String> {
-        let ledger_log = format!("tipping for post {}", post_id);
-        let tipper = self.principal_to_user(principal).ok_or("no user found")?;
-        let tipper_id = tipper.id;
-        let tipper_name = tipper.name.clone();
-        let author_id = self.posts.get(&post_id).ok_or("post not found")?.user;
-        self.cycle_transfer(
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        let (user_id, user_name) = (user.id, user.name.clone());
+        if source == target {
+            return Err("a realm can't be merged into itself".into());
+        }
+        for name in [&source, &target] {
+            let realm = self.realms.get(name).ok_or("no realm found")?;
+            if !realm.controllers.contains(&user_id) {
+                return Err("not authorized".into());
+            }
+        }
+        // merging must not expose posts of a restricted or private realm
+        let restriction = |name: &String| match self.realms.get(name).map(|realm| &realm.visibility)
+        {
+            Some(RealmVisibility::Private) => 2,
+            Some(RealmVisibility::Restricted) => 1,
+            _ => 0,
+        };
+        if restriction(&source) > restriction(&target) {
+            return Err("a realm can't be merged into a less restrictive realm".into());
+        }
+        let source_realm = self.realms.remove(&source).expect("no realm found");
+
+        for post_id in source_realm.posts.iter().chain(&source_realm.archived) {
+            if let Some(post) = self.posts.get_mut(post_id) {
+                post.realm = Some(target.clone());
+            }
+        }
+        for user_id in &source_realm.members {
+            if let Some(user) = self.users.get_mut(user_id) {
+                user.realms.retain(|name| name != &source);
+                if !user.realms.contains(&target) {
+                    user.realms.push(target.clone());
+                }
+                if user.current_realm.as_ref() == Some(&source) {
+                    user.current_realm = Some(target.clone());
+                }
+            }
+        }
+        for post in self.scheduled_posts.values_mut() {
+            if post.realm.as_ref() == Some(&source) {
+                post.realm = Some(target.clone());
+            }
+        }
+
+        let realm = self.realms.get_mut(&target).expect("no realm found");
+        realm.controllers.retain(|id| {
+            !source_realm.members.contains(id) || source_realm.controllers.contains(id)
+        });
+        realm.posts.extend(source_realm.posts);
+        realm.posts.sort_unstable();
+        realm.posts.dedup();
+        realm.archived.extend(source_realm.archived);
+        realm.archived.sort_unstable();
+        realm.members.extend(source_realm.members);
+        realm.join_requests.extend(source_realm.join_requests);
+        let members = realm.members.clone();
+        realm.join_requests.retain(|id| !members.contains(id));
+        realm.reports.extend(source_realm.reports);
+        realm.treasury += source_realm.treasury;
+
+        for name in self.realm_redirects.values_mut() {
+            if name == &source {
+                *name = target.clone();
+            }
+        }
+        self.realm_redirects.insert(source.clone(), target.clone());
+        self.recompute_realm_tiers(&target);
+
+        self.logger.info(format!(
+            "@{} merged realm {} into realm [{2}](/#/realm/{2}) 🎭",
+            user_name, source, target
+        ));
+        Ok(())
+    }
+
+    // Burns the donated tokens from the donor's account and credits them to the realm treasury.
+    pub fn donate_to_realm(
+        &mut self,
+        principal: Principal,
+        name: String,
+        amount: Token,
+        now: u64,
+    ) -> Result<(), String> {
+        if amount == 0 {
+            return Err("amount must be positive".into());
+        }
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        let (user_principal, user_name) = (user.principal, user.name.clone());
+        if !self.realms.contains_key(&name) {
+            return Err("no realm found".into());
+        }
+        crate::token::transfer_tokens(now, self, user_principal, Principal::anonymous(), amount)?;
+        let realm = self.realms.get_mut(&name).expect("no realm found");
+        realm.treasury += amount;
+        let controllers = realm.controllers.clone();
+        for user_id in controllers {
+            if let Some(user) = self
+                .users
+                .get_mut(&user_id)
+                .filter(|user| user.notification_settings.realms)
+            {
+                user.notify(format!(
+                    "@{} donated `{}` ${} to the treasury of realm [{3}](/#/realm/{3})",
+                    user_name,
+                    token::display(amount),
+                    CONFIG.token_symbol,
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_realm_tiers(
+        &mut self,
+        principal: Principal,
+        name: String,
+        tiers: Vec<Tier>,
+    ) -> Result<(), String> {
+        if tiers.len() > CONFIG.max_realm_tiers {
+            return Err(format!(
+                "not more than {} tiers are allowed",
+                CONFIG.max_realm_tiers
+            ));
+        }
+        if tiers.iter().any(|tier| {
+            tier.name.is_empty()
+                || tier.name.len() > CONFIG.max_realm_name
+                || tier.flair.chars().count() > CONFIG.max_tag_length
+        }) {
+            return Err("invalid tier name or flair".into());
+        }
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        realm.tiers = tiers;
+        self.recompute_realm_tiers(&name);
+        Ok(())
+    }
+
+    pub fn set_realm_archival(
+        &mut self,
+        principal: Principal,
+        name: String,
+        delay_hours: Option<u64>,
+        cold_storage: bool,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        realm.archive_after = delay_hours.map(|hours| hours * HOUR);
+        realm.archive_cold_storage = cold_storage;
+        Ok(())
+    }
+
+    pub fn set_realm_reward_multiplier(
+        &mut self,
+        principal: Principal,
+        name: String,
+        percentage: u32,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        let (min, max) = CONFIG.realm_reward_multiplier_range;
+        if percentage < min || percentage > max {
+            return Err(format!(
+                "the multiplier must be between {}% and {}%",
+                min, max
+            ));
+        }
+        realm.reward_multiplier = (percentage != 100).then_some(percentage);
+        Ok(())
+    }
+
+    // Overrides the global post limits in the realm; unset limits fall back to the global ones.
+    pub fn set_realm_post_limits(
+        &mut self,
+        principal: Principal,
+        name: String,
+        max_length: Option<usize>,
+        max_attachments: Option<usize>,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if let Some(0) = max_length {
+            return Err("the post length limit must be positive".into());
+        }
+        if max_length.unwrap_or_default() > CONFIG.max_realm_post_length {
+            return Err(format!(
+                "the post length limit can't exceed {} characters",
+                CONFIG.max_realm_post_length
+            ));
+        }
+        if max_attachments.unwrap_or_default() > CONFIG.max_realm_post_attachments {
+            return Err(format!(
+                "the attachment limit can't exceed {}",
+                CONFIG.max_realm_post_attachments
+            ));
+        }
+        realm.max_post_length = max_length;
+        realm.max_post_attachments = max_attachments;
+        Ok(())
+    }
+
+    pub fn set_realm_blocked_patterns(
+        &mut self,
+        principal: Principal,
+        name: String,
+        patterns: Vec<String>,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        filters::validate(&patterns)?;
+        realm.blocklist = filters::blocklist(&patterns);
+        realm.blocked_patterns = patterns;
+        Ok(())
+    }
+
+    // Applies the reward multiplier of the realm to a karma reward.
+    pub fn realm_reward(&self, realm: Option<&String>, amount: Cycles) -> Cycles {
+        match realm
+            .and_then(|name| self.realms.get(name))
+            .and_then(|realm| realm.reward_multiplier)
+        {
+            Some(percentage) => amount * percentage as Cycles / 100,
+            None => amount,
+        }
+    }
+
+    // Sets up the recurring distribution of treasury tokens; a zero amount disables it.
+    pub fn set_realm_rewards(
+        &mut self,
+        principal: Principal,
+        name: String,
+        amount: Token,
+        period_hours: u64,
+        now: u64,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if amount == 0 {
+            realm.rewards = None;
+            return Ok(());
+        }
+        if period_hours < CONFIG.min_realm_rewards_period_hours {
+            return Err(format!(
+                "the period must be at least {} hours",
+                CONFIG.min_realm_rewards_period_hours
+            ));
+        }
+        realm.rewards = Some(RealmRewards {
+            amount,
+            period: period_hours * HOUR,
+            last_distribution: now,
+        });
+        Ok(())
+    }
+
+    // Distributes the realm rewards of every elapsed period proportionally to the contributions
+    // of the members: one point per post in the realm plus one point per received upvote.
+    fn distribute_realm_rewards(&mut self, now: u64) {
+        let posts = &self.posts;
+        let mut payouts = Vec::new();
+        for (name, realm) in self.realms.iter_mut() {
+            let (amount, since) = match realm.rewards.as_mut() {
+                Some(rewards) if rewards.last_distribution + rewards.period <= now => (
+                    rewards.amount,
+                    std::mem::replace(&mut rewards.last_distribution, now),
+                ),
+                _ => continue,
+            };
+            let mut points: BTreeMap<UserId, u64> = Default::default();
+            for post in realm
+                .posts
+                .iter()
+                .rev()
+                .filter_map(|id| posts.get(id))
+                .take_while(|post| post.timestamp > since)
+                .filter(|post| !post.deleted() && realm.members.contains(&post.user))
+            {
+                let upvotes = post
+                    .reactions
+                    .iter()
+                    .filter(|(id, _)| {
+                        CONFIG
+                            .reactions
+                            .iter()
+                            .any(|(reaction, cost)| reaction == *id && *cost > 0)
+                    })
+                    .map(|(_, users)| users.len() as u64)
+                    .sum::<u64>();
+                *points.entry(post.user).or_default() += 1 + upvotes;
+            }
+            let total_points: u64 = points.values().sum();
+            let amount = amount.min(realm.treasury);
+            if total_points == 0 || amount == 0 {
+                continue;
+            }
+            for (user_id, user_points) in points {
+                let share = (amount as u128 * user_points as u128 / total_points as u128) as Token;
+                if share > 0 {
+                    realm.treasury -= share;
+                    payouts.push((name.clone(), user_id, share));
+                }
+            }
+        }
+        for (name, user_id, share) in payouts {
+            let user = match self.users.get_mut(&user_id) {
+                Some(user) => user,
+                None => continue,
+            };
+            let principal = user.principal;
+            if user.notification_settings.realms {
+                user.notify(format!(
+                    "You received `{}` ${} from the treasury of realm [{2}](/#/realm/{2}) for your contributions! 🎁",
+                    token::display(share),
+                    CONFIG.token_symbol,
+                    name
+                ));
+            }
+            crate::token::mint(self, account(principal), share);
+        }
+    }
+
+    // Moves posts which stayed in a terminal status long enough from the active realm feeds
+    // to the realm archives.
+    fn archive_posts(&mut self, now: u64) {
+        let mut archived = 0;
+        let posts = &mut self.posts;
+        for realm in self.realms.values_mut() {
+            let delay = match realm.archive_after {
+                Some(delay) => delay,
+                None => continue,
+            };
+            let (to_archive, active): (Vec<PostId>, Vec<PostId>) =
+                realm.posts.iter().partition(|id| {
+                    posts
+                        .get(id)
+                        .map(|post| post.status.terminal() && post.status_timestamp + delay <= now)
+                        .unwrap_or_default()
+                });
+            if to_archive.is_empty() {
+                continue;
+            }
+            realm.posts = active;
+            for id in to_archive {
+                let post = posts.get_mut(&id).expect("no post found");
+                post.archived = true;
+                if realm.archive_cold_storage && !post.body.is_empty() {
+                    match self.memory.write(&post.body) {
+                        Ok(coordinates) => {
+                            post.cold_body = Some(coordinates);
+                            post.body.clear();
+                        }
+                        Err(err) => self
+                            .logger
+                            .error(format!("Couldn't cold-store post {}: {}", id, err)),
+                    }
+                }
+                realm.archived.push(id);
+                archived += 1;
+            }
+            realm.archived.sort_unstable();
+        }
+        if archived > 0 {
+            self.logger
+                .info(format!("Archived `{}` resolved realm posts.", archived));
+        }
+    }
+
+    pub fn unarchive_post(&mut self, post_id: PostId) -> Result<(), String> {
+        let post = self.posts.get_mut(&post_id).ok_or("no post found")?;
+        if let Some((offset, len)) = post.cold_body {
+            post.body = post.text();
+            post.cold_body = None;
+            self.memory.free(offset, len)?;
+        }
+        post.archived = false;
+        if let Some(realm) = post
+            .realm
+            .clone()
+            .and_then(|name| self.realms.get_mut(&name))
+        {
+            realm.archived.retain(|id| id != &post_id);
+            if let Err(index) = realm.posts.binary_search(&post_id) {
+                realm.posts.insert(index, post_id);
+            }
+        }
+        Ok(())
+    }
+
+    // Recomputes the tiers of the next batch of realms with tiers, starting after the realm
+    // recomputed last and wrapping around at the end.
+    pub fn recompute_realm_tiers_batch(&mut self, batch_size: usize) {
+        let names = self
+            .realms
+            .iter()
+            .filter(|(name, realm)| {
+                name.as_str() > self.realm_tiers_cursor.as_str()
+                    && !(realm.tiers.is_empty() && realm.member_tiers.is_empty())
+            })
+            .map(|(name, _)| name.clone())
+            .take(batch_size)
+            .collect::<Vec<_>>();
+        self.realm_tiers_cursor = if names.len() < batch_size {
+            Default::default()
+        } else {
+            names.last().cloned().unwrap_or_default()
+        };
+        for name in names {
+            self.recompute_realm_tiers(&name);
+        }
+    }
+
+    // Assigns every realm member the highest tier whose requirements are met and
+    // notifies members about tier changes.
+    pub fn recompute_realm_tiers(&mut self, name: &str) {
+        let realm = match self.realms.get(name) {
+            Some(realm) => realm,
+            None => return,
+        };
+        let mut tips: HashMap<UserId, Cycles> = Default::default();
+        for post in realm.posts.iter().filter_map(|id| self.posts.get(id)) {
+            for (tipper, amount) in &post.tips {
+                *tips.entry(*tipper).or_default() += amount;
+            }
+        }
+        let member_tiers = realm
+            .members
+            .iter()
+            .filter_map(|user_id| {
+                let karma = self.users.get(user_id)?.karma();
+                let tipped = tips.get(user_id).copied().unwrap_or_default();
+                realm
+                    .tiers
+                    .iter()
+                    .rposition(|tier| karma >= tier.min_karma && tipped >= tier.min_tips)
+                    .map(|index| (*user_id, index))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut changes = Vec::new();
+        for user_id in &realm.members {
+            let (before, after) = (realm.member_tiers.get(user_id), member_tiers.get(user_id));
+            if before == after {
+                continue;
+            }
+            let message = match after.and_then(|index| realm.tiers.get(*index)) {
+                Some(tier) => format!("Your tier in realm {} is now `{}`", name, tier.name),
+                None => format!("You lost your tier in realm {}", name),
+            };
+            changes.push((*user_id, message));
+        }
+        self.realms
+            .get_mut(name)
+            .expect("no realm found")
+            .member_tiers = member_tiers;
+        for (user_id, message) in changes {
+            if let Some(user) = self
+                .users
+                .get_mut(&user_id)
+                .filter(|user| user.notification_settings.realms)
+            {
+                user.notify(message);
+            }
+        }
+    }
+
+    pub fn tip(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        tip: Cycles,
+    ) -> Result<(), String> {
+        let ledger_log = format!("tipping for post {}", post_id);
+        let tipper = self.principal_to_user(principal).ok_or("no user found")?;
+        let tipper_id = tipper.id;
+        let tipper_name = tipper.name.clone();
+        let author_id = self.posts.get(&post_id).ok_or("post not found")?.user;
+        self.cycle_transfer(
             tipper_id,
             author_id,
             tip,
@@ -404,27 +1535,109 @@ impl State {
         )?;
         let post = self.posts.get_mut(&post_id).expect("post not found");
         post.tips.push((tipper_id, tip));
-        self.users
-            .get_mut(&author_id)
-            .expect("user not found")
-            .notify_about_post(
+        let author = self.users.get_mut(&author_id).expect("user not found");
+        if author.notification_settings.rewards {
+            author.notify_about_post(
                 format!(
                     "@{} tipped you with `{}` cycles for your post",
                     tipper_name, tip,
                 ),
                 post_id,
             );
+        }
+        Ok(())
+    }
+
+    pub fn tip_tokens(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        amount: Token,
+        now: u64,
+    ) -> Result<(), String> {
+        if amount < CONFIG.minimal_token_tip {
+            return Err(format!(
+                "the minimal tip is `{}` tokens",
+                CONFIG.minimal_token_tip
+            ));
+        }
+        let tipper = self.principal_to_user(principal).ok_or("no user found")?;
+        let (tipper_id, tipper_principal, tipper_name) =
+            (tipper.id, tipper.principal, tipper.name.clone());
+        let post = self.posts.get(&post_id).ok_or("post not found")?;
+        if post.deleted() {
+            return Err("post not found".into());
+        }
+        let author = self.users.get(&post.user).ok_or("no user found")?;
+        if author.id == tipper_id {
+            return Err("you can't tip yourself".into());
+        }
+        let author_principal = author.principal;
+        // all checks are done above, so the transfer is the only step that can still fail
+        crate::token::transfer_tokens(now, self, tipper_principal, author_principal, amount)?;
+        let post = self.posts.get_mut(&post_id).expect("post not found");
+        post.token_tips.push((tipper_id, amount));
+        let author_id = post.user;
+        let author = self.users.get_mut(&author_id).expect("user not found");
+        if author.notification_settings.rewards {
+            author.notify_about_post(
+                format!(
+                    "@{} tipped you with `{}` ${} for your post",
+                    tipper_name,
+                    token::display(amount),
+                    CONFIG.token_symbol
+                ),
+                post_id,
+            );
+        }
         Ok(())
     }
 
-    pub fn tree(&self, id: PostId) -> HashMap<PostId, &'_ Post> {
+    // Returns the post with all comments, with children ordered by the sort mode. Comments
+    // scored below the threshold are returned collapsed, i.e. without their sub-threads.
+    pub fn tree(
+        &self,
+        principal: Principal,
+        id: PostId,
+        sort: CommentSort,
+        collapse_below: Option<i64>,
+    ) -> CommentTree {
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
         let mut backlog = vec![id];
-        let mut posts: HashMap<_, _> = Default::default();
-        while let Some(post) = backlog.pop().and_then(|id| self.posts.get(&id)) {
-            backlog.extend_from_slice(post.children.as_slice());
-            posts.insert(post.id, post);
+        let mut tree = CommentTree::default();
+        while let Some(post) = backlog
+            .pop()
+            .and_then(|id| self.posts.get(&id))
+            .filter(|post| self.accessible(user_id, post))
+        {
+            let mut post = post.restored();
+            let mut children = post
+                .children
+                .iter()
+                .filter_map(|id| self.posts.get(id))
+                .filter(|post| self.accessible(user_id, post))
+                .collect::<Vec<_>>();
+            match sort {
+                CommentSort::Newest => {
+                    children.sort_by_key(|post| std::cmp::Reverse((post.timestamp, post.id)))
+                }
+                CommentSort::Oldest => children.sort_by_key(|post| (post.timestamp, post.id)),
+                CommentSort::Top => children
+                    .sort_by_key(|post| (std::cmp::Reverse(post.score()), post.timestamp, post.id)),
+            }
+            post.children = children.iter().map(|post| post.id).collect();
+            let collapsed = post.id != id
+                && collapse_below
+                    .map(|threshold| post.score() < threshold)
+                    .unwrap_or_default();
+            if collapsed {
+                tree.collapsed.insert(post.id);
+            } else {
+                backlog.extend_from_slice(post.children.as_slice());
+            }
+            tree.posts.insert(post.id, post);
         }
-        posts
+        tree
     }
 
     fn new_user(&mut self, principal: Principal, timestamp: u64, name: String) -> UserId {
@@ -432,6 +1645,7 @@ impl State {
         let mut user = User::new(principal, id, timestamp, name);
         user.notify(format!("**Welcome!** 🎉 Use #{} as your personal blog, micro-blog or a photo blog. Use #hashtags to connect with others. Make sure you understand [how {0} works](/#/whitepaper). And finally, [say hello](#/new) and start earning karma!", CONFIG.name));
         self.principals.insert(principal, user.id);
+        self.user_names.insert(user.name.to_lowercase(), user.id);
         self.logger
             .info(format!("@{} joined {} 🚀", &user.name, CONFIG.name));
         self.users.insert(user.id, user);
@@ -453,6 +1667,7 @@ impl State {
             let user = self.users.get_mut(&id).expect("no user found");
             user.change_cycles(cycles, "top up by invite".to_string())?;
             user.invited_by = Some(user_id);
+            user.referral_pending = true;
             if let Some(inviter) = self.users.get_mut(&user_id) {
                 inviter.notify(format!(
                     "Your invite was used by @{}! Thanks for helping #{} grow! 🤗",
@@ -471,17 +1686,50 @@ impl State {
         Err("payment missing or the invite is invalid".to_string())
     }
 
-    pub fn invites(&self, principal: Principal) -> Vec<(String, Cycles)> {
-        self.principal_to_user(principal)
-            .map(|user| {
-                self.invites
-                    .iter()
-                    .filter(|(_, (user_id, _))| user_id == &user.id)
-                    .map(|(code, (_, cycles))| (code.clone(), *cycles))
-                    .collect::<Vec<_>>()
+    // Rewards the inviters of users who crossed the activity threshold, once per invited user.
+    fn reward_referrals(&mut self) {
+        let referrals = self
+            .users
+            .values_mut()
+            .filter(|user| {
+                user.referral_pending
+                    && user.posts.len() >= CONFIG.referral_min_posts
+                    && user.karma() + user.karma_to_reward() >= CONFIG.referral_min_karma
             })
-            .unwrap_or_default()
-    }
+            .filter_map(|user| {
+                user.referral_pending = false;
+                user.invited_by.map(|id| (id, user.name.clone()))
+            })
+            .collect::<Vec<_>>();
+        for (inviter_id, name) in referrals {
+            if !self.users.contains_key(&inviter_id) {
+                continue;
+            }
+            self.spend_to_user_karma(
+                inviter_id,
+                CONFIG.referral_reward,
+                format!("referral of @{}", name),
+            );
+            if let Some(inviter) = self.users.get_mut(&inviter_id) {
+                inviter.notify(format!(
+                    "You received `{}` karma because @{} invited by you became an active user! 🤝",
+                    CONFIG.referral_reward, name
+                ));
+            }
+        }
+    }
+
+    pub fn invites(&self, principal: Principal) -> Vec<(String, Cycles)> {
+        self.principal_to_user(principal)
+            .map(|user| {
+                self.invites
+                    .iter()
+                    .filter(|(_, (user_id, _))| user_id == &user.id)
+                    .map(|(code, (_, cycles))| (code.clone(), *cycles))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    }
 
     pub fn create_invite(&mut self, principal: Principal, cycles: Cycles) -> Result<(), String> {
         let min_cycles = CONFIG.min_cycles_for_inviting;
@@ -558,7 +1806,14 @@ impl State {
             });
     }
 
-    pub fn search(&self, principal: Principal, mut term: String) -> Vec<SearchResult> {
+    // Searches users, tags and posts; with a realm filter only the posts of the realm are
+    // searched, which requires a membership for private realms.
+    pub fn search(
+        &self,
+        principal: Principal,
+        mut term: String,
+        realm: Option<String>,
+    ) -> Vec<SearchResult> {
         const SNIPPET_LEN: usize = 100;
         term = term.to_lowercase();
         let boddy_snippet = |body: &str, i: usize| {
@@ -574,8 +1829,48 @@ impl State {
             }
             .replace('\n', " ")
         };
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
+        if let Some(realm) = realm.as_ref() {
+            match self.realms.get(realm) {
+                Some(realm)
+                    if realm.visibility != RealmVisibility::Private
+                        || user_id
+                            .map(|id| realm.members.contains(&id))
+                            .unwrap_or_default() => {}
+                _ => return Default::default(),
+            }
+        }
+        let current_realm = realm.as_ref().or_else(|| {
+            self.principal_to_user(principal)
+                .and_then(|user| user.current_realm.as_ref())
+        });
+        let eligible = |post: &Post| {
+            !post.deleted()
+                && (current_realm.is_none() || post.realm.as_ref() == current_realm)
+                && self.visible(user_id, post)
+        };
+        let search_terms = search::terms(&term);
+        let mut posts = self
+            .search_index
+            .lookup(&term)
+            .into_iter()
+            .filter_map(|(id, count)| self.posts.get(&id).map(|post| (post, count)))
+            .filter(|(post, _)| eligible(post))
+            .collect::<Vec<_>>();
+        search::rank(&mut posts, time());
+        // a post id as the search term yields the post itself first
+        if let Some(post) = term
+            .parse()
+            .ok()
+            .and_then(|id: PostId| self.posts.get(&id))
+            .filter(|post| eligible(post))
+        {
+            posts.retain(|(hit, _)| hit.id != post.id);
+            posts.insert(0, (post, 0));
+        }
         self.users
             .iter()
+            .filter(|_| realm.is_none())
             .filter_map(|(id, User { name, about, .. })| {
                 if format!("@{} {0} {} {}", name, id, about)
                     .to_lowercase()
@@ -593,6 +1888,7 @@ impl State {
             .chain(
                 self.recent_tags(principal, 500)
                     .into_iter()
+                    .filter(|_| realm.is_none())
                     .filter_map(|(tag, _)| {
                         if format!("#{} {0}", tag).to_lowercase().contains(&term) {
                             return Some(SearchResult {
@@ -605,32 +1901,142 @@ impl State {
                         None
                     }),
             )
-            .chain(self.last_posts(principal, true).filter_map(
-                |Post { id, body, user, .. }| {
-                    if id.to_string() == term {
-                        return Some(SearchResult {
-                            id: *id,
-                            user_id: *user,
-                            relevant: boddy_snippet(body, 0),
-                            result: "post".to_string(),
-                        });
-                    }
-                    let search_body = body.to_lowercase();
-                    if let Some(i) = search_body.find(&term) {
-                        return Some(SearchResult {
-                            id: *id,
-                            user_id: *user,
-                            relevant: boddy_snippet(body, i),
-                            result: "post".to_string(),
-                        });
-                    }
-                    None
-                },
-            ))
+            .chain(posts.into_iter().map(|(post, _)| {
+                let body = post.text();
+                let search_body = body.to_lowercase();
+                let i = search_terms
+                    .keys()
+                    .filter_map(|term| search_body.find(term.as_str()))
+                    .min()
+                    .unwrap_or_default();
+                SearchResult {
+                    id: post.id,
+                    user_id: post.user,
+                    relevant: boddy_snippet(&body, i),
+                    result: "post".to_string(),
+                }
+            }))
             .take(100)
             .collect()
     }
 
+    // Releases the bucket space of files not shared with other cross-posts of the same post.
+    pub fn release_files(&mut self, post_id: PostId, files: &BTreeMap<String, (u64, usize)>) {
+        let canonical_id = self
+            .posts
+            .get(&post_id)
+            .and_then(|post| post.cross_post)
+            .unwrap_or(post_id);
+        let shared = self
+            .posts
+            .get(&canonical_id)
+            .map(|post| post.cross_posts.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .chain(std::iter::once(canonical_id))
+            .filter(|id| *id != post_id)
+            .filter_map(|id| self.posts.get(&id))
+            .flat_map(|post| post.files.keys().cloned())
+            .collect::<BTreeSet<_>>();
+        for (file_id, (offset, len)) in files {
+            if shared.contains(file_id) {
+                continue;
+            }
+            if let Some(bucket_id) = file_id
+                .split('@')
+                .nth(1)
+                .and_then(|id| Principal::from_text(id).ok())
+            {
+                self.storage.release(bucket_id, *offset, *len as u64);
+            }
+        }
+    }
+
+    // Adopts orphaned buckets among the given canisters and flags other canisters controlled
+    // by the main canister alone for deletion.
+    pub async fn reconcile_buckets(
+        &mut self,
+        principal: Principal,
+        canisters: Vec<String>,
+    ) -> Result<(Vec<String>, Vec<String>), String> {
+        if !self
+            .principal_to_user(principal)
+            .map(|user| user.stalwart)
+            .unwrap_or_default()
+        {
+            return Err("only stalwarts can reconcile buckets".into());
+        }
+        let canisters = canisters
+            .iter()
+            .map(|id| Principal::from_text(id).map_err(|err| err.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (adopted, flagged) = self
+            .storage
+            .reconcile_buckets(&mut self.logger, &canisters)
+            .await;
+        let to_strings =
+            |ids: Vec<Principal>| ids.into_iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        Ok((to_strings(adopted), to_strings(flagged)))
+    }
+
+    // Deletes an empty or compacted bucket and returns its cycles to the main canister.
+    pub async fn decommission_bucket(
+        &mut self,
+        principal: Principal,
+        id: String,
+    ) -> Result<u64, String> {
+        if !self
+            .principal_to_user(principal)
+            .map(|user| user.stalwart)
+            .unwrap_or_default()
+        {
+            return Err("only stalwarts can decommission buckets".into());
+        }
+        let id = Principal::from_text(id).map_err(|err| err.to_string())?;
+        self.storage.decommission_bucket(&mut self.logger, id).await
+    }
+
+    // Moves all files referenced by posts out of the given bucket and updates the references.
+    #[allow(dead_code)]
+    pub async fn compact_bucket(&mut self, id: Principal) -> Result<(), String> {
+        let suffix = format!("@{}", id);
+        let references = self
+            .posts
+            .values()
+            .flat_map(|post| post.files.iter())
+            .filter(|(file_id, _)| file_id.ends_with(&suffix))
+            .map(|(_, (offset, len))| (*offset, *len as u64))
+            .collect::<Vec<_>>();
+        let locations = self
+            .storage
+            .compact_bucket(&mut self.logger, id, references)
+            .await?;
+        // no awaits below, so all references are rewritten atomically
+        let mut rewritten = 0;
+        for post in self.posts.values_mut() {
+            post.files = std::mem::take(&mut post.files)
+                .into_iter()
+                .map(|(file_id, (offset, len))| {
+                    match (
+                        file_id.strip_suffix(&suffix),
+                        locations.get(&(offset, len as u64)),
+                    ) {
+                        (Some(blob_id), Some((bucket_id, new_offset))) => {
+                            rewritten += 1;
+                            (format!("{}@{}", blob_id, bucket_id), (*new_offset, len))
+                        }
+                        _ => (file_id, (offset, len)),
+                    }
+                })
+                .collect();
+        }
+        self.logger.info(format!(
+            "Rewrote `{}` file references after the compaction of bucket `{}`.",
+            rewritten, id
+        ));
+        Ok(())
+    }
+
     async fn top_up(&mut self) {
         let children = self.storage.buckets.keys().cloned().collect::<Vec<_>>();
 
@@ -655,25 +2061,6 @@ impl State {
                 )),
             }
         }
-
-        // top up all children canisters
-        let mut topped_up = Vec::new();
-        for canister_id in children {
-            match crate::canisters::top_up(canister_id, ICP_CYCLES_PER_XDR).await {
-                Ok(true) => topped_up.push(canister_id),
-                Err(err) => self.critical(err),
-                _ => {}
-            }
-        }
-        if !topped_up.is_empty() {
-            self.logger.info(format!(
-                "Topped up canisters: {:?}.",
-                topped_up
-                    .into_iter()
-                    .map(|p| p.to_string())
-                    .collect::<Vec<_>>()
-            ))
-        }
     }
 
     pub fn distribute_revenue(&mut self, e8s_for_1000_kps: u64) -> HashMap<UserId, u64> {
@@ -721,12 +2108,14 @@ impl State {
                 };
                 let acc = account(user.principal);
                 let minted = (user_karma.max(0) as u64 / (1 << factor)).max(1) * base;
-                user.notify(format!(
-                    "{} minted `{}` ${} tokens for you! 💎",
-                    CONFIG.name,
-                    minted / base,
-                    CONFIG.token_symbol,
-                ));
+                if user.notification_settings.rewards {
+                    user.notify(format!(
+                        "{} minted `{}` ${} tokens for you! 💎",
+                        CONFIG.name,
+                        minted / base,
+                        CONFIG.token_symbol,
+                    ));
+                }
                 minters.push(format!("`{}` to @{}", minted / base, user.name));
                 crate::token::mint(self, acc, minted);
                 minted_tokens += minted / base;
@@ -743,7 +2132,7 @@ impl State {
                     Some(balance) if *balance > 0 => {
                         // 1% of circulating supply is vesting.
                         let vested = (circulating_supply / 100).min(*balance);
-                        let veto_threshold = 100 - CONFIG.proposal_approval_threshold as u64;
+                        let veto_threshold = 100 - self.proposal_thresholds.approval as u64;
                         let veto_power = (circulating_supply * veto_threshold) / 100;
                         // Vesting is allowed if the total voting power of the team member is below
                         // 1/2 of the veto power, or if 2/3 of total supply is minted.
@@ -877,11 +2266,13 @@ impl State {
                     user_ids.insert(user.id, user.karma_to_reward());
                     user.apply_rewards();
                     payments.push(format!("`{}` to @{}", e8s_to_icp(e8s), &user.name));
-                    user.notify(format!(
-                        "You received `{}` ICP as rewards and `{}` ICP as revenue! 💸",
-                        e8s_to_icp(user_reward),
-                        e8s_to_icp(user_revenue)
-                    ));
+                    if user.notification_settings.rewards {
+                        user.notify(format!(
+                            "You received `{}` ICP as rewards and `{}` ICP as revenue! 💸",
+                            e8s_to_icp(user_reward),
+                            e8s_to_icp(user_revenue)
+                        ));
+                    }
                 }
                 Err(err) => {
                     self.logger
@@ -920,6 +2311,8 @@ impl State {
     }
 
     pub async fn chores(&mut self, now: u64) {
+        scheduler::run(self, scheduler::TASKS, now).await;
+
         if now - self.last_chores < CONFIG.chores_interval_hours {
             return;
         }
@@ -927,6 +2320,13 @@ impl State {
 
         self.top_up().await;
 
+        if let Err(err) = self.storage.upgrade_buckets(&mut self.logger).await {
+            self.logger
+                .error(format!("Couldn't upgrade buckets: {:?}", err));
+        }
+
+        self.archive_posts(now);
+
         if now - self.last_distribution >= CONFIG.distribution_interval_hours
             // We only mint and distribute if no open proposals exists
             && self.proposals.iter().all(|p| p.status != Status::Open)
@@ -964,8 +2364,24 @@ impl State {
                     .error(format!("Couldn't execute last proposal: {:?}", err));
             }
         }
+    }
 
-        self.memory.report_health(&mut self.logger);
+    // Decays karma of inactive users once per decay period.
+    fn decay_karma(&mut self, now: u64) {
+        let period = now / CONFIG.karma_decay_period;
+        if CONFIG.karma_decay_percentage == 0 || period <= self.last_karma_decay {
+            return;
+        }
+        self.last_karma_decay = period;
+        for user in self.users.values_mut() {
+            if user.active_within_weeks(now, CONFIG.inactivity_duration_weeks) {
+                continue;
+            }
+            let decay = user.karma().max(0) * CONFIG.karma_decay_percentage / 100;
+            if decay > 0 {
+                user.change_karma(-decay, "karma decay");
+            }
+        }
     }
 
     fn clean_up(&mut self) {
@@ -1156,23 +2572,48 @@ impl State {
         principal: Principal,
         tags: Vec<String>,
         users: Vec<UserId>,
-        page: usize,
-    ) -> Vec<Post> {
+        cursor: Option<String>,
+    ) -> Result<FeedPage, String> {
         let query: HashSet<_> = tags.into_iter().map(|tag| tag.to_lowercase()).collect();
-        self.last_posts(principal, true)
-            .filter(|post| {
-                (users.is_empty() || users.contains(&post.user))
-                    && post
-                        .tags
-                        .iter()
-                        .map(|tag| tag.to_lowercase())
-                        .collect::<HashSet<_>>()
-                        .is_superset(&query)
-            })
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size)
-            .cloned()
-            .collect()
+        let posts: Box<dyn Iterator<Item = &Post>> = if query.is_empty() {
+            self.last_posts(principal, true)
+        } else {
+            // tagged posts come from the index and are scoped to the current realm
+            let user = self.principal_to_user(principal);
+            let user_id = user.map(|user| user.id);
+            let current_realm = user.and_then(|user| user.current_realm.as_ref());
+            Box::new(
+                self.discovery_index
+                    .tagged(&query)
+                    .filter_map(|id| self.posts.get(&id))
+                    .filter(move |post| {
+                        current_realm.is_none() || post.realm.as_ref() == current_realm
+                    })
+                    .filter(move |post| self.visible(user_id, post)),
+            )
+        };
+        FeedPage::new(
+            posts.filter(|post| users.is_empty() || users.contains(&post.user)),
+            cursor,
+            CONFIG.feed_page_size,
+        )
+    }
+
+    // Returns the posts mentioning the caller, newest first.
+    pub fn mentions(
+        &self,
+        principal: Principal,
+        cursor: Option<String>,
+    ) -> Result<FeedPage, String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        FeedPage::new(
+            self.discovery_index
+                .mentions(user_id)
+                .filter_map(|id| self.posts.get(&id))
+                .filter(|post| self.visible(Some(user_id), post)),
+            cursor,
+            CONFIG.feed_page_size,
+        )
     }
 
     pub fn last_posts<'a>(
@@ -1191,10 +2632,12 @@ impl State {
                 Box::new((0..=last_id).rev())
             }
         };
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
         Box::new(
             posts
                 .filter_map(move |i| self.posts.get(&i))
-                .filter(move |post| with_comments || post.parent.is_none()),
+                .filter(move |post| with_comments || post.parent.is_none())
+                .filter(move |post| self.visible(user_id, post)),
         )
     }
 
@@ -1221,6 +2664,56 @@ impl State {
             .collect()
     }
 
+    // Checks if the post is visible to the user and not authored by someone the user ignores.
+    pub fn visible(&self, user_id: Option<UserId>, post: &Post) -> bool {
+        self.accessible(user_id, post) && !self.ignores(user_id, post.user)
+    }
+
+    // Checks if the post is not held for moderation or soft-deleted, unless the user is the
+    // author, and not hidden by a private realm or a realm tier the user doesn't have.
+    pub fn accessible(&self, user_id: Option<UserId>, post: &Post) -> bool {
+        if (self.detector.held.contains(&post.id) || post.deletion.is_some())
+            && user_id != Some(post.user)
+        {
+            return false;
+        }
+        post.realm
+            .as_ref()
+            .and_then(|name| self.realms.get(name))
+            .map(|realm| realm.visible(user_id, post))
+            .unwrap_or(true)
+    }
+
+    // Returns the accessible posts from the root post to the post `id`.
+    pub fn thread_posts(&self, principal: Principal, id: PostId) -> Vec<Post> {
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
+        self.thread(id)
+            .filter_map(|id| self.posts.get(&id))
+            .filter(|post| self.accessible(user_id, post))
+            .map(Post::restored)
+            .collect()
+    }
+
+    // Returns a page of the root posts of the user without posts starting with a mention.
+    pub fn journal(&self, principal: Principal, handle: &str, page: usize) -> Vec<Post> {
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
+        self.user(handle)
+            .map(|user| {
+                user.posts
+                    .iter()
+                    .rev()
+                    .filter_map(|id| self.posts.get(id))
+                    // we filter out responses and root posts starting with tagging another user
+                    .filter(|post| post.parent.is_none() && !post.body.starts_with('@'))
+                    .filter(|post| self.accessible(user_id, post))
+                    .skip(page * CONFIG.feed_page_size)
+                    .take(CONFIG.feed_page_size)
+                    .map(Post::restored)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Returns an iterator of posts from the root post to the post `id`.
     pub fn thread(&self, id: PostId) -> Box<dyn Iterator<Item = PostId>> {
         let mut result = Vec::new();
@@ -1236,9 +2729,274 @@ impl State {
         Box::new(result.into_iter().rev())
     }
 
-    pub fn posts(&self, ids: Vec<PostId>) -> Vec<Post> {
+    // Returns the requested posts without comments of users muted by the caller.
+    pub fn posts(&self, principal: Principal, ids: Vec<PostId>) -> Vec<Post> {
+        let user_id = self.principal_to_user(principal).map(|user| user.id);
         ids.iter()
-            .filter_map(|id| self.posts.get(id).cloned())
+            .filter_map(|id| self.posts.get(id))
+            .filter(|post| self.accessible(user_id, post))
+            .filter(|post| post.parent.is_none() || !self.ignores(user_id, post.user))
+            .map(Post::restored)
+            .collect()
+    }
+
+    // Checks if the author was muted or blocked by the user.
+    pub fn ignores(&self, user_id: Option<UserId>, author: UserId) -> bool {
+        user_id
+            .and_then(|id| self.users.get(&id))
+            .map(|user| user.ignores(author))
+            .unwrap_or_default()
+    }
+
+    // Checks if the user was blocked by the blocker.
+    pub fn blocked(&self, blocker: UserId, user_id: UserId) -> bool {
+        self.users
+            .get(&blocker)
+            .map(|blocker| blocker.blocked.contains(&user_id))
+            .unwrap_or_default()
+    }
+
+    pub fn mute_user(
+        &mut self,
+        principal: Principal,
+        user_id: UserId,
+        muted: bool,
+    ) -> Result<(), String> {
+        if !self.users.contains_key(&user_id) {
+            return Err("no user found".into());
+        }
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        if user.id == user_id {
+            return Err("you can't mute yourself".into());
+        }
+        if muted {
+            user.muted.insert(user_id);
+        } else {
+            user.muted.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    pub fn set_feed_mode(&mut self, principal: Principal, mode: FeedMode) -> Result<(), String> {
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .feed_mode = mode;
+        Ok(())
+    }
+
+    pub fn set_notification_settings(
+        &mut self,
+        principal: Principal,
+        settings: NotificationSettings,
+    ) -> Result<(), String> {
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .notification_settings = settings;
+        Ok(())
+    }
+
+    pub fn block_user(
+        &mut self,
+        principal: Principal,
+        user_id: UserId,
+        blocked: bool,
+    ) -> Result<(), String> {
+        if !self.users.contains_key(&user_id) {
+            return Err("no user found".into());
+        }
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        if user.id == user_id {
+            return Err("you can't block yourself".into());
+        }
+        if blocked {
+            user.blocked.insert(user_id);
+        } else {
+            user.blocked.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    // Returns the user as seen by the caller: private data is cleared unless the caller is the
+    // user, while private collections keep their slots so that indices match the owner's view.
+    pub fn user_view(&self, principal: Principal, handle: Option<&str>) -> Option<User> {
+        let caller_id = self.principal_to_user(principal).map(|user| user.id);
+        let mut user = match handle {
+            Some(handle) => self.user(handle)?.clone(),
+            None => self.principal_to_user(principal)?.clone(),
+        };
+        user.balance = self
+            .balances
+            .get(&account(user.principal))
+            .copied()
+            .unwrap_or_default();
+        // drafts are private and served via the drafts query only
+        user.drafts.clear();
+        // muted and blocked users don't learn about it
+        if caller_id != Some(user.id) {
+            user.muted.clear();
+            user.blocked.clear();
+            for collection in user.collections.iter_mut() {
+                if !collection.public {
+                    *collection = Default::default();
+                }
+            }
+        }
+        Some(user)
+    }
+
+    // Returns non-deleted posts of a user collection; private collections are visible to the
+    // owner only.
+    pub fn collection(
+        &self,
+        principal: Principal,
+        handle: &str,
+        index: usize,
+    ) -> Result<(String, Vec<Post>), String> {
+        let user = self.user(handle).ok_or("no user found")?;
+        let caller_id = self.principal_to_user(principal).map(|user| user.id);
+        let collection = user
+            .collections
+            .get(index)
+            .filter(|collection| collection.public || caller_id == Some(user.id))
+            .ok_or("no collection found")?;
+        Ok((
+            collection.title.clone(),
+            collection
+                .posts
+                .iter()
+                .filter_map(|id| self.posts.get(id))
+                .filter(|post| self.live_post(&post.id) && self.accessible(caller_id, post))
+                .map(Post::restored)
+                .collect(),
+        ))
+    }
+
+    fn live_post(&self, id: &PostId) -> bool {
+        self.posts
+            .get(id)
+            .map(|post| !post.deleted())
+            .unwrap_or_default()
+    }
+
+    // Drops deleted posts from the collections of the user; queries reading a collection skip
+    // them, and every update of the owner prunes them lazily.
+    pub fn prune_collections(&mut self, principal: Principal) -> Result<&mut User, String> {
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        let deleted = user
+            .collections
+            .iter()
+            .flat_map(|collection| collection.posts.iter())
+            .filter(|id| !self.live_post(id))
+            .copied()
+            .collect::<BTreeSet<_>>();
+        let user = self
+            .principal_to_user_mut(principal)
+            .expect("no user found");
+        for collection in user.collections.iter_mut() {
+            collection.posts.retain(|id| !deleted.contains(id));
+        }
+        Ok(user)
+    }
+
+    // Adds a post to a user collection; deleted posts are dropped from the collection first, so
+    // that they don't count against its size limit.
+    pub fn add_to_collection(
+        &mut self,
+        principal: Principal,
+        index: usize,
+        post_id: PostId,
+    ) -> Result<(), String> {
+        if !self.live_post(&post_id) {
+            return Err("no post found".into());
+        }
+        self.prune_collections(principal)?
+            .add_to_collection(index, post_id)
+    }
+
+    // Returns users with names starting with the prefix for mention suggestions: users followed
+    // by the caller come first, then users with more followers. Only a bounded number of
+    // matching names is ranked.
+    pub fn search_users(
+        &self,
+        principal: Principal,
+        prefix: &str,
+        limit: usize,
+    ) -> Vec<(UserId, String)> {
+        let caller = self.principal_to_user(principal);
+        let prefix = prefix.to_lowercase();
+        let mut users = self
+            .user_names
+            .range(prefix.clone()..)
+            .take_while(|(name, _)| name.starts_with(&prefix))
+            .take(CONFIG.max_user_search_scan)
+            .filter_map(|(_, id)| self.users.get(id))
+            .filter(|user| {
+                caller
+                    .map(|caller| {
+                        !caller.blocked.contains(&user.id) && !user.blocked.contains(&caller.id)
+                    })
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        users.sort_by_key(|user| {
+            let followed = caller
+                .map(|caller| caller.followees.contains(&user.id))
+                .unwrap_or_default();
+            (!followed, std::cmp::Reverse(user.followers.len()), user.id)
+        });
+        users
+            .into_iter()
+            .take(limit.min(CONFIG.max_user_search_results))
+            .map(|user| (user.id, user.name.clone()))
+            .collect()
+    }
+
+    // Suggests users to follow ranked by the overlap with the interaction graph of the caller:
+    // every followee following a user and every recent post of a user the caller reacted to
+    // counts once. Only a bounded number of followees and recent posts is scanned.
+    pub fn follow_suggestions(&self, principal: Principal) -> Vec<(UserId, String, usize)> {
+        let user = match self.principal_to_user(principal) {
+            Some(user) => user,
+            None => return Default::default(),
+        };
+        let mut overlaps: HashMap<UserId, usize> = Default::default();
+        for followee in user
+            .followees
+            .iter()
+            .take(CONFIG.follow_suggestions_max_followees)
+            .filter_map(|id| self.users.get(id))
+        {
+            for id in &followee.followees {
+                *overlaps.entry(*id).or_default() += 1;
+            }
+        }
+        for post in (0..self.next_post_id)
+            .rev()
+            .filter_map(|id| self.posts.get(&id))
+            .take(CONFIG.follow_suggestions_window)
+            .filter(|post| {
+                post.reactions
+                    .values()
+                    .any(|users| users.contains(&user.id))
+            })
+        {
+            *overlaps.entry(post.user).or_default() += 1;
+        }
+        let mut suggestions = overlaps
+            .into_iter()
+            .filter(|(id, _)| *id != user.id && !user.followees.contains(id) && !user.ignores(*id))
+            .filter_map(|(id, overlap)| self.users.get(&id).map(|other| (other, overlap)))
+            .filter(|(other, _)| !other.blocked.contains(&user.id))
+            .collect::<Vec<_>>();
+        suggestions.sort_by_key(|(other, overlap)| (std::cmp::Reverse(*overlap), other.id));
+        suggestions
+            .into_iter()
+            .take(CONFIG.max_follow_suggestions)
+            .map(|(other, overlap)| (other.id, other.name.clone(), overlap))
             .collect()
     }
 
@@ -1260,11 +3018,21 @@ impl State {
         new_principal_str: String,
     ) -> Result<(), String> {
         let new_principal = Principal::from_text(new_principal_str).map_err(|e| e.to_string())?;
+        if new_principal == Principal::anonymous() {
+            return Err("the anonymous principal can't control a user".into());
+        }
         if self.principals.contains_key(&new_principal) {
             return Err("principal already controls a user".into());
         }
-        let user = self.principals.remove(&principal).ok_or("no user found")?;
-        self.principals.insert(new_principal, user);
+        let user_id = self.principals.remove(&principal).ok_or("no user found")?;
+        self.principals.insert(new_principal, user_id);
+        // the new principal takes over the role of the replaced one
+        let user = self.users.get_mut(&user_id).ok_or("no user found")?;
+        if user.principal == principal {
+            user.principal = new_principal;
+        } else if user.linked_principals.remove(&principal) {
+            user.linked_principals.insert(new_principal);
+        }
         let accounts = self
             .balances
             .keys()
@@ -1278,14 +3046,239 @@ impl State {
         Ok(())
     }
 
-    pub fn principal_to_user(&self, principal: Principal) -> Option<&User> {
-        self.principals
-            .get(&principal)
-            .and_then(|id| self.users.get(id))
-    }
-
-    pub fn principal_to_user_mut(&mut self, principal: Principal) -> Option<&mut User> {
-        let id = self.principals.get(&principal)?;
+    // Starts linking a principal to the caller's account; the link needs to be confirmed by a
+    // call from the new principal.
+    pub fn link_principal(
+        &mut self,
+        principal: Principal,
+        new_principal_str: String,
+    ) -> Result<(), String> {
+        let new_principal = Principal::from_text(new_principal_str).map_err(|e| e.to_string())?;
+        if new_principal == Principal::anonymous() {
+            return Err("the anonymous principal can't control a user".into());
+        }
+        if self.principals.contains_key(&new_principal) {
+            return Err("principal already controls a user".into());
+        }
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .pending_principal = Some(new_principal);
+        Ok(())
+    }
+
+    pub fn confirm_principal_link(
+        &mut self,
+        principal: Principal,
+        user_id: UserId,
+    ) -> Result<(), String> {
+        if self.principals.contains_key(&principal) {
+            return Err("principal already controls a user".into());
+        }
+        let user = self.users.get_mut(&user_id).ok_or("no user found")?;
+        if user.pending_principal != Some(principal) {
+            return Err("no pending link found".into());
+        }
+        user.pending_principal = None;
+        user.linked_principals.insert(principal);
+        self.principals.insert(principal, user_id);
+        Ok(())
+    }
+
+    pub fn unlink_principal(
+        &mut self,
+        principal: Principal,
+        linked_principal_str: String,
+    ) -> Result<(), String> {
+        let linked_principal =
+            Principal::from_text(linked_principal_str).map_err(|e| e.to_string())?;
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        if self.principals.get(&linked_principal) != Some(&user.id) {
+            return Err("principal is not linked".into());
+        }
+        if user.linked_principals.is_empty() {
+            return Err("the last principal can't be unlinked".into());
+        }
+        // token balances belong to the main principal
+        if linked_principal == user.principal {
+            return Err("the main principal can't be unlinked".into());
+        }
+        let user_id = user.id;
+        self.principals.remove(&linked_principal);
+        self.users
+            .get_mut(&user_id)
+            .expect("no user found")
+            .linked_principals
+            .remove(&linked_principal);
+        Ok(())
+    }
+
+    pub fn set_guardians(
+        &mut self,
+        principal: Principal,
+        guardians: Vec<String>,
+        quorum: usize,
+    ) -> Result<(), String> {
+        let guardians = guardians
+            .into_iter()
+            .map(|id| Principal::from_text(id).map_err(|e| e.to_string()))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        if guardians.contains(&Principal::anonymous()) {
+            return Err("the anonymous principal can't control a user".into());
+        }
+        if guardians.len() > CONFIG.max_guardians {
+            return Err(format!(
+                "not more than {} guardians are allowed",
+                CONFIG.max_guardians
+            ));
+        }
+        if quorum > guardians.len() || quorum == 0 && !guardians.is_empty() {
+            return Err("invalid quorum".into());
+        }
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        if guardians
+            .iter()
+            .any(|guardian| self.principals.get(guardian) == Some(&user_id))
+        {
+            return Err("own principals can't be guardians".into());
+        }
+        let user = self.users.get_mut(&user_id).expect("no user found");
+        user.guardians = guardians;
+        user.guardian_quorum = quorum;
+        user.recovery = None;
+        Ok(())
+    }
+
+    // Records a guardian's approval of binding the new principal to the account; approvals
+    // of a different principal start a new recovery. Once the quorum is reached, the
+    // recovery completes after the timelock unless the user contests it.
+    pub fn approve_recovery(
+        &mut self,
+        principal: Principal,
+        user_id: UserId,
+        new_principal_str: String,
+        now: u64,
+    ) -> Result<(), String> {
+        let new_principal = Principal::from_text(new_principal_str).map_err(|e| e.to_string())?;
+        if principal == Principal::anonymous() || new_principal == Principal::anonymous() {
+            return Err("the anonymous principal can't control a user".into());
+        }
+        if self.principals.contains_key(&new_principal) {
+            return Err("principal already controls a user".into());
+        }
+        let user = self.users.get_mut(&user_id).ok_or("no user found")?;
+        if !user.guardians.contains(&principal) {
+            return Err("not a guardian".into());
+        }
+        let recovery = match user.recovery.as_mut() {
+            Some(recovery) if recovery.principal == new_principal => recovery,
+            // an approved recovery can only be replaced by a quorum of guardians
+            Some(recovery) if recovery.approved_at.is_some() => {
+                let approvals = recovery.competing.entry(new_principal).or_default();
+                approvals.insert(principal);
+                if approvals.len() < user.guardian_quorum {
+                    return Ok(());
+                }
+                let approvals = recovery
+                    .competing
+                    .remove(&new_principal)
+                    .unwrap_or_default();
+                let replaced = recovery.principal;
+                user.notify(format!(
+                    "Your guardians replaced the approved recovery of your account to principal `{}`.",
+                    replaced
+                ));
+                user.recovery.insert(user::Recovery {
+                    principal: new_principal,
+                    approvals,
+                    approved_at: None,
+                    competing: Default::default(),
+                })
+            }
+            _ => user.recovery.insert(user::Recovery {
+                principal: new_principal,
+                approvals: Default::default(),
+                approved_at: None,
+                competing: Default::default(),
+            }),
+        };
+        recovery.approvals.insert(principal);
+        if recovery.approved_at.is_none() && recovery.approvals.len() >= user.guardian_quorum {
+            recovery.approved_at = Some(now);
+            user.notify(format!(
+                "Your guardians approved the recovery of your account to principal `{}`. If you didn't request it, contest the recovery in the settings within {} days!",
+                new_principal,
+                CONFIG.recovery_timelock / (24 * HOUR)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn contest_recovery(&mut self, principal: Principal) -> Result<(), String> {
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        if user.recovery.take().is_none() {
+            return Err("no recovery found".into());
+        }
+        Ok(())
+    }
+
+    // Binds the new principals of approved recoveries with an elapsed timelock to the accounts.
+    fn complete_recoveries(&mut self, now: u64) {
+        let recoveries = self
+            .users
+            .values_mut()
+            .filter_map(|user| match &user.recovery {
+                Some(user::Recovery {
+                    principal,
+                    approved_at: Some(time),
+                    ..
+                }) if time + CONFIG.recovery_timelock <= now => {
+                    let principal = *principal;
+                    user.recovery = None;
+                    Some((user.id, principal))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (user_id, new_principal) in recoveries {
+            if self.principals.contains_key(&new_principal) {
+                continue;
+            }
+            let user = self.users.get_mut(&user_id).expect("no user found");
+            let old_principal = std::mem::replace(&mut user.principal, new_principal);
+            let old_principals = std::mem::take(&mut user.linked_principals);
+            let name = user.name.clone();
+            for principal in old_principals.iter().chain(std::iter::once(&old_principal)) {
+                self.principals.remove(principal);
+            }
+            self.principals.insert(new_principal, user_id);
+            if let Some(acc) = self
+                .balances
+                .keys()
+                .find(|acc| acc.owner == old_principal && acc.subaccount.is_none())
+                .cloned()
+            {
+                if let Err(err) = crate::token::move_funds(self, &acc, account(new_principal)) {
+                    self.logger.error(format!(
+                        "Couldn't transfer token funds of @{} after the recovery: {:?}",
+                        name, err
+                    ));
+                }
+            }
+            self.logger
+                .info(format!("The account of @{} was recovered.", name));
+        }
+    }
+
+    pub fn principal_to_user(&self, principal: Principal) -> Option<&User> {
+        self.principals
+            .get(&principal)
+            .and_then(|id| self.users.get(id))
+    }
+
+    pub fn principal_to_user_mut(&mut self, principal: Principal) -> Option<&mut User> {
+        let id = self.principals.get(&principal)?;
         self.users.get_mut(id)
     }
 
@@ -1353,6 +3346,7 @@ impl State {
                 .map(|u| u.id)
                 .collect(),
             state_size: stable64_size() << 16,
+            stable_memory_fragmentation: self.memory.fragmentation(),
             invited_users: self
                 .users
                 .values()
@@ -1367,7 +3361,7 @@ impl State {
                 .storage
                 .buckets
                 .iter()
-                .map(|(id, size)| (id.to_string(), *size))
+                .map(|(id, stats)| (id.to_string(), stats.offset, stats.live))
                 .collect(),
             circulating_supply: self.balances.values().sum(),
         }
@@ -1378,7 +3372,7 @@ impl State {
         principal: Principal,
         time: u64,
         post_id: PostId,
-        vote: u16,
+        vote: Vec<u16>,
     ) -> Result<(), String> {
         let user = self
             .principal_to_user(principal)
@@ -1432,16 +3426,22 @@ impl State {
         Ok(())
     }
 
+    // Soft-deletes the post: it gets hidden from everyone except the author, who can restore it
+    // until the grace period ends. The deletion costs are charged right away.
     pub fn delete_post(
         &mut self,
         principal: Principal,
         post_id: PostId,
         versions: Vec<String>,
+        now: u64,
     ) -> Result<(), String> {
         let post = self.posts.get(&post_id).ok_or("no post found")?.clone();
         if self.principal_to_user(principal).map(|user| user.id) != Some(post.user) {
             return Err("not authorized".into());
         }
+        if post.deleted() {
+            return Err("post is deleted already".into());
+        }
 
         let comments_tree_penalty =
             post.tree_size as Cycles * CONFIG.post_deletion_penalty_factor as Cycles;
@@ -1497,13 +3497,67 @@ impl State {
             .expect("no user found")
             .change_karma(-karma_penalty, format!("deletion of post {}", post.id));
 
+        self.search_index.remove(post_id, &post.text());
+        discovery::unindex(self, post_id);
         self.posts
             .get_mut(&post_id)
             .expect("no post found")
-            .delete(versions);
+            .deletion = Some((now, versions));
+        self.pending_deletions
+            .insert((now + CONFIG.post_deletion_grace_period, post_id));
+        Ok(())
+    }
+
+    // Restores a soft-deleted post of the caller within the grace period.
+    pub fn restore_post(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        now: u64,
+    ) -> Result<(), String> {
+        let post = self.posts.get(&post_id).ok_or("no post found")?;
+        if self.principal_to_user(principal).map(|user| user.id) != Some(post.user) {
+            return Err("not authorized".into());
+        }
+        let deadline = match &post.deletion {
+            Some((timestamp, _)) if timestamp + CONFIG.post_deletion_grace_period > now => {
+                timestamp + CONFIG.post_deletion_grace_period
+            }
+            _ => return Err("post can't be restored".into()),
+        };
+        self.pending_deletions.remove(&(deadline, post_id));
+        let post = self.posts.get_mut(&post_id).expect("no post found");
+        post.deletion = None;
+        let text = post.text();
+        self.search_index.add(post_id, &text);
+        discovery::index(self, post_id);
         Ok(())
     }
 
+    // Deletes soft-deleted posts past the grace period and frees their files.
+    fn purge_deleted_posts(&mut self, now: u64) {
+        let expired = self
+            .pending_deletions
+            .range(..=(now, PostId::MAX))
+            .copied()
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.pending_deletions.remove(&key);
+            let (files, versions) = match self.posts.get_mut(&key.1) {
+                Some(post) => match post.deletion.take() {
+                    Some((_, versions)) => (post.files.clone(), versions),
+                    None => continue,
+                },
+                None => continue,
+            };
+            self.release_files(key.1, &files);
+            self.posts
+                .get_mut(&key.1)
+                .expect("no post found")
+                .delete(versions);
+        }
+    }
+
     pub fn react(
         &mut self,
         principal: Principal,
@@ -1523,20 +3577,38 @@ impl State {
         if post.user == user.id {
             return Err("reactions to own posts are forbidden".into());
         }
+        if post.deletion.is_some() {
+            return Err("post is deleted".into());
+        }
+        if self.blocked(post.user, user.id) {
+            return Err("you were blocked by the author".into());
+        }
         if let Some(set) = post.reactions.get(&reaction) {
             if set.contains(&user.id) {
                 return Err("double reactions are forbidden".into());
             }
         }
 
+        let canonical_id = post.cross_post.unwrap_or(post_id);
+        let rewarded = post.reaction_rewarded(
+            self.posts.get(&canonical_id).ok_or("post not found")?,
+            reaction,
+            user.id,
+        );
+
         let log = format!("reaction to post {}", post_id);
+        // Reactions already rewarded on another appearance of a cross-posted post are only counted.
+        if rewarded {
+            if !user.trusted() && delta < 0 {
+                return Err("bootcamp users can't downvote".into());
+            }
+        }
         // If the user is untrusted, they can only upvote, but this does not affect author's karma.
-        if !user.trusted() {
+        else if !user.trusted() {
             if delta < 0 {
                 return Err("bootcamp users can't downvote".into());
             }
-            self.charge(user.id, delta.abs() + CONFIG.reaction_fee, log)
-                .expect("coudln't charge user");
+            self.charge(user.id, delta.abs() + CONFIG.reaction_fee, log)?;
         }
         // If the user is trusted, they initiate a cycle transfer for upvotes, but burn their own cycles on
         // down votes + cycles and karma of the author
@@ -1555,28 +3627,85 @@ impl State {
             )
             .expect("couldn't charge user");
         } else {
+            // the realm multiplier burns a part of the reward or tops it up
+            let reward = self.realm_reward(post.realm.as_ref(), delta);
             self.cycle_transfer(
                 user.id,
                 post.user,
-                delta,
-                CONFIG.reaction_fee,
+                delta.min(reward),
+                CONFIG.reaction_fee + (delta - reward).max(0),
                 Destination::Karma,
-                log,
+                log.clone(),
             )?;
+            if reward > delta {
+                self.spend_to_user_karma(post.user, reward - delta, log);
+            }
             post.make_hot(&mut self.hot, self.users.len(), user.id);
+            webhooks::emit(
+                self,
+                post.user,
+                webhooks::EventKind::Reward,
+                user.id,
+                post_id,
+                time,
+            );
         }
 
         self.principal_to_user_mut(principal)
             .expect("no user for principal found")
             .last_activity = time;
         let user_id = user.id;
+        self.users
+            .get_mut(&user_id)
+            .expect("no user found")
+            .update_interests(&post.tags, delta.signum());
+        if !rewarded {
+            let author = self.users.get_mut(&post.user).expect("no user found");
+            // whatever the reaction credited went to other karma; the reaction itself is
+            // accounted for by the post
+            if let Some(other_karma) = author.other_karma.as_mut() {
+                *other_karma -= delta;
+            }
+            if delta > 0 {
+                author.record_upvote();
+            }
+            if post.cross_post.is_some() {
+                self.posts
+                    .get_mut(&canonical_id)
+                    .expect("no post found")
+                    .cross_post_reactions
+                    .entry(reaction)
+                    .or_default()
+                    .insert(user_id);
+            }
+        }
         let post = self.posts.get_mut(&post_id).expect("no post found");
         post.reactions.entry(reaction).or_default().insert(user_id);
         Ok(())
     }
 
+    // Applies the reactions one by one and returns their results, so that failed reactions
+    // don't revert the other ones.
+    pub fn react_many(
+        &mut self,
+        principal: Principal,
+        reactions: Vec<(PostId, u16)>,
+        time: u64,
+    ) -> Result<Vec<Result<(), String>>, String> {
+        if reactions.len() > CONFIG.max_reactions_per_batch {
+            return Err(format!(
+                "at most {} reactions can be sent at once",
+                CONFIG.max_reactions_per_batch
+            ));
+        }
+        Ok(reactions
+            .into_iter()
+            .map(|(post_id, reaction)| self.react(principal, post_id, reaction, time))
+            .collect())
+    }
+
     pub fn toggle_following_user(&mut self, principal: Principal, followee_id: UserId) -> bool {
-        let (added, (id, name)) = {
+        let (added, first_follow, (id, name)) = {
             let user = match self.principal_to_user_mut(principal) {
                 Some(user) => user,
                 _ => return false,
@@ -1589,19 +3718,62 @@ impl State {
                     user.followees.insert(followee_id);
                     true
                 },
+                user.followees.len() == 1,
                 (user.id, user.name.clone()),
             )
         };
         let followee = self.users.get_mut(&followee_id).expect("User not found");
         if added {
             followee.followers.insert(id);
-            followee.notify(format!("@{} followed you", name));
+            if followee.notification_settings.followers {
+                followee.notify(format!("@{} followed you", name));
+            }
+            if first_follow {
+                self.complete_onboarding_step(id, OnboardingStep::FirstFollow);
+            }
         } else {
             followee.followers.remove(&id);
         }
         added
     }
 
+    pub fn complete_onboarding_step(&mut self, user_id: UserId, step: OnboardingStep) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.onboarding.entry(step).or_insert(false);
+        }
+    }
+
+    pub fn claim_onboarding_rewards(&mut self, principal: Principal) -> Result<Token, String> {
+        self.claim_onboarding_rewards_from(principal, CONFIG.onboarding_rewards)
+    }
+
+    // Mints the rewards of completed steps which are enabled and weren't claimed yet.
+    fn claim_onboarding_rewards_from(
+        &mut self,
+        principal: Principal,
+        rewards: &[(OnboardingStep, Token)],
+    ) -> Result<Token, String> {
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        let mut total = 0;
+        for (step, amount) in rewards {
+            match user.onboarding.get_mut(step) {
+                Some(claimed) if !*claimed => {
+                    *claimed = true;
+                    total += amount;
+                }
+                _ => {}
+            }
+        }
+        if total == 0 {
+            return Err("no rewards to claim".into());
+        }
+        let user_principal = user.principal;
+        token::mint(self, account(user_principal), total);
+        Ok(total)
+    }
+
     pub fn toggle_following_post(&mut self, principal: Principal, post_id: PostId) -> bool {
         let user_id = match self.principal_to_user(principal) {
             Some(user) => user.id,
@@ -1678,6 +3850,134 @@ fn covered_by_feeds(
     None
 }
 
+// A post with its comments; collapsed comments come without their sub-threads, which can be
+// loaded by requesting the tree of the collapsed comment.
+#[derive(Default, Serialize)]
+pub struct CommentTree {
+    pub posts: HashMap<PostId, Post>,
+    pub collapsed: BTreeSet<PostId>,
+}
+
+// A feed page with the cursor of the next page; the cursor is missing at the end of the feed.
+#[derive(Default, Serialize)]
+pub struct FeedPage {
+    pub posts: Vec<Post>,
+    pub cursor: Option<String>,
+}
+
+impl FeedPage {
+    // Takes the page following the cursor from a feed ordered by descending post ids.
+    // Cursors refer to post ids, so new posts don't shift pages; deleted posts are skipped.
+    pub fn new<'a>(
+        posts: impl Iterator<Item = &'a Post>,
+        cursor: Option<String>,
+        size: usize,
+    ) -> Result<Self, String> {
+        let last_seen = decode_cursor(cursor)?;
+        let mut posts = posts
+            .filter(|post| last_seen.map(|id| post.id < id).unwrap_or(true))
+            .filter(|post| !post.deleted())
+            .take(size + 1)
+            .map(Post::restored)
+            .collect::<Vec<_>>();
+        let cursor = (posts.len() > size).then(|| {
+            posts.truncate(size);
+            encode_cursor(posts.last().map(|post| post.id).unwrap_or_default())
+        });
+        Ok(Self { posts, cursor })
+    }
+}
+
+impl FeedPage {
+    // Takes the page following the cursor from the recent posts of a feed ordered by their
+    // trending score. Ranked pages are not ordered by ids, so the cursor is an offset.
+    pub fn ranked<'a>(
+        posts: impl Iterator<Item = &'a Post>,
+        cursor: Option<String>,
+        size: usize,
+        now: u64,
+    ) -> Result<Self, String> {
+        let offset = decode_cursor(cursor)?.unwrap_or_default() as usize;
+        let mut ranked = posts
+            .filter(|post| !post.deleted())
+            .take(CONFIG.trending_window)
+            .map(|post| (trending_score(post, now), post))
+            .collect::<Vec<_>>();
+        sort_by_score(&mut ranked);
+        let mut posts = ranked
+            .into_iter()
+            .skip(offset)
+            .take(size + 1)
+            .map(|(_, post)| post.restored())
+            .collect::<Vec<_>>();
+        let cursor = (posts.len() > size).then(|| {
+            posts.truncate(size);
+            encode_cursor((offset + size) as u64)
+        });
+        Ok(Self { posts, cursor })
+    }
+}
+
+// Positive reactions decaying with the age of the post.
+fn trending_score(post: &Post, now: u64) -> f64 {
+    let reactions = post
+        .reactions
+        .iter()
+        .filter(|(id, _)| **id >= CONFIG.min_positive_reaction_id)
+        .map(|(_, users)| users.len())
+        .sum::<usize>();
+    let age = now.saturating_sub(post.creation_timestamp()) / HOUR;
+    reactions as f64 / ((age + 2) as f64).powf(CONFIG.trending_gravity as f64)
+}
+
+// Sorts by descending scores with newer posts first on ties.
+fn sort_by_score(posts: &mut [(f64, &Post)]) {
+    posts.sort_by(|(a, post_a), (b, post_b)| {
+        b.partial_cmp(a)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(post_b.id.cmp(&post_a.id))
+    });
+}
+
+// Picks a candidate determined by the seed, skipping the previous pick if there are others.
+fn pick_featured_post(
+    candidates: &[PostId],
+    previous: Option<PostId>,
+    seed: &[u8],
+) -> Option<PostId> {
+    let candidates = match candidates
+        .iter()
+        .filter(|id| Some(**id) != previous)
+        .copied()
+        .collect::<Vec<_>>()
+    {
+        others if others.is_empty() => candidates.to_vec(),
+        others => others,
+    };
+    if candidates.is_empty() {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&seed[..8]);
+    Some(candidates[(u64::from_be_bytes(bytes) % candidates.len() as u64) as usize])
+}
+
+pub fn encode_cursor(last_id: PostId) -> String {
+    base64::encode_config(last_id.to_be_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_cursor(cursor: Option<String>) -> Result<Option<PostId>, String> {
+    cursor
+        .map(|cursor| {
+            base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(PostId::from_be_bytes)
+                .ok_or_else(|| "invalid cursor".to_string())
+        })
+        .transpose()
+}
+
 struct IteratorMerger<'a, T> {
     iterators: Vec<std::iter::Peekable<Box<dyn Iterator<Item = &'a T> + 'a>>>,
 }
@@ -1754,64 +4054,410 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn test_principal_change() {
+    fn test_search_users() {
         let mut state = State::default();
-
-        let mut eligigble = HashMap::default();
-        for i in 1..3 {
-            let p = pr(i);
-            let id = create_user(&mut state, p);
-            let user = state.users.get_mut(&id).unwrap();
-            user.change_karma(i as Karma * 111, "test");
-            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
-            assert!(user.trusted());
-            eligigble.insert(id, user.karma_to_reward());
+        let names = ["Caller", "alice", "ALFRED", "albert", "Alvin", "bob"];
+        for (i, name) in names.iter().enumerate() {
+            create_user_with_params(&mut state, pr(i as u8), name, true);
         }
+        // bob and alvin follow albert, bob follows alfred
+        state.toggle_following_user(pr(5), 3);
+        state.toggle_following_user(pr(4), 3);
+        state.toggle_following_user(pr(5), 2);
+        // the caller follows alice
+        state.toggle_following_user(pr(0), 1);
+        let names = |users: Vec<(UserId, String)>| {
+            users.into_iter().map(|(_, name)| name).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(state.search_users(pr(0), "Al", 10)),
+            vec!["alice", "albert", "ALFRED", "Alvin"]
+        );
+        assert_eq!(
+            names(state.search_users(pr(0), "al", 2)),
+            vec!["alice", "albert"]
+        );
+        assert!(state.search_users(pr(0), "alz", 10).is_empty());
 
-        // mint tokens
-        state.mint(eligigble);
-        assert_eq!(state.ledger.len(), 2);
-        assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 11100);
-
-        let u_id = state.principal_to_user(pr(1)).unwrap().id;
-        let new_principal_str: String =
-            "yh4uw-lqajx-4dxcu-rwe6s-kgfyk-6dicz-yisbt-pjg7v-to2u5-morox-hae".into();
-        assert!(state
-            .change_principal(pr(1), new_principal_str.clone())
-            .is_ok());
-        let principal = Principal::from_text(new_principal_str).unwrap();
-        assert_eq!(state.principal_to_user(principal).unwrap().id, u_id);
-        assert!(state.balances.get(&account(pr(1))).is_none());
+        // blocked users are excluded in both directions
+        state.block_user(pr(0), 3, true).unwrap();
+        state.block_user(pr(4), 0, true).unwrap();
         assert_eq!(
-            *state.balances.get(&account(principal)).unwrap(),
-            11100 - CONFIG.transaction_fee
+            names(state.search_users(pr(0), "al", 10)),
+            vec!["alice", "ALFRED"]
+        );
+        assert_eq!(
+            names(state.search_users(pr(5), "al", 10)),
+            vec!["albert", "ALFRED", "alice", "Alvin"]
         );
     }
 
     #[actix_rt::test]
-    async fn test_post_deletion() {
+    async fn test_onboarding_rewards() {
         let mut state = State::default();
+        for i in 0..2 {
+            create_user(&mut state, pr(i));
+        }
+        let reward = |step| {
+            CONFIG
+                .onboarding_rewards
+                .iter()
+                .find(|(s, _)| *s == step)
+                .map(|(_, amount)| *amount)
+                .unwrap()
+        };
+        assert_eq!(
+            state.claim_onboarding_rewards(pr(0)),
+            Err("no rewards to claim".into())
+        );
 
-        let id = create_user(&mut state, pr(0));
-        let user = state.users.get_mut(&id).unwrap();
-        assert_eq!(user.karma_to_reward(), 0);
-        let upvoter_id = create_user(&mut state, pr(1));
-        let user = state.users.get_mut(&upvoter_id).unwrap();
-        let upvoter_cycles = user.cycles();
-        user.change_karma(1000, "test");
-        assert!(user.trusted());
-        let uid = create_user(&mut state, pr(2));
-        state
-            .users
-            .get_mut(&uid)
-            .unwrap()
-            .change_karma(1000, "test");
-
-        let post_id = add(
-            &mut state,
-            "Test".to_string(),
-            vec![],
-            pr(0),
+        // steps are completed in the action paths and every reward is paid once
+        for i in 0..2 {
+            let time = i * CONFIG.rate_limit_window;
+            add(
+                &mut state,
+                "Post".into(),
+                vec![],
+                pr(0),
+                time,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            state.claim_onboarding_rewards(pr(0)),
+            Ok(reward(OnboardingStep::FirstPost))
+        );
+        for _ in 0..3 {
+            state.toggle_following_user(pr(0), 1);
+        }
+        state.complete_onboarding_step(0, OnboardingStep::Profile);
+        assert_eq!(
+            state.claim_onboarding_rewards(pr(0)),
+            Ok(reward(OnboardingStep::FirstFollow) + reward(OnboardingStep::Profile))
+        );
+        state.complete_onboarding_step(0, OnboardingStep::Profile);
+        assert_eq!(
+            state.claim_onboarding_rewards(pr(0)),
+            Err("no rewards to claim".into())
+        );
+        assert_eq!(
+            state.balances.get(&account(pr(0))),
+            Some(
+                &(reward(OnboardingStep::FirstPost)
+                    + reward(OnboardingStep::FirstFollow)
+                    + reward(OnboardingStep::Profile))
+            )
+        );
+
+        // disabled steps don't pay out
+        state.complete_onboarding_step(1, OnboardingStep::Profile);
+        state.complete_onboarding_step(1, OnboardingStep::FirstFollow);
+        let rewards = [(OnboardingStep::FirstFollow, 50)];
+        assert_eq!(state.claim_onboarding_rewards_from(pr(1), &rewards), Ok(50));
+        assert_eq!(
+            state.claim_onboarding_rewards_from(pr(1), &rewards),
+            Err("no rewards to claim".into())
+        );
+        assert_eq!(state.balances.get(&account(pr(1))), Some(&50));
+
+        // existing users don't complete steps with later actions
+        let id = create_user(&mut state, pr(2));
+        let user = state.users.get_mut(&id).unwrap();
+        user.posts.push(0);
+        user.followees.insert(0);
+        add(
+            &mut state,
+            "Post".into(),
+            vec![],
+            pr(2),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        state.toggle_following_user(pr(2), 1);
+        assert!(state.users.get(&id).unwrap().onboarding.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_follow_suggestions() {
+        let mut state = State::default();
+        for i in 0..6 {
+            create_user(&mut state, pr(i));
+        }
+        // the caller follows 1 and 2, who follow 3 and 4; 1 follows 5 and the caller as well
+        state.toggle_following_user(pr(0), 1);
+        state.toggle_following_user(pr(0), 2);
+        for (follower, followee) in [(1, 3), (1, 4), (1, 5), (1, 0), (2, 3), (2, 4), (2, 1)].iter()
+        {
+            state.toggle_following_user(pr(*follower), *followee);
+        }
+        // the caller reacted to two posts of 5
+        for i in 0..2 {
+            let post_id = add(
+                &mut state,
+                "hello".into(),
+                vec![],
+                pr(5),
+                i * CONFIG.rate_limit_window,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            state.react(pr(0), post_id, 100, 0).unwrap();
+        }
+
+        let suggestions = |state: &State| {
+            state
+                .follow_suggestions(pr(0))
+                .into_iter()
+                .map(|(id, _, overlap)| (id, overlap))
+                .collect::<Vec<_>>()
+        };
+
+        // the caller and already followed users aren't suggested
+        assert_eq!(suggestions(&state), vec![(5, 3), (3, 2), (4, 2)]);
+
+        // blocked users are excluded in both directions
+        state.block_user(pr(0), 4, true).unwrap();
+        state.block_user(pr(3), 0, true).unwrap();
+        assert_eq!(suggestions(&state), vec![(5, 3)]);
+        assert!(state.follow_suggestions(pr(9)).is_empty());
+    }
+
+    #[test]
+    fn test_linked_principals() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+
+        // the last principal can't be unlinked
+        assert_eq!(
+            state.unlink_principal(pr(0), pr(0).to_string()),
+            Err("the last principal can't be unlinked".into())
+        );
+
+        // linking requires a confirmation from the new principal
+        assert_eq!(
+            state.link_principal(pr(0), pr(1).to_string()),
+            Err("principal already controls a user".into())
+        );
+        assert_eq!(
+            state.link_principal(pr(0), Principal::anonymous().to_string()),
+            Err("the anonymous principal can't control a user".into())
+        );
+        state.link_principal(pr(0), pr(2).to_string()).unwrap();
+        assert!(state.principal_to_user(pr(2)).is_none());
+        assert_eq!(
+            state.confirm_principal_link(pr(3), id),
+            Err("no pending link found".into())
+        );
+        assert_eq!(
+            state.confirm_principal_link(pr(1), id),
+            Err("principal already controls a user".into())
+        );
+        state.confirm_principal_link(pr(2), id).unwrap();
+
+        // both principals resolve to the same user
+        assert_eq!(state.principal_to_user(pr(0)).unwrap().id, id);
+        assert_eq!(state.principal_to_user(pr(2)).unwrap().id, id);
+        assert_eq!(
+            state.confirm_principal_link(pr(2), id),
+            Err("principal already controls a user".into())
+        );
+
+        assert_eq!(
+            state.unlink_principal(pr(2), pr(1).to_string()),
+            Err("principal is not linked".into())
+        );
+        assert_eq!(
+            state.unlink_principal(pr(2), pr(0).to_string()),
+            Err("the main principal can't be unlinked".into())
+        );
+        state.unlink_principal(pr(0), pr(2).to_string()).unwrap();
+        assert!(state.principal_to_user(pr(2)).is_none());
+        assert_eq!(
+            state.unlink_principal(pr(0), pr(0).to_string()),
+            Err("the last principal can't be unlinked".into())
+        );
+    }
+
+    #[test]
+    fn test_account_recovery() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(0));
+        let guardians = vec![pr(1).to_string(), pr(2).to_string(), pr(3).to_string()];
+        assert_eq!(
+            state.set_guardians(pr(0), guardians.clone(), 4),
+            Err("invalid quorum".into())
+        );
+        assert_eq!(
+            state.set_guardians(pr(0), vec![pr(0).to_string()], 1),
+            Err("own principals can't be guardians".into())
+        );
+        assert_eq!(
+            state.set_guardians(pr(0), vec![Principal::anonymous().to_string()], 1),
+            Err("the anonymous principal can't control a user".into())
+        );
+        state.set_guardians(pr(0), guardians, 2).unwrap();
+        assert_eq!(
+            state.approve_recovery(pr(1), id, Principal::anonymous().to_string(), 0),
+            Err("the anonymous principal can't control a user".into())
+        );
+        assert_eq!(
+            state.approve_recovery(Principal::anonymous(), id, pr(9).to_string(), 0),
+            Err("the anonymous principal can't control a user".into())
+        );
+
+        // a single approval doesn't reach the quorum
+        let new_principal = pr(9).to_string();
+        assert_eq!(
+            state.approve_recovery(pr(5), id, new_principal.clone(), 0),
+            Err("not a guardian".into())
+        );
+        state
+            .approve_recovery(pr(1), id, new_principal.clone(), 0)
+            .unwrap();
+        state.complete_recoveries(2 * CONFIG.recovery_timelock);
+        assert_eq!(state.principal_to_user(pr(0)).unwrap().id, id);
+        let recovery = state.users.get(&id).unwrap().recovery.clone().unwrap();
+        assert_eq!(recovery.approved_at, None);
+
+        // the quorum starts the timelock, the user contests the recovery
+        state
+            .approve_recovery(pr(2), id, new_principal.clone(), HOUR)
+            .unwrap();
+        assert_eq!(
+            state
+                .users
+                .get(&id)
+                .unwrap()
+                .recovery
+                .as_ref()
+                .unwrap()
+                .approved_at,
+            Some(HOUR)
+        );
+
+        // a single guardian can't replace an approved recovery
+        let other_principal = pr(8).to_string();
+        state
+            .approve_recovery(pr(3), id, other_principal.clone(), 2 * HOUR)
+            .unwrap();
+        let recovery = state.users.get(&id).unwrap().recovery.clone().unwrap();
+        assert_eq!(
+            (recovery.principal, recovery.approved_at),
+            (pr(9), Some(HOUR))
+        );
+
+        // a quorum replaces it with a new timelock and the user gets notified
+        state
+            .approve_recovery(pr(1), id, other_principal.clone(), 3 * HOUR)
+            .unwrap();
+        let user = state.users.get(&id).unwrap();
+        let recovery = user.recovery.clone().unwrap();
+        assert_eq!(
+            (recovery.principal, recovery.approved_at),
+            (pr(8), Some(3 * HOUR))
+        );
+        assert!(user
+            .inbox
+            .values()
+            .any(|n| matches!(n, Notification::Generic(msg)
+            if msg.contains("replaced the approved recovery"))));
+
+        state.contest_recovery(pr(0)).unwrap();
+        state.complete_recoveries(3 * HOUR + CONFIG.recovery_timelock);
+        assert!(state.principal_to_user(pr(8)).is_none());
+        assert_eq!(state.principal_to_user(pr(0)).unwrap().id, id);
+        assert!(state.principal_to_user(pr(9)).is_none());
+        assert_eq!(
+            state.contest_recovery(pr(0)),
+            Err("no recovery found".into())
+        );
+
+        // an uncontested recovery completes after the timelock
+        for guardian in [pr(2), pr(3)].iter() {
+            state
+                .approve_recovery(*guardian, id, new_principal.clone(), 2 * HOUR)
+                .unwrap();
+        }
+        state.complete_recoveries(2 * HOUR + CONFIG.recovery_timelock - 1);
+        assert_eq!(state.principal_to_user(pr(0)).unwrap().id, id);
+        state.complete_recoveries(2 * HOUR + CONFIG.recovery_timelock);
+        assert!(state.principal_to_user(pr(0)).is_none());
+        let user = state.principal_to_user(pr(9)).unwrap();
+        assert_eq!((user.id, user.principal), (id, pr(9)));
+        assert!(user.recovery.is_none());
+    }
+
+    #[test]
+    fn test_principal_change() {
+        let mut state = State::default();
+
+        let mut eligigble = HashMap::default();
+        for i in 1..3 {
+            let p = pr(i);
+            let id = create_user(&mut state, p);
+            let user = state.users.get_mut(&id).unwrap();
+            user.change_karma(i as Karma * 111, "test");
+            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+            assert!(user.trusted());
+            eligigble.insert(id, user.karma_to_reward());
+        }
+
+        // mint tokens
+        state.mint(eligigble);
+        assert_eq!(state.ledger.len(), 2);
+        assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 11100);
+
+        let u_id = state.principal_to_user(pr(1)).unwrap().id;
+        let new_principal_str: String =
+            "yh4uw-lqajx-4dxcu-rwe6s-kgfyk-6dicz-yisbt-pjg7v-to2u5-morox-hae".into();
+        assert!(state
+            .change_principal(pr(1), new_principal_str.clone())
+            .is_ok());
+        let principal = Principal::from_text(new_principal_str).unwrap();
+        assert_eq!(state.principal_to_user(principal).unwrap().id, u_id);
+        assert!(state.balances.get(&account(pr(1))).is_none());
+        assert_eq!(
+            *state.balances.get(&account(principal)).unwrap(),
+            11100 - CONFIG.transaction_fee
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_post_deletion() {
+        let mut state = State::default();
+
+        let id = create_user(&mut state, pr(0));
+        let user = state.users.get_mut(&id).unwrap();
+        assert_eq!(user.karma_to_reward(), 0);
+        let upvoter_id = create_user(&mut state, pr(1));
+        let user = state.users.get_mut(&upvoter_id).unwrap();
+        let upvoter_cycles = user.cycles();
+        user.change_karma(1000, "test");
+        assert!(user.trusted());
+        let uid = create_user(&mut state, pr(2));
+        state
+            .users
+            .get_mut(&uid)
+            .unwrap()
+            .change_karma(1000, "test");
+
+        let post_id = add(
+            &mut state,
+            "Test".to_string(),
+            vec![],
+            pr(0),
             0,
             None,
             None,
@@ -1853,7 +4499,7 @@ pub(crate) mod tests {
 
         let versions = vec!["a".into(), "b".into()];
         assert_eq!(
-            state.delete_post(pr(1), post_id, versions.clone()),
+            state.delete_post(pr(1), post_id, versions.clone(), 0),
             Err("not authorized".into())
         );
 
@@ -1861,7 +4507,7 @@ pub(crate) mod tests {
             .charge(id, state.users.get(&id).unwrap().cycles(), "")
             .unwrap();
         assert_eq!(
-            state.delete_post(pr(0), post_id, versions.clone()),
+            state.delete_post(pr(0), post_id, versions.clone(), 0),
             Err("not enough cycles (this post requires 37 cycles to be deleted)".into())
         );
 
@@ -1873,7 +4519,11 @@ pub(crate) mod tests {
             .unwrap();
 
         assert_eq!(&state.posts.get(&0).unwrap().body, "Test");
-        assert_eq!(state.delete_post(pr(0), post_id, versions.clone()), Ok(()));
+        assert_eq!(
+            state.delete_post(pr(0), post_id, versions.clone(), 0),
+            Ok(())
+        );
+        state.purge_deleted_posts(CONFIG.post_deletion_grace_period);
         assert_eq!(&state.posts.get(&0).unwrap().body, "");
         assert_eq!(state.posts.get(&0).unwrap().hashes.len(), versions.len());
 
@@ -1885,6 +4535,133 @@ pub(crate) mod tests {
         assert_eq!(state.users.get(&id).unwrap().karma_to_reward(), 0);
     }
 
+    #[actix_rt::test]
+    async fn test_restore_deleted_post() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let reader = create_user(&mut state, pr(1));
+        let id = add(
+            &mut state,
+            "Hello".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            state.delete_post(pr(0), id, vec!["Hello".into()], 0),
+            Ok(())
+        );
+
+        // the post is hidden from everyone except the author and can't be engaged with
+        let post = state.posts.get(&id).unwrap();
+        assert!(!state.visible(Some(reader), post));
+        assert!(state.visible(Some(post.user), post));
+        assert!(state.posts(pr(1), vec![id]).is_empty());
+        assert!(state.thread_posts(pr(1), id).is_empty());
+        assert!(state.journal(pr(1), "0", 0).is_empty());
+        assert_eq!(state.posts(pr(0), vec![id]).len(), 1);
+        assert_eq!(state.thread_posts(pr(0), id).len(), 1);
+        assert_eq!(state.journal(pr(0), "0", 0).len(), 1);
+        assert!(state.search_index.lookup("hello").is_empty());
+        assert_eq!(
+            add(
+                &mut state,
+                "Hi".into(),
+                vec![],
+                pr(1),
+                0,
+                Some(id),
+                None,
+                None
+            )
+            .await,
+            Err("deleted posts can't be commented".into())
+        );
+        assert_eq!(
+            state.react(pr(1), id, 100, 0),
+            Err("post is deleted".into())
+        );
+        assert_eq!(
+            state.delete_post(pr(0), id, vec![], 0),
+            Err("post is deleted already".into())
+        );
+
+        assert_eq!(
+            state.restore_post(pr(1), id, HOUR),
+            Err("not authorized".into())
+        );
+        let last_moment = CONFIG.post_deletion_grace_period - 1;
+        assert_eq!(state.restore_post(pr(0), id, last_moment), Ok(()));
+        assert!(state.pending_deletions.is_empty());
+        let post = state.posts.get(&id).unwrap();
+        assert!(!post.deleted());
+        assert!(state.visible(Some(reader), post));
+        assert_eq!(state.search_index.lookup("hello"), vec![(id, 1)]);
+
+        // nothing gets purged after the grace period
+        state.purge_deleted_posts(CONFIG.post_deletion_grace_period);
+        assert_eq!(state.posts.get(&id).unwrap().body, "Hello");
+    }
+
+    #[actix_rt::test]
+    async fn test_purge_deleted_post() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let id = add(
+            &mut state,
+            "Hello".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let bucket = pr(7);
+        state.storage.buckets.insert(
+            bucket,
+            storage::BucketStats {
+                offset: 10,
+                live: 10,
+                ..Default::default()
+            },
+        );
+        state
+            .posts
+            .get_mut(&id)
+            .unwrap()
+            .files
+            .insert(format!("blob@{}", bucket), (0, 10));
+        state
+            .delete_post(pr(0), id, vec!["Hello".into()], 0)
+            .unwrap();
+
+        // the files stay referenced during the grace period
+        let grace_period = CONFIG.post_deletion_grace_period;
+        state.purge_deleted_posts(grace_period - 1);
+        assert_eq!(state.storage.buckets[&bucket].live, 10);
+        assert_eq!(state.posts.get(&id).unwrap().body, "Hello");
+
+        state.purge_deleted_posts(grace_period);
+        let post = state.posts.get(&id).unwrap();
+        assert!(post.body.is_empty() && post.files.is_empty());
+        assert_eq!(post.hashes.len(), 1);
+        assert!(post.deletion.is_none() && post.deleted());
+        assert_eq!(state.storage.buckets[&bucket].live, 0);
+        assert!(state.pending_deletions.is_empty());
+        assert_eq!(
+            state.restore_post(pr(0), id, grace_period),
+            Err("post can't be restored".into())
+        );
+    }
+
     #[actix_rt::test]
     async fn test_realms() {
         let mut state = State::default();
@@ -2271,197 +5048,392 @@ pub(crate) mod tests {
         );
     }
 
-    #[test]
-    #[should_panic]
-    fn test_negative_transfer_panic() {
+    #[actix_rt::test]
+    async fn test_realm_tiers() {
         let mut state = State::default();
-        let p = pr(0);
-        let u1 = create_user_with_params(&mut state, p, "user1", true);
-        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
+        let p0 = pr(0);
+        let u0 = create_user(&mut state, p0);
         state
-            .cycle_transfer(u1, u2, -1, 0, Destination::Cycles, "")
+            .users
+            .get_mut(&u0)
+            .unwrap()
+            .change_cycles(1000, "")
             .unwrap();
+        let name = "TIERS".to_string();
+        assert_eq!(
+            state.create_realm(
+                p0,
+                name.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![u0]
+            ),
+            Ok(())
+        );
+        state.users.get_mut(&u0).unwrap().realms.push(name.clone());
+
+        let tiers = vec![Tier {
+            name: "supporter".into(),
+            min_karma: CONFIG.trusted_user_min_karma,
+            flair: "💎".into(),
+            extra_posts_per_hour: 2,
+            ..Default::default()
+        }];
+        assert_eq!(
+            state.set_realm_tiers(pr(1), name.clone(), tiers.clone()),
+            Err("no user found".into())
+        );
+        assert_eq!(state.set_realm_tiers(p0, name.clone(), tiers), Ok(()));
+        assert_eq!(
+            state.realms.get(&name).unwrap().tier(u0).unwrap().name,
+            "supporter"
+        );
+        assert_eq!(
+            state.realms.get(&name).unwrap().flairs(),
+            vec![(u0, &"💎".to_string())].into_iter().collect()
+        );
+
+        async fn post(state: &mut State) -> Result<PostId, String> {
+            add(
+                state,
+                "Test".to_string(),
+                vec![],
+                pr(0),
+                time(),
+                None,
+                Some("TIERS".to_string()),
+                None,
+            )
+            .await
+        }
+
+        // the tier grants 2 extra posts per hour
+        for _ in 0..CONFIG.max_posts_per_window + 2 {
+            assert!(post(&mut state).await.is_ok());
+        }
+        assert_eq!(
+            post(&mut state).await,
+            Err("not more than 5 posts per 60 minutes are allowed, retry in 720 seconds".into())
+        );
+
+        // the user loses karma and gets downgraded
+        state.users.get_mut(&u0).unwrap().change_karma(-10, "test");
+        state.recompute_realm_tiers(&name);
+        assert!(state.realms.get(&name).unwrap().tier(u0).is_none());
+        assert!(state.realms.get(&name).unwrap().flairs().is_empty());
+        assert_eq!(
+            post(&mut state).await,
+            Err("not more than 3 posts per 60 minutes are allowed, retry in 1200 seconds".into())
+        );
+    }
+
+    #[test]
+    fn test_realm_tiers_batch() {
+        let mut state = State::default();
+        let u0 = create_user(&mut state, pr(0));
+        let tiers = vec![Tier {
+            name: "supporter".into(),
+            min_karma: CONFIG.trusted_user_min_karma,
+            ..Default::default()
+        }];
+        let names = ["A", "B", "C", "D"];
+        for name in names {
+            state
+                .users
+                .get_mut(&u0)
+                .unwrap()
+                .change_cycles(1000, "")
+                .unwrap();
+            assert_eq!(
+                state.create_realm(
+                    pr(0),
+                    name.into(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![u0]
+                ),
+                Ok(())
+            );
+            // realms without tiers are skipped
+            if name != "C" {
+                state.realms.get_mut(name).unwrap().tiers = tiers.clone();
+            }
+        }
+        let tiered = |state: &State| {
+            names
+                .iter()
+                .filter(|name| state.realms.get(**name).unwrap().tier(u0).is_some())
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        assert!(tiered(&state).is_empty());
+
+        state.recompute_realm_tiers_batch(2);
+        assert_eq!(tiered(&state), vec!["A", "B"]);
+        assert_eq!(state.realm_tiers_cursor, "B");
+        state.recompute_realm_tiers_batch(2);
+        assert_eq!(tiered(&state), vec!["A", "B", "D"]);
+        assert!(state.realm_tiers_cursor.is_empty());
+
+        // the next round starts from the first realm again
+        state.users.get_mut(&u0).unwrap().change_karma(-10, "test");
+        state.recompute_realm_tiers_batch(2);
+        assert_eq!(tiered(&state), vec!["D"]);
     }
 
     #[actix_rt::test]
-    async fn test_tipping() {
+    async fn test_rate_limits() {
         let mut state = State::default();
-        let p = pr(0);
-        let u1 = create_user_with_params(&mut state, p, "user1", true);
-        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
-        let post_id = add(
-            &mut state,
-            "This is a #post with #tags".to_string(),
-            vec![],
-            p,
-            0,
-            None,
-            None,
-            None,
-        )
-        .await
-        .unwrap();
+        create_user(&mut state, pr(0));
 
-        let u = state.users.get_mut(&u1).unwrap();
-        assert_eq!(u.karma(), CONFIG.trusted_user_min_karma);
-        let cycles_before = u.cycles();
+        async fn post(
+            state: &mut State,
+            parent: Option<PostId>,
+            time: u64,
+        ) -> Result<PostId, String> {
+            add(
+                state,
+                "Test".to_string(),
+                vec![],
+                pr(0),
+                time,
+                parent,
+                None,
+                None,
+            )
+            .await
+        }
 
-        assert_eq!(state.tip(pr(1), post_id, 500), Ok(()));
+        let now = time();
+        let root = post(&mut state, None, now).await.unwrap();
+        for _ in 0..CONFIG.max_comments_per_window {
+            assert!(post(&mut state, Some(root), now).await.is_ok());
+        }
+        // one comment is replenished every window / limit
+        let interval = CONFIG.rate_limit_window / CONFIG.max_comments_per_window as u64;
         assert_eq!(
-            state.tip(pr(1), post_id, 600),
-            Err("not enough cycles".into())
+            post(&mut state, Some(root), now).await,
+            Err(
+                "not more than 15 comments per 60 minutes are allowed, retry in 240 seconds".into()
+            )
         );
+        assert_eq!(
+            post(&mut state, Some(root), now + interval - SECOND).await,
+            Err("not more than 15 comments per 60 minutes are allowed, retry in 1 seconds".into())
+        );
+        assert!(post(&mut state, Some(root), now + interval).await.is_ok());
+        assert!(post(&mut state, Some(root), now + interval).await.is_err());
 
-        let u = state.users.get_mut(&u1).unwrap();
-        assert_eq!(u.karma_to_reward(), 0);
-        assert_eq!(u.cycles(), cycles_before + 500);
-        let u = state.users.get_mut(&u2).unwrap();
-        assert_eq!(u.cycles(), 1000 - 500 - CONFIG.tipping_fee);
+        // comments don't consume the limit of posts
+        for _ in 1..CONFIG.max_posts_per_window {
+            assert!(post(&mut state, None, now).await.is_ok());
+        }
+        assert!(post(&mut state, None, now).await.is_err());
 
-        let p = state.posts.get(&post_id).unwrap();
-        assert_eq!(p.tips, vec![(u2, 500)]);
+        // the full limit is available again after one window
+        let later = now + CONFIG.rate_limit_window;
+        for _ in 0..CONFIG.max_posts_per_window {
+            assert!(post(&mut state, None, later).await.is_ok());
+        }
+        assert!(post(&mut state, None, later).await.is_err());
     }
 
     #[test]
-    fn test_covered_by_feed() {
-        let m = |v: Vec<&str>| v.into_iter().map(|v| v.to_string()).collect();
-        let tests = vec![
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1"]),
-                true,
+    #[should_panic]
+    fn test_negative_transfer_panic() {
+        let mut state = State::default();
+        let p = pr(0);
+        let u1 = create_user_with_params(&mut state, p, "user1", true);
+        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
+        state
+            .cycle_transfer(u1, u2, -1, 0, Destination::Cycles, "")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_trending_posts() {
+        let mut state = State::default();
+        let mut new_post = |timestamp: u64, reactions: u64| {
+            let id = state.new_post_id();
+            let mut post = Post::new(
+                0,
+                Default::default(),
+                "".into(),
+                timestamp,
                 None,
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2"]),
-                false,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2"]),
-                true,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2", "tag3"]),
-                true,
                 None,
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2", "tag3"]),
-                false,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tagX", "tag2", "tag3"]),
-                false,
-                Some(1),
-            ),
-        ];
+                None,
+            );
+            post.id = id;
+            post.reactions.insert(100, (0..reactions).collect());
+            // negative reactions don't count
+            post.reactions.insert(1, (100..200).collect());
+            state.posts.insert(id, post);
+            id
+        };
+        let old_viral = new_post(0, 50);
+        let new_liked = new_post(22 * HOUR, 5);
+        let held = new_post(22 * HOUR, 20);
+        let deleted = new_post(22 * HOUR, 20);
+        state.detector.held.insert(held);
+        state.posts.get_mut(&deleted).unwrap().deletion = Some((0, vec![]));
+        let ids = |state: &State, now| {
+            state
+                .trending_posts(pr(0), 0, now)
+                .into_iter()
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
 
-        for (i, t) in tests.iter().enumerate() {
-            let (feeds, tags, strict, result) = t;
-            if covered_by_feeds(feeds, tags, *strict) != *result {
-                panic!("Test {} failed", i)
-            }
-        }
+        // the viral post leads while it's fresh
+        assert_eq!(ids(&state, 2 * HOUR), vec![old_viral, new_liked]);
+        // a day later its decay lets a new moderately liked post overtake it
+        assert_eq!(ids(&state, 23 * HOUR), vec![new_liked, old_viral]);
     }
 
     #[test]
-    fn test_user_by_handle() {
-        let mut state = State::default();
+    fn test_featured_post_determinism() {
+        let mut seed = [0; 32];
+        seed[7] = 5;
+        // the seed fully determines the pick
+        for _ in 0..3 {
+            assert_eq!(pick_featured_post(&[1, 2, 3], None, &seed), Some(3));
+        }
+        assert_eq!(pick_featured_post(&[], None, &seed), None);
+
+        let posts = || {
+            let mut state = State::default();
+            for _ in 0..10 {
+                let id = state.new_post_id();
+                let mut post = Post::new(0, Default::default(), "".into(), HOUR, None, None, None);
+                post.id = id;
+                state.posts.insert(id, post);
+            }
+            state
+        };
+        let (mut state, mut other) = (posts(), posts());
+        state.select_featured_post(2 * HOUR);
+        other.select_featured_post(3 * HOUR);
+        assert!(state.featured_post.is_some());
+        assert_eq!(state.featured_post, other.featured_post);
+        // the selection doesn't change during the day
+        let featured = state.featured_post;
+        state.select_featured_post(20 * HOUR);
+        assert_eq!(state.featured_post, featured);
+    }
 
-        let u1 = create_user_with_params(&mut state, pr(0), "user1", true);
-        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
-        let u3 = create_user_with_params(&mut state, pr(2), "user3", true);
+    #[test]
+    fn test_featured_post_no_repeat() {
+        let mut seed = [0; 32];
+        seed[7] = 5;
+        assert_eq!(pick_featured_post(&[1, 2, 3], Some(3), &seed), Some(2));
+        // without alternatives the previous post is featured again
+        assert_eq!(pick_featured_post(&[3], Some(3), &seed), Some(3));
 
-        assert_eq!(state.user("user1").unwrap().id, u1);
-        assert_eq!(state.user("0").unwrap().id, u1);
-        assert_eq!(state.user("user2").unwrap().id, u2);
-        assert_eq!(state.user("1").unwrap().id, u2);
-        assert_eq!(state.user("user3").unwrap().id, u3);
-        assert_eq!(state.user("2").unwrap().id, u3);
-        assert!(state.user("user22").is_none());
+        let mut state = State::default();
+        let new_post = |state: &mut State| {
+            let id = state.new_post_id();
+            let mut post = Post::new(0, Default::default(), "".into(), 0, None, None, None);
+            post.id = id;
+            state.posts.insert(id, post);
+            id
+        };
+        let eligible = [new_post(&mut state), new_post(&mut state)];
+        let held = new_post(&mut state);
+        let deleted = new_post(&mut state);
+        state.detector.held.insert(held);
+        state.posts.get_mut(&deleted).unwrap().deletion = Some((0, vec![]));
+        let day = 24 * HOUR;
+        state.select_featured_post(0);
+        let (_, first) = state.featured_post.unwrap();
+        assert!(eligible.contains(&first));
+        state.select_featured_post(day);
+        let (_, second) = state.featured_post.unwrap();
+        assert!(eligible.contains(&second));
+        assert_ne!(first, second);
+        assert_eq!(state.featured_post(day).map(|post| post.id), Some(second));
+        // without eligible posts, nothing is featured on the next day
+        state.select_featured_post(CONFIG.featured_post_window);
+        assert_eq!(state.featured_post, Some((1, second)));
+        assert!(state.featured_post(CONFIG.featured_post_window).is_none());
     }
 
     #[actix_rt::test]
-    async fn test_personal_feed() {
+    async fn test_recommended_posts() {
         let mut state = State::default();
+        for i in 0..4 {
+            create_user(&mut state, pr(i));
+        }
+        let mut new_post = |body: &str, author: u8| {
+            let id = state.new_post_id();
+            let mut post = Post::new(
+                author as UserId,
+                tags(CONFIG.max_tag_length, body),
+                body.to_string(),
+                0,
+                None,
+                None,
+                None,
+            );
+            post.id = id;
+            state.posts.insert(id, post);
+            id
+        };
+        let rust_post = new_post("Learning #rust", 1);
+        let rust_post2 = new_post("More #rust and #wasm", 1);
+        let rust_post3 = new_post("Yet another #rust post", 2);
+        let cooking_post = new_post("My #cooking recipe", 3);
 
-        // create a post author and one post for its principal
-        let p = pr(0);
-        let post_author_id = create_user(&mut state, p);
-        let post_id = add(
-            &mut state,
-            "This is a #post with #tags".to_string(),
-            vec![],
-            p,
-            0,
-            None,
-            None,
-            None,
-        )
-        .await
-        .unwrap();
-        let anon = Principal::anonymous();
-
-        // create a user and make sure his feed is empty
-        let pr1 = pr(1);
-        let user_id = create_user(&mut state, pr1);
-        assert!(state
-            .user(&user_id.to_string())
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
-            .next()
-            .is_none());
-
-        // now we follow post_author_id
-        let _user = state.users.get_mut(&user_id).unwrap();
-        assert!(state.toggle_following_user(pr1, post_author_id));
+        // user 0 upvotes a post about rust
+        assert_eq!(state.react(pr(0), rust_post, 10, 0), Ok(()));
+        assert_eq!(
+            state
+                .principal_to_user(pr(0))
+                .unwrap()
+                .interests
+                .get("rust"),
+            Some(&1)
+        );
 
-        // make sure the feed contains exactly one post from post_author_id
         let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
+            .recommended_posts(pr(0), 0)
+            .into_iter()
             .map(|post| post.id)
             .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 1);
-        assert!(feed.contains(&post_id));
-
-        // now we follow a feed #post+#tags
-        let user = state.users.get_mut(&user_id).unwrap();
-        assert!(user.toggle_following_feed(
-            vec!["post".to_owned(), "tags".to_owned()]
-                .into_iter()
-                .collect()
-        ));
+        assert_eq!(feed, vec![rust_post3, rust_post2]);
+        assert!(!feed.contains(&cooking_post));
 
-        // make sure the feed still contains the same post
+        // muted authors are excluded
+        state.principal_to_user_mut(pr(0)).unwrap().muted.insert(2);
         let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
+            .recommended_posts(pr(0), 0)
+            .into_iter()
             .map(|post| post.id)
             .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 1);
-        assert!(feed.contains(&post_id));
+        assert_eq!(feed, vec![rust_post2]);
+    }
 
-        // now a different post with the same tags appears
-        let p = pr(2);
-        let _post_author_id = create_user(&mut state, p);
-        let post_id2 = add(
+    #[actix_rt::test]
+    async fn test_muting() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        assert_eq!(
+            state.mute_user(pr(0), 0, true),
+            Err("you can't mute yourself".into())
+        );
+        assert_eq!(
+            state.mute_user(pr(0), 42, true),
+            Err("no user found".into())
+        );
+        let root = add(
             &mut state,
-            "This is a different #post, but with the same #tags".to_string(),
+            "Root".into(),
             vec![],
-            p,
+            pr(0),
             0,
             None,
             None,
@@ -2469,114 +5441,67 @@ pub(crate) mod tests {
         )
         .await
         .unwrap();
-
-        // make sure the feed contains both posts
-        let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
-            .map(|post| post.id)
-            .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 2);
-        assert!(feed.contains(&post_id));
-        assert!(feed.contains(&post_id2));
-
-        // yet another post appears
-        let p = pr(3);
-        let _post_author_id = create_user(&mut state, p);
-        let post_id3 = add(
+        assert_eq!(state.mute_user(pr(0), 1, true), Ok(()));
+        let ids = |posts: Vec<&Post>| posts.into_iter().map(|post| post.id).collect::<Vec<_>>();
+
+        // replies and mentions of the muted user don't notify
+        let inbox = |state: &State| state.users.get(&0).unwrap().inbox.len();
+        let inbox_before = inbox(&state);
+        let name = state.users.get(&0).unwrap().name.clone();
+        let muted_comment = add(
             &mut state,
-            "Different #post, different #feed".to_string(),
+            format!("Reply to @{}", name),
             vec![],
-            p,
+            pr(1),
             0,
+            Some(root),
+            None,
             None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(inbox(&state), inbox_before);
+        let comment = add(
+            &mut state,
+            "Reply".into(),
+            vec![],
+            pr(2),
+            0,
+            Some(root),
             None,
             None,
         )
         .await
         .unwrap();
+        assert_eq!(inbox(&state), inbox_before + 1);
 
-        // make sure the feed contains the same old posts
-        let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
-            .map(|post| post.id)
-            .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 2);
-        assert!(feed.contains(&post_id));
-        assert!(feed.contains(&post_id2));
-
-        // now we follow a feed "post"
-        let user = state.users.get_mut(&user_id).unwrap();
-        let tags: Vec<_> = vec!["post".to_string()].into_iter().collect();
-        assert!(user.toggle_following_feed(tags.clone()));
-        // make sure the feed contains the new post
-        let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
-            .map(|post| post.id)
-            .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 3);
-        assert!(feed.contains(&post_id));
-        assert!(feed.contains(&post_id2));
-        assert!(feed.contains(&post_id3));
-
-        // Make sure we can unsubscribe and the feed gets back to 2 posts
-        let user = state.users.get_mut(&user_id).unwrap();
-        assert!(!user.toggle_following_feed(tags));
-        let feed = state
-            .users
-            .get(&user_id)
-            .unwrap()
-            .personal_feed(anon, &state, 0, true)
-            .map(|post| post.id)
-            .collect::<Vec<_>>();
-        assert_eq!(feed.len(), 2);
-        assert!(feed.contains(&post_id));
-        assert!(feed.contains(&post_id2));
-    }
-
-    #[test]
-    fn test_hashtag_extraction() {
-        let tags = |body| {
-            let c = CONFIG;
-            let mut t: Vec<_> = tags(c.max_tag_length, body).into_iter().collect();
-            t.sort_unstable();
-            t.join(" ")
-        };
-        assert_eq!(tags("This is a string without hashtags!"), "");
-        assert_eq!(tags("This is a #string with hashtags!"), "string");
-        assert_eq!(tags("#This is a #string with two hashtags!"), "This string");
-        assert_eq!(tags("This string has no tags.#bug"), "");
+        // comments of the muted user are hidden on viewed posts only for the muting user
+        let comments = vec![muted_comment, comment];
         assert_eq!(
-            tags("#This is a #string with #333 hashtags!"),
-            "333 This string"
+            ids(state.posts(pr(0), comments.clone()).iter().collect()),
+            vec![comment]
         );
-        assert_eq!(tags("#year2021"), "year2021");
-        assert_eq!(tags("#year2021 #year2021 #"), "year2021");
-        assert_eq!(tags("#Ta1 #ta2"), "Ta1 ta2");
-        assert_eq!(tags("#Tag #tag"), "Tag tag");
-        assert_eq!(tags("Это #тест-строка"), "тест-строка");
-        assert_eq!(tags("This is a #feature-request"), "feature-request");
-        assert_eq!(tags("Support #under_score"), "under_score");
-    }
+        assert_eq!(state.posts(pr(2), comments.clone()).len(), 2);
+        assert_eq!(state.posts(pr(1), comments).len(), 2);
 
-    #[actix_rt::test]
-    async fn test_cycles_accounting() {
-        let mut state = State::default();
-        let p0 = pr(0);
-        let post_author_id = create_user(&mut state, p0);
-        let post_id = add(
+        // home feeds
+        let muted_post = add(
             &mut state,
-            "test".to_string(),
+            "Muted".into(),
             vec![],
-            p0,
+            pr(1),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let post = add(
+            &mut state,
+            "Post".into(),
+            vec![],
+            pr(2),
             0,
             None,
             None,
@@ -2584,233 +5509,2653 @@ pub(crate) mod tests {
         )
         .await
         .unwrap();
-        let p = pr(1);
-        let lurker_id = create_user(&mut state, p);
-        let farmer_id = create_untrusted_user(&mut state, pr(111));
-        let c = CONFIG;
-        assert_eq!(state.burned_cycles, c.post_cost);
-        state
-            .users
-            .get_mut(&lurker_id)
-            .unwrap()
-            .change_karma(10, "");
-        state.users.get_mut(&lurker_id).unwrap().apply_rewards();
-        // make author to a new user
-        state
-            .users
-            .get_mut(&post_author_id)
-            .unwrap()
-            .change_karma(-CONFIG.trusted_user_min_karma, "");
-        let author = state.users.get(&post_author_id).unwrap();
-        let farmer = state.users.get(&farmer_id).unwrap();
-        let lurker = state.users.get(&lurker_id).unwrap();
-        assert!(!author.trusted());
-        assert!(!farmer.trusted());
-        assert!(lurker.trusted());
-        assert_eq!(author.cycles(), c.min_cycles_minted - c.post_cost);
-        assert_eq!(lurker.cycles(), c.min_cycles_minted);
-
-        assert_eq!(author.karma(), 0);
-
-        // react on the new post
-        assert!(state.react(pr(111), post_id, 1, 0).is_err());
-        // this is a noop for author
-        assert!(state.react(pr(111), post_id, 100, 0).is_ok());
-        let burned_cycles_by_reaction_from_untrusted = 11;
         assert_eq!(
-            state.users.get(&post_author_id).unwrap().cycles(),
-            c.min_cycles_minted - c.post_cost
+            ids(state.last_posts(pr(0), false).collect()),
+            vec![post, root]
         );
-        assert!(state.react(p, post_id, 50, 0).is_ok());
-        assert!(state.react(p, post_id, 100, 0).is_ok());
-        let mut reaction_costs = 6 + 11;
-        let burned_cycles_by_reactions = 1 + 1;
-        let mut rewards_from_reactions = 5 + 10;
-
-        // try to self upvote (should be a no-op)
-        assert!(state.react(p0, post_id, 100, 0).is_err());
-
-        let author = state.users.get(&post_author_id).unwrap();
-        assert_eq!(author.cycles(), c.min_cycles_minted - c.post_cost);
-        assert_eq!(author.karma_to_reward(), rewards_from_reactions);
         assert_eq!(
-            state.burned_cycles,
-            c.post_cost + burned_cycles_by_reactions + burned_cycles_by_reaction_from_untrusted
+            ids(state.last_posts(pr(2), false).collect()),
+            vec![post, muted_post, root]
         );
-
-        let lurker = state.users.get(&lurker_id).unwrap();
-        assert_eq!(lurker.cycles(), c.min_cycles_minted - reaction_costs);
-
-        // downvote
-        assert!(state.react(p, post_id, 1, 0).is_ok());
-        let reaction_penalty = -3;
-        rewards_from_reactions -= 3;
-        reaction_costs += 3;
-        let author = state.users.get(&post_author_id).unwrap();
-        let lurker = state.users.get(&lurker_id).unwrap();
+        assert!(state.toggle_following_user(pr(0), 1));
+        assert!(state.toggle_following_user(pr(0), 2));
+        let user = state.users.get(&0).unwrap();
         assert_eq!(
-            author.cycles(),
-            c.min_cycles_minted - c.post_cost + reaction_penalty
+            ids(user.personal_feed(pr(0), &state, false).collect()),
+            vec![post]
         );
-        assert_eq!(author.karma_to_reward(), rewards_from_reactions);
-        assert_eq!(lurker.cycles(), c.min_cycles_minted - reaction_costs);
+        state.hot.extend([muted_post, post]);
         assert_eq!(
-            state.burned_cycles,
-            c.post_cost
-                + burned_cycles_by_reactions
-                + burned_cycles_by_reaction_from_untrusted
-                + 2 * 3
+            state
+                .hot_posts(pr(0), 0)
+                .into_iter()
+                .map(|post| post.id)
+                .collect::<Vec<_>>(),
+            vec![post]
         );
+        assert_eq!(state.hot_posts(pr(1), 0).len(), 2);
 
-        add(
+        // realm feeds
+        let realm = "MUTING".to_string();
+        state
+            .users
+            .get_mut(&2)
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        assert_eq!(
+            state.create_realm(
+                pr(2),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![2]
+            ),
+            Ok(())
+        );
+        for i in 0..2 {
+            assert!(state.toggle_realm_membership(pr(i), realm.clone()));
+            state.enter_realm(pr(i), realm.clone());
+        }
+        let realm_post = add(
             &mut state,
-            "test".to_string(),
+            "Realm post".into(),
             vec![],
-            p0,
+            pr(1),
             0,
-            Some(0),
             None,
+            Some(realm.clone()),
             None,
         )
         .await
         .unwrap();
+        assert!(state.last_posts(pr(0), false).next().is_none());
         assert_eq!(
-            state.burned_cycles,
-            2 * c.post_cost
-                + burned_cycles_by_reactions
-                + burned_cycles_by_reaction_from_untrusted
-                + 2 * 3
+            ids(state.last_posts(pr(1), false).collect()),
+            vec![realm_post]
         );
-        let author = state.users.get(&post_author_id).unwrap();
+
+        // unmuting restores everything
+        assert_eq!(state.mute_user(pr(0), 1, false), Ok(()));
         assert_eq!(
-            author.cycles(),
-            c.min_cycles_minted - c.post_cost - c.post_cost + reaction_penalty
+            ids(state.last_posts(pr(0), false).collect()),
+            vec![realm_post]
         );
+        assert_eq!(state.posts(pr(0), vec![muted_comment]).len(), 1);
+    }
 
-        let author = state.users.get_mut(&post_author_id).unwrap();
-        author
-            .change_cycles(c.post_cost - 1 - author.cycles(), "")
-            .unwrap();
-        assert!(add(
+    #[actix_rt::test]
+    async fn test_blocking() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        let root = add(
             &mut state,
-            "test".to_string(),
+            "Root".into(),
             vec![],
-            p0,
+            pr(0),
             0,
             None,
             None,
-            None
+            None,
         )
         .await
-        .is_err());
+        .unwrap();
+        let old_comment = add(
+            &mut state,
+            "Reply".into(),
+            vec![],
+            pr(1),
+            0,
+            Some(root),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            state.block_user(pr(0), 0, true),
+            Err("you can't block yourself".into())
+        );
+        assert_eq!(state.block_user(pr(0), 1, true), Ok(()));
 
-        let lurker = state.users.get_mut(&lurker_id).unwrap();
-        lurker.change_cycles(-lurker.cycles(), "").unwrap();
+        // the blocked user can't comment on, react to or mention the blocker
+        let blocked = "you were blocked by the author".to_string();
         assert_eq!(
-            state.react(p, post_id, 10, 0),
-            Err("not enough cycles".into())
+            add(
+                &mut state,
+                "Reply".into(),
+                vec![],
+                pr(1),
+                0,
+                Some(root),
+                None,
+                None
+            )
+            .await,
+            Err(blocked.clone())
+        );
+        assert_eq!(state.react(pr(1), root, 10, 0), Err(blocked));
+        let name = state.users.get(&0).unwrap().name.clone();
+        assert_eq!(
+            add(
+                &mut state,
+                format!("Hello @{}", name),
+                vec![],
+                pr(1),
+                0,
+                None,
+                None,
+                None
+            )
+            .await,
+            Err(format!("you can't mention @{}", name))
         );
-    }
 
-    #[test]
-    fn test_following() {
-        let mut state = State::default();
-        let p = pr(0);
-        let id = create_user(&mut state, p);
+        // other interactions are unaffected
+        let comment = add(
+            &mut state,
+            "Reply".into(),
+            vec![],
+            pr(2),
+            0,
+            Some(root),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(add(
+            &mut state,
+            "Reply".into(),
+            vec![],
+            pr(1),
+            0,
+            Some(comment),
+            None,
+            None
+        )
+        .await
+        .is_ok());
+        assert_eq!(state.react(pr(2), root, 10, 0), Ok(()));
 
-        let u1 = create_user(&mut state, pr(1));
-        let u2 = create_user(&mut state, pr(2));
-        let u3 = create_user(&mut state, pr(3));
+        // the earlier comment is hidden for the blocker only
+        assert!(state.posts(pr(0), vec![old_comment]).is_empty());
+        assert_eq!(state.posts(pr(2), vec![old_comment]).len(), 1);
 
-        assert!(state.toggle_following_user(p, 1));
-        assert!(state.toggle_following_user(p, 2));
-        assert!(state.toggle_following_user(p, 3));
+        // the blocker doesn't see the blocked user's content
+        let post = add(
+            &mut state,
+            "Post".into(),
+            vec![],
+            pr(1),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!state.last_posts(pr(0), false).any(|p| p.id == post));
+        assert!(state.last_posts(pr(2), false).any(|p| p.id == post));
 
-        let f1 = &state.user(&u1.to_string()).unwrap().followers;
-        assert_eq!(f1.len(), 1);
-        assert!(f1.contains(&id));
-        let f2 = &state.user(&u2.to_string()).unwrap().followers;
-        assert_eq!(f2.len(), 1);
-        assert!(f2.contains(&id));
-        let f3 = &state.user(&u3.to_string()).unwrap().followers;
-        assert_eq!(f3.len(), 1);
-        assert!(f3.contains(&id));
+        assert_eq!(state.block_user(pr(0), 1, false), Ok(()));
+        assert_eq!(state.react(pr(1), root, 10, 0), Ok(()));
+    }
 
-        assert!(!state.toggle_following_user(p, 1));
-        assert!(!state.toggle_following_user(p, 2));
-        assert!(!state.toggle_following_user(p, 3));
+    #[actix_rt::test]
+    async fn test_collections() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        for _ in 0..3 {
+            add(
+                &mut state,
+                "Test".to_string(),
+                vec![],
+                pr(0),
+                0,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let user = state.users.get_mut(&id).unwrap();
+        assert_eq!(
+            user.create_collection("".into(), true),
+            Err("invalid collection title".into())
+        );
+        assert_eq!(user.create_collection("public".into(), true), Ok(0));
+        assert_eq!(user.create_collection("private".into(), false), Ok(1));
+        for post_id in [2, 0, 1] {
+            assert_eq!(user.add_to_collection(0, post_id), Ok(()));
+        }
+        assert_eq!(
+            user.add_to_collection(0, 1),
+            Err("post already in collection".into())
+        );
+        assert_eq!(user.add_to_collection(1, 0), Ok(()));
+        assert_eq!(
+            user.reorder_collection(0, vec![1, 2]),
+            Err("new order doesn't match the collection".into())
+        );
+        assert_eq!(user.reorder_collection(0, vec![1, 2, 0]), Ok(()));
 
-        let f1 = &state.user(&u1.to_string()).unwrap().followers;
-        assert!(!f1.contains(&id));
-        let f2 = &state.user(&u2.to_string()).unwrap().followers;
-        assert!(!f2.contains(&id));
-        let f3 = &state.user(&u3.to_string()).unwrap().followers;
-        assert!(!f3.contains(&id));
+        let ids = |result: Result<(String, Vec<Post>), String>| {
+            result
+                .unwrap()
+                .1
+                .into_iter()
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
+        let handle = id.to_string();
+        // the order is preserved
+        assert_eq!(ids(state.collection(pr(1), &handle, 0)), vec![1, 2, 0]);
+
+        // deleted posts are skipped
+        state.posts.get_mut(&2).unwrap().delete(vec!["Test".into()]);
+        assert_eq!(ids(state.collection(pr(1), &handle, 0)), vec![1, 0]);
+        assert_eq!(state.user(&handle).unwrap().collections[0].posts.len(), 3);
+        // and dropped from the collection on the next update of the owner
+        state.prune_collections(pr(0)).unwrap();
+        assert_eq!(
+            state.user(&handle).unwrap().collections[0].posts,
+            vec![1, 0]
+        );
 
-        let tags: Vec<_> = vec!["tag1".to_string(), "tag2".to_string()]
-            .into_iter()
-            .collect();
-        let tags2: Vec<_> = vec!["tag1".to_owned()].into_iter().collect();
-        let user = state.users.get_mut(&id).unwrap();
-        assert!(user.toggle_following_feed(tags.clone()));
-        assert!(user.toggle_following_feed(tags2.clone()));
-        assert!(!user.toggle_following_feed(tags));
-        assert!(!user.toggle_following_feed(tags2));
+        // private collections are hidden from others
+        assert_eq!(ids(state.collection(pr(0), &handle, 1)), vec![0]);
+        let user = state.user(&handle).unwrap();
+        assert_eq!(user.collections.len(), 2);
+        assert_eq!(user.collections.iter().filter(|c| c.public).count(), 1);
+        assert_eq!(
+            state.collection(pr(1), &handle, 1).err(),
+            Some("no collection found".into())
+        );
+        let view = state.user_view(pr(1), Some(&handle)).unwrap();
+        assert_eq!(view.collections.len(), 2);
+        assert!(view.collections[1].title.is_empty());
+        assert!(view.collections[1].posts.is_empty());
+
+        // the owner sees private collections from a linked principal too
+        state.principals.insert(pr(2), id);
+        state
+            .users
+            .get_mut(&id)
+            .unwrap()
+            .linked_principals
+            .insert(pr(2));
+        let view = state.user_view(pr(2), None).unwrap();
+        assert_eq!(view.id, id);
+        assert_eq!(view.collections[1].title, "private");
+        assert_eq!(view.collections[1].posts, vec![0]);
+        let view = state.user_view(pr(2), Some(&handle)).unwrap();
+        assert_eq!(view.collections[1].posts, vec![0]);
+        assert_eq!(ids(state.collection(pr(2), &handle, 1)), vec![0]);
     }
 
     #[test]
-    fn test_stalwarts() {
+    fn test_collection_limits() {
         let mut state = State::default();
-        let now = CONFIG.min_stalwart_account_age_weeks as u64 * WEEK;
+        let id = create_user(&mut state, pr(0));
+        for _ in 0..=CONFIG.max_collection_size {
+            let post_id = state.new_post_id();
+            let mut post = Post::new(id, Default::default(), "Test".into(), 0, None, None, None);
+            post.id = post_id;
+            state.posts.insert(post_id, post);
+        }
 
-        for i in 0..100 {
-            let id = create_user(&mut state, pr(i as u8));
-            let user = state.users.get_mut(&id).unwrap();
-            user.change_karma(i as i64, "");
-            user.apply_rewards();
-            // every second user was active
-            if i % 2 == 0 {
-                user.last_activity = now;
-                user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
-                user.timestamp = 0;
-            }
+        let user = state.users.get_mut(&id).unwrap();
+        for i in 0..CONFIG.max_collections {
+            assert_eq!(user.create_collection(format!("c{}", i), true), Ok(i));
         }
+        assert_eq!(
+            user.create_collection("one more".into(), true),
+            Err(format!(
+                "not more than {} collections are allowed",
+                CONFIG.max_collections
+            ))
+        );
 
-        state.recompute_stalwarts(now + WEEK * 2);
+        assert_eq!(
+            state.add_to_collection(pr(0), CONFIG.max_collections, 0),
+            Err("no collection found".into())
+        );
+        assert_eq!(
+            state.add_to_collection(pr(0), 0, 4242),
+            Err("no post found".into())
+        );
+        for post_id in 0..CONFIG.max_collection_size as PostId {
+            assert_eq!(state.add_to_collection(pr(0), 0, post_id), Ok(()));
+        }
+        let last_id = CONFIG.max_collection_size as PostId;
+        let size_error = Err(format!(
+            "not more than {} posts per collection are allowed",
+            CONFIG.max_collection_size
+        ));
+        assert_eq!(state.add_to_collection(pr(0), 0, last_id), size_error);
 
-        // make sure we have right number of stalwarts
+        // deleted posts can't be added and are dropped from the collection lazily
+        state.posts.get_mut(&3).unwrap().delete(vec!["Test".into()]);
         assert_eq!(
-            state.users.values().filter(|u| u.stalwart).count(),
-            CONFIG.stalwart_percentage
+            state.add_to_collection(pr(0), 1, 3),
+            Err("no post found".into())
         );
+        let posts = |state: &State| state.users.get(&id).unwrap().collections[0].posts.clone();
+        assert!(posts(&state).contains(&3));
+        assert_eq!(state.add_to_collection(pr(0), 0, last_id), Ok(()));
+        let posts = posts(&state);
+        assert_eq!(posts.len(), CONFIG.max_collection_size);
+        assert!(!posts.contains(&3));
+        assert_eq!(posts.last(), Some(&last_id));
     }
 
     #[actix_rt::test]
-    async fn test_invites() {
+    async fn test_tipping() {
         let mut state = State::default();
-        let principal = pr(1);
-        let id = create_user(&mut state, principal);
+        let p = pr(0);
+        let u1 = create_user_with_params(&mut state, p, "user1", true);
+        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
+        let post_id = add(
+            &mut state,
+            "This is a #post with #tags".to_string(),
+            vec![],
+            p,
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
 
-        // use too many cycles
+        let u = state.users.get_mut(&u1).unwrap();
+        assert_eq!(u.karma(), CONFIG.trusted_user_min_karma);
+        let cycles_before = u.cycles();
+
+        assert_eq!(state.tip(pr(1), post_id, 500), Ok(()));
         assert_eq!(
-            state.create_invite(principal, 1111),
-            Err("not enough cycles".to_string())
+            state.tip(pr(1), post_id, 600),
+            Err("not enough cycles".into())
         );
 
-        // use enough cycles and make sure they were deducted
-        let prev_balance = state.users.get(&id).unwrap().cycles();
-        assert_eq!(state.create_invite(principal, 111), Ok(()));
-        let new_balance = state.users.get(&id).unwrap().cycles();
-        assert_eq!(new_balance, prev_balance - 111);
-        let invite = state.invites(principal);
-        assert_eq!(invite.len(), 1);
-        let (code, cycles) = invite.get(0).unwrap();
-        assert_eq!(*cycles, 111);
+        let u = state.users.get_mut(&u1).unwrap();
+        assert_eq!(u.karma_to_reward(), 0);
+        assert_eq!(u.cycles(), cycles_before + 500);
+        let u = state.users.get_mut(&u2).unwrap();
+        assert_eq!(u.cycles(), 1000 - 500 - CONFIG.tipping_fee);
 
-        // use the invite
-        let err = state
-            .create_user(pr(2), "name".to_string(), Some(code.clone()))
-            .await;
-        assert!(err.is_ok())
+        let p = state.posts.get(&post_id).unwrap();
+        assert_eq!(p.tips, vec![(u2, 500)]);
+    }
+
+    async fn comment_tree(state: &mut State) -> Vec<PostId> {
+        create_user(state, pr(0));
+        let mut ids = Vec::new();
+        // root, three comments and a reply to the first and the last comment
+        for (i, parent) in [None, Some(0), Some(0), Some(0), Some(1), Some(3)]
+            .iter()
+            .enumerate()
+        {
+            let parent = parent.map(|index: usize| ids[index]);
+            let time = i as u64 * CONFIG.rate_limit_window;
+            let id = add(
+                state,
+                "Post".into(),
+                vec![],
+                pr(0),
+                time,
+                parent,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        let mut react = |id: PostId, reaction: u16, users: Vec<UserId>| {
+            state
+                .posts
+                .get_mut(&id)
+                .unwrap()
+                .reactions
+                .insert(reaction, users.into_iter().collect());
+        };
+        // the second comment has the highest score, the third is downvoted
+        react(ids[2], 100, vec![1, 2]);
+        react(ids[3], 1, vec![1]);
+        react(ids[4], 10, vec![1]);
+        ids
+    }
+
+    #[actix_rt::test]
+    async fn test_comment_sorting() {
+        let mut state = State::default();
+        let ids = comment_tree(&mut state).await;
+        let children = |tree: &CommentTree| tree.posts.get(&ids[0]).unwrap().children.clone();
+
+        let tree = state.tree(pr(0), ids[0], CommentSort::Oldest, None);
+        assert_eq!(tree.posts.len(), 6);
+        assert_eq!(children(&tree), vec![ids[1], ids[2], ids[3]]);
+        let tree = state.tree(pr(0), ids[0], CommentSort::Newest, None);
+        assert_eq!(children(&tree), vec![ids[3], ids[2], ids[1]]);
+        let tree = state.tree(pr(0), ids[0], CommentSort::Top, None);
+        assert_eq!(children(&tree), vec![ids[2], ids[1], ids[3]]);
+
+        // equal scores fall back to the timestamp
+        state.posts.get_mut(&ids[1]).unwrap().timestamp = 10 * CONFIG.rate_limit_window;
+        let tree = state.tree(pr(0), ids[0], CommentSort::Top, None);
+        assert_eq!(children(&tree), vec![ids[2], ids[1], ids[3]]);
+        state.posts.get_mut(&ids[3]).unwrap().reactions.clear();
+        let tree = state.tree(pr(0), ids[0], CommentSort::Top, None);
+        assert_eq!(children(&tree), vec![ids[2], ids[3], ids[1]]);
+    }
+
+    #[actix_rt::test]
+    async fn test_comment_collapsing() {
+        let mut state = State::default();
+        let ids = comment_tree(&mut state).await;
+
+        // the downvoted comment is collapsed and its reply is not returned
+        let tree = state.tree(pr(0), ids[0], CommentSort::Oldest, Some(0));
+        assert_eq!(tree.collapsed, vec![ids[3]].into_iter().collect());
+        assert!(tree.posts.contains_key(&ids[3]));
+        assert!(!tree.posts.contains_key(&ids[5]));
+        assert_eq!(tree.posts.len(), 5);
+
+        // comments with less than one upvote are collapsed
+        let tree = state.tree(pr(0), ids[0], CommentSort::Oldest, Some(1));
+        assert_eq!(tree.collapsed, vec![ids[1], ids[3]].into_iter().collect());
+        assert_eq!(tree.posts.len(), 4);
+
+        // a collapsed sub-thread is expanded by requesting its tree
+        let tree = state.tree(pr(0), ids[3], CommentSort::Oldest, None);
+        assert!(tree.collapsed.is_empty());
+        assert_eq!(tree.posts.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_notification_settings() {
+        let mut state = State::default();
+        create_user_with_params(&mut state, pr(0), "alice", true);
+        for i in 1..3 {
+            create_user(&mut state, pr(i));
+        }
+        let post_id = add(
+            &mut state,
+            "Hello".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let realm = "REALM".to_string();
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        state
+            .set_realm_visibility(pr(0), realm.clone(), RealmVisibility::Private)
+            .unwrap();
+
+        let inbox_size = |state: &State| state.users.get(&0).unwrap().inbox.len();
+        let all_off = NotificationSettings {
+            mentions: false,
+            replies: false,
+            followers: false,
+            rewards: false,
+            realms: false,
+        };
+        let mut time = 0;
+        for enabled in [false, true].iter() {
+            let settings = |f: fn(&mut NotificationSettings)| {
+                let mut settings = all_off.clone();
+                if *enabled {
+                    f(&mut settings)
+                }
+                settings
+            };
+            let principal = pr(if *enabled { 2 } else { 1 });
+            let delta = *enabled as usize;
+
+            // existing notifications are kept
+            let inbox = inbox_size(&state);
+            state
+                .set_notification_settings(pr(0), settings(|s| s.replies = true))
+                .unwrap();
+            assert_eq!(inbox_size(&state), inbox);
+            time += CONFIG.rate_limit_window;
+            add(
+                &mut state,
+                "Reply".into(),
+                vec![],
+                principal,
+                time,
+                Some(post_id),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(inbox_size(&state), inbox + delta);
+
+            let inbox = inbox_size(&state);
+            state
+                .set_notification_settings(pr(0), settings(|s| s.mentions = true))
+                .unwrap();
+            time += CONFIG.rate_limit_window;
+            add(
+                &mut state,
+                "Hi @alice".into(),
+                vec![],
+                principal,
+                time,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(inbox_size(&state), inbox + delta);
+
+            let inbox = inbox_size(&state);
+            state
+                .set_notification_settings(pr(0), settings(|s| s.followers = true))
+                .unwrap();
+            assert!(state.toggle_following_user(principal, 0));
+            assert_eq!(inbox_size(&state), inbox + delta);
+
+            let inbox = inbox_size(&state);
+            state
+                .set_notification_settings(pr(0), settings(|s| s.rewards = true))
+                .unwrap();
+            state.tip(principal, post_id, 10).unwrap();
+            assert_eq!(inbox_size(&state), inbox + delta);
+
+            let inbox = inbox_size(&state);
+            state
+                .set_notification_settings(pr(0), settings(|s| s.realms = true))
+                .unwrap();
+            assert!(!state.toggle_realm_membership(principal, realm.clone()));
+            assert_eq!(inbox_size(&state), inbox + delta);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_private_realms() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        let realm = "PRIVATE".to_string();
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert_eq!(
+            state.set_realm_visibility(pr(1), realm.clone(), RealmVisibility::Private),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.set_realm_visibility(pr(0), realm.clone(), RealmVisibility::Private),
+            Ok(())
+        );
+        // controllers join without a request
+        assert!(state.toggle_realm_membership(pr(0), realm.clone()));
+
+        // joining creates a request for the controllers
+        let inbox_size = |state: &State, id| state.users.get(&id).unwrap().inbox.len();
+        let controller_inbox = inbox_size(&state, 0);
+        assert!(!state.toggle_realm_membership(pr(1), realm.clone()));
+        assert!(!state.toggle_realm_membership(pr(2), realm.clone()));
+        let r = state.realms.get(&realm).unwrap();
+        assert_eq!(r.join_requests, vec![1, 2].into_iter().collect());
+        assert!(!r.members.contains(&1));
+        assert_eq!(inbox_size(&state, 0), controller_inbox + 2);
+
+        // only controllers approve or reject requests
+        assert_eq!(
+            state.resolve_join_request(pr(1), realm.clone(), 2, true),
+            Err("not authorized".into())
+        );
+        let requester_inbox = inbox_size(&state, 1);
+        assert_eq!(
+            state.resolve_join_request(pr(0), realm.clone(), 1, true),
+            Ok(())
+        );
+        assert_eq!(
+            state.resolve_join_request(pr(0), realm.clone(), 2, false),
+            Ok(())
+        );
+        assert_eq!(
+            state.resolve_join_request(pr(0), realm.clone(), 2, true),
+            Err("no join request found".into())
+        );
+        assert_eq!(inbox_size(&state, 1), requester_inbox + 1);
+        let r = state.realms.get(&realm).unwrap();
+        assert!(r.join_requests.is_empty());
+        assert!(r.members.contains(&1));
+        assert!(!r.members.contains(&2));
+        assert!(state.users.get(&1).unwrap().realms.contains(&realm));
+        assert!(!state.users.get(&2).unwrap().realms.contains(&realm));
+
+        // private realm posts are excluded from global feeds and search for non-members
+        let post_id = add(
+            &mut state,
+            "Secret #plans".to_string(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            Some(realm.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        let feed = |state: &State, p| {
+            state
+                .last_posts(p, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
+        assert!(feed(&state, pr(1)).contains(&post_id));
+        assert!(!feed(&state, pr(2)).contains(&post_id));
+        let tags = vec!["plans".to_string()];
+        let by_tags = |state: &State, p, tags| state.posts_by_tags(p, tags, vec![], None).unwrap();
+        assert_eq!(by_tags(&state, pr(1), tags.clone()).posts.len(), 1);
+        assert!(by_tags(&state, pr(2), tags).posts.is_empty());
+        assert_eq!(state.search(pr(1), "secret".into(), None).len(), 1);
+        assert!(state.search(pr(2), "secret".into(), None).is_empty());
+
+        // queries by post id don't serve the posts to non-members either
+        let comment_id = add(
+            &mut state,
+            "Secret reply".to_string(),
+            vec![],
+            pr(1),
+            0,
+            Some(post_id),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let ids = vec![post_id, comment_id];
+        assert_eq!(state.posts(pr(1), ids.clone()).len(), 2);
+        assert!(state.posts(pr(2), ids).is_empty());
+        assert_eq!(state.thread_posts(pr(1), comment_id).len(), 2);
+        assert!(state.thread_posts(pr(2), comment_id).is_empty());
+        assert_eq!(
+            state
+                .tree(pr(1), post_id, CommentSort::Oldest, None)
+                .posts
+                .len(),
+            2
+        );
+        assert!(state
+            .tree(pr(2), post_id, CommentSort::Oldest, None)
+            .posts
+            .is_empty());
+        let handle = state.users.get(&0).unwrap().name.clone();
+        assert_eq!(state.journal(pr(1), &handle, 0).len(), 1);
+        assert!(state.journal(pr(2), &handle, 0).is_empty());
+        assert!(matches!(
+            post::resolve_quote(&state, pr(2), post_id),
+            post::Quote::Tombstone(_)
+        ));
+    }
+
+    #[test]
+    fn test_realm_invites() {
+        let mut state = State::default();
+        for i in 0..4 {
+            create_user(&mut state, pr(i));
+        }
+        let realm = "INVITES".to_string();
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        state
+            .set_realm_visibility(pr(0), realm.clone(), RealmVisibility::Restricted)
+            .unwrap();
+        assert_eq!(
+            state.create_realm_invite(pr(1), realm.clone(), 1, None, &[1], 0),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.redeem_realm_invite(pr(1), "nope".into(), 0),
+            Err("invite not found".into())
+        );
+
+        // exhaustion and double redemption
+        let code = state
+            .create_realm_invite(pr(0), realm.clone(), 2, None, &[1], 0)
+            .unwrap();
+        assert_eq!(
+            state.create_realm_invite(pr(0), realm.clone(), 2, None, &[1], 0),
+            Err("invite code collision, please retry".into())
+        );
+        assert_eq!(
+            state.redeem_realm_invite(pr(1), code.clone(), 0),
+            Ok(realm.clone())
+        );
+        assert_eq!(
+            state.redeem_realm_invite(pr(1), code.clone(), 0),
+            Err("invite already redeemed".into())
+        );
+        assert_eq!(
+            state
+                .realms
+                .get(&realm)
+                .unwrap()
+                .invites
+                .get(&code)
+                .unwrap()
+                .uses_left,
+            1
+        );
+        assert_eq!(
+            state.redeem_realm_invite(pr(2), code.clone(), 0),
+            Ok(realm.clone())
+        );
+        assert_eq!(
+            state.redeem_realm_invite(pr(3), code, 0),
+            Err("invite is fully used".into())
+        );
+        for i in 1..3 {
+            assert!(state.realms.get(&realm).unwrap().members.contains(&i));
+            assert!(state.users.get(&i).unwrap().realms.contains(&realm));
+        }
+
+        // expiry
+        let code = state
+            .create_realm_invite(pr(0), realm.clone(), 5, Some(1), &[2], 0)
+            .unwrap();
+        assert_eq!(
+            state.redeem_realm_invite(pr(3), code.clone(), HOUR),
+            Err("invite expired".into())
+        );
+        assert!(!state.realms.get(&realm).unwrap().members.contains(&3));
+        assert_eq!(
+            state.redeem_realm_invite(pr(3), code, HOUR - 1),
+            Ok(realm.clone())
+        );
+
+        // used up invites are pruned and codes are only visible to controllers
+        let r = state.realms.get(&realm).unwrap();
+        assert_eq!(r.view(Some(0)).invites.len(), 1);
+        assert!(r.view(Some(1)).invites.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_cursor_pagination() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let mut ids = Vec::new();
+        for i in 0..8 {
+            ids.push(
+                add(
+                    &mut state,
+                    format!("Post {}", i),
+                    vec![],
+                    pr(0),
+                    i * HOUR,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let page = |state: &State, cursor| {
+            let page = FeedPage::new(state.last_posts(pr(0), false), cursor, 2).unwrap();
+            let ids = page.posts.iter().map(|post| post.id).collect::<Vec<_>>();
+            (ids, page.cursor)
+        };
+        let (first, cursor) = page(&state, None);
+        assert_eq!(first, vec![ids[7], ids[6]]);
+
+        // new posts don't shift the following pages
+        for i in 0..2 {
+            add(
+                &mut state,
+                format!("New post {}", i),
+                vec![],
+                pr(0),
+                (8 + i) * HOUR,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let (second, cursor) = page(&state, cursor);
+        assert_eq!(second, vec![ids[5], ids[4]]);
+
+        // deleted posts are skipped, even if the cursor refers to one
+        for id in [ids[4], ids[2]].iter() {
+            state.posts.get_mut(id).unwrap().delete(vec!["body".into()]);
+        }
+        let (third, cursor) = page(&state, cursor);
+        assert_eq!(third, vec![ids[3], ids[1]]);
+
+        // the last page has no cursor
+        let (last, cursor) = page(&state, cursor);
+        assert_eq!(last, vec![ids[0]]);
+        assert!(cursor.is_none());
+
+        assert_eq!(
+            FeedPage::new(state.last_posts(pr(0), false), Some("?".into()), 2).err(),
+            Some("invalid cursor".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_pinned_posts() {
+        let mut state = State::default();
+        for i in 0..2 {
+            create_user(&mut state, pr(i));
+        }
+        let realm = "PINS".to_string();
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert!(state.toggle_realm_membership(pr(0), realm.clone()));
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            ids.push(
+                add(
+                    &mut state,
+                    format!("Post {}", i),
+                    vec![],
+                    pr(0),
+                    i * HOUR,
+                    None,
+                    Some(realm.clone()),
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let outside = add(
+            &mut state,
+            "Outside".into(),
+            vec![],
+            pr(0),
+            6 * HOUR,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let feed = |state: &State| {
+            state
+                .realm_posts(pr(1), &realm, None, false)
+                .unwrap()
+                .posts
+                .into_iter()
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(feed(&state), vec![ids[4], ids[3], ids[2], ids[1], ids[0]]);
+
+        assert_eq!(
+            state.pin_post(pr(1), realm.clone(), ids[0]),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.pin_post(pr(0), realm.clone(), outside),
+            Err("post is not in the realm".into())
+        );
+        state
+            .posts
+            .get_mut(&ids[4])
+            .unwrap()
+            .delete(vec!["Post 4".into()]);
+        assert_eq!(
+            state.pin_post(pr(0), realm.clone(), ids[4]),
+            Err("deleted posts can't be pinned".into())
+        );
+
+        // pinned posts come first, the latest pin on top, and aren't repeated below
+        for id in [ids[0], ids[2], ids[1]].iter() {
+            assert_eq!(state.pin_post(pr(0), realm.clone(), *id), Ok(()));
+        }
+        assert_eq!(
+            state.pin_post(pr(0), realm.clone(), ids[1]),
+            Err("post is already pinned".into())
+        );
+        assert_eq!(feed(&state), vec![ids[1], ids[2], ids[0], ids[3]]);
+
+        // unpinning keeps the order of the remaining pins
+        assert_eq!(state.unpin_post(pr(0), realm.clone(), ids[2]), Ok(()));
+        assert_eq!(
+            state.unpin_post(pr(0), realm.clone(), ids[2]),
+            Err("post is not pinned".into())
+        );
+        assert_eq!(
+            state.realms.get(&realm).unwrap().pinned,
+            vec![ids[1], ids[0]]
+        );
+        assert_eq!(feed(&state), vec![ids[1], ids[0], ids[3], ids[2]]);
+    }
+
+    #[test]
+    fn test_karma_decay() {
+        let mut state = State::default();
+        let active = create_user(&mut state, pr(0));
+        let inactive = create_user(&mut state, pr(1));
+        let now = CONFIG.karma_decay_period * 10;
+        state.users.get_mut(&active).unwrap().last_activity = now;
+        state.users.get_mut(&inactive).unwrap().last_activity = 0;
+        let karma = |state: &State, id| state.users.get(&id).unwrap().karma();
+        let initial = karma(&state, inactive);
+        let decayed = initial - initial * CONFIG.karma_decay_percentage / 100;
+
+        // decaying twice within one period has no effect
+        state.decay_karma(now);
+        state.decay_karma(now + CONFIG.karma_decay_period - 1);
+        assert_eq!(karma(&state, inactive), decayed);
+        assert_eq!(karma(&state, active), initial);
+
+        state.decay_karma(now + CONFIG.karma_decay_period);
+        assert_eq!(
+            karma(&state, inactive),
+            decayed - decayed * CONFIG.karma_decay_percentage / 100
+        );
+        assert_eq!(karma(&state, active), initial);
+    }
+
+    #[actix_rt::test]
+    async fn test_token_tipping() {
+        let mut state = State::default();
+        let u1 = create_user_with_params(&mut state, pr(0), "user1", true);
+        create_user_with_params(&mut state, pr(1), "user2", true);
+        let post_id = add(
+            &mut state,
+            "Tip me".to_string(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        state.balances.insert(account(pr(1)), 1000);
+        let balance = |state: &State, p| state.balances.get(&account(p)).copied();
+
+        assert_eq!(
+            state.tip_tokens(pr(0), post_id, 100, 0),
+            Err("you can't tip yourself".into())
+        );
+        assert_eq!(
+            state.tip_tokens(pr(1), post_id, CONFIG.minimal_token_tip - 1, 0),
+            Err(format!(
+                "the minimal tip is `{}` tokens",
+                CONFIG.minimal_token_tip
+            ))
+        );
+        assert_eq!(
+            state.tip_tokens(pr(1), post_id, 1000, 0),
+            Err("not enough tokens".into())
+        );
+        assert!(state.posts.get(&post_id).unwrap().token_tips.is_empty());
+        assert_eq!(balance(&state, pr(1)), Some(1000));
+
+        assert_eq!(state.tip_tokens(pr(1), post_id, 250, 0), Ok(()));
+        assert_eq!(state.tip_tokens(pr(1), post_id, 50, 0), Ok(()));
+        assert_eq!(balance(&state, pr(0)), Some(300));
+        assert_eq!(
+            balance(&state, pr(1)),
+            Some(1000 - 300 - 2 * CONFIG.transaction_fee)
+        );
+        let tips = &state.posts.get(&post_id).unwrap().token_tips;
+        assert_eq!(tips.len(), 2);
+        assert_eq!(tips.iter().map(|(_, amount)| amount).sum::<Token>(), 300);
+        assert!(state
+            .users
+            .get(&u1)
+            .unwrap()
+            .inbox
+            .values()
+            .any(|n| matches!(n, Notification::NewPost(msg, id)
+                if *id == post_id && msg.contains("tipped you with `2.50` $TAGGR"))));
+
+        // tips sent from a linked principal are paid from the main account
+        let u2 = state.principal_to_user(pr(1)).unwrap().id;
+        state.link_principal(pr(1), pr(2).to_string()).unwrap();
+        state.confirm_principal_link(pr(2), u2).unwrap();
+        assert_eq!(state.tip_tokens(pr(2), post_id, 100, 0), Ok(()));
+        assert_eq!(balance(&state, pr(0)), Some(400));
+        assert_eq!(
+            balance(&state, pr(1)),
+            Some(1000 - 400 - 3 * CONFIG.transaction_fee)
+        );
+        assert_eq!(balance(&state, pr(2)), None);
+
+        // after a principal change, tips are paid from the new main account
+        state.change_principal(pr(1), pr(3).to_string()).unwrap();
+        let user = state.users.get(&u2).unwrap();
+        assert_eq!(user.principal, pr(3));
+        assert!(user.linked_principals.contains(&pr(2)));
+        assert_eq!(state.tip_tokens(pr(2), post_id, 100, 0), Ok(()));
+        assert_eq!(balance(&state, pr(0)), Some(500));
+        assert_eq!(balance(&state, pr(1)), None);
+        assert_eq!(
+            balance(&state, pr(3)),
+            Some(1000 - 500 - 5 * CONFIG.transaction_fee)
+        );
+
+        // a linked principal changed by itself stays a linked one
+        state.change_principal(pr(2), pr(5).to_string()).unwrap();
+        let user = state.users.get(&u2).unwrap();
+        assert_eq!(user.principal, pr(3));
+        assert!(user.linked_principals.contains(&pr(5)));
+        assert!(!user.linked_principals.contains(&pr(2)));
+    }
+
+    #[test]
+    fn test_covered_by_feed() {
+        let m = |v: Vec<&str>| v.into_iter().map(|v| v.to_string()).collect();
+        let tests = vec![
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1"]),
+                true,
+                None,
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2"]),
+                false,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2"]),
+                true,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2", "tag3"]),
+                true,
+                None,
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2", "tag3"]),
+                false,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tagX", "tag2", "tag3"]),
+                false,
+                Some(1),
+            ),
+        ];
+
+        for (i, t) in tests.iter().enumerate() {
+            let (feeds, tags, strict, result) = t;
+            if covered_by_feeds(feeds, tags, *strict) != *result {
+                panic!("Test {} failed", i)
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_by_handle() {
+        let mut state = State::default();
+
+        let u1 = create_user_with_params(&mut state, pr(0), "user1", true);
+        let u2 = create_user_with_params(&mut state, pr(1), "user2", true);
+        let u3 = create_user_with_params(&mut state, pr(2), "user3", true);
+
+        assert_eq!(state.user("user1").unwrap().id, u1);
+        assert_eq!(state.user("0").unwrap().id, u1);
+        assert_eq!(state.user("user2").unwrap().id, u2);
+        assert_eq!(state.user("1").unwrap().id, u2);
+        assert_eq!(state.user("user3").unwrap().id, u3);
+        assert_eq!(state.user("2").unwrap().id, u3);
+        assert!(state.user("user22").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_feed_modes() {
+        let mut state = State::default();
+        for i in 0..2 {
+            create_user(&mut state, pr(i));
+        }
+        assert!(state.toggle_following_user(pr(0), 1));
+        let post = |state: &mut State, timestamp: u64, reactions: u64| {
+            let id = state.new_post_id();
+            let mut post = Post::new(
+                1,
+                Default::default(),
+                "".into(),
+                timestamp,
+                None,
+                None,
+                None,
+            );
+            post.id = id;
+            post.reactions.insert(100, (0..reactions).collect());
+            state.posts.insert(id, post);
+            state.users.get_mut(&1).unwrap().posts.push(id);
+            id
+        };
+        let liked = post(&mut state, 0, 30);
+        let ignored = post(&mut state, HOUR, 0);
+        let newest = post(&mut state, 2 * HOUR, 2);
+        let page = |state: &State, cursor| {
+            let user = state.users.get(&0).unwrap();
+            let page = user
+                .feed_page(pr(0), state, false, cursor, 3 * HOUR)
+                .unwrap();
+            let ids = page.posts.iter().map(|post| post.id).collect::<Vec<_>>();
+            (ids, page.cursor)
+        };
+
+        // the default mode keeps the chronological order
+        assert_eq!(
+            state.users.get(&0).unwrap().feed_mode,
+            FeedMode::Chronological
+        );
+        assert_eq!(page(&state, None).0, vec![newest, ignored, liked]);
+
+        // the same candidates get ranked by the trending score
+        state.set_feed_mode(pr(0), FeedMode::Ranked).unwrap();
+        assert_eq!(page(&state, None).0, vec![liked, newest, ignored]);
+    }
+
+    #[actix_rt::test]
+    async fn test_followed_tags() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        let user = state.users.get_mut(&0).unwrap();
+        // tags are normalized
+        assert!(user.toggle_following_tag("#Rust"));
+        assert!(!user.toggle_following_tag("rust"));
+        assert!(user.followed_tags.is_empty());
+        assert!(!user.toggle_following_tag("#"));
+        assert!(user.toggle_following_tag("RUST"));
+        assert!(user.toggle_following_tag("wasm"));
+        assert_eq!(
+            user.followed_tags.iter().cloned().collect::<Vec<_>>(),
+            vec!["rust".to_string(), "wasm".to_string()]
+        );
+
+        let post = |state: &mut State, body: &str, author| {
+            let id = state.new_post_id();
+            let mut post = Post::new(
+                author,
+                tags(CONFIG.max_tag_length, body),
+                body.to_string(),
+                0,
+                None,
+                None,
+                None,
+            );
+            post.id = id;
+            state.posts.insert(id, post);
+            state.users.get_mut(&author).unwrap().posts.push(id);
+            id
+        };
+        let both_tags = post(&mut state, "About #Rust and #WASM", 1);
+        let followee_post = post(&mut state, "Untagged", 2);
+        let other_post = post(&mut state, "About #cooking", 1);
+        let followee_tagged_post = post(&mut state, "More #rust", 2);
+        assert!(state.toggle_following_user(pr(0), 2));
+
+        // posts with several followed tags and from followed users appear once
+        let feed = state
+            .users
+            .get(&0)
+            .unwrap()
+            .personal_feed(pr(0), &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed, vec![followee_tagged_post, followee_post, both_tags]);
+        assert!(!feed.contains(&other_post));
+    }
+
+    #[actix_rt::test]
+    async fn test_personal_feed() {
+        let mut state = State::default();
+
+        // create a post author and one post for its principal
+        let p = pr(0);
+        let post_author_id = create_user(&mut state, p);
+        let post_id = add(
+            &mut state,
+            "This is a #post with #tags".to_string(),
+            vec![],
+            p,
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let anon = Principal::anonymous();
+
+        // create a user and make sure his feed is empty
+        let pr1 = pr(1);
+        let user_id = create_user(&mut state, pr1);
+        assert!(state
+            .user(&user_id.to_string())
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .next()
+            .is_none());
+
+        // now we follow post_author_id
+        let _user = state.users.get_mut(&user_id).unwrap();
+        assert!(state.toggle_following_user(pr1, post_author_id));
+
+        // make sure the feed contains exactly one post from post_author_id
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 1);
+        assert!(feed.contains(&post_id));
+
+        // now we follow a feed #post+#tags
+        let user = state.users.get_mut(&user_id).unwrap();
+        assert!(user.toggle_following_feed(
+            vec!["post".to_owned(), "tags".to_owned()]
+                .into_iter()
+                .collect()
+        ));
+
+        // make sure the feed still contains the same post
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 1);
+        assert!(feed.contains(&post_id));
+
+        // now a different post with the same tags appears
+        let p = pr(2);
+        let _post_author_id = create_user(&mut state, p);
+        let post_id2 = add(
+            &mut state,
+            "This is a different #post, but with the same #tags".to_string(),
+            vec![],
+            p,
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // make sure the feed contains both posts
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 2);
+        assert!(feed.contains(&post_id));
+        assert!(feed.contains(&post_id2));
+
+        // yet another post appears
+        let p = pr(3);
+        let _post_author_id = create_user(&mut state, p);
+        let post_id3 = add(
+            &mut state,
+            "Different #post, different #feed".to_string(),
+            vec![],
+            p,
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // make sure the feed contains the same old posts
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 2);
+        assert!(feed.contains(&post_id));
+        assert!(feed.contains(&post_id2));
+
+        // now we follow a feed "post"
+        let user = state.users.get_mut(&user_id).unwrap();
+        let tags: Vec<_> = vec!["post".to_string()].into_iter().collect();
+        assert!(user.toggle_following_feed(tags.clone()));
+        // make sure the feed contains the new post
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 3);
+        assert!(feed.contains(&post_id));
+        assert!(feed.contains(&post_id2));
+        assert!(feed.contains(&post_id3));
+
+        // Make sure we can unsubscribe and the feed gets back to 2 posts
+        let user = state.users.get_mut(&user_id).unwrap();
+        assert!(!user.toggle_following_feed(tags));
+        let feed = state
+            .users
+            .get(&user_id)
+            .unwrap()
+            .personal_feed(anon, &state, true)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(feed.len(), 2);
+        assert!(feed.contains(&post_id));
+        assert!(feed.contains(&post_id2));
+    }
+
+    #[test]
+    fn test_hashtag_extraction() {
+        let tags = |body| {
+            let c = CONFIG;
+            let mut t: Vec<_> = tags(c.max_tag_length, body).into_iter().collect();
+            t.sort_unstable();
+            t.join(" ")
+        };
+        assert_eq!(tags("This is a string without hashtags!"), "");
+        assert_eq!(tags("This is a #string with hashtags!"), "string");
+        assert_eq!(tags("#This is a #string with two hashtags!"), "This string");
+        assert_eq!(tags("This string has no tags.#bug"), "");
+        assert_eq!(
+            tags("#This is a #string with #333 hashtags!"),
+            "333 This string"
+        );
+        assert_eq!(tags("#year2021"), "year2021");
+        assert_eq!(tags("#year2021 #year2021 #"), "year2021");
+        assert_eq!(tags("#Ta1 #ta2"), "Ta1 ta2");
+        assert_eq!(tags("#Tag #tag"), "Tag tag");
+        assert_eq!(tags("Это #тест-строка"), "тест-строка");
+        assert_eq!(tags("This is a #feature-request"), "feature-request");
+        assert_eq!(tags("Support #under_score"), "under_score");
+    }
+
+    #[actix_rt::test]
+    async fn test_comment_reactions() {
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        let root = add(
+            &mut state,
+            "Post".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let comment = add(
+            &mut state,
+            "Comment".into(),
+            vec![],
+            pr(1),
+            0,
+            Some(root),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // comments are rewarded like posts and keep their own reactions
+        assert_eq!(state.react(pr(0), comment, 100, 0), Ok(()));
+        assert_eq!(state.react(pr(2), comment, 100, 0), Ok(()));
+        assert_eq!(state.react(pr(2), comment, 50, 0), Ok(()));
+        assert_eq!(
+            state.react(pr(1), comment, 100, 0),
+            Err("reactions to own posts are forbidden".into())
+        );
+        assert_eq!(
+            state.react(pr(0), comment, 100, 0),
+            Err("double reactions are forbidden".into())
+        );
+        assert_eq!(state.users.get(&1).unwrap().karma_to_reward(), 25);
+        assert!(state.posts.get(&root).unwrap().reactions.is_empty());
+
+        // the thread returns the tallies of the comment
+        let tallies = state
+            .thread(comment)
+            .filter_map(|id| state.posts.get(&id))
+            .map(|post| {
+                post.reactions
+                    .iter()
+                    .map(|(id, users)| (*id, users.len()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tallies, vec![vec![], vec![(50, 1), (100, 2)]]);
+    }
+
+    #[actix_rt::test]
+    async fn test_react_many() {
+        let mut state = State::default();
+        for i in 0..2 {
+            create_user(&mut state, pr(i));
+        }
+        let mut ids = Vec::new();
+        for (i, author) in [1, 1, 0].iter().enumerate() {
+            ids.push(
+                add(
+                    &mut state,
+                    "Post".into(),
+                    vec![],
+                    pr(*author),
+                    i as u64 * CONFIG.rate_limit_window,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let cycles = state.users.get(&0).unwrap().cycles();
+
+        // failed reactions are reported without reverting the valid ones
+        assert_eq!(
+            state.react_many(
+                pr(0),
+                vec![
+                    (ids[0], 100),
+                    (ids[2], 100),
+                    (ids[1], 50),
+                    (ids[0], 100),
+                    (42, 10)
+                ],
+                0
+            ),
+            Ok(vec![
+                Ok(()),
+                Err("reactions to own posts are forbidden".into()),
+                Ok(()),
+                Err("double reactions are forbidden".into()),
+                Err("post not found".into())
+            ])
+        );
+        assert_eq!(state.users.get(&1).unwrap().karma_to_reward(), 15);
+        assert_eq!(
+            state.users.get(&0).unwrap().cycles(),
+            cycles - 15 - 2 * CONFIG.reaction_fee
+        );
+        assert!(state.posts.get(&ids[1]).unwrap().reactions[&50].contains(&0));
+
+        assert_eq!(
+            state.react_many(
+                pr(0),
+                vec![(ids[1], 10); CONFIG.max_reactions_per_batch + 1],
+                0
+            ),
+            Err("at most 50 reactions can be sent at once".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_cycles_accounting() {
+        let mut state = State::default();
+        let p0 = pr(0);
+        let post_author_id = create_user(&mut state, p0);
+        let post_id = add(
+            &mut state,
+            "test".to_string(),
+            vec![],
+            p0,
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let p = pr(1);
+        let lurker_id = create_user(&mut state, p);
+        let farmer_id = create_untrusted_user(&mut state, pr(111));
+        let c = CONFIG;
+        assert_eq!(state.burned_cycles, c.post_cost);
+        state
+            .users
+            .get_mut(&lurker_id)
+            .unwrap()
+            .change_karma(10, "");
+        state.users.get_mut(&lurker_id).unwrap().apply_rewards();
+        // make author to a new user
+        state
+            .users
+            .get_mut(&post_author_id)
+            .unwrap()
+            .change_karma(-CONFIG.trusted_user_min_karma, "");
+        let author = state.users.get(&post_author_id).unwrap();
+        let farmer = state.users.get(&farmer_id).unwrap();
+        let lurker = state.users.get(&lurker_id).unwrap();
+        assert!(!author.trusted());
+        assert!(!farmer.trusted());
+        assert!(lurker.trusted());
+        assert_eq!(author.cycles(), c.min_cycles_minted - c.post_cost);
+        assert_eq!(lurker.cycles(), c.min_cycles_minted);
+
+        assert_eq!(author.karma(), 0);
+
+        // react on the new post
+        assert!(state.react(pr(111), post_id, 1, 0).is_err());
+        // this is a noop for author
+        assert!(state.react(pr(111), post_id, 100, 0).is_ok());
+        let burned_cycles_by_reaction_from_untrusted = 11;
+        assert_eq!(
+            state.users.get(&post_author_id).unwrap().cycles(),
+            c.min_cycles_minted - c.post_cost
+        );
+        assert!(state.react(p, post_id, 50, 0).is_ok());
+        assert!(state.react(p, post_id, 100, 0).is_ok());
+        let mut reaction_costs = 6 + 11;
+        let burned_cycles_by_reactions = 1 + 1;
+        let mut rewards_from_reactions = 5 + 10;
+
+        // try to self upvote (should be a no-op)
+        assert!(state.react(p0, post_id, 100, 0).is_err());
+
+        let author = state.users.get(&post_author_id).unwrap();
+        assert_eq!(author.cycles(), c.min_cycles_minted - c.post_cost);
+        assert_eq!(author.karma_to_reward(), rewards_from_reactions);
+        assert_eq!(
+            state.burned_cycles,
+            c.post_cost + burned_cycles_by_reactions + burned_cycles_by_reaction_from_untrusted
+        );
+
+        let lurker = state.users.get(&lurker_id).unwrap();
+        assert_eq!(lurker.cycles(), c.min_cycles_minted - reaction_costs);
+
+        // downvote
+        assert!(state.react(p, post_id, 1, 0).is_ok());
+        let reaction_penalty = -3;
+        rewards_from_reactions -= 3;
+        reaction_costs += 3;
+        let author = state.users.get(&post_author_id).unwrap();
+        let lurker = state.users.get(&lurker_id).unwrap();
+        assert_eq!(
+            author.cycles(),
+            c.min_cycles_minted - c.post_cost + reaction_penalty
+        );
+        assert_eq!(author.karma_to_reward(), rewards_from_reactions);
+        assert_eq!(lurker.cycles(), c.min_cycles_minted - reaction_costs);
+        assert_eq!(
+            state.burned_cycles,
+            c.post_cost
+                + burned_cycles_by_reactions
+                + burned_cycles_by_reaction_from_untrusted
+                + 2 * 3
+        );
+
+        add(
+            &mut state,
+            "test".to_string(),
+            vec![],
+            p0,
+            0,
+            Some(0),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            state.burned_cycles,
+            2 * c.post_cost
+                + burned_cycles_by_reactions
+                + burned_cycles_by_reaction_from_untrusted
+                + 2 * 3
+        );
+        let author = state.users.get(&post_author_id).unwrap();
+        assert_eq!(
+            author.cycles(),
+            c.min_cycles_minted - c.post_cost - c.post_cost + reaction_penalty
+        );
+
+        let author = state.users.get_mut(&post_author_id).unwrap();
+        author
+            .change_cycles(c.post_cost - 1 - author.cycles(), "")
+            .unwrap();
+        assert!(add(
+            &mut state,
+            "test".to_string(),
+            vec![],
+            p0,
+            0,
+            None,
+            None,
+            None
+        )
+        .await
+        .is_err());
+
+        let lurker = state.users.get_mut(&lurker_id).unwrap();
+        lurker.change_cycles(-lurker.cycles(), "").unwrap();
+        assert_eq!(
+            state.react(p, post_id, 10, 0),
+            Err("not enough cycles".into())
+        );
+    }
+
+    #[test]
+    fn test_following() {
+        let mut state = State::default();
+        let p = pr(0);
+        let id = create_user(&mut state, p);
+
+        let u1 = create_user(&mut state, pr(1));
+        let u2 = create_user(&mut state, pr(2));
+        let u3 = create_user(&mut state, pr(3));
+
+        assert!(state.toggle_following_user(p, 1));
+        assert!(state.toggle_following_user(p, 2));
+        assert!(state.toggle_following_user(p, 3));
+
+        let f1 = &state.user(&u1.to_string()).unwrap().followers;
+        assert_eq!(f1.len(), 1);
+        assert!(f1.contains(&id));
+        let f2 = &state.user(&u2.to_string()).unwrap().followers;
+        assert_eq!(f2.len(), 1);
+        assert!(f2.contains(&id));
+        let f3 = &state.user(&u3.to_string()).unwrap().followers;
+        assert_eq!(f3.len(), 1);
+        assert!(f3.contains(&id));
+
+        assert!(!state.toggle_following_user(p, 1));
+        assert!(!state.toggle_following_user(p, 2));
+        assert!(!state.toggle_following_user(p, 3));
+
+        let f1 = &state.user(&u1.to_string()).unwrap().followers;
+        assert!(!f1.contains(&id));
+        let f2 = &state.user(&u2.to_string()).unwrap().followers;
+        assert!(!f2.contains(&id));
+        let f3 = &state.user(&u3.to_string()).unwrap().followers;
+        assert!(!f3.contains(&id));
+
+        let tags: Vec<_> = vec!["tag1".to_string(), "tag2".to_string()]
+            .into_iter()
+            .collect();
+        let tags2: Vec<_> = vec!["tag1".to_owned()].into_iter().collect();
+        let user = state.users.get_mut(&id).unwrap();
+        assert!(user.toggle_following_feed(tags.clone()));
+        assert!(user.toggle_following_feed(tags2.clone()));
+        assert!(!user.toggle_following_feed(tags));
+        assert!(!user.toggle_following_feed(tags2));
+    }
+
+    #[test]
+    fn test_stalwarts() {
+        let mut state = State::default();
+        let now = CONFIG.min_stalwart_account_age_weeks as u64 * WEEK;
+
+        for i in 0..100 {
+            let id = create_user(&mut state, pr(i as u8));
+            let user = state.users.get_mut(&id).unwrap();
+            user.change_karma(i as i64, "");
+            user.apply_rewards();
+            // every second user was active
+            if i % 2 == 0 {
+                user.last_activity = now;
+                user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
+                user.timestamp = 0;
+            }
+        }
+
+        state.recompute_stalwarts(now + WEEK * 2);
+
+        // make sure we have right number of stalwarts
+        assert_eq!(
+            state.users.values().filter(|u| u.stalwart).count(),
+            CONFIG.stalwart_percentage
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_invites() {
+        let mut state = State::default();
+        let principal = pr(1);
+        let id = create_user(&mut state, principal);
+
+        // use too many cycles
+        assert_eq!(
+            state.create_invite(principal, 1111),
+            Err("not enough cycles".to_string())
+        );
+
+        // use enough cycles and make sure they were deducted
+        let prev_balance = state.users.get(&id).unwrap().cycles();
+        assert_eq!(state.create_invite(principal, 111), Ok(()));
+        let new_balance = state.users.get(&id).unwrap().cycles();
+        assert_eq!(new_balance, prev_balance - 111);
+        let invite = state.invites(principal);
+        assert_eq!(invite.len(), 1);
+        let (code, cycles) = invite.get(0).unwrap();
+        assert_eq!(*cycles, 111);
+
+        // use the invite
+        let err = state
+            .create_user(pr(2), "name".to_string(), Some(code.clone()))
+            .await;
+        assert!(err.is_ok())
+    }
+
+    #[actix_rt::test]
+    async fn test_referral_rewards() {
+        let mut state = State::default();
+        let inviter = create_user(&mut state, pr(1));
+        state.create_invite(pr(1), 500).unwrap();
+        let (code, _) = state.invites(pr(1)).pop().unwrap();
+        state
+            .create_user(pr(2), "referee".to_string(), Some(code))
+            .await
+            .unwrap();
+        let referee = state.principal_to_user(pr(2)).unwrap().id;
+        assert!(state.users.get(&referee).unwrap().referral_pending);
+        let karma = |state: &State| state.users.get(&inviter).unwrap().karma_to_reward();
+        let karma_before = karma(&state);
+
+        // no reward before the activity threshold is reached
+        for i in 0..CONFIG.referral_min_posts as u64 {
+            state.reward_referrals();
+            assert_eq!(karma(&state), karma_before);
+            add(
+                &mut state,
+                "Hello".into(),
+                vec![],
+                pr(2),
+                i * CONFIG.rate_limit_window,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        state.reward_referrals();
+        assert_eq!(karma(&state), karma_before);
+        state
+            .users
+            .get_mut(&referee)
+            .unwrap()
+            .change_karma(CONFIG.referral_min_karma, "");
+
+        // the reward is paid out exactly once
+        for _ in 0..3 {
+            state.reward_referrals();
+            assert_eq!(karma(&state), karma_before + CONFIG.referral_reward);
+        }
+        assert!(!state.users.get(&referee).unwrap().referral_pending);
+    }
+
+    async fn realm_with_rewards(state: &mut State, name: &str) {
+        for i in 0..3 {
+            create_user(state, pr(i));
+        }
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                name.into(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        for i in 1..3 {
+            assert!(state.toggle_realm_membership(pr(i), name.into()));
+        }
+        state.realms.get_mut(name).unwrap().treasury = 1000;
+        assert_eq!(
+            state.set_realm_rewards(pr(1), name.into(), 400, 24, 0),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.set_realm_rewards(pr(0), name.into(), 400, 1, 0),
+            Err("the period must be at least 24 hours".into())
+        );
+        state
+            .set_realm_rewards(pr(0), name.into(), 400, 24, 0)
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_scoped_search() {
+        let mut state = State::default();
+        let realm = "ORCHARD".to_string();
+        realm_with_rewards(&mut state, &realm).await;
+        let mut ids = Vec::new();
+        for (i, picked_realm) in [Some(realm.clone()), None].iter().enumerate() {
+            ids.push(
+                add(
+                    &mut state,
+                    "Apples and pears".into(),
+                    vec![],
+                    pr(1),
+                    i as u64 * CONFIG.rate_limit_window,
+                    None,
+                    picked_realm.clone(),
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let search = |state: &State, p, realm: Option<&str>| {
+            state
+                .search(p, "apples".into(), realm.map(|name| name.to_string()))
+                .into_iter()
+                .map(|result| (result.result, result.id))
+                .collect::<Vec<_>>()
+        };
+
+        // only posts of the realm are found
+        let realm_post = vec![("post".to_string(), ids[0])];
+        assert_eq!(search(&state, pr(5), Some(&realm)), realm_post);
+        assert_eq!(search(&state, pr(5), None).len(), 2);
+        assert!(search(&state, pr(5), Some("NOPE")).is_empty());
+
+        // private realms are only searchable by members
+        state
+            .set_realm_visibility(pr(0), realm.clone(), RealmVisibility::Private)
+            .unwrap();
+        create_user(&mut state, pr(3));
+        assert_eq!(search(&state, pr(2), Some(&realm)), realm_post);
+        assert!(search(&state, pr(3), Some(&realm)).is_empty());
+        assert!(search(&state, pr(5), Some(&realm)).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_post_limits() {
+        let mut state = State::default();
+        let realm = "SHORT".to_string();
+        realm_with_rewards(&mut state, &realm).await;
+        assert_eq!(
+            state.set_realm_post_limits(pr(1), realm.clone(), Some(10), None),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.set_realm_post_limits(pr(0), realm.clone(), Some(0), None),
+            Err("the post length limit must be positive".into())
+        );
+        assert_eq!(
+            state.set_realm_post_limits(pr(0), realm.clone(), None, Some(1000)),
+            Err("the attachment limit can't exceed 32".into())
+        );
+        assert_eq!(
+            state.set_realm_post_limits(pr(0), realm.clone(), Some(10), Some(1)),
+            Ok(())
+        );
+
+        async fn post(
+            state: &mut State,
+            body: &str,
+            blobs: usize,
+            realm: Option<String>,
+        ) -> Result<PostId, String> {
+            let blobs = (0..blobs)
+                .map(|i| (format!("blob{}", i), ByteBuf::from(vec![1])))
+                .collect();
+            let time = state.next_post_id * CONFIG.rate_limit_window;
+            add(state, body.into(), blobs, pr(1), time, None, realm, None).await
+        }
+        let long_body = "Eleven char";
+        assert_eq!(
+            post(&mut state, long_body, 0, Some(realm.clone())).await,
+            Err("invalid post content".into())
+        );
+        assert_eq!(
+            post(&mut state, "Short", 2, Some(realm.clone())).await,
+            Err("at most 1 attachments are allowed".into())
+        );
+        assert!(post(&mut state, "Short", 0, Some(realm.clone()))
+            .await
+            .is_ok());
+
+        // other realms and unset limits fall back to the global limits
+        assert!(post(&mut state, long_body, 0, None).await.is_ok());
+        assert_eq!(
+            post(&mut state, "Short", CONFIG.max_post_attachments + 1, None).await,
+            Err(format!(
+                "at most {} attachments are allowed",
+                CONFIG.max_post_attachments
+            ))
+        );
+        state
+            .set_realm_post_limits(pr(0), realm.clone(), None, None)
+            .unwrap();
+        assert!(post(&mut state, long_body, 0, Some(realm)).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_blocked_patterns() {
+        let mut state = State::default();
+        let realm = "FILTERED".to_string();
+        realm_with_rewards(&mut state, &realm).await;
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            state.set_realm_blocked_patterns(pr(1), realm.clone(), patterns(&["spam"])),
+            Err("not authorized".into())
+        );
+        assert!(state
+            .set_realm_blocked_patterns(pr(0), realm.clone(), patterns(&["spam", "[z-a]"]))
+            .unwrap_err()
+            .starts_with("invalid pattern"));
+        assert!(state
+            .realms
+            .get(&realm)
+            .unwrap()
+            .blocked_patterns
+            .is_empty());
+        state
+            .set_realm_blocked_patterns(pr(0), realm.clone(), patterns(&["spam", r"buy\s+now"]))
+            .unwrap();
+
+        let blocked = Err("the post contains content blocked in this realm".into());
+        async fn post(
+            state: &mut State,
+            body: &str,
+            parent: Option<PostId>,
+            realm: Option<String>,
+        ) -> Result<PostId, String> {
+            let time = state.next_post_id * CONFIG.rate_limit_window;
+            add(state, body.into(), vec![], pr(1), time, parent, realm, None).await
+        }
+        assert_eq!(
+            post(&mut state, "Some SPAM", None, Some(realm.clone())).await,
+            blocked
+        );
+        assert_eq!(
+            post(&mut state, "Buy   Now!", None, Some(realm.clone())).await,
+            blocked
+        );
+        let id = post(&mut state, "Hello", None, Some(realm.clone()))
+            .await
+            .unwrap();
+        // comments inherit the realm of the parent
+        assert_eq!(post(&mut state, "spam", Some(id), None).await, blocked);
+        assert!(post(&mut state, "Hi", Some(id), None).await.is_ok());
+
+        // posts outside of the realm aren't filtered
+        assert!(post(&mut state, "Some spam", None, None).await.is_ok());
+
+        // without a compiled set, the realm rejects all content
+        state.realms.get_mut(&realm).unwrap().blocklist = None;
+        assert_eq!(
+            post(&mut state, "Hello", None, Some(realm.clone())).await,
+            blocked
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_reward_multiplier() {
+        let mut state = State::default();
+        let realm = "WEIGHTED";
+        realm_with_rewards(&mut state, realm).await;
+        assert_eq!(
+            state.set_realm_reward_multiplier(pr(1), realm.into(), 200),
+            Err("not authorized".into())
+        );
+        for percentage in [0, 49, 201].iter() {
+            assert_eq!(
+                state.set_realm_reward_multiplier(pr(0), realm.into(), *percentage),
+                Err("the multiplier must be between 50% and 200%".into())
+            );
+        }
+        assert_eq!(
+            state.set_realm_reward_multiplier(pr(0), realm.into(), 200),
+            Ok(())
+        );
+
+        let karma = |state: &State| state.users.get(&1).unwrap().karma_to_reward();
+        let realm_post = add(
+            &mut state,
+            "In the realm".into(),
+            vec![],
+            pr(1),
+            0,
+            None,
+            Some(realm.into()),
+            None,
+        )
+        .await
+        .unwrap();
+        let other_post = add(
+            &mut state,
+            "Outside".into(),
+            vec![],
+            pr(1),
+            CONFIG.rate_limit_window,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // the reward of reactions and responses in the realm is doubled
+        state.react(pr(2), realm_post, 100, 0).unwrap();
+        assert_eq!(karma(&state), 20);
+        add(
+            &mut state,
+            "Reply".into(),
+            vec![],
+            pr(2),
+            0,
+            Some(realm_post),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(karma(&state), 20 + 2 * CONFIG.response_reward);
+        state.react(pr(2), other_post, 100, 0).unwrap();
+        assert_eq!(karma(&state), 30 + 2 * CONFIG.response_reward);
+
+        // a lowered multiplier burns a part of the reward
+        state
+            .set_realm_reward_multiplier(pr(0), realm.into(), 50)
+            .unwrap();
+        state.react(pr(0), realm_post, 100, 0).unwrap();
+        assert_eq!(karma(&state), 35 + 2 * CONFIG.response_reward);
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_rewards_split() {
+        let mut state = State::default();
+        let realm = "REWARDS";
+        realm_with_rewards(&mut state, realm).await;
+        let balance = |state: &State, i| {
+            state
+                .balances
+                .get(&account(pr(i)))
+                .copied()
+                .unwrap_or_default()
+        };
+        let mut ids = Vec::new();
+        for (i, principal) in [pr(1), pr(1), pr(2)].iter().enumerate() {
+            let time = (i as u64 + 1) * CONFIG.rate_limit_window;
+            let id = add(
+                &mut state,
+                "Contribution".into(),
+                vec![],
+                *principal,
+                time,
+                None,
+                Some(realm.into()),
+                None,
+            )
+            .await
+            .unwrap();
+            ids.push(id);
+        }
+        state
+            .posts
+            .get_mut(&ids[0])
+            .unwrap()
+            .reactions
+            .insert(100, vec![0].into_iter().collect());
+        // downvotes don't count
+        state
+            .posts
+            .get_mut(&ids[2])
+            .unwrap()
+            .reactions
+            .insert(1, vec![0].into_iter().collect());
+
+        // nothing happens before the period is over
+        state.distribute_realm_rewards(23 * HOUR);
+        assert_eq!(state.realms.get(realm).unwrap().treasury, 1000);
+
+        // 3 points for the first user and 1 point for the second one
+        state.distribute_realm_rewards(24 * HOUR);
+        assert_eq!((balance(&state, 1), balance(&state, 2)), (300, 100));
+        assert_eq!(state.realms.get(realm).unwrap().treasury, 600);
+
+        // the distribution is capped by the treasury
+        state.realms.get_mut(realm).unwrap().treasury = 200;
+        add(
+            &mut state,
+            "More".into(),
+            vec![],
+            pr(2),
+            30 * HOUR,
+            None,
+            Some(realm.into()),
+            None,
+        )
+        .await
+        .unwrap();
+        state.distribute_realm_rewards(48 * HOUR);
+        assert_eq!((balance(&state, 1), balance(&state, 2)), (300, 300));
+        assert_eq!(state.realms.get(realm).unwrap().treasury, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_rewards_empty_period() {
+        let mut state = State::default();
+        let realm = "REWARDS";
+        realm_with_rewards(&mut state, realm).await;
+        state.distribute_realm_rewards(24 * HOUR);
+        let r = state.realms.get(realm).unwrap();
+        assert_eq!(r.treasury, 1000);
+        assert_eq!(r.rewards.as_ref().unwrap().last_distribution, 24 * HOUR);
+        assert!(state.balances.is_empty());
+
+        // posts from before the period don't count
+        add(
+            &mut state,
+            "Late".into(),
+            vec![],
+            pr(1),
+            24 * HOUR,
+            None,
+            Some(realm.into()),
+            None,
+        )
+        .await
+        .unwrap();
+        state.distribute_realm_rewards(48 * HOUR);
+        assert_eq!(state.realms.get(realm).unwrap().treasury, 1000);
+
+        // disabled rewards are not distributed
+        state
+            .set_realm_rewards(pr(0), realm.into(), 0, 0, 48 * HOUR)
+            .unwrap();
+        assert!(state.realms.get(realm).unwrap().rewards.is_none());
+    }
+
+    async fn realms_to_merge(state: &mut State) -> (PostId, PostId) {
+        for i in 0..4 {
+            create_user(state, pr(i));
+        }
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(2 * CONFIG.realm_cost, "")
+            .unwrap();
+        for (name, controllers) in [("SOURCE", vec![0, 1]), ("TARGET", vec![0, 2])] {
+            state
+                .create_realm(
+                    pr(0),
+                    name.into(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    controllers,
+                )
+                .unwrap();
+        }
+        for (i, name) in [(1, "SOURCE"), (2, "SOURCE"), (3, "SOURCE"), (2, "TARGET")] {
+            assert!(state.toggle_realm_membership(pr(i), name.into()));
+        }
+        let source_post = add(
+            state,
+            "Source".into(),
+            vec![],
+            pr(3),
+            0,
+            None,
+            Some("SOURCE".into()),
+            None,
+        )
+        .await
+        .unwrap();
+        let target_post = add(
+            state,
+            "Target".into(),
+            vec![],
+            pr(2),
+            0,
+            None,
+            Some("TARGET".into()),
+            None,
+        )
+        .await
+        .unwrap();
+        (source_post, target_post)
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_merge_posts() {
+        let mut state = State::default();
+        let (source_post, target_post) = realms_to_merge(&mut state).await;
+        state.principal_to_user_mut(pr(3)).unwrap().current_realm = Some("SOURCE".into());
+        assert_eq!(
+            state.merge_realms(pr(1), "SOURCE".into(), "TARGET".into()),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            state.merge_realms(pr(0), "TARGET".into(), "TARGET".into()),
+            Err("a realm can't be merged into itself".into())
+        );
+        state.realms.get_mut("SOURCE").unwrap().visibility = RealmVisibility::Private;
+        assert_eq!(
+            state.merge_realms(pr(0), "SOURCE".into(), "TARGET".into()),
+            Err("a realm can't be merged into a less restrictive realm".into())
+        );
+        state.realms.get_mut("SOURCE").unwrap().visibility = RealmVisibility::Public;
+
+        // archived posts move over too
+        let archived_post = source_post + 100;
+        let mut post = Post::new(
+            3,
+            Default::default(),
+            "Archived".into(),
+            0,
+            None,
+            None,
+            None,
+        );
+        post.id = archived_post;
+        post.realm = Some("SOURCE".into());
+        post.archived = true;
+        state.posts.insert(archived_post, post);
+        state
+            .realms
+            .get_mut("SOURCE")
+            .unwrap()
+            .archived
+            .push(archived_post);
+
+        state
+            .merge_realms(pr(0), "SOURCE".into(), "TARGET".into())
+            .unwrap();
+        assert_eq!(
+            state.posts.get(&archived_post).unwrap().realm,
+            Some("TARGET".into())
+        );
+        assert_eq!(
+            state.realms.get("TARGET").unwrap().archived,
+            vec![archived_post]
+        );
+
+        // posts and members moved over
+        assert!(!state.realms.contains_key("SOURCE"));
+        let realm = state.realms.get("TARGET").unwrap();
+        assert_eq!(realm.posts, vec![source_post, target_post]);
+        assert_eq!(
+            state.posts.get(&source_post).unwrap().realm,
+            Some("TARGET".into())
+        );
+        assert_eq!(realm.members, (0..4).collect());
+        let user = state.principal_to_user(pr(3)).unwrap();
+        assert_eq!(user.realms, vec!["TARGET".to_string()]);
+        assert_eq!(user.current_realm, Some("TARGET".into()));
+        assert_eq!(
+            state.principal_to_user(pr(2)).unwrap().realms,
+            vec!["TARGET".to_string()]
+        );
+
+        // the lower role wins: 2 was only a member of the source realm, 1 didn't control the target
+        assert_eq!(realm.controllers, vec![0]);
+
+        // old links resolve and the old name stays reserved
+        assert_eq!(state.resolve_realm_name("SOURCE"), "TARGET");
+        assert_eq!(
+            state
+                .realm_posts(pr(3), &state.resolve_realm_name("SOURCE"), None, false)
+                .unwrap()
+                .posts
+                .len(),
+            2
+        );
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        assert_eq!(
+            state.create_realm(
+                pr(0),
+                "SOURCE".into(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            ),
+            Err("realm name taken".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_merge_treasury() {
+        let mut state = State::default();
+        realms_to_merge(&mut state).await;
+        state.realms.get_mut("SOURCE").unwrap().treasury = 300;
+        state.realms.get_mut("TARGET").unwrap().treasury = 200;
+        state
+            .merge_realms(pr(0), "SOURCE".into(), "TARGET".into())
+            .unwrap();
+        assert_eq!(state.realms.get("TARGET").unwrap().treasury, 500);
+    }
+
+    #[actix_rt::test]
+    async fn test_realm_archival() {
+        let mut state = State::default();
+        let p0 = pr(0);
+        let u0 = create_user(&mut state, p0);
+        state
+            .users
+            .get_mut(&u0)
+            .unwrap()
+            .change_cycles(1000, "")
+            .unwrap();
+        let name = "ISSUES".to_string();
+        assert_eq!(
+            state.create_realm(
+                p0,
+                name.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![u0]
+            ),
+            Ok(())
+        );
+        state.users.get_mut(&u0).unwrap().realms.push(name.clone());
+        state.enter_realm(p0, name.clone());
+        assert_eq!(
+            state.set_realm_archival(pr(1), name.clone(), Some(24), false),
+            Err("no user found".into())
+        );
+        assert_eq!(
+            state.set_realm_archival(p0, name.clone(), Some(24), false),
+            Ok(())
+        );
+
+        let mut ids = Vec::new();
+        for body in ["Bug report", "Feature request"] {
+            ids.push(
+                add(
+                    &mut state,
+                    body.to_string(),
+                    vec![],
+                    p0,
+                    0,
+                    None,
+                    Some(name.clone()),
+                    None,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let (bug, feature) = (ids[0], ids[1]);
+        let active_feed = |state: &State| {
+            state
+                .last_posts(p0, false)
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
+
+        // terminal statuses can only be reopened
+        assert_eq!(
+            post::set_status(&mut state, p0, bug, post::PostStatus::Resolved, 0),
+            Ok(())
+        );
+        assert_eq!(
+            post::set_status(&mut state, p0, bug, post::PostStatus::InProgress, 0),
+            Err("status can't be changed from Resolved to InProgress".into())
+        );
+
+        // the post isn't archived before the delay passed
+        state.archive_posts(23 * HOUR);
+        assert_eq!(active_feed(&state), vec![feature, bug]);
+
+        state.archive_posts(24 * HOUR);
+        assert_eq!(active_feed(&state), vec![feature]);
+        assert_eq!(state.realms.get(&name).unwrap().archived, vec![bug]);
+
+        // archived posts are still retrievable and searchable
+        assert_eq!(state.posts(pr(0), vec![bug])[0].body, "Bug report");
+        assert!(state
+            .search(p0, "bug".into(), None)
+            .iter()
+            .any(|result| result.result == "post" && result.id == bug));
+        assert_eq!(
+            edit(
+                &mut state,
+                bug,
+                "Edit".into(),
+                vec![],
+                "".into(),
+                Some(name.clone()),
+                p0,
+                0
+            )
+            .await,
+            Err("archived posts can't be edited".into())
+        );
+
+        // reopening brings the post back to the active feed
+        assert_eq!(
+            post::set_status(&mut state, p0, bug, post::PostStatus::Open, 25 * HOUR),
+            Ok(())
+        );
+        assert_eq!(active_feed(&state), vec![feature, bug]);
+        assert!(state.realms.get(&name).unwrap().archived.is_empty());
+    }
+
+    #[test]
+    fn test_release_files() {
+        let mut state = State::default();
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        state.storage.buckets.insert(
+            bucket,
+            storage::BucketStats {
+                offset: 158,
+                live: 150,
+                failed: None,
+                wasm_hash: None,
+            },
+        );
+        let files: BTreeMap<_, _> = vec![
+            (format!("a@{}", bucket), (8, 100)),
+            (format!("b@{}", bucket), (108, 50)),
+        ]
+        .into_iter()
+        .collect();
+        for id in 0..2 {
+            let mut post = Post::new(0, Default::default(), "Test".into(), 0, None, None, None);
+            post.id = state.new_post_id();
+            post.files = files.clone();
+            state.posts.insert(id, post);
+        }
+        state.posts.get_mut(&0).unwrap().cross_posts.push(1);
+        state.posts.get_mut(&1).unwrap().cross_post = Some(0);
+        let live = |state: &State| state.storage.buckets.get(&bucket).unwrap().live;
+
+        // files shared with the cross-post are still live
+        state.release_files(0, &files);
+        assert_eq!(live(&state), 150);
+
+        state.posts.get_mut(&0).unwrap().delete(vec![]);
+        state.release_files(1, &files);
+        assert_eq!(live(&state), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_cross_post_rewards() {
+        let mut state = State::default();
+        let p0 = pr(0);
+        let u0 = create_user(&mut state, p0);
+        let (u1, u2) = (
+            create_user(&mut state, pr(1)),
+            create_user(&mut state, pr(2)),
+        );
+        state
+            .users
+            .get_mut(&u0)
+            .unwrap()
+            .change_cycles(2000, "")
+            .unwrap();
+        for name in ["A", "B"] {
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    name.to_string(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![u0]
+                ),
+                Ok(())
+            );
+        }
+        state.users.get_mut(&u0).unwrap().realms.push("A".into());
+        let id = add(
+            &mut state,
+            "Hello world".to_string(),
+            vec![],
+            p0,
+            0,
+            None,
+            Some("A".into()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            post::cross_post(&mut state, p0, id, "B".into(), 0),
+            Err("not a member of the realm B".into())
+        );
+        state.users.get_mut(&u0).unwrap().realms.push("B".into());
+        assert_eq!(
+            post::cross_post(&mut state, pr(1), id, "B".into(), 0),
+            Err("not a member of the realm B".into())
+        );
+        let reference = post::cross_post(&mut state, p0, id, "B".into(), 0).unwrap();
+        assert_eq!(
+            post::cross_post(&mut state, p0, id, "B".into(), 0),
+            Err("post is already in this realm".into())
+        );
+        assert_eq!(state.realms.get("B").unwrap().posts, vec![reference]);
+
+        let karma = |state: &State| state.users.get(&u0).unwrap().karma_to_reward();
+        let initial_karma = karma(&state);
+
+        // reacting to the post in both realms pays the author once
+        assert_eq!(state.react(pr(1), id, 10, 0), Ok(()));
+        assert_eq!(karma(&state), initial_karma + 1);
+        assert_eq!(state.react(pr(1), reference, 10, 0), Ok(()));
+        assert_eq!(karma(&state), initial_karma + 1);
+        assert_eq!(
+            state.react(pr(1), reference, 10, 0),
+            Err("double reactions are forbidden".into())
+        );
+
+        // the same holds if the first reaction is made on the reference
+        assert_eq!(state.react(pr(2), reference, 10, 0), Ok(()));
+        assert_eq!(karma(&state), initial_karma + 2);
+        assert_eq!(state.react(pr(2), id, 10, 0), Ok(()));
+        assert_eq!(karma(&state), initial_karma + 2);
+
+        // reaction counts are kept per realm
+        for post_id in [id, reference] {
+            assert_eq!(
+                state.posts.get(&post_id).unwrap().reactions.get(&10),
+                Some(&vec![u1, u2].into_iter().collect())
+            );
+        }
+        assert_eq!(crate::audit::reaction_karma(&state, u0), 2);
+        let user = state.users.get(&u0).unwrap();
+        assert_eq!(user.total_karma(), user.other_karma.unwrap() + 2);
     }
 }
@@ -1,15 +1,133 @@
 use super::user::UserId;
+use super::webhooks::EventKind;
 use super::*;
-use crate::reports::Report;
+use crate::reports::{RealmReport, Report};
 use serde::{Deserialize, Serialize};
 
 pub type PostId = u64;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PollMode {
+    #[default]
+    Single,
+    // up to the given number of options
+    MultiSelect(u16),
+    // options ranked by preference, tallied by instant-runoff
+    RankedChoice,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Poll {
     options: Vec<String>,
+    // for ranked-choice polls, only the first preferences are counted here
     votes: BTreeMap<u16, BTreeSet<UserId>>,
     deadline: u64,
+    #[serde(default)]
+    mode: PollMode,
+    // ranked options of each voter in ranked-choice polls
+    #[serde(default)]
+    ballots: BTreeMap<UserId, Vec<u16>>,
+    // votes are accepted strictly before this time
+    #[serde(default)]
+    closes_at: Option<u64>,
+    #[serde(default)]
+    closed: bool,
+}
+
+impl Poll {
+    // Polls created before the closing time was stored close relative to the post timestamp.
+    fn closes_at(&self, post_timestamp: u64) -> u64 {
+        self.closes_at
+            .unwrap_or(post_timestamp + HOUR * self.deadline)
+    }
+
+    fn valid(&self) -> Result<(), String> {
+        match self.mode {
+            PollMode::MultiSelect(n) if n == 0 || n as usize > self.options.len() => {
+                Err("invalid number of selectable options".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Replaces the previous vote of the user, if any.
+    fn vote(&mut self, user_id: UserId, vote: Vec<u16>) -> Result<(), String> {
+        let max_choices = match self.mode {
+            PollMode::Single => 1,
+            PollMode::MultiSelect(n) => n as usize,
+            PollMode::RankedChoice => self.options.len(),
+        };
+        let choices = vote.iter().collect::<BTreeSet<_>>();
+        if vote.is_empty()
+            || vote.len() > max_choices
+            || choices.len() < vote.len()
+            || choices.iter().any(|id| **id as usize >= self.options.len())
+        {
+            return Err("invalid vote".into());
+        }
+        for voters in self.votes.values_mut() {
+            voters.remove(&user_id);
+        }
+        self.votes.retain(|_, voters| !voters.is_empty());
+        let counted = if self.mode == PollMode::RankedChoice {
+            let first = vote[0];
+            self.ballots.insert(user_id, vote);
+            vec![first]
+        } else {
+            vote
+        };
+        for id in counted {
+            self.votes.entry(id).or_default().insert(user_id);
+        }
+        Ok(())
+    }
+
+    // Computes the instant-runoff rounds of a ranked-choice poll: each round maps the remaining
+    // options to their counts. Ballots count for their highest-ranked remaining option, and all
+    // options tied for the fewest votes are eliminated together.
+    pub fn runoff_rounds(&self) -> Vec<BTreeMap<u16, usize>> {
+        let mut eliminated = BTreeSet::new();
+        let mut rounds = Vec::new();
+        loop {
+            let mut counts: BTreeMap<u16, usize> = (0..self.options.len() as u16)
+                .filter(|id| !eliminated.contains(id))
+                .map(|id| (id, 0))
+                .collect();
+            for ballot in self.ballots.values() {
+                if let Some(count) = ballot
+                    .iter()
+                    .find(|id| !eliminated.contains(*id))
+                    .and_then(|id| counts.get_mut(id))
+                {
+                    *count += 1;
+                }
+            }
+            let total: usize = counts.values().sum();
+            let max = counts.values().max().copied().unwrap_or_default();
+            let min = counts.values().min().copied().unwrap_or_default();
+            rounds.push(counts.clone());
+            // stop on a majority or if all remaining options are tied
+            if total == 0 || max * 2 > total || min == max {
+                return rounds;
+            }
+            eliminated.extend(
+                counts
+                    .into_iter()
+                    .filter(|(_, count)| *count == min)
+                    .map(|(id, _)| id),
+            );
+        }
+    }
+
+    // Returns the option with a majority in the last runoff round, if any.
+    pub fn runoff_winner(&self) -> Option<u16> {
+        let last_round = self.runoff_rounds().pop()?;
+        let total: usize = last_round.values().sum();
+        last_round
+            .into_iter()
+            .find(|(_, count)| count * 2 > total)
+            .map(|(id, _)| id)
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -18,6 +136,36 @@ pub enum Extension {
     Proposal(u32),
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PostStatus {
+    #[default]
+    Open,
+    InProgress,
+    Resolved,
+    Closed,
+}
+
+// Order of the comments returned with a post tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum CommentSort {
+    Newest,
+    #[default]
+    Oldest,
+    // by the reaction score
+    Top,
+}
+
+impl PostStatus {
+    pub fn terminal(&self) -> bool {
+        matches!(self, PostStatus::Resolved | PostStatus::Closed)
+    }
+
+    // Posts in a terminal status can only be reopened.
+    fn can_change_to(&self, status: PostStatus) -> bool {
+        *self != status && (!self.terminal() || status == PostStatus::Open)
+    }
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Post {
     pub id: PostId,
@@ -35,10 +183,50 @@ pub struct Post {
     pub tree_update: u64,
     pub report: Option<Report>,
     pub tips: Vec<(UserId, Cycles)>,
+    #[serde(default)]
+    pub token_tips: Vec<(UserId, crate::token::Token)>,
+    #[serde(default)]
+    pub ckbtc_tips: Vec<(UserId, u64)>,
+    #[serde(default)]
+    pub bounty: Option<super::bounties::Bounty>,
     pub extension: Option<Extension>,
     pub realm: Option<String>,
     #[serde(default)]
     pub hashes: Vec<String>,
+    #[serde(default)]
+    pub status: PostStatus,
+    #[serde(default)]
+    pub status_timestamp: u64,
+    #[serde(default)]
+    pub archived: bool,
+    // stable memory coordinates of the body moved to the cold storage
+    #[serde(default)]
+    pub cold_body: Option<(u64, u64)>,
+    // the canonical post, if this post is a cross-post reference
+    #[serde(default)]
+    pub cross_post: Option<PostId>,
+    #[serde(default)]
+    pub cross_posts: Vec<PostId>,
+    // rewarded reactions made on the cross-post references
+    #[serde(default)]
+    pub cross_post_reactions: BTreeMap<u16, BTreeSet<UserId>>,
+    // previous bodies with the timestamps of their replacement, oldest first
+    #[serde(default)]
+    pub edits: Vec<(u64, String)>,
+    #[serde(default)]
+    pub realm_report: Option<RealmReport>,
+    // the post this post quotes with a commentary
+    #[serde(default)]
+    pub quoted: Option<PostId>,
+    // the time of a soft deletion and the versions to be hashed once the grace period ends
+    #[serde(default)]
+    pub deletion: Option<(u64, Vec<String>)>,
+    // users mentioned in any version of the post, which get notified only once
+    #[serde(default)]
+    pub mentioned: BTreeSet<UserId>,
+    // ownership transfers as (timestamp, previous owner, new owner)
+    #[serde(default)]
+    pub transfers: Vec<(u64, UserId, UserId)>,
 }
 
 impl Storable for Post {
@@ -62,7 +250,10 @@ impl Post {
     ) -> Self {
         // initialize all extensions properly
         if let Some(Extension::Poll(poll)) = &mut extension {
-            poll.votes.clear()
+            poll.votes.clear();
+            poll.ballots.clear();
+            poll.closes_at = Some(timestamp + HOUR * poll.deadline);
+            poll.closed = false;
         };
 
         Self {
@@ -78,21 +269,90 @@ impl Post {
             patches: Default::default(),
             files: Default::default(),
             tips: Default::default(),
+            token_tips: Default::default(),
+            ckbtc_tips: Default::default(),
+            bounty: None,
             hashes: Default::default(),
             tree_size: 0,
             tree_update: timestamp,
             report: None,
             extension,
             realm,
+            status: Default::default(),
+            status_timestamp: timestamp,
+            archived: false,
+            cold_body: None,
+            edits: Default::default(),
+            realm_report: None,
+            quoted: None,
+            cross_post: None,
+            cross_posts: Default::default(),
+            cross_post_reactions: Default::default(),
+            deletion: None,
+            mentioned: Default::default(),
+            transfers: Default::default(),
         }
     }
 
+    // Checks if the reaction of the user was already rewarded on another appearance
+    // of the same canonical post.
+    pub fn reaction_rewarded(&self, canonical: &Post, reaction: u16, user_id: UserId) -> bool {
+        let contains = |reactions: &BTreeMap<u16, BTreeSet<UserId>>| {
+            reactions
+                .get(&reaction)
+                .map(|users| users.contains(&user_id))
+                .unwrap_or_default()
+        };
+        contains(&canonical.cross_post_reactions)
+            || self.cross_post.is_some() && contains(&canonical.reactions)
+    }
+
+    // Sum of the reactions weighted by their costs; downvotes count negatively.
+    pub fn score(&self) -> i64 {
+        self.reactions
+            .iter()
+            .map(|(id, users)| {
+                CONFIG
+                    .reactions
+                    .iter()
+                    .find(|(reaction, _)| reaction == id)
+                    .map(|(_, cost)| *cost)
+                    .unwrap_or_default()
+                    * users.len() as i64
+            })
+            .sum()
+    }
+
+    // Soft-deleted posts count as deleted until they are restored.
+    pub fn deleted(&self) -> bool {
+        !self.hashes.is_empty() || self.deletion.is_some()
+    }
+
+    // Returns the body, reading it from the cold storage if needed.
+    pub fn text(&self) -> String {
+        match self.cold_body {
+            Some((offset, len)) => memory::Memory::read(offset, len)
+                .unwrap_or_else(|err| panic!("couldn't read the post body: {}", err)),
+            None => self.body.clone(),
+        }
+    }
+
+    // Returns a copy of the post with the body restored from the cold storage.
+    pub fn restored(&self) -> Post {
+        let mut post = self.clone();
+        if self.cold_body.is_some() {
+            post.body = self.text();
+            post.cold_body = None;
+        }
+        post
+    }
+
     pub fn vote_on_poll(
         &mut self,
         user_id: UserId,
         user_realms: Vec<String>,
         time: u64,
-        vote: u16,
+        vote: Vec<u16>,
     ) -> Result<(), String> {
         if let Some(realm) = self.realm.as_ref() {
             if !user_realms.contains(realm) {
@@ -100,26 +360,51 @@ impl Post {
             }
         }
         if let Some(Extension::Poll(poll)) = self.extension.as_mut() {
-            // no multiple choice
-            if poll.votes.values().flatten().any(|id| id == &user_id) {
-                return Err("double vote".to_string());
-            }
-            if time < self.timestamp + HOUR * poll.deadline && poll.options.len() as u16 > vote {
-                poll.votes.entry(vote).or_default().insert(user_id);
+            if poll.closed || time >= poll.closes_at(self.timestamp) {
+                return Err("poll is closed".into());
             }
+            poll.vote(user_id, vote)?;
         }
         Ok(())
     }
 
-    pub fn valid(&self, blobs: &[(String, Blob)]) -> Result<(), String> {
-        if self.body.is_empty() || self.body.chars().count() > CONFIG.max_post_length {
+    // Checks the post against the limits of the realm, if one is given, or the global ones.
+    pub fn valid(&self, blobs: &[(String, Blob)], realm: Option<&Realm>) -> Result<(), String> {
+        let max_length = realm
+            .and_then(|realm| realm.max_post_length)
+            .unwrap_or(CONFIG.max_post_length);
+        let max_attachments = realm
+            .and_then(|realm| realm.max_post_attachments)
+            .unwrap_or(CONFIG.max_post_attachments);
+        if self.body.is_empty() || self.body.chars().count() > max_length {
             return Err("invalid post content".into());
         }
+        if blobs.len() > max_attachments {
+            return Err(format!(
+                "at most {} attachments are allowed",
+                max_attachments
+            ));
+        }
         if !blobs.iter().all(|(key, blob)| {
             key.len() <= 8 && blob.len() > 0 && blob.len() <= CONFIG.max_blob_size_bytes
         }) {
             return Err("invalid blobs".into());
         }
+        if let Some(Extension::Poll(poll)) = &self.extension {
+            poll.valid()?;
+        }
+        if realm
+            .map(|realm| {
+                filters::blocked(
+                    &realm.blocked_patterns,
+                    realm.blocklist.as_ref(),
+                    &self.body,
+                )
+            })
+            .unwrap_or_default()
+        {
+            return Err("the post contains content blocked in this realm".into());
+        }
         Ok(())
     }
 
@@ -128,30 +413,30 @@ impl Post {
         state: &mut State,
         blobs: Vec<(String, Blob)>,
     ) -> Result<(), String> {
-        for (id, blob) in blobs.into_iter() {
-            // only if the id is new, add it.
-            if self.files.keys().any(|file_id| file_id.contains(&id)) {
-                continue;
-            }
-            match state
-                .storage
-                .write_to_bucket(&mut state.logger, blob.as_slice())
-                .await
-                .clone()
-            {
-                Ok((bucket_id, offset)) => {
+        // only if the id is new, add it.
+        let blobs = blobs
+            .into_iter()
+            .filter(|(id, _)| !self.files.keys().any(|file_id| file_id.contains(id)))
+            .collect::<Vec<_>>();
+        let slices = blobs
+            .iter()
+            .map(|(_, blob)| blob.as_slice())
+            .collect::<Vec<_>>();
+        match state.storage.write_many(&mut state.logger, &slices).await {
+            Ok(locations) => {
+                for ((id, _), (bucket_id, offset, len)) in blobs.iter().zip(locations) {
                     self.files
-                        .insert(format!("{}@{}", id, bucket_id), (offset, blob.len()));
-                }
-                Err(err) => {
-                    state
-                        .logger
-                        .error(format!("Couldn't write a blob to bucket: {:?}", err));
-                    return Err(err);
+                        .insert(format!("{}@{}", id, bucket_id), (offset, len));
                 }
-            };
+                Ok(())
+            }
+            Err(err) => {
+                state
+                    .logger
+                    .error(format!("Couldn't write a blob to bucket: {:?}", err));
+                Err(err)
+            }
         }
-        Ok(())
     }
 
     pub fn vote_on_report(&mut self, stalwarts: usize, stalwart: UserId, confirmed: bool) {
@@ -168,10 +453,37 @@ impl Post {
         }
     }
 
+    pub fn creation_timestamp(&self) -> u64 {
+        // the first patch carries the creation timestamp
+        self.patches
+            .first()
+            .map(|(time, _)| *time)
+            .unwrap_or(self.timestamp)
+    }
+
+    // Records the replaced body unless the last recorded revision is still within the grace
+    // period. Must be called before the patch of the edit is stored.
+    pub fn record_edit(&mut self, previous_body: String, timestamp: u64) {
+        let last_revision = match (self.edits.last(), self.patches.first()) {
+            (Some((time, _)), _) => *time,
+            // the first patch carries the creation timestamp
+            (None, Some((time, _))) => *time,
+            (None, None) => self.timestamp,
+        };
+        if timestamp.saturating_sub(last_revision) < CONFIG.post_edit_grace_period {
+            return;
+        }
+        self.edits.push((timestamp, previous_body));
+        let excess = self.edits.len().saturating_sub(CONFIG.max_post_edits);
+        self.edits.drain(..excess);
+    }
+
     pub fn delete(&mut self, versions: Vec<String>) {
+        self.deletion = None;
         self.files.clear();
         self.body.clear();
         self.patches.clear();
+        self.edits.clear();
         self.hashes = versions
             .into_iter()
             .map(|value| {
@@ -265,18 +577,29 @@ pub async fn edit(
         // unauthorized
         return Err("unauthorized".into());
     }
+    if post.archived {
+        return Err("archived posts can't be edited".into());
+    }
     if let Some(false) = picked_realm.as_ref().map(|name| user.realms.contains(name)) {
         // user didn't join this realm
         return Err("you're not in the realm".into());
     }
     let user_id = user.id;
     post.tags = tags(CONFIG.max_tag_length, &body);
-    post.body = body;
-    post.valid(&blobs)?;
+    // posts created before mentions were tracked don't re-notify about old mentions
+    post.mentioned
+        .extend(mentioned_users(state, user_id, &post.body));
+    let previous_body = std::mem::replace(&mut post.body, body);
+    let realm = picked_realm
+        .as_ref()
+        .or(post.realm.as_ref())
+        .and_then(|name| state.realms.get(name));
+    post.valid(&blobs, realm)?;
     let files_before = post.files.len();
     post.save_blobs(state, blobs).await?;
     let costs = post.costs(post.files.len().saturating_sub(files_before));
     state.charge(user_id, costs, format!("editing of post {}", id))?;
+    post.record_edit(previous_body, timestamp);
     post.patches.push((post.timestamp, patch));
     post.timestamp = timestamp;
 
@@ -300,13 +623,267 @@ pub async fn edit(
         }
     }
 
+    if let Some(previous) = state.posts.get(&id) {
+        state.search_index.remove(id, &previous.body);
+    }
+    discovery::unindex(state, id);
+    state.search_index.add(id, &post.body);
     state
         .posts
         .insert(id, post)
         .expect("previous post should exists");
+    discovery::index(state, id);
+    notify_mentions(state, id, &vec![user_id].into_iter().collect());
+    Ok(())
+}
+
+pub fn set_status(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    status: PostStatus,
+    time: u64,
+) -> Result<(), String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let post = state.posts.get(&post_id).ok_or("no post found")?;
+    let realm = post.realm.as_ref().and_then(|name| state.realms.get(name));
+    if post.user != user_id
+        && !realm
+            .map(|realm| realm.controllers.contains(&user_id))
+            .unwrap_or_default()
+    {
+        return Err("not authorized".into());
+    }
+    if !post.status.can_change_to(status) {
+        return Err(format!(
+            "status can't be changed from {:?} to {:?}",
+            post.status, status
+        ));
+    }
+    if post.archived {
+        state.unarchive_post(post_id)?;
+    }
+    let post = state.posts.get_mut(&post_id).expect("no post found");
+    post.status = status;
+    post.status_timestamp = time;
     Ok(())
 }
 
+// Reassigns a realm post to another member of the realm; only realm controllers can transfer
+// posts. Rewards of past reactions stay with the previous owner.
+pub fn transfer(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    new_owner: UserId,
+    now: u64,
+) -> Result<(), String> {
+    let controller = state.principal_to_user(principal).ok_or("no user found")?;
+    let (controller_id, controller_name) = (controller.id, controller.name.clone());
+    let post = state.posts.get(&post_id).ok_or("no post found")?;
+    if post.deleted() {
+        return Err("no post found".into());
+    }
+    let realm_name = post
+        .realm
+        .clone()
+        .ok_or("only realm posts can be transferred")?;
+    let realm = state.realms.get(&realm_name).ok_or("no realm found")?;
+    if !realm.controllers.contains(&controller_id) {
+        return Err("not authorized".into());
+    }
+    if post.cross_post.is_some() || !post.cross_posts.is_empty() {
+        return Err("cross-posted posts can't be transferred".into());
+    }
+    // the escrowed tokens of open bounties belong to the post owner
+    if post
+        .bounty
+        .as_ref()
+        .map(|bounty| bounty.answer.is_none() && !bounty.refunded)
+        .unwrap_or_default()
+    {
+        return Err("posts with open bounties can't be transferred".into());
+    }
+    if !realm.members.contains(&new_owner) {
+        return Err("the new owner is not a member of the realm".into());
+    }
+    let previous_owner = post.user;
+    if previous_owner == new_owner {
+        return Err("the user owns the post already".into());
+    }
+    let new_owner_name = state
+        .users
+        .get(&new_owner)
+        .ok_or("no user found")?
+        .name
+        .clone();
+    // the reaction karma follows the post for audits, the karma itself stays and becomes the
+    // previous owner's other karma
+    let reaction_karma = audit::post_reaction_karma(post);
+    let post = state.posts.get_mut(&post_id).expect("no post found");
+    post.user = new_owner;
+    post.transfers.push((now, previous_owner, new_owner));
+
+    if let Some(user) = state.users.get_mut(&previous_owner) {
+        user.posts.retain(|id| id != &post_id);
+        if let Some(other_karma) = user.other_karma.as_mut() {
+            *other_karma += reaction_karma;
+        }
+        user.notify_about_post(
+            format!(
+                "@{} transferred your post to @{}",
+                controller_name, new_owner_name
+            ),
+            post_id,
+        );
+    }
+    let user = state.users.get_mut(&new_owner).expect("no user found");
+    let index = user.posts.binary_search(&post_id).unwrap_or_else(|i| i);
+    user.posts.insert(index, post_id);
+    if let Some(other_karma) = user.other_karma.as_mut() {
+        *other_karma -= reaction_karma;
+    }
+    user.notify_about_post(
+        format!("@{} transferred a post to you", controller_name),
+        post_id,
+    );
+    state.logger.info(format!(
+        "@{} transferred post [{}](#/post/{1}) in realm /{} to @{}",
+        controller_name, post_id, realm_name, new_owner_name
+    ));
+    Ok(())
+}
+
+// Creates a reference to the root post of the caller in another realm.
+pub fn cross_post(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    realm: String,
+    timestamp: u64,
+) -> Result<PostId, String> {
+    let user = state.principal_to_user(principal).ok_or("no user found")?;
+    let user_id = user.id;
+    if !state.realms.contains_key(&realm) {
+        return Err("no realm found".into());
+    }
+    if !user.realms.contains(&realm) {
+        return Err(format!("not a member of the realm {}", realm));
+    }
+    let post = state.posts.get(&post_id).ok_or("no post found")?;
+    if post.user != user_id {
+        return Err("unauthorized".into());
+    }
+    if post.parent.is_some() || post.cross_post.is_some() || post.deleted() {
+        return Err("only root posts can be cross-posted".into());
+    }
+    if post.realm.as_ref() == Some(&realm)
+        || post
+            .cross_posts
+            .iter()
+            .filter_map(|id| state.posts.get(id))
+            .any(|post| post.realm.as_ref() == Some(&realm))
+    {
+        return Err("post is already in this realm".into());
+    }
+    let mut reference = Post::new(
+        user_id,
+        post.tags.clone(),
+        post.text(),
+        timestamp,
+        None,
+        None,
+        Some(realm.clone()),
+    );
+    reference.files = post.files.clone();
+    reference.cross_post = Some(post_id);
+    state.charge(
+        user_id,
+        CONFIG.post_cost,
+        format!("cross-post of post {}", post_id),
+    )?;
+    let id = state.new_post_id();
+    reference.id = id;
+    state
+        .posts
+        .get_mut(&post_id)
+        .expect("no post found")
+        .cross_posts
+        .push(id);
+    state.search_index.add(id, &reference.body);
+    state.posts.insert(id, reference);
+    discovery::index(state, id);
+    state
+        .realms
+        .get_mut(&realm)
+        .expect("no realm found")
+        .posts
+        .push(id);
+    Ok(id)
+}
+
+// A quoted post as rendered inside of the quoting post.
+#[derive(Serialize)]
+pub enum Quote {
+    Post(Box<Post>),
+    // the quoted post was deleted
+    Tombstone(PostId),
+}
+
+pub fn resolve_quote(state: &State, principal: Principal, id: PostId) -> Quote {
+    let user_id = state.principal_to_user(principal).map(|user| user.id);
+    match state.posts.get(&id) {
+        Some(post) if !post.deleted() && state.accessible(user_id, post) => {
+            Quote::Post(Box::new(post.restored()))
+        }
+        _ => Quote::Tombstone(id),
+    }
+}
+
+// Creates a new root post referencing the quoted post.
+pub async fn quote(
+    state: &mut State,
+    body: String,
+    blobs: Vec<(String, Blob)>,
+    principal: Principal,
+    timestamp: u64,
+    quoted: PostId,
+    realm: Option<String>,
+) -> Result<PostId, String> {
+    let user = state.principal_to_user(principal).ok_or("no user found")?;
+    let (user_id, user_name, trusted) = (user.id, user.name.clone(), user.trusted());
+    let quoted_post = state.posts.get(&quoted).ok_or("no post found")?;
+    if quoted_post.deleted() {
+        return Err("deleted posts can't be quoted".into());
+    }
+    let author = quoted_post.user;
+    if state.blocked(author, user_id) {
+        return Err("you were blocked by the author".into());
+    }
+    let id = add(state, body, blobs, principal, timestamp, None, realm, None).await?;
+    state.posts.get_mut(&id).expect("no post found").quoted = Some(quoted);
+    if author != user_id {
+        if trusted {
+            state.spend_to_user_karma(
+                author,
+                CONFIG.quote_reward,
+                format!("quote of post {}", quoted),
+            );
+        }
+        if let Some(user) = state
+            .users
+            .get_mut(&author)
+            .filter(|user| user.notification_settings.mentions)
+        {
+            user.notify_about_post(format!("@{} quoted your post", user_name), id);
+        }
+    }
+    Ok(id)
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn add(
     state: &mut State,
@@ -338,42 +915,75 @@ pub async fn add(
         return Err("Bots can't create comments currently".into());
     }
 
-    if !matches!(extension, Some(Extension::Proposal(_))) {
-        let limit = if user.is_bot() {
-            1
-        } else if parent.is_none() {
-            CONFIG.max_posts_per_hour
-        } else {
-            CONFIG.max_comments_per_hour
-        } as usize;
-        if user
-            .posts
-            .iter()
-            .rev()
-            .filter_map(|id| state.posts.get(id))
-            .filter(|post| {
-                !(parent.is_none() ^ post.parent.is_none())
-                    && post.timestamp > timestamp.saturating_sub(HOUR)
-            })
-            .count()
-            >= limit
-        {
-            return Err(format!(
-                "not more than {} {} per hour are allowed",
-                limit,
-                if parent.is_none() {
-                    "posts"
-                } else {
-                    "comments"
-                }
-            ));
+    if let Some(parent_post) = parent.and_then(|id| state.posts.get(&id)) {
+        if state.blocked(parent_post.user, user.id) {
+            return Err("you were blocked by the author".into());
         }
+        if !crate::proposals::discussion_open(state, parent_post.id) {
+            return Err("the proposal discussion is closed".into());
+        }
+        if parent_post.deletion.is_some() {
+            return Err("deleted posts can't be commented".into());
+        }
+    }
+    if let Some(handle) = user_handles(CONFIG.max_tag_length, &body)
+        .into_iter()
+        .find(|handle| {
+            state
+                .user(handle)
+                .map(|mentioned| state.blocked(mentioned.id, user.id))
+                .unwrap_or_default()
+        })
+    {
+        return Err(format!("you can't mention @{}", handle));
     }
 
     let realm = match parent.and_then(|id| state.posts.get(&id)) {
         None => picked_realm.or_else(|| user.current_realm.clone()),
         Some(post) => post.realm.clone(),
     };
+
+    let mut rate_limit = None;
+    if !matches!(extension, Some(Extension::Proposal(_))) {
+        // realm tiers can grant higher rate limits
+        let (extra_posts, extra_comments) = realm
+            .as_ref()
+            .and_then(|name| state.realms.get(name))
+            .and_then(|realm| realm.tier(user.id))
+            .map(|tier| (tier.extra_posts_per_hour, tier.extra_comments_per_hour))
+            .unwrap_or_default();
+        let (limit, current) = if parent.is_none() {
+            (
+                CONFIG.max_posts_per_window.saturating_add(extra_posts),
+                user.post_limit,
+            )
+        } else {
+            (
+                CONFIG
+                    .max_comments_per_window
+                    .saturating_add(extra_comments),
+                user.comment_limit,
+            )
+        };
+        let limit = if user.is_bot() { 1 } else { limit as u64 };
+        match current.check(limit, CONFIG.rate_limit_window, timestamp) {
+            Ok(next) => rate_limit = Some(next),
+            Err(wait) => {
+                return Err(format!(
+                    "not more than {} {} per {} minutes are allowed, retry in {} seconds",
+                    limit,
+                    if parent.is_none() {
+                        "posts"
+                    } else {
+                        "comments"
+                    },
+                    CONFIG.rate_limit_window / MINUTE,
+                    wait.div_ceil(SECOND)
+                ))
+            }
+        }
+    }
+
     if let Some(name) = &realm {
         if !user.realms.contains(name) {
             return Err(format!("not a member of the realm {}", name));
@@ -390,17 +1000,36 @@ pub async fn add(
         realm.clone(),
     );
     let costs = post.costs(blobs.len());
-    post.valid(&blobs)?;
+    post.valid(
+        &blobs,
+        realm.as_ref().and_then(|name| state.realms.get(name)),
+    )?;
     let trusted_user = user.trusted();
     state.charge(user_id, costs, "new post".to_string())?;
     post.save_blobs(state, blobs).await?;
     let id = state.new_post_id();
     let user = state.users.get_mut(&user_id).expect("no user found");
     user.posts.push(id);
+    let first_post = user.posts.len() == 1;
+    user.record_post(timestamp);
+    if let Some(rate_limit) = rate_limit {
+        if parent.is_none() {
+            user.post_limit = rate_limit;
+        } else {
+            user.comment_limit = rate_limit;
+        }
+    }
+    // existing users don't get onboarding rewards for later posts
+    if first_post {
+        state.complete_onboarding_step(user_id, OnboardingStep::FirstPost);
+    }
     post.id = id;
     if let Some(realm) = realm.and_then(|name| state.realms.get_mut(&name)) {
         realm.posts.push(id);
     }
+    if let Some(Extension::Poll(poll)) = &post.extension {
+        state.open_polls.insert((poll.closes_at(timestamp), id));
+    }
     if let Some(parent_post) = post
         .parent
         .and_then(|parent_id| state.posts.get_mut(&parent_id))
@@ -410,11 +1039,16 @@ pub async fn add(
         let parent_post_author = parent_post.user;
         if parent_post.user != user_id && trusted_user {
             let log = format!("response to post {}", parent_post.id);
+            let reward = state.realm_reward(post.realm.as_ref(), CONFIG.response_reward);
             // Reward user for spawning activity with his post.
-            state.spend_to_user_karma(parent_post_author, CONFIG.response_reward, log)
+            state.spend_to_user_karma(parent_post_author, reward, log)
         }
     }
+    state.search_index.add(id, &post.body);
     state.posts.insert(post.id, post.clone());
+    discovery::index(state, id);
+    crate::coordination::check(state, id);
+    crate::spam::check(state, id);
     notify_about(state, &post);
 
     state
@@ -431,6 +1065,302 @@ pub async fn add(
     Ok(id)
 }
 
+// A post composed in advance, which gets created at the publication time, so that charges,
+// rewards and notifications happen only then.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduledPost {
+    pub id: u64,
+    pub user: UserId,
+    pub body: String,
+    pub blobs: Vec<(String, Blob)>,
+    pub parent: Option<PostId>,
+    pub realm: Option<String>,
+    pub extension: Option<Extension>,
+}
+
+fn validate_schedule(
+    state: &State,
+    user_id: UserId,
+    scheduled_post: &ScheduledPost,
+    timestamp: u64,
+    scheduled_at: u64,
+) -> Result<(), String> {
+    if scheduled_at <= timestamp || scheduled_at > timestamp + CONFIG.max_schedule_ahead {
+        return Err(format!(
+            "the publication time must be within the next {} weeks",
+            CONFIG.max_schedule_ahead / WEEK
+        ));
+    }
+    let user = state.users.get(&user_id).ok_or("no user found")?;
+    if let Some(name) = &scheduled_post.realm {
+        if !user.realms.contains(name) {
+            return Err(format!("not a member of the realm {}", name));
+        }
+    }
+    let post = Post::new(
+        user_id,
+        Default::default(),
+        scheduled_post.body.clone(),
+        timestamp,
+        scheduled_post.parent,
+        None,
+        None,
+    );
+    post.valid(
+        &scheduled_post.blobs,
+        scheduled_post
+            .realm
+            .as_ref()
+            .and_then(|name| state.realms.get(name)),
+    )
+}
+
+// Stores the post until the publication time and returns its schedule id.
+#[allow(clippy::too_many_arguments)]
+pub fn schedule(
+    state: &mut State,
+    body: String,
+    blobs: Vec<(String, Blob)>,
+    principal: Principal,
+    timestamp: u64,
+    scheduled_at: u64,
+    parent: Option<PostId>,
+    realm: Option<String>,
+    extension: Option<Extension>,
+) -> Result<u64, String> {
+    let user = state.principal_to_user(principal).ok_or("no user found")?;
+    let user_id = user.id;
+    if state
+        .scheduled_posts
+        .values()
+        .filter(|post| post.user == user_id)
+        .count()
+        >= CONFIG.max_scheduled_posts
+    {
+        return Err(format!(
+            "not more than {} scheduled posts are allowed",
+            CONFIG.max_scheduled_posts
+        ));
+    }
+    let realm = match parent.and_then(|id| state.posts.get(&id)) {
+        None => realm.or_else(|| user.current_realm.clone()),
+        Some(post) => post.realm.clone(),
+    };
+    let id = state.next_scheduled_post_id;
+    let scheduled_post = ScheduledPost {
+        id,
+        user: user_id,
+        body,
+        blobs,
+        parent,
+        realm,
+        extension,
+    };
+    validate_schedule(state, user_id, &scheduled_post, timestamp, scheduled_at)?;
+    state.next_scheduled_post_id += 1;
+    state
+        .scheduled_posts
+        .insert((scheduled_at, id), scheduled_post);
+    Ok(id)
+}
+
+fn scheduled_post_key(state: &State, principal: Principal, id: u64) -> Result<(u64, u64), String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let (key, post) = state
+        .scheduled_posts
+        .iter()
+        .find(|((_, post_id), _)| *post_id == id)
+        .ok_or("no scheduled post found")?;
+    if post.user != user_id {
+        return Err("unauthorized".into());
+    }
+    Ok(*key)
+}
+
+pub fn edit_scheduled(
+    state: &mut State,
+    principal: Principal,
+    id: u64,
+    body: String,
+    blobs: Vec<(String, Blob)>,
+    timestamp: u64,
+    scheduled_at: u64,
+) -> Result<(), String> {
+    let key = scheduled_post_key(state, principal, id)?;
+    let mut scheduled_post = state
+        .scheduled_posts
+        .get(&key)
+        .expect("no post found")
+        .clone();
+    scheduled_post.body = body;
+    scheduled_post.blobs = blobs;
+    validate_schedule(
+        state,
+        scheduled_post.user,
+        &scheduled_post,
+        timestamp,
+        scheduled_at,
+    )?;
+    state.scheduled_posts.remove(&key);
+    state
+        .scheduled_posts
+        .insert((scheduled_at, id), scheduled_post);
+    Ok(())
+}
+
+pub fn cancel_scheduled(state: &mut State, principal: Principal, id: u64) -> Result<(), String> {
+    let key = scheduled_post_key(state, principal, id)?;
+    state.scheduled_posts.remove(&key);
+    Ok(())
+}
+
+// Creates all posts with an elapsed publication time.
+pub async fn publish_scheduled(state: &mut State, now: u64) {
+    let due = state
+        .scheduled_posts
+        .range(..(now + 1, 0))
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    for key in due {
+        let scheduled_post = state.scheduled_posts.remove(&key).expect("no post found");
+        let principal = match state.users.get(&scheduled_post.user) {
+            Some(user) => user.principal,
+            None => continue,
+        };
+        let result = add(
+            state,
+            scheduled_post.body,
+            scheduled_post.blobs,
+            principal,
+            now,
+            scheduled_post.parent,
+            scheduled_post.realm,
+            scheduled_post.extension,
+        )
+        .await;
+        let user = match state.users.get_mut(&scheduled_post.user) {
+            Some(user) => user,
+            None => continue,
+        };
+        match result {
+            Ok(post_id) => user.notify_about_post("Your scheduled post was published", post_id),
+            Err(err) => user.notify(format!(
+                "Your scheduled post couldn't be published: {}",
+                err
+            )),
+        }
+    }
+}
+
+// Publishes the given draft; the draft is removed only if the post was created.
+pub async fn publish_draft(
+    state: &mut State,
+    principal: Principal,
+    draft_id: u64,
+    timestamp: u64,
+) -> Result<PostId, String> {
+    let draft = state
+        .principal_to_user_mut(principal)
+        .ok_or("no user found")?
+        .delete_draft(draft_id)?;
+    let result = add(
+        state,
+        draft.body.clone(),
+        vec![],
+        principal,
+        timestamp,
+        None,
+        draft.realm.clone(),
+        None,
+    )
+    .await;
+    if result.is_err() {
+        if let Some(user) = state.principal_to_user_mut(principal) {
+            user.drafts.push(draft);
+            user.drafts.sort_by_key(|draft| draft.id);
+        }
+    }
+    result
+}
+
+// Marks all polls past their closing time as closed and notifies their authors.
+pub fn close_polls(state: &mut State, now: u64) {
+    let expired = state
+        .open_polls
+        .range(..=(now, PostId::MAX))
+        .copied()
+        .collect::<Vec<_>>();
+    for key in expired {
+        state.open_polls.remove(&key);
+        let post = match state.posts.get_mut(&key.1) {
+            Some(post) => post,
+            None => continue,
+        };
+        if let Some(Extension::Poll(poll)) = post.extension.as_mut() {
+            poll.closed = true;
+            let (author, post_id) = (post.user, post.id);
+            if let Some(user) = state.users.get_mut(&author) {
+                user.notify_about_post("Your poll has closed", post_id);
+            }
+        }
+    }
+}
+
+pub fn mentioned_users(state: &State, author_id: UserId, body: &str) -> BTreeSet<UserId> {
+    user_handles(CONFIG.max_tag_length, body)
+        .into_iter()
+        .filter_map(|handle| state.user(&handle).map(|user| user.id))
+        .filter(|id| *id != author_id)
+        .collect()
+}
+
+// Notifies users mentioned in the post unless they were mentioned in an earlier version of it
+// or are in the skipped set. Returns the notified users.
+fn notify_mentions(state: &mut State, post_id: PostId, skipped: &HashSet<UserId>) -> Vec<UserId> {
+    let post = match state.posts.get(&post_id) {
+        Some(post) => post,
+        None => return Default::default(),
+    };
+    let (author_id, timestamp) = (post.user, post.timestamp);
+    let mentioned = mentioned_users(state, author_id, &post.body)
+        .into_iter()
+        .filter(|id| !post.mentioned.contains(id))
+        .collect::<Vec<_>>();
+    let post = state.posts.get_mut(&post_id).expect("no post found");
+    post.mentioned.extend(mentioned.iter().copied());
+    let author_name = match state.users.get(&author_id) {
+        Some(user) => user.name.clone(),
+        None => return Default::default(),
+    };
+    let mut notified = Vec::new();
+    for mentioned_user_id in mentioned {
+        if skipped.contains(&mentioned_user_id) || state.ignores(Some(mentioned_user_id), author_id)
+        {
+            continue;
+        }
+        let user = state
+            .users
+            .get_mut(&mentioned_user_id)
+            .expect("no user found");
+        if user.notification_settings.mentions {
+            user.notify_about_post(format!("@{} mentioned you in a post", author_name), post_id);
+        }
+        notified.push(mentioned_user_id);
+        webhooks::emit(
+            state,
+            mentioned_user_id,
+            EventKind::Mention,
+            author_id,
+            post_id,
+            timestamp,
+        );
+    }
+    notified
+}
+
 fn notify_about(state: &mut State, post: &Post) {
     let post_user_name = state
         .users
@@ -446,34 +1376,28 @@ fn notify_about(state: &mut State, post: &Post) {
         .and_then(|parent_id| state.posts.get(&parent_id))
     {
         let parent_author = parent.user;
-        if parent_author != post.user {
+        if parent_author != post.user && !state.ignores(Some(parent_author), post.user) {
             if let Some(user) = state.users.get_mut(&parent_author) {
-                user.notify_about_post(
-                    format!("@{} replied to your post", post_user_name,),
+                if user.notification_settings.replies {
+                    user.notify_about_post(
+                        format!("@{} replied to your post", post_user_name,),
+                        post.id,
+                    );
+                }
+                notified.insert(user.id);
+                webhooks::emit(
+                    state,
+                    parent_author,
+                    EventKind::Reply,
+                    post.user,
                     post.id,
+                    post.timestamp,
                 );
-                notified.insert(user.id);
             }
         }
     }
 
-    user_handles(CONFIG.max_tag_length, &post.body)
-        .into_iter()
-        .filter_map(|handle| state.user(&handle).map(|user| user.id))
-        .filter(|id| !notified.contains(id))
-        .collect::<Vec<_>>()
-        .into_iter()
-        .for_each(|mentioned_user_id| {
-            let user = state
-                .users
-                .get_mut(&mentioned_user_id)
-                .expect("no user found");
-            user.notify_about_post(
-                format!("@{} mentioned you in a post", post_user_name),
-                post.id,
-            );
-            notified.insert(user.id);
-        });
+    notified.extend(notify_mentions(state, post.id, &notified));
 
     state
         .thread(post.id)
@@ -487,7 +1411,7 @@ fn notify_about(state: &mut State, post: &Post) {
         .collect::<Vec<_>>()
         .into_iter()
         .for_each(|(post_id, user_id)| {
-            if notified.contains(&user_id) {
+            if notified.contains(&user_id) || state.ignores(Some(user_id), post.user) {
                 return;
             }
             if let Some(user) = state.users.get_mut(&user_id) {
@@ -495,6 +1419,40 @@ fn notify_about(state: &mut State, post: &Post) {
             }
             notified.insert(user_id);
         });
+
+    // voters learn about new top-level comments on the proposal
+    let proposal_id =
+        post.parent
+            .and_then(|id| state.posts.get(&id))
+            .and_then(|parent| match parent.extension {
+                Some(Extension::Proposal(id)) => Some(id),
+                _ => None,
+            });
+    let voters = proposal_id
+        .and_then(|id| state.proposals.get(id as usize))
+        .map(|proposal| {
+            proposal
+                .bulletins
+                .iter()
+                .map(|(user_id, _, _)| *user_id)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    for user_id in voters {
+        if notified.contains(&user_id) || state.ignores(Some(user_id), post.user) {
+            continue;
+        }
+        if let Some(user) = state.users.get_mut(&user_id) {
+            user.notify_about_post(
+                format!(
+                    "@{} commented on the proposal #{} you voted on",
+                    post_user_name,
+                    proposal_id.unwrap_or_default()
+                ),
+                post.id,
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -524,7 +1482,7 @@ mod tests {
     fn test_validity() {
         let mut p = Post::default();
         // empty body
-        assert!(p.valid(Default::default()).is_err());
+        assert!(p.valid(Default::default(), None).is_err());
 
         // too long body
         p.body = String::from_utf8(
@@ -537,11 +1495,11 @@ mod tests {
                 .collect::<Vec<_>>(),
         )
         .unwrap();
-        assert!(p.valid(Default::default()).is_err());
+        assert!(p.valid(Default::default(), None).is_err());
 
         // valid body
         p.body = "Hello world!".to_string();
-        assert!(p.valid(Default::default()).is_ok());
+        assert!(p.valid(Default::default(), None).is_ok());
 
         // too long blob id
         assert!(p
@@ -557,7 +1515,8 @@ mod tests {
                             .collect::<Vec<_>>()
                     )
                 )]
-                .as_slice()
+                .as_slice(),
+                None
             )
             .is_err());
 
@@ -575,13 +1534,561 @@ mod tests {
                             .collect::<Vec<_>>()
                     )
                 )]
-                .as_slice()
+                .as_slice(),
+                None
             )
             .is_ok());
 
         // empty blob
         assert!(p
-            .valid(vec![("abcdefgh".to_string(), Default::default())].as_slice())
+            .valid(
+                vec![("abcdefgh".to_string(), Default::default())].as_slice(),
+                None
+            )
             .is_err());
     }
+
+    #[actix_rt::test]
+    async fn test_scheduled_posts() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        let author = create_user(&mut state, pr(0));
+        let commenter = create_user(&mut state, pr(1));
+        let post_id = add(
+            &mut state,
+            "Hello".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let karma = state.users.get(&author).unwrap().karma_to_reward();
+        let inbox = |state: &State, id| state.users.get(&id).unwrap().inbox.len();
+        let (author_inbox, commenter_inbox) = (inbox(&state, author), inbox(&state, commenter));
+        let schedule_comment = |state: &mut State, body: &str| {
+            schedule(
+                state,
+                body.into(),
+                vec![],
+                pr(1),
+                0,
+                HOUR,
+                Some(post_id),
+                None,
+                None,
+            )
+        };
+
+        assert_eq!(
+            schedule(
+                &mut state,
+                "Now".into(),
+                vec![],
+                pr(1),
+                5,
+                5,
+                None,
+                None,
+                None
+            ),
+            Err("the publication time must be within the next 4 weeks".into())
+        );
+        assert_eq!(schedule_comment(&mut state, "First"), Ok(0));
+        assert_eq!(schedule_comment(&mut state, "Second"), Ok(1));
+
+        // the second post gets canceled before the publication
+        assert_eq!(
+            cancel_scheduled(&mut state, pr(0), 1),
+            Err("unauthorized".into())
+        );
+        assert_eq!(cancel_scheduled(&mut state, pr(1), 1), Ok(()));
+        assert_eq!(
+            cancel_scheduled(&mut state, pr(1), 1),
+            Err("no scheduled post found".into())
+        );
+        assert_eq!(
+            edit_scheduled(&mut state, pr(1), 0, "Edited".into(), vec![], 0, 2 * HOUR),
+            Ok(())
+        );
+
+        // nothing is published before the publication time
+        publish_scheduled(&mut state, HOUR).await;
+        assert_eq!(state.posts.len(), 1);
+        assert!(state.posts.get(&post_id).unwrap().children.is_empty());
+        assert_eq!(inbox(&state, author), author_inbox);
+        assert_eq!(state.users.get(&author).unwrap().karma_to_reward(), karma);
+
+        // rewards and notifications happen at the publication
+        publish_scheduled(&mut state, 2 * HOUR).await;
+        assert!(state.scheduled_posts.is_empty());
+        assert_eq!(state.posts.len(), 2);
+        let comment = state.posts.get(&1).unwrap();
+        assert_eq!(comment.body, "Edited");
+        assert_eq!(comment.timestamp, 2 * HOUR);
+        assert_eq!(comment.user, commenter);
+        assert_eq!(state.posts.get(&post_id).unwrap().children, vec![1]);
+        assert_eq!(inbox(&state, author), author_inbox + 1);
+        assert_eq!(
+            state.users.get(&author).unwrap().karma_to_reward(),
+            karma + CONFIG.response_reward
+        );
+        assert_eq!(inbox(&state, commenter), commenter_inbox + 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_publish_draft() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(0));
+        let user = state.users.get_mut(&id).unwrap();
+        let draft_id = user.save_draft(None, "Draft".into(), None, 0).unwrap();
+        let broken_id = user
+            .save_draft(None, "Draft in a realm".into(), Some("NOREALM".into()), 0)
+            .unwrap();
+
+        // a failed publication keeps the draft
+        assert!(publish_draft(&mut state, pr(0), broken_id, 0)
+            .await
+            .is_err());
+        assert_eq!(state.users.get(&id).unwrap().drafts.len(), 2);
+        assert_eq!(
+            publish_draft(&mut state, pr(0), 42, 0).await,
+            Err("no draft found".into())
+        );
+
+        let post_id = publish_draft(&mut state, pr(0), draft_id, 0).await.unwrap();
+        assert_eq!(state.posts.get(&post_id).unwrap().body, "Draft");
+        let drafts = &state.users.get(&id).unwrap().drafts;
+        assert_eq!(drafts.len(), 1);
+        assert_eq!(drafts[0].id, broken_id);
+        assert_eq!(
+            publish_draft(&mut state, pr(0), draft_id, 0).await,
+            Err("no draft found".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_edit_history() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let id = add(&mut state, "v0".into(), vec![], pr(0), 0, None, None, None)
+            .await
+            .unwrap();
+        let grace = CONFIG.post_edit_grace_period;
+        async fn edit_post(
+            state: &mut State,
+            id: PostId,
+            body: &str,
+            time: u64,
+        ) -> Result<(), String> {
+            edit(state, id, body.into(), vec![], "".into(), None, pr(0), time).await
+        }
+
+        // quick fixes within the grace period aren't recorded
+        edit_post(&mut state, id, "v1", grace - 1).await.unwrap();
+        assert!(state.posts.get(&id).unwrap().edits.is_empty());
+
+        let mut time = grace - 1;
+        for i in 2..(CONFIG.max_post_edits + 4) {
+            time += grace;
+            edit_post(&mut state, id, &format!("v{}", i), time)
+                .await
+                .unwrap();
+            // an immediate follow-up edit doesn't add an entry
+            edit_post(&mut state, id, &format!("v{}", i), time + 1)
+                .await
+                .unwrap();
+        }
+
+        // only the latest revisions are kept, the oldest first
+        let post = state.posts.get(&id).unwrap();
+        assert_eq!(post.body, format!("v{}", CONFIG.max_post_edits + 3));
+        assert_eq!(post.edits.len(), CONFIG.max_post_edits);
+        let first = 4;
+        for (i, (timestamp, body)) in post.edits.iter().enumerate() {
+            assert_eq!(body, &format!("v{}", first + i - 1));
+            assert_eq!(*timestamp, grace - 1 + (first + i - 1) as u64 * grace);
+        }
+
+        let mut post = post.clone();
+        post.delete(vec![]);
+        assert!(post.edits.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_mention_deduplication() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+        }
+        let name = |state: &State, id| state.users.get(&id).unwrap().name.clone();
+        let inbox = |state: &State, id| state.users.get(&id).unwrap().inbox.len();
+        let (name1, name2) = (name(&state, 1), name(&state, 2));
+        let (inbox1, inbox2) = (inbox(&state, 1), inbox(&state, 2));
+
+        // repeated mentions of the same user, also by the id, notify once
+        let id = add(
+            &mut state,
+            format!("@{} and @{}, hi @1", name1, name1),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(inbox(&state, 1), inbox1 + 1);
+
+        // edits notify only newly mentioned users, also if a mention is re-added
+        async fn edit_post(state: &mut State, id: PostId, body: String, time: u64) {
+            edit(state, id, body, vec![], "".into(), None, pr(0), time)
+                .await
+                .unwrap()
+        }
+        edit_post(&mut state, id, "no mentions".into(), 1).await;
+        edit_post(&mut state, id, format!("@{} @{}", name1, name2), 2).await;
+        assert_eq!(inbox(&state, 1), inbox1 + 1);
+        assert_eq!(inbox(&state, 2), inbox2 + 1);
+        edit_post(&mut state, id, format!("@{} @{} @0", name2, name1), 3).await;
+        assert_eq!(inbox(&state, 1), inbox1 + 1);
+        assert_eq!(inbox(&state, 2), inbox2 + 1);
+        assert_eq!(
+            state.posts.get(&id).unwrap().mentioned,
+            vec![1, 2].into_iter().collect()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_post_transfer() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        for i in 0..4 {
+            create_user(&mut state, pr(i));
+        }
+        for (controller, realm) in [(0, "TEAM"), (3, "OTHER")].iter() {
+            let p = pr(*controller);
+            state
+                .principal_to_user_mut(p)
+                .unwrap()
+                .change_cycles(CONFIG.realm_cost, "")
+                .unwrap();
+            state
+                .create_realm(
+                    p,
+                    realm.to_string(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![*controller as UserId],
+                )
+                .unwrap();
+        }
+        for i in 1..3 {
+            assert!(state.toggle_realm_membership(pr(i), "TEAM".into()));
+        }
+        let post_id = add(
+            &mut state,
+            "Announcement".into(),
+            vec![],
+            pr(1),
+            0,
+            None,
+            Some("TEAM".into()),
+            None,
+        )
+        .await
+        .unwrap();
+        let karma = |state: &State, id| state.users.get(&id).unwrap().karma_to_reward();
+        state.react(pr(0), post_id, 100, 0).unwrap();
+        assert_eq!((karma(&state, 1), karma(&state, 2)), (10, 0));
+
+        // only controllers transfer posts to members of the same realm
+        assert_eq!(
+            transfer(&mut state, pr(1), post_id, 2, 0),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            transfer(&mut state, pr(3), post_id, 3, 0),
+            Err("not authorized".into())
+        );
+        assert_eq!(
+            transfer(&mut state, pr(0), post_id, 3, 0),
+            Err("the new owner is not a member of the realm".into())
+        );
+        state.posts.get_mut(&post_id).unwrap().bounty = Some(crate::bounties::Bounty {
+            amount: 100,
+            deadline: 10,
+            answer: None,
+            refunded: false,
+        });
+        assert_eq!(
+            transfer(&mut state, pr(0), post_id, 2, 0),
+            Err("posts with open bounties can't be transferred".into())
+        );
+        state.posts.get_mut(&post_id).unwrap().bounty = None;
+        assert_eq!(transfer(&mut state, pr(0), post_id, 2, 5), Ok(()));
+        let post = state.posts.get(&post_id).unwrap();
+        assert_eq!(post.user, 2);
+        assert_eq!(post.transfers, vec![(5, 1, 2)]);
+        assert!(!state.users.get(&1).unwrap().posts.contains(&post_id));
+        assert!(state.users.get(&2).unwrap().posts.contains(&post_id));
+
+        // past rewards stay, new ones go to the new owner
+        state.react(pr(3), post_id, 100, 0).unwrap();
+        assert_eq!((karma(&state, 1), karma(&state, 2)), (10, 10));
+        for id in 1..3 {
+            let user = state.users.get(&id).unwrap();
+            assert_eq!(
+                user.total_karma(),
+                user.other_karma.unwrap() + crate::audit::reaction_karma(&state, id)
+            );
+        }
+    }
+
+    fn poll_post(options: usize, mode: PollMode) -> Post {
+        let poll = Poll {
+            options: (0..options).map(|i| format!("Option {}", i)).collect(),
+            votes: Default::default(),
+            deadline: 24,
+            mode,
+            ballots: Default::default(),
+            closes_at: None,
+            closed: false,
+        };
+        Post::new(
+            0,
+            Default::default(),
+            "Poll".into(),
+            0,
+            None,
+            Some(Extension::Poll(poll)),
+            None,
+        )
+    }
+
+    fn poll(post: &Post) -> &Poll {
+        match &post.extension {
+            Some(Extension::Poll(poll)) => poll,
+            _ => panic!("no poll"),
+        }
+    }
+
+    #[test]
+    fn test_multi_select_poll() {
+        assert_eq!(
+            poll_post(3, PollMode::MultiSelect(4)).valid(&[], None),
+            Err("invalid number of selectable options".into())
+        );
+        assert_eq!(
+            poll_post(3, PollMode::MultiSelect(0)).valid(&[], None),
+            Err("invalid number of selectable options".into())
+        );
+        let mut post = poll_post(3, PollMode::MultiSelect(2));
+        assert_eq!(post.valid(&[], None), Ok(()));
+        let invalid = Err("invalid vote".to_string());
+        // the upper bound, duplicates and unknown options are rejected
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![0, 1, 2]), invalid);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![1, 1]), invalid);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![3]), invalid);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![]), invalid);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![0, 2]), Ok(()));
+        assert_eq!(post.vote_on_poll(2, vec![], 0, vec![2]), Ok(()));
+        let votes = |post: &Post| {
+            poll(post)
+                .votes
+                .iter()
+                .map(|(id, voters)| (*id, voters.len()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(votes(&post), vec![(0, 1), (2, 2)]);
+
+        // changed votes are re-tallied
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![1]), Ok(()));
+        assert_eq!(votes(&post), vec![(1, 1), (2, 1)]);
+
+        // a single choice poll accepts exactly one option
+        let mut post = poll_post(3, PollMode::Single);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![0, 1]), invalid);
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![0]), Ok(()));
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![1]), Ok(()));
+        assert_eq!(votes(&post), vec![(1, 1)]);
+        assert_eq!(
+            post.vote_on_poll(1, vec![], 24 * HOUR, vec![2]),
+            Err("poll is closed".into())
+        );
+    }
+
+    #[test]
+    fn test_ranked_choice_poll() {
+        let mut post = poll_post(3, PollMode::RankedChoice);
+        // a tie for the last place eliminates both options
+        for (voter, ballot) in vec![vec![0], vec![0], vec![1, 0], vec![2, 1]]
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(
+                post.vote_on_poll(voter as UserId, vec![], 0, ballot),
+                Ok(())
+            );
+        }
+        let rounds = poll(&post).runoff_rounds();
+        assert_eq!(
+            rounds,
+            vec![
+                vec![(0, 2), (1, 1), (2, 1)].into_iter().collect(),
+                vec![(0, 3)].into_iter().collect(),
+            ]
+        );
+        assert_eq!(poll(&post).runoff_winner(), Some(0));
+
+        // changed ballots are re-tallied
+        assert_eq!(post.vote_on_poll(3, vec![], 0, vec![2, 0]), Ok(()));
+        assert_eq!(post.vote_on_poll(2, vec![], 0, vec![1, 2]), Ok(()));
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![1, 2]), Ok(()));
+        let rounds = poll(&post).runoff_rounds();
+        assert_eq!(
+            rounds,
+            vec![
+                vec![(0, 1), (1, 2), (2, 1)].into_iter().collect(),
+                vec![(1, 2)].into_iter().collect(),
+            ]
+        );
+        assert_eq!(poll(&post).runoff_winner(), Some(1));
+
+        // a complete tie has no winner
+        assert_eq!(post.vote_on_poll(0, vec![], 0, vec![0, 1]), Ok(()));
+        assert_eq!(post.vote_on_poll(3, vec![], 0, vec![2, 1]), Ok(()));
+        assert_eq!(post.vote_on_poll(2, vec![], 0, vec![2, 0]), Ok(()));
+        assert_eq!(post.vote_on_poll(1, vec![], 0, vec![0, 2]), Ok(()));
+        assert_eq!(
+            poll(&post).runoff_rounds(),
+            vec![
+                vec![(0, 2), (1, 0), (2, 2)].into_iter().collect(),
+                vec![(0, 2), (2, 2)].into_iter().collect(),
+            ]
+        );
+        assert_eq!(poll(&post).runoff_winner(), None);
+        // only first preferences are counted in the votes
+        assert_eq!(poll(&post).votes.values().flatten().count(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_poll_closing() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        let author = create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        let extension = poll_post(2, PollMode::Single).extension;
+        let created = 5 * HOUR;
+        let id = add(
+            &mut state,
+            "Poll".into(),
+            vec![],
+            pr(0),
+            created,
+            None,
+            None,
+            extension,
+        )
+        .await
+        .unwrap();
+        let closes_at = created + 24 * HOUR;
+        assert_eq!(
+            poll(state.posts.get(&id).unwrap()).closes_at,
+            Some(closes_at)
+        );
+        assert_eq!(
+            state.open_polls,
+            vec![(closes_at, id)].into_iter().collect()
+        );
+
+        // votes at the closing time are rejected
+        assert_eq!(
+            state.vote_on_poll(pr(1), closes_at - 1, id, vec![0]),
+            Ok(())
+        );
+        assert_eq!(
+            state.vote_on_poll(pr(1), closes_at, id, vec![1]),
+            Err("poll is closed".into())
+        );
+
+        let inbox = |state: &State| state.users.get(&author).unwrap().inbox.len();
+        let inbox_before = inbox(&state);
+        close_polls(&mut state, closes_at - 1);
+        assert!(!poll(state.posts.get(&id).unwrap()).closed);
+        assert_eq!(inbox(&state), inbox_before);
+
+        close_polls(&mut state, closes_at);
+        assert!(poll(state.posts.get(&id).unwrap()).closed);
+        assert!(state.open_polls.is_empty());
+        assert_eq!(inbox(&state), inbox_before + 1);
+
+        // closed polls reject votes regardless of the time
+        assert_eq!(
+            state.vote_on_poll(pr(1), created, id, vec![1]),
+            Err("poll is closed".into())
+        );
+        let votes = &poll(state.posts.get(&id).unwrap()).votes;
+        assert_eq!(votes.get(&0).map(|voters| voters.len()), Some(1));
+        assert!(votes.get(&1).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_quotes() {
+        use crate::env::tests::{create_user, pr};
+        let mut state = State::default();
+        let author = create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        let id = add(
+            &mut state,
+            "Original".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let karma = state.users.get(&author).unwrap().karma_to_reward();
+        let inbox = state.users.get(&author).unwrap().inbox.len();
+        let quote_id = quote(&mut state, "Look!".into(), vec![], pr(1), 0, id, None)
+            .await
+            .unwrap();
+        let user = state.users.get(&author).unwrap();
+        assert_eq!(user.karma_to_reward(), karma + CONFIG.quote_reward);
+        assert_eq!(user.inbox.len(), inbox + 1);
+        assert_eq!(state.posts.get(&quote_id).unwrap().quoted, Some(id));
+        assert!(
+            matches!(resolve_quote(&state, pr(0), id), Quote::Post(post) if post.body == "Original")
+        );
+
+        // a deleted original is resolved to a tombstone
+        assert_eq!(
+            state.delete_post(pr(0), id, vec!["Original".into()], 0),
+            Ok(())
+        );
+        assert!(
+            matches!(resolve_quote(&state, pr(0), id), Quote::Tombstone(quoted) if quoted == id)
+        );
+        assert!(matches!(
+            resolve_quote(&state, pr(0), 42),
+            Quote::Tombstone(42)
+        ));
+        // the quoting post is kept
+        assert_eq!(state.posts.get(&quote_id).unwrap().body, "Look!");
+        assert_eq!(
+            quote(&mut state, "Again".into(), vec![], pr(1), 0, id, None).await,
+            Err("deleted posts can't be quoted".into())
+        );
+    }
 }
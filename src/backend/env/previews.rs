@@ -0,0 +1,194 @@
+use super::config::CONFIG;
+use super::storage::CallFn;
+use super::State;
+use candid::CandidType;
+use ic_cdk::api::call::call_raw;
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const PREFIX: &str = "canister:";
+
+#[derive(CandidType, Deserialize)]
+struct CanisterInfoRequest {
+    canister_id: Principal,
+    num_requested_changes: Option<u64>,
+}
+
+// Only the fields used by previews; the remaining fields of the response are skipped.
+#[derive(CandidType, Deserialize)]
+struct CanisterInfoResponse {
+    module_hash: Option<Vec<u8>>,
+    controllers: Vec<Principal>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CanisterPreview {
+    pub id: String,
+    pub controllers: Vec<String>,
+    pub module_hash: Option<String>,
+}
+
+// Resolved canister references keyed by the canister id along with the time of resolution.
+#[derive(Default)]
+pub struct Previews {
+    cache: BTreeMap<Principal, (u64, CanisterPreview)>,
+    // replaces calls to the management canister if set
+    call: Option<CallFn>,
+}
+
+impl Previews {
+    fn get(&self, id: &Principal, now: u64) -> Option<&CanisterPreview> {
+        self.cache
+            .get(id)
+            .filter(|(timestamp, _)| *timestamp + CONFIG.canister_preview_ttl > now)
+            .map(|(_, preview)| preview)
+    }
+
+    // Evicts the oldest resolution if the cache is full.
+    fn insert(&mut self, id: Principal, preview: CanisterPreview, now: u64) {
+        if !self.cache.contains_key(&id) && self.cache.len() >= CONFIG.max_canister_previews {
+            if let Some(oldest) = self
+                .cache
+                .iter()
+                .min_by_key(|(_, (timestamp, _))| *timestamp)
+                .map(|(id, _)| *id)
+            {
+                self.cache.remove(&oldest);
+            }
+        }
+        self.cache.insert(id, (now, preview));
+    }
+
+    async fn canister_info(&self, id: Principal) -> Result<CanisterInfoResponse, String> {
+        let args = candid::encode_one(CanisterInfoRequest {
+            canister_id: id,
+            num_requested_changes: None,
+        })
+        .map_err(|err| err.to_string())?;
+        let bytes = match &self.call {
+            Some(call) => call(Principal::management_canister(), "canister_info", args).await?,
+            None => call_raw(Principal::management_canister(), "canister_info", &args, 0)
+                .await
+                .map_err(|err| format!("couldn't resolve canister {}: {:?}", id, err))?,
+        };
+        candid::decode_one(&bytes).map_err(|err| err.to_string())
+    }
+}
+
+// Returns the ids of all `canister:<id>` references in the text with a valid principal.
+pub fn canister_references(text: &str) -> Vec<Principal> {
+    let mut references = Vec::new();
+    for (i, _) in text.match_indices(PREFIX) {
+        let preceded_by_word = text[..i]
+            .chars()
+            .last()
+            .map(|c| c.is_alphanumeric())
+            .unwrap_or_default();
+        if preceded_by_word {
+            continue;
+        }
+        let id: String = text[i + PREFIX.len()..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect();
+        if let Ok(principal) = Principal::from_text(id) {
+            if !references.contains(&principal) {
+                references.push(principal);
+            }
+        }
+    }
+    references
+}
+
+// Resolves the basic metadata of a referenced canister. Resolutions are cached for a while,
+// failures are not cached and get rendered as plain text.
+pub async fn resolve(state: &mut State, id: String, now: u64) -> Result<CanisterPreview, String> {
+    let principal = canister_references(&format!("{}{}", PREFIX, id))
+        .pop()
+        .ok_or("invalid canister id")?;
+    if let Some(preview) = state.previews.get(&principal, now) {
+        return Ok(preview.clone());
+    }
+    let info = state.previews.canister_info(principal).await?;
+    let preview = CanisterPreview {
+        id: principal.to_text(),
+        controllers: info.controllers.iter().map(|id| id.to_text()).collect(),
+        module_hash: info.module_hash.map(hex::encode),
+    };
+    state.previews.insert(principal, preview.clone(), now);
+    Ok(preview)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::tests::pr;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_canister_references() {
+        let id = pr(1).to_text();
+        assert_eq!(
+            canister_references(&format!(
+                "See canister:{}, (canister:{}) and canister:{}!",
+                id,
+                pr(2).to_text(),
+                id
+            )),
+            vec![pr(1), pr(2)]
+        );
+        // invalid ids and prefixes inside of words are ignored
+        assert!(canister_references("canister:xyz canister: canister:").is_empty());
+        assert!(canister_references(&format!("mycanister:{}", id)).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_resolution_fallback() {
+        let mut state = State::default();
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        state.previews.call = Some(Box::new(move |_, _, args| {
+            counter.set(counter.get() + 1);
+            let request: CanisterInfoRequest = candid::decode_one(&args).unwrap();
+            let result = if request.canister_id == pr(1) {
+                Ok(candid::encode_one(CanisterInfoResponse {
+                    module_hash: Some(vec![1, 2]),
+                    controllers: vec![pr(3)],
+                })
+                .unwrap())
+            } else {
+                Err("canister not found".to_string())
+            };
+            Box::pin(async move { result })
+        }));
+
+        assert_eq!(
+            resolve(&mut state, "xyz".into(), 0).await,
+            Err("invalid canister id".into())
+        );
+        let preview = CanisterPreview {
+            id: pr(1).to_text(),
+            controllers: vec![pr(3).to_text()],
+            module_hash: Some("0102".into()),
+        };
+        assert_eq!(resolve(&mut state, pr(1).to_text(), 0).await, Ok(preview));
+        // cached resolutions don't trigger calls until they expire
+        resolve(&mut state, pr(1).to_text(), 1).await.unwrap();
+        assert_eq!(calls.get(), 1);
+        resolve(&mut state, pr(1).to_text(), CONFIG.canister_preview_ttl)
+            .await
+            .unwrap();
+        assert_eq!(calls.get(), 2);
+
+        // failed resolutions are not cached
+        for _ in 0..2 {
+            assert_eq!(
+                resolve(&mut state, pr(2).to_text(), 0).await,
+                Err("canister not found".into())
+            );
+        }
+        assert_eq!(calls.get(), 4);
+    }
+}
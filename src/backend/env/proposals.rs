@@ -11,6 +11,7 @@ use ic_cdk::export::candid::Principal;
 use ic_cdk::id;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 pub enum Status {
@@ -18,6 +19,28 @@ pub enum Status {
     Rejected,
     Executed,
     Cancelled,
+    // approved, waiting for the timelock to elapse
+    Adopted,
+    // the voting period ended with too little participation
+    NoQuorum,
+    // approved, but the execution of the payload failed
+    Failed,
+}
+
+// Governance parameters in percent of the voting power, changeable via proposals.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub quorum: u16,
+    pub approval: u16,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            quorum: CONFIG.proposal_quorum,
+            approval: CONFIG.proposal_approval_threshold,
+        }
+    }
 }
 
 impl Default for Status {
@@ -39,32 +62,60 @@ pub struct Proposal {
     pub payload: Payload,
     pub bulletins: Vec<(UserId, bool, Token)>,
     voting_power: Token,
+    #[serde(default)]
+    pub adopted_at: Option<u64>,
+    // the error of a failed execution
+    #[serde(default)]
+    pub error: Option<String>,
+    // token balances of eligible voters at the creation of the proposal; direct and delegated
+    // votes are counted with these balances, so tokens moved during the vote count only once
+    #[serde(default)]
+    voting_balances: Option<HashMap<UserId, Token>>,
 }
 
 impl Proposal {
+    // Returns the voting power of every eligible user who held tokens; proposals created
+    // before the snapshots were introduced use the current balances.
+    fn balances(&self, state: &State) -> HashMap<UserId, Token> {
+        match &self.voting_balances {
+            Some(balances) => balances.clone(),
+            None => voting_balances(state, &self.payload),
+        }
+    }
+
     fn vote(&mut self, state: &State, principal: Principal, approve: bool) -> Result<(), String> {
         let user = state.principal_to_user(principal).ok_or("no user found")?;
         if !user.trusted() {
             return Err("only trusted users can vote".into());
         }
-        if self.bulletins.iter().any(|(voter, _, _)| *voter == user.id) {
+        if !self.payload.eligible_voter(state, user.id) {
+            return Err("only realm members can vote on this proposal".into());
+        }
+        if let Some(bulletin) = self
+            .bulletins
+            .iter_mut()
+            .find(|(voter, _, _)| *voter == user.id)
+        {
+            // during the timelock, voters can withdraw their approval to veto the proposal
+            if self.status == Status::Adopted && bulletin.1 && !approve {
+                bulletin.1 = false;
+                return Ok(());
+            }
             return Err("double vote".into());
         }
-        let balance = state
-            .balances
-            .get(&account(principal))
+        let balance = self
+            .balances(state)
+            .get(&user.id)
+            .copied()
             .ok_or_else(|| "only token holders can vote".to_string())?;
 
-        self.bulletins.push((user.id, approve, *balance));
+        self.bulletins.push((user.id, approve, balance));
         Ok(())
     }
 
     async fn execute(&mut self, state: &mut State, time: u64) -> Result<(), String> {
-        let supply_of_users_total: Token = state
-            .balances
-            .iter()
-            .filter_map(|(acc, balance)| state.principal_to_user(acc.owner).map(|_| *balance))
-            .sum();
+        let balances = self.balances(state);
+        let supply_of_users_total: Token = balances.values().sum();
         // decrease the total number according to the delay
         let delay =
             ((100 - (time.saturating_sub(self.timestamp) / (HOUR * 24))).max(1)) as f64 / 100.0;
@@ -77,7 +128,7 @@ impl Proposal {
         }
         self.voting_power = voting_power;
 
-        let (approvals, rejects): (Token, Token) =
+        let (mut approvals, mut rejects): (Token, Token) =
             self.bulletins
                 .iter()
                 .fold((0, 0), |(approvals, rejects), (_, approved, balance)| {
@@ -88,7 +139,38 @@ impl Proposal {
                     }
                 });
 
-        if rejects * 100 >= voting_power * (100 - CONFIG.proposal_approval_threshold) as u64 {
+        // the power of users who didn't vote flows to the voters they delegated to
+        let votes: HashMap<UserId, bool> = self
+            .bulletins
+            .iter()
+            .map(|(user_id, approved, _)| (*user_id, *approved))
+            .collect();
+        for (user_id, balance) in &balances {
+            if votes.contains_key(user_id) {
+                continue;
+            }
+            match delegated_vote(state, *user_id, self.payload.topic(), &votes) {
+                Some(true) => approvals += balance,
+                Some(false) => rejects += balance,
+                None => {}
+            }
+        }
+
+        if let Some(adopted_at) = self.adopted_at {
+            if rejects * 100 >= voting_power * CONFIG.proposal_veto_threshold as u64 {
+                self.status = Status::Rejected;
+                state.logger.info(format!(
+                    "Adopted proposal `{}` was vetoed during the timelock.",
+                    self.id
+                ));
+            } else if time >= adopted_at + self.payload.timelock() {
+                self.apply(state, time).await;
+            }
+            return Ok(());
+        }
+
+        let thresholds = state.proposal_thresholds.clone();
+        if rejects * 100 >= voting_power * (100 - thresholds.approval) as u64 {
             self.status = Status::Rejected;
             // if proposal was rejected without a controversion, penalize the proposer
             if approvals * 100 < CONFIG.proposal_controversy_threashold as u64 * rejects {
@@ -110,41 +192,58 @@ impl Proposal {
             return Ok(());
         }
 
-        if approvals * 100 >= voting_power * CONFIG.proposal_approval_threshold as u64 {
-            match &self.payload {
-                Payload::SetController(controller) => {
-                    let principal = Principal::from_text(controller).map_err(|e| e.to_string())?;
-                    add_controller(principal).await?;
-                    state.logger.info(format!(
-                        "`{}` was added as a controller of the main cansiter via proposal execution.",
-                        principal
-                    ));
-                }
-                Payload::Fund(receiver, tokens) => {
-                    let receiver = Principal::from_text(receiver).map_err(|e| e.to_string())?;
-                    crate::token::mint(
-                        state,
-                        account(receiver),
-                        *tokens * 10_u64.pow(CONFIG.token_decimals as u32),
-                    );
-                    state.logger.info(format!(
-                        "`{}` ${} tokens were minted for `{}` via proposal execution.",
-                        tokens, CONFIG.token_symbol, receiver
-                    ));
-                    if let Some(user) = state.principal_to_user_mut(receiver) {
-                        user.notify(format!(
-                            "`{}` ${} tokens were minted for you via proposal execution.",
-                            tokens, CONFIG.token_symbol,
-                        ))
-                    }
-                }
-                _ => {}
-            }
-            self.status = Status::Executed;
+        let approved = if approvals * 100 >= voting_power * thresholds.approval as u64 {
+            true
+        } else if time < self.timestamp + CONFIG.proposal_voting_period {
+            return Ok(());
+        } else if (approvals + rejects) * 100 < voting_power * thresholds.quorum as u64 {
+            self.status = Status::NoQuorum;
+            state.logger.info(format!(
+                "Proposal `{}` didn't reach the quorum of `{}%`.",
+                self.id, thresholds.quorum
+            ));
+            return Ok(());
+        } else {
+            // after the voting period, the participating voting power decides
+            approvals * 100 >= (approvals + rejects) * thresholds.approval as u64
+        };
+
+        if !approved {
+            self.status = Status::Rejected;
+            state.logger.info(format!(
+                "Proposal `{}` didn't reach the approval of `{}%`.",
+                self.id, thresholds.approval
+            ));
+            return Ok(());
         }
 
+        if self.payload.timelock() > 0 {
+            self.status = Status::Adopted;
+            self.adopted_at = Some(time);
+            state.logger.info(format!(
+                "Proposal `{}` was adopted and will be executed after the timelock.",
+                self.id
+            ));
+            return Ok(());
+        }
+        self.apply(state, time).await;
         Ok(())
     }
+
+    // Executes the payload; failures are recorded on the proposal.
+    async fn apply(&mut self, state: &mut State, time: u64) {
+        match self.payload.execute(state, time).await {
+            Ok(()) => self.status = Status::Executed,
+            Err(err) => {
+                state.logger.error(format!(
+                    "Execution of proposal `{}` failed: {}",
+                    self.id, err
+                ));
+                self.status = Status::Failed;
+                self.error = Some(err);
+            }
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -153,6 +252,18 @@ pub enum Payload {
     Release(Release),
     SetController(String),
     Fund(String, Token),
+    // starts a karma audit; corrects discrepancies if set to true
+    KarmaAudit(bool),
+    // the bucket size limit in bytes
+    SetMaxBucketSize(u64),
+    // realm, receiver and the amount of tokens spent from the realm treasury
+    RealmSpend(String, String, Token),
+    SetThresholds(Thresholds),
+    // receiver, tokens, the cliff and the vesting duration in days
+    Vest(String, Token, u64, u64),
+    // stops the vesting with the given id
+    RevokeVesting(u64),
+    SetCoordinationSettings(crate::env::coordination::Settings),
 }
 
 impl Default for Payload {
@@ -162,6 +273,24 @@ impl Default for Payload {
 }
 
 impl Payload {
+    // Delay between the adoption and the execution of a proposal.
+    fn timelock(&self) -> u64 {
+        match self {
+            Payload::Release(_) => CONFIG.proposal_timelock_release,
+            Payload::SetController(_) | Payload::SetThresholds(_) => {
+                CONFIG.proposal_timelock_controller
+            }
+            Payload::Fund(_, _)
+            | Payload::RealmSpend(_, _, _)
+            | Payload::Vest(_, _, _, _)
+            | Payload::RevokeVesting(_) => CONFIG.proposal_timelock_funding,
+            Payload::KarmaAudit(_)
+            | Payload::SetMaxBucketSize(_)
+            | Payload::SetCoordinationSettings(_)
+            | Payload::Noop => 0,
+        }
+    }
+
     fn validate(&mut self) -> Result<(), String> {
         match self {
             Payload::Release(release) => {
@@ -187,10 +316,270 @@ impl Payload {
                     ));
                 }
             }
+            Payload::Vest(receiver, tokens, cliff_days, duration_days) => {
+                Principal::from_text(receiver).map_err(|err| err.to_string())?;
+                if *tokens == 0 || *tokens > CONFIG.max_funding_amount {
+                    return Err(format!(
+                        "the grant must be between 1 and {} tokens",
+                        CONFIG.max_funding_amount
+                    ));
+                }
+                if cliff_days > duration_days
+                    || *duration_days * 24 * HOUR > CONFIG.max_vesting_duration
+                {
+                    return Err("invalid vesting duration".to_string());
+                }
+            }
+            Payload::SetMaxBucketSize(0) => {
+                return Err("the bucket size limit must be positive".to_string());
+            }
+            Payload::SetCoordinationSettings(settings) => settings.validate()?,
+            Payload::SetThresholds(Thresholds { quorum, approval }) => {
+                if *quorum == 0 || *quorum > 100 {
+                    return Err("the quorum must be between 1 and 100 percent".to_string());
+                }
+                if *approval <= 50 || *approval >= 100 {
+                    return Err("the approval must be between 51 and 99 percent".to_string());
+                }
+            }
+            Payload::RealmSpend(_, receiver, tokens) => {
+                Principal::from_text(receiver).map_err(|err| err.to_string())?;
+                if *tokens == 0 {
+                    return Err("amount must be positive".to_string());
+                }
+            }
             _ => {}
         }
         Ok(())
     }
+
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Payload::Noop => "noop",
+            Payload::Release(_) => "release",
+            Payload::SetController(_) => "controller",
+            Payload::Fund(_, _) => "funding",
+            Payload::KarmaAudit(_) => "audit",
+            Payload::SetMaxBucketSize(_) => "storage",
+            Payload::RealmSpend(_, _, _) => "realm",
+            Payload::SetThresholds(_) => "governance",
+            Payload::SetCoordinationSettings(_) => "moderation",
+            Payload::Vest(_, _, _, _) | Payload::RevokeVesting(_) => "funding",
+        }
+    }
+
+    // Realm proposals are decided by the realm members only.
+    fn eligible_voter(&self, state: &State, user_id: UserId) -> bool {
+        match self {
+            Payload::RealmSpend(realm, _, _) => state
+                .realms
+                .get(realm)
+                .map(|realm| realm.members.contains(&user_id))
+                .unwrap_or_default(),
+            _ => true,
+        }
+    }
+
+    // Executes the payload of an approved proposal.
+    async fn execute(&self, state: &mut State, time: u64) -> Result<(), String> {
+        match self {
+            Payload::SetController(controller) => {
+                let principal = Principal::from_text(controller).map_err(|e| e.to_string())?;
+                add_controller(principal).await?;
+                state.logger.info(format!(
+                    "`{}` was added as a controller of the main cansiter via proposal execution.",
+                    principal
+                ));
+            }
+            Payload::Fund(receiver, tokens) => {
+                let receiver = Principal::from_text(receiver).map_err(|e| e.to_string())?;
+                crate::token::mint(
+                    state,
+                    account(receiver),
+                    *tokens * 10_u64.pow(CONFIG.token_decimals as u32),
+                );
+                state.logger.info(format!(
+                    "`{}` ${} tokens were minted for `{}` via proposal execution.",
+                    tokens, CONFIG.token_symbol, receiver
+                ));
+                if let Some(user) = state
+                    .principal_to_user_mut(receiver)
+                    .filter(|user| user.notification_settings.rewards)
+                {
+                    user.notify(format!(
+                        "`{}` ${} tokens were minted for you via proposal execution.",
+                        tokens, CONFIG.token_symbol,
+                    ))
+                }
+            }
+            Payload::RealmSpend(realm_id, receiver, tokens) => {
+                let receiver = Principal::from_text(receiver).map_err(|e| e.to_string())?;
+                let realm = state.realms.get_mut(realm_id).ok_or("no realm found")?;
+                let amount = base_units(*tokens);
+                if realm.treasury < amount {
+                    return Err("insufficient realm treasury".into());
+                }
+                realm.treasury -= amount;
+                crate::token::mint(state, account(receiver), amount);
+                state.logger.info(format!(
+                    "`{}` ${} tokens were spent from the treasury of realm `{}` for `{}` via proposal execution.",
+                    tokens, CONFIG.token_symbol, realm_id, receiver
+                ));
+            }
+            Payload::Vest(receiver, tokens, cliff_days, duration_days) => {
+                let receiver = Principal::from_text(receiver).map_err(|e| e.to_string())?;
+                let id = crate::token::create_vesting(
+                    state,
+                    receiver,
+                    *tokens * 10_u64.pow(CONFIG.token_decimals as u32),
+                    cliff_days * 24 * HOUR,
+                    duration_days * 24 * HOUR,
+                    time,
+                )?;
+                state.logger.info(format!(
+                    "`{}` ${} tokens vesting over `{}` days were granted to `{}` via proposal execution (vesting id: `{}`).",
+                    tokens, CONFIG.token_symbol, duration_days, receiver, id
+                ));
+            }
+            Payload::RevokeVesting(id) => {
+                let forfeited = crate::token::revoke_vesting(state, *id as usize, time)?;
+                state.logger.info(format!(
+                    "Vesting `{}` was revoked via proposal execution; `{}` ${} token units were forfeited.",
+                    id, forfeited, CONFIG.token_symbol
+                ));
+            }
+            Payload::SetThresholds(thresholds) => {
+                state.proposal_thresholds = thresholds.clone();
+                state.logger.info(format!(
+                    "The proposal quorum was set to `{}%` and the approval to `{}%` via proposal execution.",
+                    thresholds.quorum, thresholds.approval
+                ));
+            }
+            Payload::SetCoordinationSettings(settings) => {
+                state.detector.settings = settings.clone();
+                state.logger.info(format!(
+                    "The coordination detector settings were changed via proposal execution: `{:?}`.",
+                    settings
+                ));
+            }
+            Payload::KarmaAudit(correct) => crate::audit::start(state, *correct),
+            Payload::SetMaxBucketSize(size) => {
+                state.storage.set_max_bucket_size(*size)?;
+                state.logger.info(format!(
+                    "The bucket size limit was set to `{}` bytes via proposal execution.",
+                    size
+                ));
+            }
+            // releases are installed by the upgrade logic once executed
+            Payload::Release(_) | Payload::Noop => {}
+        }
+        Ok(())
+    }
+}
+
+// Proposal threads only accept comments while the proposal is not resolved.
+pub fn discussion_open(state: &State, post_id: PostId) -> bool {
+    match state
+        .thread(post_id)
+        .next()
+        .and_then(|id| state.posts.get(&id))
+        .and_then(|post| post.extension.as_ref())
+    {
+        Some(Extension::Proposal(id)) => state
+            .proposals
+            .get(*id as usize)
+            .map(|proposal| matches!(proposal.status, Status::Open | Status::Adopted))
+            .unwrap_or(true),
+        _ => true,
+    }
+}
+
+// Converts whole tokens of a proposal payload into token base units.
+fn base_units(tokens: Token) -> Token {
+    tokens.saturating_mul(10_u64.pow(CONFIG.token_decimals as u32))
+}
+
+pub const ALL_TOPICS: &str = "*";
+
+const TOPICS: &[&str] = &[
+    "noop",
+    "release",
+    "controller",
+    "funding",
+    "audit",
+    "storage",
+    "realm",
+    "governance",
+    "moderation",
+];
+
+// Sums up the token balances of users eligible to vote on the payload.
+fn voting_balances(state: &State, payload: &Payload) -> HashMap<UserId, Token> {
+    let mut balances: HashMap<UserId, Token> = Default::default();
+    for (acc, balance) in &state.balances {
+        if let Some(user) = state
+            .principal_to_user(acc.owner)
+            .filter(|user| payload.eligible_voter(state, user.id))
+        {
+            *balances.entry(user.id).or_default() += balance;
+        }
+    }
+    balances
+}
+
+// Follows the delegation chain of the user until a user who voted; returns their vote or None
+// if the chain ends without a vote or runs into a cycle.
+fn delegated_vote(
+    state: &State,
+    user_id: UserId,
+    topic: &str,
+    votes: &HashMap<UserId, bool>,
+) -> Option<bool> {
+    let mut visited = HashSet::new();
+    let mut current = user_id;
+    while visited.insert(current) {
+        let user = state.users.get(&current)?;
+        current = *user
+            .delegations
+            .get(topic)
+            .or_else(|| user.delegations.get(ALL_TOPICS))?;
+        if let Some(approved) = votes.get(&current) {
+            return Some(*approved);
+        }
+    }
+    None
+}
+
+// Sets or removes (if the delegate is None) the delegation of the caller's voting power for the
+// given topic or for all topics.
+pub fn delegate(
+    state: &mut State,
+    principal: Principal,
+    topic: Option<String>,
+    delegate: Option<UserId>,
+) -> Result<(), String> {
+    let topic = topic.unwrap_or_else(|| ALL_TOPICS.to_string());
+    if topic != ALL_TOPICS && !TOPICS.contains(&topic.as_str()) {
+        return Err("unknown topic".into());
+    }
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    if let Some(delegate_id) = delegate {
+        if delegate_id == user_id {
+            return Err("you can't delegate to yourself".into());
+        }
+        if !state.users.contains_key(&delegate_id) {
+            return Err("no delegate found".into());
+        }
+    }
+    let user = state.users.get_mut(&user_id).expect("no user found");
+    match delegate {
+        Some(delegate_id) => user.delegations.insert(topic, delegate_id),
+        None => user.delegations.remove(&topic),
+    };
+    Ok(())
 }
 
 pub async fn propose(
@@ -201,8 +590,18 @@ pub async fn propose(
     time: u64,
 ) -> Result<u32, String> {
     let user = state.principal_to_user(caller).ok_or("user not found")?;
-    if !user.stalwart {
-        return Err("only stalwarts can create proposals".to_string());
+    match &payload {
+        Payload::RealmSpend(realm, _, tokens) => {
+            let realm = state.realms.get(realm).ok_or("no realm found")?;
+            if !realm.controllers.contains(&user.id) {
+                return Err("only realm controllers can propose treasury spendings".to_string());
+            }
+            if realm.treasury < base_units(*tokens) {
+                return Err("insufficient realm treasury".to_string());
+            }
+        }
+        _ if !user.stalwart => return Err("only stalwarts can create proposals".to_string()),
+        _ => {}
     }
     if description.is_empty() {
         return Err("description is empty".to_string());
@@ -236,6 +635,7 @@ pub async fn propose(
         Some(Extension::Proposal(id)),
     )
     .await?;
+    let voting_balances = voting_balances(state, &payload);
     state.proposals.push(Proposal {
         description: Default::default(),
         post_id,
@@ -245,6 +645,9 @@ pub async fn propose(
         payload,
         bulletins: Vec::default(),
         voting_power: 0,
+        adopted_at: None,
+        error: None,
+        voting_balances: Some(voting_balances),
         id,
     });
     let msg = format!(
@@ -271,15 +674,17 @@ pub async fn vote_on_proposal(
     let proposal = proposals
         .get_mut(proposal_id as usize)
         .ok_or_else(|| "no proposals founds".to_string())?;
-    if proposal.status != Status::Open {
+    if !matches!(proposal.status, Status::Open | Status::Adopted) {
         state.proposals = proposals;
         return Err("last proposal is not open".into());
     }
+    let voters = proposal.bulletins.len();
     if let Err(err) = proposal.vote(state, caller, approved) {
         state.proposals = proposals;
         return Err(err);
     }
-    if let Some(user) = state.principal_to_user(caller) {
+    let first_vote = proposal.bulletins.len() > voters;
+    if let Some(user) = state.principal_to_user(caller).filter(|_| first_vote) {
         state.spend_to_user_karma(user.id, CONFIG.voting_reward, "voting rewards");
     }
     state.proposals = proposals;
@@ -307,7 +712,7 @@ pub(super) async fn execute_proposal(
     let proposal = proposals
         .get_mut(proposal_id as usize)
         .ok_or_else(|| "no proposals founds".to_string())?;
-    if proposal.status != Status::Open {
+    if !matches!(proposal.status, Status::Open | Status::Adopted) {
         state.proposals = proposals;
         return Err("last proposal is not open".into());
     }
@@ -318,6 +723,9 @@ pub(super) async fn execute_proposal(
             .logger
             .error(format!("Proposal execution failed: {:?}", err));
     }
+    if !matches!(proposal.status, Status::Open | Status::Adopted) {
+        proposal.voting_balances = None;
+    }
     if previous_state != proposal.status {
         state.denotify_users(&|user| user.active_within_weeks(time, 1) && user.balance > 0);
         state.logger.info(format!(
@@ -641,6 +1049,117 @@ mod tests {
         )
     }
 
+    #[actix_rt::test]
+    async fn test_proposal_timelock() {
+        let mut state = State::default();
+
+        let mut eligigble = HashMap::default();
+        for i in 1..=10 {
+            let id = create_user(&mut state, pr(i));
+            let user = state.users.get_mut(&id).unwrap();
+            user.change_karma(100, "test");
+            eligigble.insert(id, user.karma_to_reward());
+        }
+        state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+        state.mint(eligigble);
+
+        let receiver = pr(1).to_string();
+        let balance = *state.balances.get(&account(pr(1))).unwrap();
+        let timelock = CONFIG.proposal_timelock_funding;
+        assert!(timelock > 0);
+
+        // a passed proposal is only adopted
+        let prop_id = propose(
+            &mut state,
+            pr(1),
+            "test".into(),
+            Payload::Fund(receiver.clone(), 10),
+            time(),
+        )
+        .await
+        .expect("couldn't propose");
+        for i in 1..=7 {
+            assert_eq!(
+                vote_on_proposal(&mut state, time(), pr(i), prop_id, true).await,
+                Ok(())
+            );
+        }
+        let proposal = state.proposals.get(prop_id as usize).unwrap();
+        assert_eq!(proposal.status, Status::Adopted);
+        assert_eq!(proposal.adopted_at, Some(time()));
+
+        // nothing happens before the timelock elapses
+        assert_eq!(
+            execute_proposal(&mut state, prop_id, time() + timelock - 1).await,
+            Ok(())
+        );
+        assert_eq!(
+            state.proposals.get(prop_id as usize).unwrap().status,
+            Status::Adopted
+        );
+        assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), balance);
+
+        // the proposal gets executed after the timelock
+        assert_eq!(
+            execute_proposal(&mut state, prop_id, time() + timelock).await,
+            Ok(())
+        );
+        assert_eq!(
+            state.proposals.get(prop_id as usize).unwrap().status,
+            Status::Executed
+        );
+        assert_eq!(
+            *state.balances.get(&account(pr(1))).unwrap(),
+            balance + 1000
+        );
+
+        // an adopted proposal gets vetoed within the timelock
+        let prop_id = propose(
+            &mut state,
+            pr(1),
+            "test".into(),
+            Payload::Fund(receiver, 10),
+            time(),
+        )
+        .await
+        .expect("couldn't propose");
+        for i in 1..=7 {
+            assert_eq!(
+                vote_on_proposal(&mut state, time(), pr(i), prop_id, true).await,
+                Ok(())
+            );
+        }
+        assert_eq!(
+            state.proposals.get(prop_id as usize).unwrap().status,
+            Status::Adopted
+        );
+        for i in 8..=10 {
+            assert_eq!(
+                vote_on_proposal(&mut state, time(), pr(i), prop_id, false).await,
+                Ok(())
+            );
+        }
+        assert_eq!(
+            state.proposals.get(prop_id as usize).unwrap().status,
+            Status::Adopted
+        );
+        // approvals can be withdrawn during the timelock
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, time(), pr(i), prop_id, false).await,
+                Ok(())
+            );
+        }
+        assert_eq!(
+            state.proposals.get(prop_id as usize).unwrap().status,
+            Status::Rejected
+        );
+        assert_eq!(
+            *state.balances.get(&account(pr(1))).unwrap(),
+            balance + 1000
+        );
+    }
+
     #[actix_rt::test]
     async fn test_reducing_voting_power() {
         let mut state = State::default();
@@ -735,4 +1254,444 @@ mod tests {
             proposers_karma - CONFIG.proposal_rejection_penalty as i64
         );
     }
+
+    #[actix_rt::test]
+    async fn test_realm_treasury() {
+        let mut state = State::default();
+        for i in 1..=3 {
+            create_user(&mut state, pr(i));
+            state.balances.insert(account(pr(i)), 1000);
+        }
+        let realm = "TREASURY".to_string();
+        state
+            .principal_to_user_mut(pr(1))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(1),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert!(state.toggle_realm_membership(pr(2), realm.clone()));
+
+        // donations accrue in the treasury
+        assert_eq!(
+            state.donate_to_realm(pr(3), "NONE".into(), 100, 0),
+            Err("no realm found".into())
+        );
+        assert_eq!(
+            state.donate_to_realm(pr(3), realm.clone(), 2000, 0),
+            Err("not enough tokens".into())
+        );
+        assert_eq!(state.donate_to_realm(pr(3), realm.clone(), 300, 0), Ok(()));
+        assert_eq!(state.donate_to_realm(pr(3), realm.clone(), 200, 0), Ok(()));
+        assert_eq!(state.realms.get(&realm).unwrap().treasury, 500);
+        assert_eq!(
+            state.balances.get(&account(pr(3))),
+            Some(&(1000 - 500 - 2 * CONFIG.transaction_fee))
+        );
+
+        // overspending and non-controller proposals are rejected
+        let receiver = pr(3).to_string();
+        let spend = |tokens| Payload::RealmSpend(realm.clone(), receiver.clone(), tokens);
+        assert_eq!(
+            propose(&mut state, pr(1), "test".into(), spend(6), 0).await,
+            Err("insufficient realm treasury".into())
+        );
+        assert_eq!(
+            propose(&mut state, pr(2), "test".into(), spend(3), 0).await,
+            Err("only realm controllers can propose treasury spendings".into())
+        );
+        let id = propose(&mut state, pr(1), "test".into(), spend(3), 0)
+            .await
+            .expect("couldn't create proposal");
+
+        // only realm members vote and their stake makes the voting power
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, pr(3), id, true).await,
+            Err("only realm members can vote on this proposal".into())
+        );
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        assert_eq!(state.proposals[id as usize].status, Status::Adopted);
+
+        let time = CONFIG.proposal_timelock_funding;
+        assert_eq!(execute_proposal(&mut state, id, time).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::Executed);
+        assert_eq!(state.realms.get(&realm).unwrap().treasury, 200);
+        assert_eq!(
+            state.balances.get(&account(pr(3))),
+            Some(&(1000 - 200 - 2 * CONFIG.transaction_fee))
+        );
+
+        // the treasury is checked again on execution
+        let id = propose(&mut state, pr(1), "test".into(), spend(2), time)
+            .await
+            .unwrap();
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, time, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        state.realms.get_mut(&realm).unwrap().treasury = 100;
+        assert_eq!(execute_proposal(&mut state, id, 2 * time).await, Ok(()));
+        let proposal = &state.proposals[id as usize];
+        assert_eq!(proposal.status, Status::Failed);
+        assert_eq!(proposal.error, Some("insufficient realm treasury".into()));
+        assert_eq!(state.realms.get(&realm).unwrap().treasury, 100);
+    }
+
+    #[actix_rt::test]
+    async fn test_vote_delegation() {
+        let mut state = State::default();
+        // user ids 0..6 with principals 1..7
+        for i in 1..=6 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            state.balances.insert(account(pr(i)), 1000);
+        }
+        let p = |id: UserId| pr(id as u8 + 1);
+        assert_eq!(
+            delegate(&mut state, p(0), None, Some(0)),
+            Err("you can't delegate to yourself".into())
+        );
+        assert_eq!(
+            delegate(&mut state, p(0), Some("memes".into()), Some(1)),
+            Err("unknown topic".into())
+        );
+
+        // 0 and 1 delegate in a cycle, 2 -> 3 -> 4 with a topic override 2 -> 5
+        for (from, to) in [(0, 1), (1, 0), (2, 3), (3, 4)].iter() {
+            assert_eq!(delegate(&mut state, p(*from), None, Some(*to)), Ok(()));
+        }
+        assert_eq!(
+            delegate(&mut state, p(2), Some("noop".into()), Some(5)),
+            Ok(())
+        );
+
+        let votes = vec![(4, false), (5, true)].into_iter().collect();
+        assert_eq!(delegated_vote(&state, 3, "noop", &votes), Some(false));
+        assert_eq!(delegated_vote(&state, 2, "noop", &votes), Some(true));
+        assert_eq!(delegated_vote(&state, 2, "release", &votes), Some(false));
+        assert_eq!(delegated_vote(&state, 0, "noop", &votes), None);
+
+        // every payload topic can be delegated
+        let topic = Payload::SetCoordinationSettings(Default::default()).topic();
+        assert_eq!(
+            delegate(&mut state, p(2), Some(topic.into()), Some(4)),
+            Ok(())
+        );
+        assert_eq!(delegated_vote(&state, 2, topic, &votes), Some(false));
+        assert_eq!(delegate(&mut state, p(2), Some(topic.into()), None), Ok(()));
+        assert_eq!(delegated_vote(&state, 1, "release", &votes), None);
+        let votes = vec![(5, true)].into_iter().collect();
+        assert_eq!(delegated_vote(&state, 2, "release", &votes), None);
+
+        // delegated power counts in the tally; 0 overrides its delegation by voting directly
+        let id = propose(&mut state, p(0), "test".into(), Payload::Noop, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, p(0), id, false).await,
+            Ok(())
+        );
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, p(4), id, true).await,
+            Ok(())
+        );
+        // 0 and 1 reject, 3 and 4 approve, 2 waits for 5
+        assert_eq!(state.proposals[id as usize].status, Status::Open);
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, p(5), id, true).await,
+            Ok(())
+        );
+        assert_eq!(state.proposals[id as usize].status, Status::Executed);
+    }
+
+    #[actix_rt::test]
+    async fn test_delegated_power_snapshot() {
+        let mut state = State::default();
+        for i in 1..=4 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            state.balances.insert(account(pr(i)), 1000);
+        }
+        assert_eq!(delegate(&mut state, pr(4), None, Some(0)), Ok(()));
+        let id = propose(&mut state, pr(1), "test".into(), Payload::Noop, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, pr(1), id, true).await,
+            Ok(())
+        );
+        assert_eq!(state.proposals[id as usize].status, Status::Open);
+
+        // moving the voted tokens to the delegator doesn't count them twice
+        state.balances.insert(account(pr(1)), 0);
+        state.balances.insert(account(pr(4)), 2000);
+        assert_eq!(execute_proposal(&mut state, id, 0).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::Open);
+    }
+
+    #[actix_rt::test]
+    async fn test_proposal_discussion() {
+        let mut state = State::default();
+        for i in 1..=3 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            state.balances.insert(account(pr(i)), 1000);
+        }
+        let id = propose(&mut state, pr(1), "test".into(), Payload::Noop, 0)
+            .await
+            .unwrap();
+        let post_id = state.proposals[id as usize].post_id;
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, pr(2), id, true).await,
+            Ok(())
+        );
+
+        // voters get notified about top-level comments only
+        let inbox = |state: &State| state.principal_to_user(pr(2)).unwrap().inbox.len();
+        let voter_inbox = inbox(&state);
+        let comment_id = post::add(
+            &mut state,
+            "Why?".into(),
+            vec![],
+            pr(3),
+            0,
+            Some(post_id),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(inbox(&state), voter_inbox + 1);
+        post::add(
+            &mut state,
+            "Because.".into(),
+            vec![],
+            pr(1),
+            0,
+            Some(comment_id),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(inbox(&state), voter_inbox + 1);
+
+        // the thread becomes read-only after the resolution
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, pr(3), id, true).await,
+            Ok(())
+        );
+        assert_eq!(state.proposals[id as usize].status, Status::Executed);
+        for parent in [post_id, comment_id].iter() {
+            assert_eq!(
+                post::add(
+                    &mut state,
+                    "Too late".into(),
+                    vec![],
+                    pr(3),
+                    0,
+                    Some(*parent),
+                    None,
+                    None,
+                )
+                .await,
+                Err("the proposal discussion is closed".into())
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_proposal_quorum() {
+        let mut state = State::default();
+        for i in 1..=10 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            state.balances.insert(account(pr(i)), 1000);
+        }
+        assert_eq!(state.proposal_thresholds, Thresholds::default());
+        let end = CONFIG.proposal_voting_period;
+
+        // too little participation by the end of the voting period
+        let id = propose(&mut state, pr(1), "test".into(), Payload::Noop, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            vote_on_proposal(&mut state, 0, pr(1), id, true).await,
+            Ok(())
+        );
+        assert_eq!(execute_proposal(&mut state, id, end - 1).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::Open);
+        assert_eq!(execute_proposal(&mut state, id, end).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::NoQuorum);
+
+        // enough participation, but not enough approvals
+        let id = propose(&mut state, pr(1), "test".into(), Payload::Noop, 0)
+            .await
+            .unwrap();
+        for i in 1..=4 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, i % 2 == 0).await,
+                Ok(())
+            );
+        }
+        assert_eq!(state.proposals[id as usize].status, Status::Open);
+        assert_eq!(execute_proposal(&mut state, id, end).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::Rejected);
+
+        // thresholds are changed via proposals only
+        let thresholds = |quorum, approval| Payload::SetThresholds(Thresholds { quorum, approval });
+        assert_eq!(
+            propose(&mut state, pr(1), "test".into(), thresholds(0, 60), 0).await,
+            Err("the quorum must be between 1 and 100 percent".into())
+        );
+        assert_eq!(
+            propose(&mut state, pr(1), "test".into(), thresholds(10, 50), 0).await,
+            Err("the approval must be between 51 and 99 percent".into())
+        );
+        let id = propose(&mut state, pr(1), "test".into(), thresholds(10, 51), 0)
+            .await
+            .unwrap();
+        for i in 1..=7 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        assert_eq!(state.proposals[id as usize].status, Status::Adopted);
+        let time = CONFIG.proposal_timelock_controller;
+        assert_eq!(execute_proposal(&mut state, id, time).await, Ok(()));
+        assert_eq!(
+            state.proposal_thresholds,
+            Thresholds {
+                quorum: 10,
+                approval: 51
+            }
+        );
+
+        // the new thresholds apply at the next tally
+        let id = propose(&mut state, pr(1), "test".into(), Payload::Noop, time)
+            .await
+            .unwrap();
+        assert_eq!(
+            vote_on_proposal(&mut state, time, pr(1), id, true).await,
+            Ok(())
+        );
+        assert_eq!(execute_proposal(&mut state, id, time + end).await, Ok(()));
+        assert_eq!(state.proposals[id as usize].status, Status::Executed);
+    }
+
+    #[actix_rt::test]
+    async fn test_payload_execution() {
+        let mut state = State::default();
+        for i in 1..=3 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            state.balances.insert(account(pr(i)), 1000);
+        }
+
+        // a config change is applied on execution
+        let id = propose(
+            &mut state,
+            pr(1),
+            "test".into(),
+            Payload::SetMaxBucketSize(1024),
+            0,
+        )
+        .await
+        .unwrap();
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        let proposal = &state.proposals[id as usize];
+        assert_eq!(proposal.status, Status::Executed);
+        assert_eq!(proposal.error, None);
+        assert_eq!(state.storage.max_bucket_size(), 1024);
+
+        // coordination detector settings are validated and applied on execution
+        let mut settings = crate::env::coordination::Settings {
+            min_accounts: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            propose(
+                &mut state,
+                pr(1),
+                "test".into(),
+                Payload::SetCoordinationSettings(settings.clone()),
+                0,
+            )
+            .await,
+            Err("a burst requires at least 2 accounts".into())
+        );
+        settings.min_accounts = 2;
+        settings.auto_hold = true;
+        let id = propose(
+            &mut state,
+            pr(1),
+            "test".into(),
+            Payload::SetCoordinationSettings(settings.clone()),
+            0,
+        )
+        .await
+        .unwrap();
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        assert_eq!(state.proposals[id as usize].status, Status::Executed);
+        assert_eq!(state.detector.settings, settings);
+
+        // a failing payload gets its error recorded instead of failing the last vote
+        let id = propose(
+            &mut state,
+            pr(1),
+            "test".into(),
+            Payload::RevokeVesting(7),
+            0,
+        )
+        .await
+        .unwrap();
+        for i in 1..=2 {
+            assert_eq!(
+                vote_on_proposal(&mut state, 0, pr(i), id, true).await,
+                Ok(())
+            );
+        }
+        let time = CONFIG.proposal_timelock_funding;
+        assert_eq!(execute_proposal(&mut state, id, time).await, Ok(()));
+        let proposal = &state.proposals[id as usize];
+        assert_eq!(proposal.status, Status::Failed);
+        assert_eq!(proposal.error, Some("no vesting found".into()));
+        assert!(state
+            .logger
+            .events
+            .iter()
+            .any(|event| event.message.contains("no vesting found")));
+
+        // failed proposals are final
+        assert_eq!(
+            execute_proposal(&mut state, id, time).await,
+            Err("last proposal is not open".into())
+        );
+    }
 }
@@ -32,6 +32,153 @@ impl Report {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Resolution {
+    Dismissed,
+    Removed,
+}
+
+// A report of a realm post, triaged by the realm controllers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RealmReport {
+    // reporter -> (reason, timestamp)
+    pub reporters: BTreeMap<UserId, (String, u64)>,
+    // the resolution, the controller who resolved the report and the time
+    pub resolution: Option<(Resolution, UserId, u64)>,
+}
+
+pub fn report_to_realm(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    reason: String,
+    time: u64,
+) -> Result<(), String> {
+    if reason.len() > 1000 {
+        return Err("reason too long".into());
+    }
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let post = state.posts.get_mut(&post_id).ok_or("no post found")?;
+    if post.user == user_id {
+        return Err("you can't report your own post".into());
+    }
+    let realm_name = post.realm.clone().ok_or("post isn't in a realm")?;
+    // a report on a resolved post opens a new one
+    if post
+        .realm_report
+        .as_ref()
+        .map(|report| report.resolution.is_some())
+        .unwrap_or(true)
+    {
+        post.realm_report = Some(Default::default());
+    }
+    let report = post.realm_report.as_mut().expect("no report found");
+    // repeated reports of the same user are ignored
+    if report.reporters.contains_key(&user_id) {
+        return Ok(());
+    }
+    report.reporters.insert(user_id, (reason, time));
+    let new_report = report.reporters.len() == 1;
+    let realm = state.realms.get_mut(&realm_name).ok_or("no realm found")?;
+    realm.reports.insert(post_id);
+    if new_report {
+        for controller in realm.controllers.clone() {
+            if let Some(user) = state.users.get_mut(&controller) {
+                user.notify_about_post(
+                    format!("A post in realm {} was reported", realm_name),
+                    post_id,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns posts with open reports of the realm.
+pub fn realm_reports(
+    state: &State,
+    principal: Principal,
+    realm: &str,
+) -> Result<Vec<Post>, String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let realm = state.realms.get(realm).ok_or("no realm found")?;
+    if !realm.controllers.contains(&user_id) {
+        return Err("not authorized".into());
+    }
+    Ok(realm
+        .reports
+        .iter()
+        .filter_map(|id| state.posts.get(id))
+        .map(Post::restored)
+        .collect())
+}
+
+pub fn resolve_realm_report(
+    state: &mut State,
+    principal: Principal,
+    post_id: PostId,
+    resolution: Resolution,
+    time: u64,
+) -> Result<(), String> {
+    let user_id = state
+        .principal_to_user(principal)
+        .ok_or("no user found")?
+        .id;
+    let post = state.posts.get(&post_id).ok_or("no post found")?;
+    let realm_name = post.realm.clone().ok_or("post isn't in a realm")?;
+    let realm = state.realms.get_mut(&realm_name).ok_or("no realm found")?;
+    if !realm.controllers.contains(&user_id) {
+        return Err("not authorized".into());
+    }
+    if !matches!(&post.realm_report, Some(report) if report.resolution.is_none()) {
+        return Err("no open report found".into());
+    }
+    realm.reports.remove(&post_id);
+    // posts held as suspected spam are released or removed with the report
+    state.detector.held.remove(&post_id);
+    let post = state.posts.get_mut(&post_id).expect("no post found");
+    let files = post.files.clone();
+    if resolution == Resolution::Removed {
+        post.delete(vec![post.body.clone()]);
+    }
+    let report = post.realm_report.as_mut().expect("no report found");
+    report.resolution = Some((resolution, user_id, time));
+    let reporters = report.reporters.keys().copied().collect::<Vec<_>>();
+    let author = post.user;
+    if resolution == Resolution::Removed {
+        state.release_files(post_id, &files);
+        if let Some(user) = state.users.get_mut(&author) {
+            user.notify_about_post(
+                format!(
+                    "Your post was removed by the controllers of realm {}",
+                    realm_name
+                ),
+                post_id,
+            );
+        }
+    }
+    for reporter in reporters {
+        if let Some(user) = state.users.get_mut(&reporter) {
+            user.notify_about_post(
+                match resolution {
+                    Resolution::Dismissed => "Your report was dismissed by the realm controllers",
+                    Resolution::Removed => {
+                        "The post reported by you was removed by the realm controllers"
+                    }
+                },
+                post_id,
+            );
+        }
+    }
+    Ok(())
+}
+
 pub fn vote_on_report(state: &mut State, principal: Principal, post_id: PostId, vote: bool) {
     let user = state
         .principal_to_user(principal)
@@ -42,6 +189,7 @@ pub fn vote_on_report(state: &mut State, principal: Principal, post_id: PostId,
     }
     let stalwarts = state.users.values().filter(|u| u.stalwart).count();
     let post = state.posts.get_mut(&post_id).expect("no post found");
+    let files = post.files.clone();
     post.vote_on_report(stalwarts, user.id, vote);
     let report = match &post.report {
         Some(report) if report.closed => report.clone(),
@@ -65,6 +213,7 @@ pub fn vote_on_report(state: &mut State, principal: Principal, post_id: PostId,
             .get_mut(&report.reporter)
             .expect("no user found");
         reporter.notify_about_post(format!("The post reported by you was deleted by stalwarts. Thanks for keeping {} safe and clean!", CONFIG.name), post.id);
+        state.release_files(post_id, &files);
         state
             .cycle_transfer(
                 post_author_id,
@@ -358,4 +507,121 @@ mod tests {
             CONFIG.stalwart_moderation_reward * 2
         );
     }
+
+    #[actix_rt::test]
+    async fn test_realm_reports() {
+        let mut state = State::default();
+        for i in 0..4 {
+            create_user(&mut state, pr(i));
+        }
+        let realm = "MODERATED".to_string();
+        state
+            .users
+            .get_mut(&0)
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        assert_eq!(
+            state.create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0]
+            ),
+            Ok(())
+        );
+        assert!(state.toggle_realm_membership(pr(1), realm.clone()));
+        let post_id = add(
+            &mut state,
+            "bad post".to_string(),
+            vec![],
+            pr(1),
+            0,
+            None,
+            Some(realm.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report_to_realm(&mut state, pr(1), post_id, "spam".into(), 0),
+            Err("you can't report your own post".into())
+        );
+        // repeated reports of the same user are deduplicated
+        for time in 0..3 {
+            assert_eq!(
+                report_to_realm(&mut state, pr(2), post_id, "spam".into(), time),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            report_to_realm(&mut state, pr(3), post_id, "offensive".into(), 5),
+            Ok(())
+        );
+        let report = state.posts.get(&post_id).unwrap().realm_report.clone();
+        assert_eq!(
+            report.unwrap().reporters.into_iter().collect::<Vec<_>>(),
+            vec![(2, ("spam".into(), 0)), (3, ("offensive".into(), 5))]
+        );
+
+        // only controllers can see the queue and resolve reports
+        assert_eq!(
+            realm_reports(&state, pr(2), &realm).err(),
+            Some("not authorized".into())
+        );
+        let queue = realm_reports(&state, pr(0), &realm).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].id, post_id);
+        assert_eq!(
+            resolve_realm_report(&mut state, pr(2), post_id, Resolution::Removed, 10),
+            Err("not authorized".into())
+        );
+
+        // dismissal closes the report and keeps the post
+        assert_eq!(
+            resolve_realm_report(&mut state, pr(0), post_id, Resolution::Dismissed, 10),
+            Ok(())
+        );
+        let post = state.posts.get(&post_id).unwrap();
+        assert_eq!(post.body, "bad post");
+        assert_eq!(
+            post.realm_report.as_ref().unwrap().resolution,
+            Some((Resolution::Dismissed, 0, 10))
+        );
+        assert!(realm_reports(&state, pr(0), &realm).unwrap().is_empty());
+        assert_eq!(
+            resolve_realm_report(&mut state, pr(0), post_id, Resolution::Removed, 11),
+            Err("no open report found".into())
+        );
+
+        // a new report reopens the post and removal deletes it
+        assert_eq!(
+            report_to_realm(&mut state, pr(2), post_id, "still spam".into(), 20),
+            Ok(())
+        );
+        let report = state
+            .posts
+            .get(&post_id)
+            .unwrap()
+            .realm_report
+            .clone()
+            .unwrap();
+        assert_eq!(report.reporters.len(), 1);
+        assert!(report.resolution.is_none());
+        assert_eq!(realm_reports(&state, pr(0), &realm).unwrap().len(), 1);
+        assert_eq!(
+            resolve_realm_report(&mut state, pr(0), post_id, Resolution::Removed, 30),
+            Ok(())
+        );
+        let post = state.posts.get(&post_id).unwrap();
+        assert!(post.body.is_empty());
+        assert_eq!(
+            post.realm_report.as_ref().unwrap().resolution,
+            Some((Resolution::Removed, 0, 30))
+        );
+        assert!(realm_reports(&state, pr(0), &realm).unwrap().is_empty());
+    }
 }
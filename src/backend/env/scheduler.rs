@@ -0,0 +1,314 @@
+use super::config::CONFIG;
+use super::{
+    audit, bounties, ckbtc, post, proposals, proposals::Status, webhooks, State, HOUR, MINUTE,
+};
+use ic_cdk::api::canister_balance;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Task {
+    ExecuteProposals,
+    PublishScheduled,
+    ClosePolls,
+    RefundBounties,
+    PurgeDeletedPosts,
+    Webhooks,
+    Recoveries,
+    Audit,
+    ReferralRewards,
+    RealmRewards,
+    RealmTiers,
+    FeaturedPost,
+    DecayKarma,
+    BucketTopUps,
+    StorageMetrics,
+    CkbtcWithdrawals,
+}
+
+impl Task {
+    // Tasks making inter-canister calls; the instruction counter restarts after their first await.
+    fn awaits(&self) -> bool {
+        matches!(
+            self,
+            Task::ExecuteProposals
+                | Task::PublishScheduled
+                | Task::Webhooks
+                | Task::BucketTopUps
+                | Task::CkbtcWithdrawals
+        )
+    }
+}
+
+// Every task runs at most once per interval; due tasks with a lower priority value run first,
+// except for tasks making calls, which run after all other ones.
+pub const TASKS: &[(Task, u64, u8)] = &[
+    (Task::ExecuteProposals, 15 * MINUTE, 0),
+    (Task::PublishScheduled, 15 * MINUTE, 1),
+    (Task::ClosePolls, 15 * MINUTE, 1),
+    (Task::RefundBounties, HOUR, 2),
+    (Task::PurgeDeletedPosts, HOUR, 3),
+    (Task::Webhooks, 15 * MINUTE, 2),
+    (Task::Recoveries, HOUR, 2),
+    (Task::Audit, 15 * MINUTE, 3),
+    (Task::ReferralRewards, HOUR, 4),
+    (Task::RealmRewards, HOUR, 4),
+    (Task::RealmTiers, HOUR, 4),
+    (Task::FeaturedPost, HOUR, 4),
+    (Task::DecayKarma, 6 * HOUR, 5),
+    (Task::BucketTopUps, HOUR, 5),
+    (Task::StorageMetrics, HOUR, 6),
+    (Task::CkbtcWithdrawals, 15 * MINUTE, 2),
+];
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Scheduler {
+    // the time at which every task is due next
+    next_runs: BTreeMap<Task, u64>,
+    // replaces the instruction counter if set
+    #[serde(skip)]
+    counter: Option<Box<dyn Fn() -> u64>>,
+}
+
+impl Scheduler {
+    fn instructions(&self) -> u64 {
+        match &self.counter {
+            Some(counter) => counter(),
+            None => instruction_counter(),
+        }
+    }
+
+    // Returns the due tasks ordered by priority. Tasks seen for the first time get staggered
+    // over their interval, so that tasks with the same interval don't all run in the same tick.
+    fn due(&mut self, tasks: &[(Task, u64, u8)], now: u64) -> Vec<(Task, u64)> {
+        for (i, (task, interval, _)) in tasks.iter().enumerate() {
+            self.next_runs
+                .entry(*task)
+                .or_insert(now + interval * i as u64 / tasks.len() as u64);
+        }
+        let mut due = tasks
+            .iter()
+            .filter(|(task, _, _)| self.next_runs.get(task).copied().unwrap_or_default() <= now)
+            .collect::<Vec<_>>();
+        due.sort_by_key(|(_, _, priority)| *priority);
+        due.into_iter()
+            .map(|(task, interval, _)| (*task, *interval))
+            .collect()
+    }
+
+    // Moves the next run of the task by its interval past the current time, keeping its phase.
+    fn done(&mut self, task: Task, interval: u64, now: u64) {
+        let next_run = self.next_runs.entry(task).or_default();
+        let missed = (now.saturating_sub(*next_run) / interval.max(1)) + 1;
+        *next_run += missed * interval;
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn instruction_counter() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn instruction_counter() -> u64 {
+    0
+}
+
+async fn execute(state: &mut State, task: Task, now: u64) {
+    match task {
+        Task::ExecuteProposals => {
+            // execute adopted proposals with an elapsed timelock
+            for proposal_id in state
+                .proposals
+                .iter()
+                .filter(|p| p.status == Status::Adopted)
+                .map(|p| p.id)
+                .take(CONFIG.max_timelocked_executions)
+                .collect::<Vec<_>>()
+            {
+                if let Err(err) = proposals::execute_proposal(state, proposal_id, now).await {
+                    state
+                        .logger
+                        .error(format!("Couldn't execute adopted proposal: {:?}", err));
+                }
+            }
+        }
+        Task::PublishScheduled => post::publish_scheduled(state, now).await,
+        Task::ClosePolls => post::close_polls(state, now),
+        Task::RefundBounties => bounties::refund_expired(state, now),
+        Task::PurgeDeletedPosts => state.purge_deleted_posts(now),
+        Task::Webhooks => webhooks::deliver(state, now).await,
+        Task::Recoveries => state.complete_recoveries(now),
+        Task::Audit => {
+            if state.audit.is_some() {
+                audit::step(state, CONFIG.karma_audit_batch_size);
+            }
+        }
+        Task::ReferralRewards => state.reward_referrals(),
+        Task::RealmRewards => state.distribute_realm_rewards(now),
+        Task::RealmTiers => state.recompute_realm_tiers_batch(CONFIG.realm_tiers_batch_size),
+        Task::FeaturedPost => state.select_featured_post(now),
+        Task::DecayKarma => state.decay_karma(now),
+        Task::BucketTopUps => {
+            state
+                .storage
+                .top_up_buckets(&mut state.logger, canister_balance(), now)
+                .await;
+        }
+        Task::CkbtcWithdrawals => ckbtc::retry_withdrawals(state).await,
+        Task::StorageMetrics => {
+            state
+                .storage
+                .report_metrics(&mut state.logger, state.memory.metrics(), now);
+        }
+    }
+}
+
+// Runs the due tasks by priority until the instruction budget of the tick is exhausted; the
+// remaining tasks stay due and run first in the next tick. Returns the executed tasks.
+// The instruction counter only covers the current message, so the budget is checked before the
+// first await only: tasks making calls run after all other tasks, and once the first of them has
+// run, the remaining ones run without checks.
+pub async fn run(state: &mut State, tasks: &[(Task, u64, u8)], now: u64) -> Vec<Task> {
+    let start = state.scheduler.instructions();
+    let mut due = state.scheduler.due(tasks, now);
+    due.sort_by_key(|(task, _)| task.awaits());
+    let mut executed = Vec::new();
+    let mut awaited = false;
+    for (task, interval) in &due {
+        if !awaited
+            && state.scheduler.instructions().saturating_sub(start)
+                >= CONFIG.chores_instruction_budget
+        {
+            state.logger.info(format!(
+                "Deferred `{}` scheduled tasks to the next tick: the instruction budget is exhausted.",
+                due.len() - executed.len()
+            ));
+            break;
+        }
+        execute(state, *task, now).await;
+        awaited |= task.awaits();
+        state.scheduler.done(*task, *interval, now);
+        executed.push(*task);
+    }
+    executed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_task_staggering() {
+        let mut scheduler = Scheduler::default();
+        let tasks = [
+            (Task::ClosePolls, 4 * HOUR, 0),
+            (Task::RefundBounties, 4 * HOUR, 0),
+            (Task::Recoveries, 4 * HOUR, 0),
+            (Task::ReferralRewards, 4 * HOUR, 0),
+        ];
+        // with the same interval, every task runs in a different tick
+        for round in 0..3 {
+            for (i, (task, interval, _)) in tasks.iter().enumerate() {
+                let now = round * 4 * HOUR + i as u64 * HOUR;
+                assert_eq!(scheduler.due(&tasks, now), vec![(*task, *interval)]);
+                scheduler.done(*task, *interval, now);
+            }
+        }
+
+        // missed runs are skipped without losing the phase
+        let now = 20 * HOUR + 30 * MINUTE;
+        assert_eq!(scheduler.due(&tasks, now).len(), 4);
+        scheduler.done(Task::Recoveries, 4 * HOUR, now);
+        assert_eq!(scheduler.next_runs[&Task::Recoveries], 22 * HOUR);
+    }
+
+    #[actix_rt::test]
+    async fn test_budget_deferral() {
+        let mut state = State::default();
+        // every check of the counter consumes half of the budget
+        let counter = Rc::new(Cell::new(0));
+        let mock = counter.clone();
+        state.scheduler.counter = Some(Box::new(move || {
+            mock.set(mock.get() + CONFIG.chores_instruction_budget / 2);
+            mock.get()
+        }));
+        let tasks = [
+            (Task::RefundBounties, HOUR, 2),
+            (Task::ClosePolls, HOUR, 1),
+            (Task::Recoveries, HOUR, 0),
+        ];
+        state.scheduler.next_runs = tasks.iter().map(|(task, _, _)| (*task, 0)).collect();
+
+        // the budget suffices for one task per tick, which gets picked by priority
+        assert_eq!(run(&mut state, &tasks, 0).await, vec![Task::Recoveries]);
+        assert!(state
+            .logger
+            .events
+            .last()
+            .unwrap()
+            .message
+            .contains("Deferred `2` scheduled tasks"));
+        assert_eq!(
+            run(&mut state, &tasks, 15 * MINUTE).await,
+            vec![Task::ClosePolls]
+        );
+        assert_eq!(
+            run(&mut state, &tasks, 30 * MINUTE).await,
+            vec![Task::RefundBounties]
+        );
+        // all tasks ran, so nothing is due until the next interval
+        assert!(run(&mut state, &tasks, 45 * MINUTE).await.is_empty());
+        assert_eq!(run(&mut state, &tasks, HOUR).await, vec![Task::Recoveries]);
+    }
+
+    #[actix_rt::test]
+    async fn test_budget_before_first_await() {
+        let mut state = State::default();
+        let checks = Rc::new(Cell::new(0));
+        let mock = checks.clone();
+        state.scheduler.counter = Some(Box::new(move || {
+            mock.set(mock.get() + 1);
+            0
+        }));
+        let tasks = [
+            (Task::ExecuteProposals, HOUR, 0),
+            (Task::PublishScheduled, HOUR, 1),
+            (Task::ClosePolls, HOUR, 2),
+            (Task::RefundBounties, HOUR, 3),
+        ];
+        state.scheduler.next_runs = tasks.iter().map(|(task, _, _)| (*task, 0)).collect();
+
+        // tasks making calls run last and the budget isn't checked after the first of them
+        assert_eq!(
+            run(&mut state, &tasks, 0).await,
+            vec![
+                Task::ClosePolls,
+                Task::RefundBounties,
+                Task::ExecuteProposals,
+                Task::PublishScheduled
+            ]
+        );
+        // one read for the start and one check before each task up to the first await
+        assert_eq!(checks.get(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_webhooks_after_synchronous_tasks() {
+        let mut state = State::default();
+        let tasks = [
+            (Task::Webhooks, HOUR, 0),
+            (Task::DecayKarma, HOUR, 5),
+            (Task::Recoveries, HOUR, 6),
+        ];
+        state.scheduler.next_runs = tasks.iter().map(|(task, _, _)| (*task, 0)).collect();
+
+        // webhook deliveries make HTTP outcalls, so they run after the synchronous tasks
+        assert_eq!(
+            run(&mut state, &tasks, 0).await,
+            vec![Task::DecayKarma, Task::Recoveries, Task::Webhooks]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use super::config::CONFIG;
+use super::post::{Post, PostId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// Increase to rebuild the index on the next upgrade, e.g. after a change of the tokenization.
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    version: u32,
+    // normalized term -> post id -> term frequency
+    terms: BTreeMap<String, BTreeMap<PostId, u32>>,
+}
+
+// Splits the text into lowercased alphanumeric terms with their frequencies.
+pub fn terms(text: &str) -> BTreeMap<String, u32> {
+    let mut terms = BTreeMap::new();
+    for term in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty() && term.chars().count() <= CONFIG.max_search_term_length)
+    {
+        *terms.entry(term.to_lowercase()).or_default() += 1;
+    }
+    terms
+}
+
+impl SearchIndex {
+    // Indexes all posts which are not deleted.
+    pub fn build(posts: &HashMap<PostId, Post>) -> Self {
+        let mut index = Self {
+            version: INDEX_VERSION,
+            ..Default::default()
+        };
+        for post in posts.values().filter(|post| !post.deleted()) {
+            index.add(post.id, &post.text());
+        }
+        index
+    }
+
+    pub fn outdated(&self) -> bool {
+        self.version != INDEX_VERSION
+    }
+
+    pub fn add(&mut self, post_id: PostId, text: &str) {
+        for (term, count) in terms(text) {
+            self.terms.entry(term).or_default().insert(post_id, count);
+        }
+    }
+
+    pub fn remove(&mut self, post_id: PostId, text: &str) {
+        for term in terms(text).keys() {
+            if let Some(posts) = self.terms.get_mut(term) {
+                posts.remove(&post_id);
+                if posts.is_empty() {
+                    self.terms.remove(term);
+                }
+            }
+        }
+    }
+
+    // Returns the posts containing all terms of the query with the total frequency of these terms.
+    pub fn lookup(&self, query: &str) -> Vec<(PostId, u32)> {
+        let mut lists = Vec::new();
+        for term in terms(query).keys() {
+            match self.terms.get(term) {
+                Some(posts) => lists.push(posts),
+                None => return Default::default(),
+            }
+        }
+        lists.sort_unstable_by_key(|posts| posts.len());
+        let (shortest, rest) = match lists.split_first() {
+            Some(lists) => lists,
+            None => return Default::default(),
+        };
+        shortest
+            .iter()
+            .filter_map(|(id, count)| {
+                rest.iter()
+                    .try_fold(*count, |total, posts| posts.get(id).map(|c| total + c))
+                    .map(|total| (*id, total))
+            })
+            .collect()
+    }
+}
+
+// Sorts the hits by the term frequency discounted by the post age, newer posts first on ties.
+pub fn rank(hits: &mut [(&Post, u32)], now: u64) {
+    let period = CONFIG.search_recency_period as f64;
+    let score = |post: &Post, count: u32| {
+        count as f64 * period / (period + now.saturating_sub(post.timestamp) as f64)
+    };
+    hits.sort_by(|(a, a_count), (b, b_count)| {
+        score(b, *b_count)
+            .partial_cmp(&score(a, *a_count))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.id.cmp(&a.id))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post::{add, edit},
+        tests::{create_user, pr},
+        State,
+    };
+
+    async fn new_post(state: &mut State, body: &str) -> PostId {
+        let time = state.posts.len() as u64 * CONFIG.rate_limit_window;
+        add(state, body.into(), vec![], pr(0), time, None, None, None)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_terms() {
+        let terms = terms("Hello, #World! hello-world @bob");
+        assert_eq!(terms.get("hello"), Some(&2));
+        assert_eq!(terms.get("world"), Some(&2));
+        assert_eq!(terms.get("bob"), Some(&1));
+        assert_eq!(terms.len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_incremental_updates() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let id = new_post(&mut state, "Rust canisters").await;
+        let other = new_post(&mut state, "Rust is fun").await;
+        assert_eq!(state.search_index.lookup("rust"), vec![(id, 1), (other, 1)]);
+
+        edit(
+            &mut state,
+            id,
+            "Motoko canisters".into(),
+            vec![],
+            "".into(),
+            None,
+            pr(0),
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.search_index.lookup("rust"), vec![(other, 1)]);
+        assert_eq!(state.search_index.lookup("motoko"), vec![(id, 1)]);
+        assert_eq!(state.search_index.lookup("canisters"), vec![(id, 1)]);
+
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(1000, "")
+            .unwrap();
+        state.delete_post(pr(0), id, vec![], 0).unwrap();
+        assert!(state.search_index.lookup("motoko").is_empty());
+        assert!(!state.search_index.terms.contains_key("canisters"));
+
+        // a rebuilt index matches the incrementally maintained one
+        let index = SearchIndex::build(&state.posts);
+        assert!(!index.outdated());
+        assert_eq!(index.terms, state.search_index.terms);
+    }
+
+    #[actix_rt::test]
+    async fn test_multi_term_ranking() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let old = new_post(&mut state, "taggr search search search").await;
+        let single = new_post(&mut state, "taggr search").await;
+        new_post(&mut state, "search only").await;
+        let frequent = new_post(&mut state, "taggr taggr search search").await;
+        let newest = new_post(&mut state, "search taggr").await;
+        state.posts.get_mut(&old).unwrap().timestamp = 0;
+        let now = CONFIG.search_recency_period;
+        for id in [single, frequent, newest].iter() {
+            state.posts.get_mut(id).unwrap().timestamp = now;
+        }
+
+        // only posts containing all terms match
+        let hits = state.search_index.lookup("Search TAGGR");
+        assert_eq!(hits.len(), 4);
+
+        let mut hits = hits
+            .into_iter()
+            .map(|(id, count)| (state.posts.get(&id).unwrap(), count))
+            .collect::<Vec<_>>();
+        rank(&mut hits, now);
+        assert_eq!(
+            hits.iter().map(|(post, _)| post.id).collect::<Vec<_>>(),
+            // the old post with 4 hits scores like a new post with 2 hits
+            vec![frequent, newest, single, old]
+        );
+    }
+}
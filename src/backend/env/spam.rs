@@ -0,0 +1,188 @@
+use super::config::CONFIG;
+use super::post::PostId;
+use super::State;
+
+// Computes the spam score of a post from the link density, repeated content of the author
+// and the author's account age and karma.
+pub fn score(state: &State, post_id: PostId) -> u32 {
+    let post = match state.posts.get(&post_id) {
+        Some(post) => post,
+        None => return 0,
+    };
+    let user = match state.users.get(&post.user) {
+        Some(user) => user,
+        None => return 0,
+    };
+    let words = post.body.split_whitespace().count() as u32;
+    let links = post
+        .body
+        .split_whitespace()
+        .filter(|word| word.contains("http://") || word.contains("https://"))
+        .count() as u32;
+    let mut score = CONFIG.spam_link_density_weight * links / words.max(1);
+
+    let body = post.body.trim().to_lowercase();
+    if user
+        .posts
+        .iter()
+        .rev()
+        .filter(|id| **id != post_id)
+        .take(CONFIG.spam_duplicate_lookback)
+        .filter_map(|id| state.posts.get(id))
+        .any(|other| other.body.trim().to_lowercase() == body)
+    {
+        score += CONFIG.spam_duplicate_weight;
+    }
+
+    if post.timestamp.saturating_sub(user.timestamp) < CONFIG.spam_new_account_age {
+        score += CONFIG.spam_new_account_weight;
+    }
+    if user.karma() < CONFIG.trusted_user_min_karma {
+        score += CONFIG.spam_low_karma_weight;
+    }
+    score
+}
+
+// Holds the post for review if its spam score reaches the threshold: realm posts go to the
+// moderation queue of the realm, other posts are released by stalwarts.
+// Returns true if the post was held.
+pub fn check(state: &mut State, post_id: PostId) -> bool {
+    let score = score(state, post_id);
+    if score < CONFIG.spam_hold_threshold {
+        return false;
+    }
+    state.detector.held.insert(post_id);
+    let post = state.posts.get_mut(&post_id).expect("no post found");
+    let message = format!(
+        "Post [{0}](#/post/{0}) was held for review with a spam score of `{1}`",
+        post_id, score
+    );
+    match post.realm.clone() {
+        Some(name) if state.realms.contains_key(&name) => {
+            post.realm_report = Some(Default::default());
+            let realm = state.realms.get_mut(&name).expect("no realm found");
+            realm.reports.insert(post_id);
+            for controller in realm.controllers.clone() {
+                if let Some(user) = state.users.get_mut(&controller) {
+                    user.notify(&message);
+                }
+            }
+        }
+        _ => state.notify_users(&|user| user.stalwart, &message),
+    }
+    state.logger.info(message);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post::add,
+        tests::{create_untrusted_user, create_user, pr},
+        WEEK,
+    };
+
+    #[actix_rt::test]
+    async fn test_spam_is_held() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        create_untrusted_user(&mut state, pr(1));
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        let realm = "REALM".to_string();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert!(state.toggle_realm_membership(pr(1), realm.clone()));
+
+        let body = "Claim https://scam.example https://scam.example/claim";
+        let first = add(&mut state, body.into(), vec![], pr(1), 0, None, None, None)
+            .await
+            .unwrap();
+        // links, a new account and low karma: 40 + 20 + 20
+        assert_eq!(score(&state, first), 80);
+        assert!(state.detector.held.contains(&first));
+        assert!(!state.visible(Some(0), state.posts.get(&first).unwrap()));
+        assert!(state.visible(Some(1), state.posts.get(&first).unwrap()));
+
+        // a repeated post in a realm lands in the moderation queue
+        let second = add(
+            &mut state,
+            body.into(),
+            vec![],
+            pr(1),
+            CONFIG.rate_limit_window,
+            None,
+            Some(realm.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(score(&state, second), 120);
+        assert!(state.detector.held.contains(&second));
+        assert!(state.realms.get(&realm).unwrap().reports.contains(&second));
+
+        // the controllers release the post by dismissing the report
+        crate::reports::resolve_realm_report(
+            &mut state,
+            pr(0),
+            second,
+            crate::reports::Resolution::Dismissed,
+            0,
+        )
+        .unwrap();
+        assert!(!state.detector.held.contains(&second));
+        assert!(state.realms.get(&realm).unwrap().reports.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_normal_post_passes() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let body = "Check out the docs at https://docs.example and tell me what you think.";
+        let time = 2 * WEEK;
+        let id = add(
+            &mut state,
+            body.into(),
+            vec![],
+            pr(0),
+            time,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(score(&state, id), 60 / 12);
+        assert!(!state.detector.held.contains(&id));
+        assert!(state.visible(Some(1), state.posts.get(&id).unwrap()));
+
+        // the same post from a new account still passes
+        state.users.get_mut(&0).unwrap().timestamp = time;
+        let id = add(
+            &mut state,
+            body.into(),
+            vec![],
+            pr(0),
+            time + CONFIG.rate_limit_window,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(score(&state, id), 60 / 12 + 40 + 20);
+        assert!(!state.detector.held.contains(&id));
+    }
+}
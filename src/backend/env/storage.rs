@@ -1,61 +1,1601 @@
-use crate::canisters::{install, CanisterInstallMode};
+use crate::canisters::{delete, install, settings, stop, topup_with_cycles, CanisterInstallMode};
 use candid::Principal;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::future::join_all;
 use ic_cdk::api::call::call_raw;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
 
-use super::{config::CONFIG, Logger};
+use super::{config::CONFIG, images::strip_metadata, memory::MemoryMetrics, time, Logger};
+
+pub type CallFn =
+    Box<dyn Fn(Principal, &str, Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, String>>>>>;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "BucketStatsFormat")]
+pub struct BucketStats {
+    // the write offset, which only grows
+    pub offset: u64,
+    // bytes referenced by live content
+    pub live: u64,
+    // the time of the last failed write
+    pub failed: Option<u64>,
+    // the hash of the installed WASM module
+    pub wasm_hash: Option<String>,
+}
+
+impl BucketStats {
+    fn writable(&self, max_bucket_size: u64, now: u64) -> bool {
+        self.offset < max_bucket_size
+            && self
+                .failed
+                .map(|timestamp| timestamp + CONFIG.bucket_failure_backoff <= now)
+                .unwrap_or(true)
+    }
+}
+
+// Buckets used to be stored with their offset only.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BucketStatsFormat {
+    Offset(u64),
+    Stats {
+        offset: u64,
+        #[serde(default)]
+        live: Option<u64>,
+        #[serde(default)]
+        failed: Option<u64>,
+        #[serde(default)]
+        wasm_hash: Option<String>,
+    },
+}
+
+impl From<BucketStatsFormat> for BucketStats {
+    fn from(format: BucketStatsFormat) -> Self {
+        // without any tracking, all bytes of a bucket are considered live
+        match format {
+            BucketStatsFormat::Offset(offset) => BucketStats {
+                offset,
+                live: offset,
+                failed: None,
+                wasm_hash: None,
+            },
+            BucketStatsFormat::Stats {
+                offset,
+                live,
+                failed,
+                wasm_hash,
+            } => BucketStats {
+                offset,
+                live: live.unwrap_or(offset),
+                failed,
+                wasm_hash,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StorageMetrics {
+    #[serde(flatten)]
+    memory: MemoryMetrics,
+    buckets: usize,
+    bucket_bytes: u64,
+    live_bucket_bytes: u64,
+}
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Storage {
-    pub buckets: BTreeMap<Principal, u64>,
+    pub buckets: BTreeMap<Principal, BucketStats>,
+    #[serde(default)]
+    last_metrics: u64,
+    // overrides the configured bucket size limit
+    #[serde(default)]
+    max_bucket_size: Option<u64>,
+    // canisters without the bucket WASM module flagged for deletion
+    #[serde(default)]
+    pub orphans: BTreeSet<Principal>,
+    // hashes of all bucket WASM modules installed so far
+    #[serde(default)]
+    wasm_hashes: BTreeSet<String>,
+    #[serde(default)]
+    last_top_up: u64,
+    // compacted buckets without any live blobs, which can be deleted
+    #[serde(default)]
+    pub compacted: BTreeSet<Principal>,
+    // the location of every stored blob by the hash of its content
+    #[serde(default)]
+    pub hashes: BTreeMap<String, (Principal, u64, usize)>,
+    // the content hash and the number of references of every stored blob by its location
+    #[serde(default)]
+    refs: BTreeMap<(Principal, u64), (String, u32)>,
+    // replaces inter-canister calls to buckets if set
+    #[serde(skip)]
+    call: Option<CallFn>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BucketSummary {
+    pub buckets: usize,
+    // bytes written to all buckets
+    pub bytes: u64,
+    // bytes referenced by live content in all buckets
+    pub live_bytes: u64,
 }
 
 const BUCKET_WASM_GZ: &[u8] =
     include_bytes!("../../../target/wasm32-unknown-unknown/release/bucket.wasm.gz");
 
+fn parse_offset(response: &[u8]) -> Result<u64, String> {
+    let mut offset_bytes: [u8; 8] = Default::default();
+    if response.len() != offset_bytes.len() {
+        return Err(format!(
+            "unexpected write response of {} bytes",
+            response.len()
+        ));
+    }
+    offset_bytes.copy_from_slice(response);
+    Ok(u64::from_be_bytes(offset_bytes))
+}
+
+fn blob_hash(blob: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(blob);
+    format!("{:x}", hasher.finalize())
+}
+
+fn wasm_hash(wasm: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(wasm);
+    format!("{:x}", hasher.finalize())
+}
+
+// The header byte of blobs stored in buckets.
+const RAW_BLOB: u8 = 0;
+const GZIP_BLOB: u8 = 1;
+
+// Compresses blobs not smaller than the threshold, if it saves space.
+fn encode_blob(blob: &[u8], threshold: usize) -> Vec<u8> {
+    if blob.len() >= threshold {
+        let mut encoder = GzEncoder::new(vec![GZIP_BLOB], Compression::default());
+        if let Ok(compressed) = encoder.write_all(blob).and_then(|_| encoder.finish()) {
+            if compressed.len() <= blob.len() {
+                return compressed;
+            }
+        }
+    }
+    let mut encoded = Vec::with_capacity(blob.len() + 1);
+    encoded.push(RAW_BLOB);
+    encoded.extend_from_slice(blob);
+    encoded
+}
+
+// Blobs written before the header was introduced are images, which never start with 0 or 1.
+fn decode_blob(blob: Vec<u8>) -> Result<Vec<u8>, String> {
+    match blob.first() {
+        Some(&RAW_BLOB) => Ok(blob[1..].to_vec()),
+        Some(&GZIP_BLOB) => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&blob[1..])
+                .read_to_end(&mut decoded)
+                .map_err(|err| format!("couldn't decompress the blob: {}", err))?;
+            Ok(decoded)
+        }
+        _ => Ok(blob),
+    }
+}
+
 impl Storage {
-    async fn allocate_space(
-        &mut self,
-        max_bucket_size: u64,
-        logger: &mut Logger,
-    ) -> Result<Principal, String> {
+    pub fn max_bucket_size(&self) -> u64 {
+        self.max_bucket_size.unwrap_or(CONFIG.max_bucket_size)
+    }
+
+    // Returns each bucket with its offset and the bucket size limit.
+    pub fn bucket_report(&self) -> Vec<(Principal, u64, u64)> {
+        let max_bucket_size = self.max_bucket_size();
+        self.buckets
+            .iter()
+            .map(|(id, stats)| (*id, stats.offset, max_bucket_size))
+            .collect()
+    }
+
+    pub fn bucket_summary(&self) -> BucketSummary {
+        BucketSummary {
+            buckets: self.buckets.len(),
+            bytes: self.buckets.values().map(|stats| stats.offset).sum(),
+            live_bytes: self.buckets.values().map(|stats| stats.live).sum(),
+        }
+    }
+
+    // Buckets above the new limit stop receiving writes.
+    pub fn set_max_bucket_size(&mut self, size: u64) -> Result<(), String> {
+        if size == 0 {
+            return Err("the bucket size limit must be positive".into());
+        }
+        self.max_bucket_size = Some(size);
+        Ok(())
+    }
+
+    async fn allocate_space(&mut self, logger: &mut Logger) -> Result<Principal, String> {
+        let now = time();
+        let max_bucket_size = self.max_bucket_size();
         if let Some((id, _)) = self
             .buckets
             .iter()
-            .find(|(_, size)| **size < max_bucket_size)
+            .find(|(_, stats)| stats.writable(max_bucket_size, now))
         {
             return Ok(*id);
         }
         let id = crate::canisters::new().await?;
         logger.info(format!("New bucket {} created.", id));
-        self.buckets.insert(id, 0);
-        install(id, BUCKET_WASM_GZ, CanisterInstallMode::Install).await?;
+        self.buckets.insert(id, Default::default());
+        self.install(id, BUCKET_WASM_GZ, CanisterInstallMode::Install)
+            .await?;
+        let hash = wasm_hash(BUCKET_WASM_GZ);
+        if let Some(stats) = self.buckets.get_mut(&id) {
+            stats.wasm_hash = Some(hash.clone());
+        }
+        self.wasm_hashes.insert(hash);
         logger.info(format!("WASM installed to bucket {}.", id));
         Ok(id)
     }
 
-    #[allow(dead_code)]
-    async fn upgrade_buckets(&self) -> Result<(), String> {
-        for id in self.buckets.keys() {
-            install(*id, BUCKET_WASM_GZ, CanisterInstallMode::Upgrade).await?;
+    async fn call(&self, id: Principal, method: &str, args: Vec<u8>) -> Result<Vec<u8>, String> {
+        match &self.call {
+            Some(call) => call(id, method, args).await,
+            None => call_raw(id, method, &args, 0)
+                .await
+                .map_err(|err| format!("couldn't call {} on a bucket: {:?}", method, err)),
         }
-        Ok(())
     }
 
+    async fn install(
+        &self,
+        id: Principal,
+        wasm: &[u8],
+        mode: CanisterInstallMode,
+    ) -> Result<(), String> {
+        match &self.call {
+            Some(call) => call(id, "install_code", wasm.to_vec()).await.map(|_| ()),
+            None => install(id, wasm, mode).await,
+        }
+    }
+
+    // Returns the controllers and the hash of the installed WASM module of the canister.
+    async fn status(&self, id: Principal) -> Result<(Vec<Principal>, Option<String>), String> {
+        let (controllers, module_hash) = match &self.call {
+            Some(call) => {
+                let hash = call(id, "canister_status", Vec::new()).await?;
+                let controllers = call(id, "controllers", Vec::new()).await?;
+                (
+                    candid::decode_one(&controllers).map_err(|err| err.to_string())?,
+                    (!hash.is_empty()).then_some(hash),
+                )
+            }
+            None => settings(id)
+                .await
+                .map(|status| (status.settings.controllers, status.module_hash))?,
+        };
+        Ok((
+            controllers,
+            module_hash.map(|bytes| {
+                bytes
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            }),
+        ))
+    }
+
+    // Checks the given canisters for buckets unknown to the storage, e.g. because the
+    // registration of a new bucket was lost to a trap. The list is only a hint: canisters not
+    // controlled by the main canister alone are skipped. Canisters running any bucket WASM
+    // module installed so far get adopted with their current offset, all others get flagged
+    // for deletion. Canisters failing a check are skipped. Returns the adopted and flagged
+    // canisters.
+    pub async fn reconcile_buckets(
+        &mut self,
+        logger: &mut Logger,
+        canisters: &[Principal],
+    ) -> (Vec<Principal>, Vec<Principal>) {
+        let mut known_hashes = self.wasm_hashes.clone();
+        known_hashes.insert(wasm_hash(BUCKET_WASM_GZ));
+        known_hashes.extend(
+            self.buckets
+                .values()
+                .filter_map(|stats| stats.wasm_hash.clone()),
+        );
+        let (mut adopted, mut flagged) = (Vec::new(), Vec::new());
+        for id in canisters {
+            if self.buckets.contains_key(id) || self.orphans.contains(id) {
+                continue;
+            }
+            let (controllers, module_hash) = match self.status(*id).await {
+                Ok(status) => status,
+                Err(err) => {
+                    logger.error(format!(
+                        "Couldn't get the status of canister `{}`: {}",
+                        id, err
+                    ));
+                    continue;
+                }
+            };
+            if controllers != [super::id()] {
+                logger.error(format!(
+                    "Canister `{}` isn't controlled by the main canister alone and is skipped.",
+                    id
+                ));
+                continue;
+            }
+            if !module_hash
+                .as_ref()
+                .map(|hash| known_hashes.contains(hash))
+                .unwrap_or_default()
+            {
+                logger.error(format!(
+                    "Canister `{}` doesn't run a bucket WASM module and is flagged for deletion.",
+                    id
+                ));
+                self.orphans.insert(*id);
+                flagged.push(*id);
+                continue;
+            }
+            let mut args = 0_u64.to_be_bytes().to_vec();
+            args.extend_from_slice(&8_u64.to_be_bytes());
+            let offset = match self
+                .call(*id, "read", args)
+                .await
+                .and_then(|bytes| parse_offset(&bytes))
+            {
+                Ok(offset) => offset,
+                Err(err) => {
+                    logger.error(format!(
+                        "Couldn't read the offset of canister `{}`: {}",
+                        id, err
+                    ));
+                    continue;
+                }
+            };
+            // buckets running an older module get upgraded with the next bucket upgrade
+            self.buckets.insert(
+                *id,
+                BucketStats {
+                    offset,
+                    wasm_hash: module_hash,
+                    ..Default::default()
+                },
+            );
+            logger.info(format!(
+                "Orphaned bucket `{}` was adopted with offset `{}`.",
+                id, offset
+            ));
+            adopted.push(*id);
+        }
+        (adopted, flagged)
+    }
+
+    async fn cycle_balance(&self, id: Principal) -> Result<u64, String> {
+        let bytes = self.call(id, "balance", Vec::new()).await?;
+        let mut balance: [u8; 8] = Default::default();
+        if bytes.len() != balance.len() {
+            return Err(format!(
+                "unexpected balance response of {} bytes",
+                bytes.len()
+            ));
+        }
+        balance.copy_from_slice(&bytes);
+        Ok(u64::from_be_bytes(balance))
+    }
+
+    async fn deposit_cycles(&self, id: Principal, cycles: u64) -> Result<(), String> {
+        match &self.call {
+            Some(call) => call(id, "deposit_cycles", cycles.to_be_bytes().to_vec())
+                .await
+                .map(|_| ()),
+            None => topup_with_cycles(id, cycles).await,
+        }
+    }
+
+    // Tops up buckets with a cycle balance below the threshold once per interval, as long as
+    // the balance of the main canister doesn't fall below the critical level.
+    // Returns the topped up buckets.
+    pub async fn top_up_buckets(
+        &mut self,
+        logger: &mut Logger,
+        mut main_balance: u64,
+        now: u64,
+    ) -> Vec<Principal> {
+        let mut topped_up = Vec::new();
+        if now.saturating_sub(self.last_top_up) < CONFIG.bucket_top_up_interval {
+            return topped_up;
+        }
+        self.last_top_up = now;
+        let ids = self.buckets.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            let balance = match self.cycle_balance(id).await {
+                Ok(balance) => balance,
+                Err(err) => {
+                    logger.error(format!(
+                        "Couldn't get the balance of bucket `{}`: {}",
+                        id, err
+                    ));
+                    continue;
+                }
+            };
+            if balance >= CONFIG.bucket_min_cycle_balance {
+                continue;
+            }
+            if main_balance.saturating_sub(CONFIG.bucket_top_up_cycles)
+                < CONFIG.critical_cycle_balance_main
+            {
+                logger.error(format!(
+                    "Skipped the top-up of bucket `{}`: the main canister balance `{}` is critically low.",
+                    id, main_balance
+                ));
+                continue;
+            }
+            match self.deposit_cycles(id, CONFIG.bucket_top_up_cycles).await {
+                Ok(()) => {
+                    main_balance -= CONFIG.bucket_top_up_cycles;
+                    logger.info(format!(
+                        "Bucket `{}` was topped up with `{}` cycles (balance was `{}`).",
+                        id, CONFIG.bucket_top_up_cycles, balance
+                    ));
+                    topped_up.push(id);
+                }
+                Err(err) => {
+                    logger.error(format!("Couldn't top up bucket `{}`: {}", id, err));
+                }
+            }
+        }
+        topped_up
+    }
+
+    // Upgrades all buckets not running the embedded WASM module yet.
+    // Returns the numbers of upgraded and skipped buckets.
+    pub async fn upgrade_buckets(&mut self, logger: &mut Logger) -> Result<(usize, usize), String> {
+        let hash = wasm_hash(BUCKET_WASM_GZ);
+        let outdated = self
+            .buckets
+            .iter()
+            .filter(|(_, stats)| stats.wasm_hash.as_ref() != Some(&hash))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let skipped = self.buckets.len() - outdated.len();
+        for (upgraded, id) in outdated.iter().enumerate() {
+            if let Err(err) = self
+                .install(*id, BUCKET_WASM_GZ, CanisterInstallMode::Upgrade)
+                .await
+            {
+                logger.error(format!(
+                    "Upgrade of bucket `{}` failed after `{}` upgraded buckets: {}",
+                    id, upgraded, err
+                ));
+                return Err(err);
+            }
+            // the hash is recorded right away, so that a repeated call continues from here
+            if let Some(stats) = self.buckets.get_mut(id) {
+                stats.wasm_hash = Some(hash.clone());
+            }
+            self.wasm_hashes.insert(hash.clone());
+        }
+        if !outdated.is_empty() {
+            logger.info(format!(
+                "Upgraded `{}` buckets, skipped `{}` up-to-date buckets.",
+                outdated.len(),
+                skipped
+            ));
+        }
+        Ok((outdated.len(), skipped))
+    }
+
+    // Writes the encoded blob unless the same content is stored already and returns its
+    // location and the number of stored bytes.
     pub async fn write_to_bucket(
         &mut self,
         logger: &mut Logger,
         blob: &[u8],
+    ) -> Result<(Principal, u64, usize), String> {
+        let blob = strip_metadata(blob);
+        let blob = blob.as_ref();
+        let hash = blob_hash(blob);
+        if let Some(location) = self.add_ref(&hash) {
+            return Ok(location);
+        }
+        let encoded = encode_blob(blob, CONFIG.blob_compression_threshold);
+        let (id, offset) = self.write(logger, &encoded).await?;
+        Ok(self.register(hash, (id, offset, encoded.len())))
+    }
+
+    // Returns the location of a stored blob with the given hash and counts the new reference.
+    fn add_ref(&mut self, hash: &str) -> Option<(Principal, u64, usize)> {
+        let location = *self.hashes.get(hash)?;
+        if let Some((_, count)) = self.refs.get_mut(&(location.0, location.1)) {
+            *count += 1;
+        }
+        Some(location)
+    }
+
+    // Records the location of a newly written blob with one reference. If the same content
+    // was stored concurrently, the new copy is dropped in favor of the existing one.
+    fn register(
+        &mut self,
+        hash: String,
+        (id, offset, len): (Principal, u64, usize),
+    ) -> (Principal, u64, usize) {
+        if let Some(location) = self.add_ref(&hash) {
+            self.release_bytes(id, len as u64);
+            return location;
+        }
+        self.hashes.insert(hash.clone(), (id, offset, len));
+        self.refs.insert((id, offset), (hash, 1));
+        (id, offset, len)
+    }
+
+    // Buckets running the current wasm certify written blobs for serving over HTTP.
+    fn write_method(&self, id: Principal) -> &'static str {
+        match self.buckets.get(&id) {
+            Some(stats) if stats.wasm_hash.as_ref() == Some(&wasm_hash(BUCKET_WASM_GZ)) => {
+                "write_certified"
+            }
+            _ => "write",
+        }
+    }
+
+    async fn write(
+        &mut self,
+        logger: &mut Logger,
+        blob: &[u8],
     ) -> Result<(Principal, u64), String> {
-        let id = self.allocate_space(CONFIG.max_bucket_size, logger).await?;
-        let response = call_raw(id, "write", blob, 0)
-            .await
-            .map_err(|err| format!("couldn't call write on a bucket: {:?}", err))?;
-        let mut offset_bytes: [u8; 8] = Default::default();
-        offset_bytes.copy_from_slice(&response);
-        let offset = u64::from_be_bytes(offset_bytes);
-        self.buckets.insert(id, offset + blob.len() as u64);
-        Ok((id, offset))
+        let mut error = String::new();
+        for attempt in 1..=CONFIG.bucket_write_attempts {
+            let id = self.allocate_space(logger).await?;
+            match self
+                .call(id, self.write_method(id), blob.to_vec())
+                .await
+                .and_then(|response| parse_offset(&response))
+            {
+                Ok(offset) => {
+                    self.written(id, offset, blob.len());
+                    return Ok((id, offset));
+                }
+                Err(err) => {
+                    self.write_failed(logger, id, attempt, &err);
+                    error = err;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    // Writes the blobs not stored yet and returns the locations of all blobs in the same order.
+    // Identical blobs are written only once.
+    pub async fn write_many(
+        &mut self,
+        logger: &mut Logger,
+        blobs: &[&[u8]],
+    ) -> Result<Vec<(Principal, u64, usize)>, String> {
+        let stripped = blobs
+            .iter()
+            .map(|blob| strip_metadata(blob))
+            .collect::<Vec<_>>();
+        let blobs = stripped
+            .iter()
+            .map(|blob| blob.as_ref())
+            .collect::<Vec<_>>();
+        let hashes = blobs.iter().map(|blob| blob_hash(blob)).collect::<Vec<_>>();
+        let mut seen = BTreeSet::new();
+        let new = (0..blobs.len())
+            .filter(|i| !self.hashes.contains_key(&hashes[*i]) && seen.insert(&hashes[*i]))
+            .collect::<Vec<_>>();
+        let slices = new.iter().map(|i| blobs[*i]).collect::<Vec<_>>();
+        let locations = self.write_new(logger, &slices).await?;
+        let mut registered = BTreeSet::new();
+        for (i, location) in new.iter().zip(locations) {
+            self.register(hashes[*i].clone(), location);
+            registered.insert(*i);
+        }
+        Ok(hashes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, hash)| {
+                if registered.contains(&i) {
+                    self.hashes.get(hash).copied()
+                } else {
+                    self.add_ref(hash)
+                }
+            })
+            .collect())
+    }
+
+    // Writes the encoded blobs concurrently to all writable buckets and returns their
+    // locations in the same order. Writes to the same bucket stay sequential, so that the
+    // bucket offsets are updated in order. Blobs of a failed bucket get retried one by one.
+    async fn write_new(
+        &mut self,
+        logger: &mut Logger,
+        blobs: &[&[u8]],
+    ) -> Result<Vec<(Principal, u64, usize)>, String> {
+        if blobs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let encoded = blobs
+            .iter()
+            .map(|blob| encode_blob(blob, CONFIG.blob_compression_threshold))
+            .collect::<Vec<_>>();
+        let now = time();
+        let mut targets = self
+            .buckets
+            .iter()
+            .filter(|(_, stats)| stats.writable(self.max_bucket_size(), now))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            targets.push(self.allocate_space(logger).await?);
+        }
+        let mut queues: BTreeMap<Principal, Vec<usize>> = BTreeMap::new();
+        for i in 0..encoded.len() {
+            queues
+                .entry(targets[i % targets.len()])
+                .or_default()
+                .push(i);
+        }
+
+        let storage = &*self;
+        let encoded = &encoded;
+        let results = join_all(queues.into_iter().map(|(id, queue)| async move {
+            let mut written = Vec::new();
+            for (n, i) in queue.iter().enumerate() {
+                match storage
+                    .call(id, storage.write_method(id), encoded[*i].clone())
+                    .await
+                    .and_then(|response| parse_offset(&response))
+                {
+                    Ok(offset) => written.push((*i, offset)),
+                    Err(err) => return (id, written, queue[n..].to_vec(), Some(err)),
+                }
+            }
+            (id, written, Vec::new(), None)
+        }))
+        .await;
+
+        let mut locations = vec![None; encoded.len()];
+        let mut retries = Vec::new();
+        for (id, written, remaining, error) in results {
+            for (i, offset) in written {
+                self.written(id, offset, encoded[i].len());
+                locations[i] = Some((id, offset, encoded[i].len()));
+            }
+            if let Some(err) = error {
+                self.write_failed(logger, id, 1, &err);
+                retries.extend(remaining);
+            }
+        }
+        retries.sort_unstable();
+        for i in retries {
+            let (id, offset) = self.write(logger, &encoded[i]).await?;
+            locations[i] = Some((id, offset, encoded[i].len()));
+        }
+        Ok(locations.into_iter().flatten().collect())
+    }
+
+    fn written(&mut self, id: Principal, offset: u64, len: usize) {
+        let stats = self.buckets.entry(id).or_default();
+        // concurrent writes can complete in any order
+        stats.offset = stats.offset.max(offset + len as u64);
+        stats.live += len as u64;
+    }
+
+    // Marks the bucket as failed, so that it's skipped by the next attempts.
+    fn write_failed(&mut self, logger: &mut Logger, id: Principal, attempt: usize, err: &str) {
+        if let Some(stats) = self.buckets.get_mut(&id) {
+            stats.failed = Some(time());
+        }
+        logger.error(format!(
+            "Write attempt `{}` to bucket `{}` failed: {}",
+            attempt, id, err
+        ));
+    }
+
+    // Logs the stable memory and bucket metrics, at most once per interval.
+    pub fn report_metrics(&mut self, logger: &mut Logger, memory: MemoryMetrics, now: u64) -> bool {
+        if now < self.last_metrics + CONFIG.storage_metrics_interval {
+            return false;
+        }
+        self.last_metrics = now;
+        logger.metrics(&StorageMetrics {
+            memory,
+            buckets: self.buckets.len(),
+            bucket_bytes: self.buckets.values().map(|stats| stats.offset).sum(),
+            live_bucket_bytes: self.buckets.values().map(|stats| stats.live).sum(),
+        });
+        true
+    }
+
+    // Drops a reference to the blob and marks its bytes as not live anymore once the last
+    // reference is gone.
+    pub fn release(&mut self, id: Principal, offset: u64, len: u64) {
+        if let Some((hash, count)) = self.refs.get_mut(&(id, offset)) {
+            *count = count.saturating_sub(1);
+            if *count > 0 {
+                return;
+            }
+            let hash = hash.clone();
+            self.refs.remove(&(id, offset));
+            self.hashes.remove(&hash);
+        }
+        self.release_bytes(id, len);
+    }
+
+    fn release_bytes(&mut self, id: Principal, len: u64) {
+        if let Some(stats) = self.buckets.get_mut(&id) {
+            stats.live = stats.live.saturating_sub(len);
+        }
+    }
+
+    pub async fn read_from_bucket(
+        &self,
+        id: Principal,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, String> {
+        if !self.buckets.contains_key(&id) {
+            return Err(format!("bucket {} not found", id));
+        }
+        decode_blob(self.read(id, offset, len).await?)
+    }
+
+    async fn read(&self, id: Principal, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+        let mut args = offset.to_be_bytes().to_vec();
+        args.extend_from_slice(&len.to_be_bytes());
+        let blob = self.call(id, "read", args).await?;
+        if blob.len() as u64 != len {
+            return Err(format!(
+                "bucket {} returned {} bytes instead of {}",
+                id,
+                blob.len(),
+                len
+            ));
+        }
+        Ok(blob)
+    }
+
+    // Copies all referenced blobs of the bucket to other buckets and decommissions it.
+    // Returns the new location for every `(offset, len)` reference.
+    pub async fn compact_bucket<I: IntoIterator<Item = (u64, u64)>>(
+        &mut self,
+        logger: &mut Logger,
+        id: Principal,
+        references: I,
+    ) -> Result<BTreeMap<(u64, u64), (Principal, u64)>, String> {
+        // the bucket is removed first, so that no blob gets copied into it
+        let stats = self
+            .buckets
+            .remove(&id)
+            .ok_or(format!("bucket {} not found", id))?;
+        let references = references.into_iter().collect::<BTreeSet<_>>();
+        logger.info(format!(
+            "Compacting bucket `{}`: `{}` blobs with `{}` bytes (`{}` live) out of `{}` bytes.",
+            id,
+            references.len(),
+            references.iter().map(|(_, len)| len).sum::<u64>(),
+            stats.live,
+            stats.offset
+        ));
+        let mut locations = BTreeMap::new();
+        for (offset, len) in references {
+            let result = match self.read(id, offset, len).await {
+                // blobs are copied as they are stored
+                Ok(blob) => self.write(logger, &blob).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(location) => {
+                    locations.insert((offset, len), location);
+                }
+                Err(err) => {
+                    self.buckets.insert(id, stats);
+                    logger.error(format!(
+                        "Compaction of bucket `{}` aborted after `{}` blobs: {}",
+                        id,
+                        locations.len(),
+                        err
+                    ));
+                    return Err(err);
+                }
+            }
+        }
+        logger.info(format!(
+            "Copied `{}` blobs out of bucket `{}`.",
+            locations.len(),
+            id
+        ));
+        // the references of the copied blobs move along
+        for ((offset, len), (new_id, new_offset)) in &locations {
+            if let Some((hash, count)) = self.refs.remove(&(id, *offset)) {
+                self.hashes
+                    .insert(hash.clone(), (*new_id, *new_offset, *len as usize));
+                self.refs.insert((*new_id, *new_offset), (hash, count));
+            }
+        }
+        self.compacted.insert(id);
+        logger.info(format!(
+            "Bucket `{}` was decommissioned and won't be topped up anymore.",
+            id
+        ));
+        Ok(locations)
+    }
+
+    async fn manage(&self, id: Principal, method: &str) -> Result<(), String> {
+        match &self.call {
+            Some(call) => call(id, method, Vec::new()).await.map(|_| ()),
+            None if method == "stop_canister" => stop(id).await,
+            None => delete(id).await,
+        }
+    }
+
+    // Withdraws the cycles of an empty bucket to the main canister, then stops and deletes it.
+    // Buckets with live blobs are refused.
+    pub async fn decommission_bucket(
+        &mut self,
+        logger: &mut Logger,
+        id: Principal,
+    ) -> Result<u64, String> {
+        // the bucket is removed first, so that no blob gets written to it
+        let stats = match self.buckets.get(&id) {
+            Some(stats) if stats.live > 0 => {
+                return Err(format!("bucket {} still has {} live bytes", id, stats.live))
+            }
+            Some(_) => self.buckets.remove(&id),
+            None if self.compacted.contains(&id) => None,
+            None => return Err(format!("bucket {} not found", id)),
+        };
+        let result = match self.call(id, "withdraw_cycles", Vec::new()).await {
+            Ok(response) => parse_offset(&response),
+            Err(err) => Err(err),
+        };
+        let cycles = match result {
+            Ok(cycles) => cycles,
+            Err(err) => {
+                if let Some(stats) = stats {
+                    self.buckets.insert(id, stats);
+                }
+                logger.error(format!(
+                    "Couldn't withdraw the cycles of bucket `{}`: {}",
+                    id, err
+                ));
+                return Err(err);
+            }
+        };
+        // without cycles, the bucket can't serve anything anymore
+        self.compacted.insert(id);
+        for method in ["stop_canister", "delete_canister"].iter() {
+            if let Err(err) = self.manage(id, method).await {
+                logger.error(format!("Couldn't delete bucket `{}`: {}", id, err));
+                return Err(err);
+            }
+        }
+        self.compacted.remove(&id);
+        logger.info(format!(
+            "Bucket `{}` was deleted and `{}` cycles were returned.",
+            id, cycles
+        ));
+        Ok(cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn u64_at(bytes: &[u8], offset: usize) -> usize {
+        let mut arr: [u8; 8] = Default::default();
+        arr.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_be_bytes(arr) as usize
+    }
+
+    type Buckets = Rc<RefCell<BTreeMap<Principal, Vec<u8>>>>;
+
+    // Mocks buckets by their stable memories with the offset pointer at the beginning.
+    fn mock_storage(ids: &[Principal]) -> (Storage, Buckets) {
+        let buckets: Buckets = Rc::new(RefCell::new(
+            ids.iter()
+                .map(|id| (*id, 8_u64.to_be_bytes().to_vec()))
+                .collect(),
+        ));
+        let mock = buckets.clone();
+        let storage = Storage {
+            buckets: ids.iter().map(|id| (*id, Default::default())).collect(),
+            last_metrics: 0,
+            max_bucket_size: None,
+            orphans: Default::default(),
+            wasm_hashes: Default::default(),
+            last_top_up: 0,
+            compacted: Default::default(),
+            hashes: Default::default(),
+            refs: Default::default(),
+            call: Some(Box::new(move |id, method, args| {
+                let mut buckets = mock.borrow_mut();
+                // buckets without memory trap
+                let memory = match buckets.get_mut(&id) {
+                    Some(memory) => memory,
+                    None => return Box::pin(async { Err("bucket trapped".into()) }),
+                };
+                let result = match method {
+                    "write" | "write_certified" => {
+                        let offset = memory.len() as u64;
+                        memory.extend_from_slice(&args);
+                        Ok(offset.to_be_bytes().to_vec())
+                    }
+                    "read" => {
+                        let (offset, len) = (u64_at(&args, 0), u64_at(&args, 8));
+                        Ok(memory[offset..(offset + len).min(memory.len())].to_vec())
+                    }
+                    "install_code" | "stop_canister" | "delete_canister" => Ok(Vec::new()),
+                    "withdraw_cycles" => Ok(1000_u64.to_be_bytes().to_vec()),
+                    // canisters with an empty memory have no module installed
+                    "canister_status" if memory.is_empty() => Ok(Vec::new()),
+                    "canister_status" => {
+                        let mut hasher = Sha256::new();
+                        hasher.update(BUCKET_WASM_GZ);
+                        Ok(hasher.finalize().to_vec())
+                    }
+                    "controllers" => Ok(candid::encode_one(vec![super::super::id()]).unwrap()),
+                    _ => Err("unknown method".into()),
+                };
+                Box::pin(async move { result })
+            })),
+        };
+        (storage, buckets)
+    }
+
+    #[actix_rt::test]
+    async fn test_bucket_read_write() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, memory) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+
+        // small blobs are stored with the header byte only
+        let (id, offset, len) = storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+        assert_eq!((id, offset, len), (bucket, 8, 6));
+        let (_, offset2, len2) = storage
+            .write_to_bucket(&mut logger, b"world!")
+            .await
+            .unwrap();
+        assert_eq!((offset2, len2), (14, 7));
+        assert_eq!(
+            storage.buckets.get(&bucket),
+            Some(&BucketStats {
+                offset: 21,
+                live: 13,
+                failed: None,
+                wasm_hash: None
+            })
+        );
+        storage.release(bucket, offset, 6);
+        assert_eq!(storage.buckets.get(&bucket).unwrap().live, 7);
+
+        assert_eq!(
+            storage.read_from_bucket(bucket, offset, 6).await,
+            Ok(b"hello".to_vec())
+        );
+        assert_eq!(
+            storage.read_from_bucket(bucket, offset2, 7).await,
+            Ok(b"world!".to_vec())
+        );
+        assert_eq!(
+            storage.read_from_bucket(bucket, offset2, 10).await,
+            Err(format!("bucket {} returned 7 bytes instead of 10", bucket))
+        );
+
+        // unknown buckets are not called
+        let unknown = Principal::anonymous();
+        assert_eq!(
+            storage.read_from_bucket(unknown, 0, 5).await,
+            Err(format!("bucket {} not found", unknown))
+        );
+        assert_eq!(memory.borrow().get(&bucket).unwrap().len(), 21);
+    }
+
+    #[actix_rt::test]
+    async fn test_blob_deduplication() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, memory) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+
+        let location = storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"hello").await,
+            Ok(location)
+        );
+        let locations = storage
+            .write_many(&mut logger, &[b"world", b"hello", b"world"])
+            .await
+            .unwrap();
+        assert_eq!(locations[1], location);
+        assert_eq!(locations[0], locations[2]);
+        assert_ne!(locations[0], location);
+
+        // every content was written once
+        assert_eq!(memory.borrow().get(&bucket).unwrap().len(), 8 + 6 + 6);
+        assert_eq!(storage.buckets.get(&bucket).unwrap().live, 12);
+        assert_eq!(storage.refs.get(&(bucket, location.1)).unwrap().1, 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_refcounted_release() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, _) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+        let (_, offset, len) = storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+        storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+
+        // the bytes stay live until the last reference is released
+        storage.release(bucket, offset, len as u64);
+        assert_eq!(storage.buckets.get(&bucket).unwrap().live, 6);
+        assert!(!storage.hashes.is_empty());
+        storage.release(bucket, offset, len as u64);
+        assert_eq!(storage.buckets.get(&bucket).unwrap().live, 0);
+        assert!(storage.hashes.is_empty() && storage.refs.is_empty());
+
+        // the same content uploaded after the release is written again
+        let (_, new_offset, _) = storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+        assert_eq!(new_offset, offset + len as u64);
+    }
+
+    #[actix_rt::test]
+    async fn test_write_failover() {
+        let trapped = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let healthy = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, memory) = mock_storage(&[healthy]);
+        storage.buckets.insert(trapped, Default::default());
+        assert_eq!(storage.buckets.keys().next(), Some(&trapped));
+        let mut logger = Logger::default();
+
+        // the first bucket traps, so the blob is written to the second one
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"hello").await,
+            Ok((healthy, 8, 6))
+        );
+        assert_eq!(storage.buckets.get(&trapped).unwrap().failed, Some(time()));
+        assert_eq!(storage.buckets.get(&trapped).unwrap().offset, 0);
+
+        // the failed bucket is skipped until the backoff passes
+        let now = time();
+        assert!(!storage.buckets[&trapped].writable(CONFIG.max_bucket_size, now));
+        assert!(storage.buckets[&trapped]
+            .writable(CONFIG.max_bucket_size, now + CONFIG.bucket_failure_backoff));
+
+        // all attempts fail
+        memory.borrow_mut().clear();
+        storage
+            .buckets
+            .insert(Principal::anonymous(), Default::default());
+        for stats in storage.buckets.values_mut() {
+            stats.failed = None;
+        }
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"world").await,
+            Err("bucket trapped".into())
+        );
+        assert!(storage.buckets.values().all(|stats| stats.failed.is_some()));
+    }
+
+    #[actix_rt::test]
+    async fn test_max_bucket_size() {
+        let (mut storage, _) = mock_storage(&[
+            Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        ]);
+        let ids = storage.buckets.keys().cloned().collect::<Vec<_>>();
+        let mut logger = Logger::default();
+        assert_eq!(storage.max_bucket_size(), CONFIG.max_bucket_size);
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"hello").await,
+            Ok((ids[0], 8, 6))
+        );
+
+        // the first bucket is above the lowered limit
+        assert_eq!(
+            storage.set_max_bucket_size(0),
+            Err("the bucket size limit must be positive".into())
+        );
+        assert_eq!(storage.set_max_bucket_size(10), Ok(()));
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"world").await,
+            Ok((ids[1], 8, 6))
+        );
+
+        // a raised limit makes the first bucket writable again
+        assert_eq!(storage.set_max_bucket_size(100), Ok(()));
+        assert_eq!(
+            storage.write_to_bucket(&mut logger, b"again").await,
+            Ok((ids[0], 14, 6))
+        );
+    }
+
+    #[test]
+    fn test_bucket_report() {
+        let (mut storage, _) = mock_storage(&[]);
+        assert_eq!(storage.bucket_summary(), BucketSummary::default());
+        let ids = [
+            Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        ];
+        for (i, id) in ids.iter().enumerate() {
+            storage.buckets.insert(
+                *id,
+                BucketStats {
+                    offset: 100 * (i as u64 + 1),
+                    live: 50,
+                    ..Default::default()
+                },
+            );
+        }
+        storage.set_max_bucket_size(1000).unwrap();
+
+        let mut report = storage.bucket_report();
+        report.sort_by_key(|(_, offset, _)| *offset);
+        assert_eq!(report, vec![(ids[0], 100, 1000), (ids[1], 200, 1000)]);
+        assert_eq!(
+            storage.bucket_summary(),
+            BucketSummary {
+                buckets: 2,
+                bytes: 300,
+                live_bytes: 100,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_reconcile_buckets() {
+        let known = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let orphan = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let empty = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let outdated = Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap();
+        let foreign = Principal::from_text("renrk-eyaaa-aaaaa-aaada-cai").unwrap();
+        let (mut storage, memory) = mock_storage(&[known, orphan, empty, outdated, foreign]);
+        for id in [orphan, empty, outdated, foreign] {
+            storage.buckets.remove(&id);
+        }
+        memory.borrow_mut().get_mut(&orphan).unwrap()[..8].copy_from_slice(&64_u64.to_be_bytes());
+        memory.borrow_mut().get_mut(&empty).unwrap().clear();
+        storage.wasm_hashes.insert("0102".into());
+        let call = storage.call.take().unwrap();
+        storage.call = Some(Box::new(move |id, method, args| match method {
+            // a bucket running an older module
+            "canister_status" if id == outdated => Box::pin(async { Ok(vec![1, 2]) }),
+            // a canister with another controller
+            "controllers" if id == foreign => Box::pin(async move {
+                Ok(candid::encode_one(vec![super::super::id(), foreign]).unwrap())
+            }),
+            _ => call(id, method, args),
+        }));
+        let mut logger = Logger::default();
+
+        // failing and foreign canisters are skipped without aborting the run
+        let failing = Principal::anonymous();
+        assert_eq!(
+            storage
+                .reconcile_buckets(
+                    &mut logger,
+                    &[failing, known, orphan, empty, outdated, foreign]
+                )
+                .await,
+            (vec![orphan, outdated], vec![empty])
+        );
+        let stats = storage.buckets.get(&orphan).unwrap();
+        assert_eq!(stats.offset, 64);
+        assert_eq!(stats.wasm_hash, Some(wasm_hash(BUCKET_WASM_GZ)));
+        assert_eq!(
+            storage.buckets.get(&outdated).unwrap().wasm_hash,
+            Some("0102".into())
+        );
+        assert!(!storage.buckets.contains_key(&empty));
+        assert!(storage.orphans.contains(&empty));
+        assert!(!storage.buckets.contains_key(&foreign));
+        assert!(!storage.orphans.contains(&foreign));
+        assert!(logger.events.iter().any(|event| event
+            .message
+            .contains("isn't controlled by the main canister")));
+
+        // a second run finds nothing new
+        assert_eq!(
+            storage
+                .reconcile_buckets(&mut logger, &[known, orphan, empty, outdated, foreign])
+                .await,
+            (vec![], vec![])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_upgrade_buckets() {
+        let (mut storage, memory) = mock_storage(&[
+            Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            Principal::anonymous(),
+        ]);
+        let ids = storage.buckets.keys().cloned().collect::<Vec<_>>();
+        let mut logger = Logger::default();
+
+        // the last bucket traps, but the upgraded ones are recorded
+        memory.borrow_mut().remove(&ids[2]);
+        assert_eq!(
+            storage.upgrade_buckets(&mut logger).await,
+            Err("bucket trapped".into())
+        );
+        memory
+            .borrow_mut()
+            .insert(ids[2], 8_u64.to_be_bytes().to_vec());
+        assert_eq!(storage.upgrade_buckets(&mut logger).await, Ok((1, 2)));
+        // upgraded buckets certify written blobs
+        assert!(ids
+            .iter()
+            .all(|id| storage.write_method(*id) == "write_certified"));
+
+        // a second call with the same WASM module is a no-op
+        memory.borrow_mut().clear();
+        assert_eq!(storage.upgrade_buckets(&mut logger).await, Ok((0, 3)));
+
+        // outdated buckets are upgraded
+        storage.buckets.get_mut(&ids[1]).unwrap().wasm_hash = Some("outdated".into());
+        assert_eq!(storage.write_method(ids[1]), "write");
+        assert_eq!(
+            storage.upgrade_buckets(&mut logger).await,
+            Err("bucket trapped".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_write_many() {
+        let (mut storage, memory) = mock_storage(&[
+            Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            Principal::anonymous(),
+        ]);
+        let ids = storage.buckets.keys().cloned().collect::<Vec<_>>();
+        let mut logger = Logger::default();
+        assert_eq!(storage.write_many(&mut logger, &[]).await, Ok(vec![]));
+
+        // the last bucket traps, so its blobs get retried on the healthy buckets
+        memory.borrow_mut().remove(&ids[2]);
+        let blobs = (0..8_u8)
+            .map(|i| vec![i; 1 + i as usize])
+            .collect::<Vec<_>>();
+        let slices = blobs.iter().map(|blob| blob.as_slice()).collect::<Vec<_>>();
+        let locations = storage.write_many(&mut logger, &slices).await.unwrap();
+        assert_eq!(locations.len(), blobs.len());
+        assert!(storage.buckets[&ids[2]].failed.is_some());
+
+        // offsets don't collide and the bucket stats match the bucket memories
+        for id in &ids[..2] {
+            let mut ranges = locations
+                .iter()
+                .filter(|(bucket_id, _, _)| bucket_id == id)
+                .map(|(_, offset, len)| (*offset, *offset + *len as u64))
+                .collect::<Vec<_>>();
+            assert!(ranges.len() > 1);
+            ranges.sort_unstable();
+            assert!(ranges.windows(2).all(|pair| pair[0].1 <= pair[1].0));
+            assert_eq!(
+                storage.buckets[id].offset,
+                memory.borrow().get(id).unwrap().len() as u64
+            );
+        }
+        for (blob, (id, offset, len)) in blobs.iter().zip(locations) {
+            assert_eq!(
+                storage.read_from_bucket(id, offset, len as u64).await,
+                Ok(blob.clone())
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_storage_metrics() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, _) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+        storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+        let memory = MemoryMetrics {
+            stable_size: 65536,
+            boundary: 1024,
+            free_bytes: 100,
+            largest_free_segment: 60,
+            segments: 2,
+            allocator_ops: 7,
+            cached_objects: 0,
+        };
+
+        let now = CONFIG.storage_metrics_interval;
+        assert!(storage.report_metrics(&mut logger, memory.clone(), now));
+        let event = logger.events.last().unwrap();
+        assert_eq!(event.level, "METRICS");
+        assert_eq!(
+            event.message,
+            r#"{"stable_size":65536,"boundary":1024,"free_bytes":100,"largest_free_segment":60,"segments":2,"allocator_ops":7,"cached_objects":0,"buckets":1,"bucket_bytes":14,"live_bucket_bytes":6}"#
+        );
+
+        // metrics are rate-limited
+        let events = logger.events.len();
+        assert!(!storage.report_metrics(&mut logger, memory.clone(), now + 1));
+        assert_eq!(logger.events.len(), events);
+        assert!(storage.report_metrics(&mut logger, memory, now + CONFIG.storage_metrics_interval));
+        assert_eq!(logger.events.len(), events + 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_blob_compression() {
+        let threshold = CONFIG.blob_compression_threshold;
+
+        // small blobs are stored raw
+        let encoded = encode_blob(b"hello", threshold);
+        assert_eq!(encoded, b"\x00hello".to_vec());
+        assert_eq!(decode_blob(encoded), Ok(b"hello".to_vec()));
+
+        // large compressible blobs are compressed
+        let text = b"Lorem ipsum dolor sit amet. ".repeat(100);
+        let compressed = encode_blob(&text, threshold);
+        assert_eq!(compressed[0], GZIP_BLOB);
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(decode_blob(compressed.clone()), Ok(text.clone()));
+
+        // large blobs are stored raw if compression doesn't pay off
+        let mut state = 1_u64;
+        let noise = (0..threshold * 2)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<_>>();
+        let encoded = encode_blob(&noise, threshold);
+        assert_eq!(encoded[0], RAW_BLOB);
+        assert_eq!(encoded.len(), noise.len() + 1);
+        assert_eq!(decode_blob(encoded), Ok(noise));
+
+        // legacy blobs without a header are returned as they are
+        let jpeg = vec![0xff, 0xd8, 0xff, 0xe0];
+        assert_eq!(decode_blob(jpeg.clone()), Ok(jpeg));
+
+        // broken compressed blobs are reported
+        let mut broken = compressed.clone();
+        broken.truncate(broken.len() / 2);
+        assert!(decode_blob(broken).is_err());
+
+        // buckets store the compressed blob and return the original one
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, _) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+        let (_, offset, len) = storage.write_to_bucket(&mut logger, &text).await.unwrap();
+        assert_eq!(len, compressed.len());
+        assert_eq!(
+            storage.read_from_bucket(bucket, offset, len as u64).await,
+            Ok(text)
+        );
+    }
+
+    #[test]
+    fn test_bucket_stats_migration() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let legacy: BTreeMap<Principal, u64> = vec![(bucket, 42)].into_iter().collect();
+        let buckets: BTreeMap<Principal, BucketStats> =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&legacy).unwrap()).unwrap();
+        let stats = BucketStats {
+            offset: 42,
+            live: 42,
+            failed: None,
+            wasm_hash: None,
+        };
+        assert_eq!(buckets.get(&bucket), Some(&stats));
+
+        let stats = BucketStats {
+            offset: 42,
+            live: 7,
+            failed: Some(1),
+            wasm_hash: Some("abc".into()),
+        };
+        let buckets: BTreeMap<Principal, BucketStats> = serde_cbor::from_slice(
+            &serde_cbor::to_vec(
+                &vec![(bucket, stats.clone())]
+                    .into_iter()
+                    .collect::<BTreeMap<_, _>>(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(buckets.get(&bucket), Some(&stats));
+    }
+
+    #[actix_rt::test]
+    async fn test_compact_bucket() {
+        let old = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let new = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let (mut storage, memory) = mock_storage(&[old]);
+        let mut logger = Logger::default();
+
+        let mut offsets = Vec::new();
+        for blob in [&b"aaa"[..], b"bbbb", b"cc", b"dddddd"].iter() {
+            let (id, offset, _) = storage.write_to_bucket(&mut logger, blob).await.unwrap();
+            assert_eq!(id, old);
+            offsets.push(offset);
+        }
+        assert_eq!(storage.buckets.get(&old).unwrap().offset, 27);
+
+        // the second and the last blobs were deleted, the first one is referenced twice
+        let references = vec![(offsets[0], 4), (offsets[2], 3), (offsets[0], 4)];
+        memory
+            .borrow_mut()
+            .insert(new, 8_u64.to_be_bytes().to_vec());
+        storage.buckets.insert(new, Default::default());
+
+        // a broken reference aborts the compaction and keeps the bucket
+        let mut broken = references.clone();
+        broken.push((offsets[3], 10));
+        assert!(storage
+            .compact_bucket(&mut logger, old, broken)
+            .await
+            .is_err());
+        assert_eq!(storage.buckets.get(&old).unwrap().offset, 27);
+
+        let locations = storage
+            .compact_bucket(&mut logger, old, references)
+            .await
+            .unwrap();
+        assert_eq!(locations.len(), 2);
+        assert!(!storage.buckets.contains_key(&old));
+        assert!(storage.compacted.contains(&old));
+        for ((offset, len), expected) in [((offsets[0], 4), &b"aaa"[..]), ((offsets[2], 3), b"cc")]
+            .iter()
+            .cloned()
+        {
+            let (id, new_offset) = locations.get(&(offset, len)).unwrap();
+            assert_eq!(*id, new);
+            assert_eq!(
+                storage.read_from_bucket(*id, *new_offset, len).await,
+                Ok(expected.to_vec())
+            );
+        }
+
+        // only live blobs were copied (twice, because of the aborted attempt)
+        assert_eq!(storage.buckets.get(&new).unwrap().offset, 8 + 2 * 7);
+    }
+
+    #[actix_rt::test]
+    async fn test_decommission_bucket() {
+        let bucket = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let (mut storage, _) = mock_storage(&[bucket]);
+        let mut logger = Logger::default();
+        let (_, offset, len) = storage
+            .write_to_bucket(&mut logger, b"hello")
+            .await
+            .unwrap();
+
+        // buckets with live blobs are refused
+        assert_eq!(
+            storage.decommission_bucket(&mut logger, bucket).await,
+            Err(format!("bucket {} still has {} live bytes", bucket, len))
+        );
+        assert!(storage.buckets.contains_key(&bucket));
+
+        storage.release(bucket, offset, len as u64);
+        assert_eq!(
+            storage.decommission_bucket(&mut logger, bucket).await,
+            Ok(1000)
+        );
+        assert!(!storage.buckets.contains_key(&bucket));
+        assert!(storage.compacted.is_empty());
+        assert!(logger
+            .events
+            .last()
+            .unwrap()
+            .message
+            .contains("was deleted"));
+        assert_eq!(
+            storage.decommission_bucket(&mut logger, bucket).await,
+            Err(format!("bucket {} not found", bucket))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_bucket_top_ups() {
+        let low = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let healthy = Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap();
+        let balances: Rc<RefCell<BTreeMap<Principal, u64>>> = Rc::new(RefCell::new(
+            vec![
+                (low, CONFIG.bucket_min_cycle_balance - 1),
+                (healthy, CONFIG.bucket_min_cycle_balance),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let mock = balances.clone();
+        let mut storage = Storage {
+            buckets: [low, healthy]
+                .iter()
+                .map(|id| (*id, Default::default()))
+                .collect(),
+            call: Some(Box::new(move |id, method, args| {
+                let mut balances = mock.borrow_mut();
+                let balance = balances.get_mut(&id).expect("no bucket");
+                let result = match method {
+                    "balance" => Ok(balance.to_be_bytes().to_vec()),
+                    "deposit_cycles" => {
+                        *balance += u64_at(&args, 0) as u64;
+                        Ok(Vec::new())
+                    }
+                    _ => Err("unknown method".into()),
+                };
+                Box::pin(async move { result })
+            })),
+            ..Default::default()
+        };
+        let mut logger = Logger::default();
+        let main_balance = CONFIG.critical_cycle_balance_main + CONFIG.bucket_top_up_cycles;
+
+        // only buckets below the threshold get topped up
+        let now = CONFIG.bucket_top_up_interval;
+        assert_eq!(
+            storage.top_up_buckets(&mut logger, main_balance, now).await,
+            vec![low]
+        );
+        assert_eq!(
+            balances.borrow().get(&low),
+            Some(&(CONFIG.bucket_min_cycle_balance - 1 + CONFIG.bucket_top_up_cycles))
+        );
+        assert_eq!(
+            balances.borrow().get(&healthy),
+            Some(&CONFIG.bucket_min_cycle_balance)
+        );
+        assert!(logger
+            .events
+            .last()
+            .unwrap()
+            .message
+            .contains("was topped up"));
+
+        // buckets are checked once per interval
+        balances.borrow_mut().insert(low, 0);
+        assert!(storage
+            .top_up_buckets(&mut logger, main_balance, now + 1)
+            .await
+            .is_empty());
+        assert_eq!(balances.borrow().get(&low), Some(&0));
+
+        // top-ups are skipped if the main canister balance is critically low
+        let now = now + CONFIG.bucket_top_up_interval;
+        assert!(storage
+            .top_up_buckets(&mut logger, main_balance - 1, now)
+            .await
+            .is_empty());
+        assert_eq!(balances.borrow().get(&low), Some(&0));
+        assert!(logger
+            .events
+            .last()
+            .unwrap()
+            .message
+            .contains("critically low"));
     }
 }
@@ -245,6 +245,17 @@ fn transfer(
     Ok(0)
 }
 
+// Formats token base units as a decimal amount with all token decimals.
+pub fn display(amount: Token) -> String {
+    let base = 10_u64.pow(CONFIG.token_decimals as u32);
+    format!(
+        "{}.{:0width$}",
+        amount / base,
+        amount % base,
+        width = CONFIG.token_decimals as usize
+    )
+}
+
 pub fn account(owner: Principal) -> Account {
     Account {
         owner,
@@ -271,6 +282,172 @@ pub fn mint(state: &mut State, account: Account, tokens: Token) {
     });
 }
 
+// Transfers tokens between the default accounts of two principals, charging the standard fee.
+pub fn transfer_tokens(
+    now: u64,
+    state: &mut State,
+    from: Principal,
+    to: Principal,
+    amount: Token,
+) -> Result<(), String> {
+    transfer(
+        now,
+        state,
+        from,
+        TransferArgs {
+            from_subaccount: None,
+            to: account(to),
+            amount: amount as u128,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        },
+    )
+    .map(|_| ())
+    .map_err(|err| match err {
+        TransferError::InsufficientFunds(_) => "not enough tokens".into(),
+        err => format!("transfer failed: {:?}", err),
+    })
+}
+
+// Holds the tokens locked by bounties.
+pub fn escrow_account() -> Account {
+    let mut subaccount = vec![0; 32];
+    subaccount[31] = 1;
+    Account {
+        owner: super::id(),
+        subaccount: Some(subaccount),
+    }
+}
+
+// Moves tokens without a fee between the escrow and the default account of a user.
+fn move_escrowed(
+    now: u64,
+    state: &mut State,
+    from: Account,
+    to: Account,
+    amount: Token,
+) -> Result<(), String> {
+    let balance = state.balances.get(&from).copied().unwrap_or_default();
+    if balance < amount {
+        return Err("not enough tokens".into());
+    }
+    if balance == amount {
+        state.balances.remove(&from);
+    } else {
+        state.balances.insert(from.clone(), balance - amount);
+    }
+    *state.balances.entry(to.clone()).or_default() += amount;
+    state.ledger.push(Transaction {
+        timestamp: now,
+        from,
+        to,
+        amount,
+        fee: 0,
+        memo: None,
+    });
+    Ok(())
+}
+
+pub fn lock(now: u64, state: &mut State, owner: Principal, amount: Token) -> Result<(), String> {
+    move_escrowed(now, state, account(owner), escrow_account(), amount)
+}
+
+pub fn release(now: u64, state: &mut State, to: Principal, amount: Token) -> Result<(), String> {
+    move_escrowed(now, state, escrow_account(), account(to), amount)
+}
+
+// A token grant unlocking linearly from its start until the end of its duration, with nothing
+// unlocked before the cliff. A cliff equal to the duration unlocks the whole grant at once.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Vesting {
+    pub beneficiary: Principal,
+    pub total: Token,
+    pub claimed: Token,
+    pub start: u64,
+    pub cliff: u64,
+    pub duration: u64,
+    // the time at which the vesting was stopped by a proposal
+    pub revoked: Option<u64>,
+}
+
+impl Vesting {
+    // Tokens unlocked until now or until the revocation, whichever is earlier.
+    pub fn vested(&self, now: u64) -> Token {
+        let now = self.revoked.map(|time| time.min(now)).unwrap_or(now);
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed < self.cliff {
+            0
+        } else if elapsed >= self.duration {
+            self.total
+        } else {
+            (self.total as u128 * elapsed as u128 / self.duration as u128) as Token
+        }
+    }
+
+    pub fn claimable(&self, now: u64) -> Token {
+        self.vested(now).saturating_sub(self.claimed)
+    }
+}
+
+pub fn create_vesting(
+    state: &mut State,
+    beneficiary: Principal,
+    total: Token,
+    cliff: u64,
+    duration: u64,
+    now: u64,
+) -> Result<usize, String> {
+    if total == 0 {
+        return Err("amount must be positive".into());
+    }
+    if cliff > duration || duration > CONFIG.max_vesting_duration {
+        return Err("invalid vesting duration".into());
+    }
+    state.vestings.push(Vesting {
+        beneficiary,
+        total,
+        claimed: 0,
+        start: now,
+        cliff,
+        duration,
+        revoked: None,
+    });
+    Ok(state.vestings.len() - 1)
+}
+
+// Mints the unlocked but not yet claimed tokens of the grant for its beneficiary.
+pub fn claim_vested(
+    state: &mut State,
+    caller: Principal,
+    id: usize,
+    now: u64,
+) -> Result<Token, String> {
+    let vesting = state
+        .vestings
+        .get_mut(id)
+        .filter(|vesting| vesting.beneficiary == caller)
+        .ok_or("no vesting found")?;
+    let amount = vesting.claimable(now);
+    if amount == 0 {
+        return Err("no tokens to claim".into());
+    }
+    vesting.claimed += amount;
+    mint(state, account(caller), amount);
+    Ok(amount)
+}
+
+// Stops the vesting of the grant; already unlocked tokens remain claimable. Returns the
+// amount of forfeited tokens.
+pub fn revoke_vesting(state: &mut State, id: usize, now: u64) -> Result<Token, String> {
+    let vesting = state.vestings.get_mut(id).ok_or("no vesting found")?;
+    if vesting.revoked.is_some() {
+        return Err("vesting already revoked".into());
+    }
+    vesting.revoked = Some(now);
+    Ok(vesting.total - vesting.vested(now))
+}
+
 pub fn move_funds(state: &mut State, from: &Account, to: Account) -> Result<u128, TransferError> {
     let balance = state.balances.get(from).copied().unwrap_or_default();
     let mut n = 0;
@@ -303,6 +480,78 @@ mod tests {
         Principal::from_slice(&v)
     }
 
+    #[test]
+    fn test_display() {
+        let base = 10_u64.pow(CONFIG.token_decimals as u32);
+        let zeros = "0".repeat(CONFIG.token_decimals as usize);
+        assert_eq!(display(0), format!("0.{}", zeros));
+        assert_eq!(display(7 * base), format!("7.{}", zeros));
+        assert_eq!(display(12 * base + 5), format!("12.{}5", &zeros[1..]));
+    }
+
+    #[test]
+    fn test_linear_vesting() {
+        let mut state = State::default();
+        let day = 24 * 60 * MINUTE;
+        assert_eq!(
+            create_vesting(&mut state, pr(1), 1000, 2 * day, day, 0),
+            Err("invalid vesting duration".into())
+        );
+        let id = create_vesting(&mut state, pr(1), 1000, 0, 100 * day, 10 * day).unwrap();
+        let vesting = &state.vestings[id];
+        assert_eq!(vesting.vested(5 * day), 0);
+        assert_eq!(vesting.vested(35 * day), 250);
+        assert_eq!(vesting.vested(110 * day), 1000);
+        assert_eq!(vesting.vested(500 * day), 1000);
+
+        // only the beneficiary can claim and only the not yet claimed part
+        assert_eq!(
+            claim_vested(&mut state, pr(2), id, 35 * day),
+            Err("no vesting found".into())
+        );
+        assert_eq!(claim_vested(&mut state, pr(1), id, 35 * day), Ok(250));
+        assert_eq!(
+            claim_vested(&mut state, pr(1), id, 35 * day),
+            Err("no tokens to claim".into())
+        );
+        assert_eq!(claim_vested(&mut state, pr(1), id, 60 * day), Ok(250));
+        assert_eq!(claim_vested(&mut state, pr(1), id, 200 * day), Ok(500));
+        assert_eq!(state.balances[&account(pr(1))], 1000);
+    }
+
+    #[test]
+    fn test_vesting_cliff_and_revocation() {
+        let mut state = State::default();
+        let day = 24 * 60 * MINUTE;
+        // a linear grant with a cliff after a quarter of the duration
+        let linear = create_vesting(&mut state, pr(1), 400, 25 * day, 100 * day, 0).unwrap();
+        // a grant unlocking at once
+        let cliff = create_vesting(&mut state, pr(2), 300, 50 * day, 50 * day, 0).unwrap();
+        assert_eq!(state.vestings[linear].vested(25 * day - 1), 0);
+        assert_eq!(state.vestings[linear].vested(25 * day), 100);
+        assert_eq!(state.vestings[cliff].vested(50 * day - 1), 0);
+        assert_eq!(state.vestings[cliff].vested(50 * day), 300);
+
+        // revoking keeps the vested tokens claimable and stops further vesting
+        assert_eq!(revoke_vesting(&mut state, linear, 50 * day), Ok(200));
+        assert_eq!(
+            revoke_vesting(&mut state, linear, 60 * day),
+            Err("vesting already revoked".into())
+        );
+        assert_eq!(claim_vested(&mut state, pr(1), linear, 100 * day), Ok(200));
+        assert_eq!(
+            claim_vested(&mut state, pr(1), linear, 200 * day),
+            Err("no tokens to claim".into())
+        );
+
+        // revoking before the cliff forfeits the whole grant
+        assert_eq!(revoke_vesting(&mut state, cliff, 49 * day), Ok(300));
+        assert_eq!(
+            claim_vested(&mut state, pr(2), cliff, 100 * day),
+            Err("no tokens to claim".into())
+        );
+    }
+
     #[test]
     fn test_transfers() {
         let mut state = State::default();
@@ -1,4 +1,6 @@
+use super::webhooks::Webhook;
 use super::*;
+use candid::CandidType;
 use ic_ledger_types::AccountIdentifier;
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +20,103 @@ pub enum Notification {
     WatchedPostEntries(Vec<u64>),
 }
 
+// A rate limit following the generic cell rate algorithm: every action moves the theoretical
+// arrival time by window / limit, and actions are allowed while it stays within one window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    arrival: u64,
+}
+
+impl RateLimit {
+    // Returns the rate limit after one more action or the time to wait until it is allowed.
+    pub fn check(&self, limit: u64, window: u64, now: u64) -> Result<RateLimit, u64> {
+        let arrival = self.arrival.max(now) + window / limit.max(1);
+        if arrival > now + window {
+            return Err(arrival - now - window);
+        }
+        Ok(RateLimit { arrival })
+    }
+}
+
+// A request of the guardians to bind a new principal to the account.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recovery {
+    pub principal: Principal,
+    pub approvals: BTreeSet<Principal>,
+    // the time the quorum was reached, starting the timelock
+    pub approved_at: Option<u64>,
+    // approvals of other principals; they replace an approved recovery only with a quorum
+    #[serde(default)]
+    pub competing: BTreeMap<Principal, BTreeSet<Principal>>,
+}
+
+// Event types the user gets notified about; all are enabled by default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub mentions: bool,
+    pub replies: bool,
+    pub followers: bool,
+    pub rewards: bool,
+    pub realms: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            mentions: true,
+            replies: true,
+            followers: true,
+            rewards: true,
+            realms: true,
+        }
+    }
+}
+
+// The order of the personal feed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FeedMode {
+    #[default]
+    Chronological,
+    // ordered by the trending score
+    Ranked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Badge {
+    FirstPost,
+    // CONFIG.badge_upvotes upvotes received
+    Upvotes,
+    // posts on CONFIG.badge_streak_days consecutive days
+    Streak,
+}
+
+// One-time actions of new users rewarded with tokens.
+#[derive(
+    CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum OnboardingStep {
+    // a non-empty about section
+    Profile,
+    FirstPost,
+    FirstFollow,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Collection {
+    pub title: String,
+    pub posts: Vec<PostId>,
+    pub public: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Draft {
+    pub id: u64,
+    pub body: String,
+    pub realm: Option<String>,
+    pub timestamp: u64,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: UserId,
@@ -46,6 +145,66 @@ pub struct User {
     pub balance: Token,
     pub active_weeks: u32,
     pub principal: Principal,
+    // tag weights derived from user's reactions
+    #[serde(default)]
+    pub interests: BTreeMap<String, i64>,
+    #[serde(default)]
+    pub muted: BTreeSet<UserId>,
+    // blocked users can't interact with the user
+    #[serde(default)]
+    pub blocked: BTreeSet<UserId>,
+    // lower-cased tags whose posts appear in the personal feed
+    #[serde(default)]
+    pub followed_tags: BTreeSet<String>,
+    // karma from all sources except reactions; karma audits check that the actual karma
+    // exceeds it by the karma recomputed from the reactions on user's posts
+    #[serde(default)]
+    pub other_karma: Option<Karma>,
+    #[serde(default)]
+    pub collections: Vec<Collection>,
+    // unpublished posts, only visible to the user
+    #[serde(default)]
+    pub drafts: Vec<Draft>,
+    // proposal topic (or "*" for all topics) to the user receiving the voting power
+    #[serde(default)]
+    pub delegations: BTreeMap<String, UserId>,
+    #[serde(default)]
+    pub badges: BTreeSet<Badge>,
+    // upvotes received on user's posts
+    #[serde(default)]
+    pub upvotes: u64,
+    // the last day with a post and the number of consecutive days with posts until then
+    #[serde(default)]
+    pub streak: (u64, u32),
+    #[serde(default)]
+    pub webhook: Option<Webhook>,
+    #[serde(default)]
+    pub post_limit: RateLimit,
+    #[serde(default)]
+    pub comment_limit: RateLimit,
+    #[serde(default)]
+    pub notification_settings: NotificationSettings,
+    // the inviter is rewarded once the user crosses the activity threshold
+    #[serde(default)]
+    pub referral_pending: bool,
+    // further principals authenticating as the user
+    #[serde(default)]
+    pub linked_principals: BTreeSet<Principal>,
+    // a principal which needs to confirm the link
+    #[serde(default)]
+    pub pending_principal: Option<Principal>,
+    // principals which can recover the account by a quorum
+    #[serde(default)]
+    pub guardians: BTreeSet<Principal>,
+    #[serde(default)]
+    pub guardian_quorum: usize,
+    #[serde(default)]
+    pub recovery: Option<Recovery>,
+    #[serde(default)]
+    pub feed_mode: FeedMode,
+    // completed onboarding steps and whether their rewards were claimed
+    #[serde(default)]
+    pub onboarding: BTreeMap<OnboardingStep, bool>,
 }
 
 impl User {
@@ -81,6 +240,29 @@ impl User {
             balance: 0,
             active_weeks: 0,
             principal,
+            interests: Default::default(),
+            muted: Default::default(),
+            blocked: Default::default(),
+            followed_tags: Default::default(),
+            other_karma: Some(0),
+            collections: Default::default(),
+            drafts: Default::default(),
+            delegations: Default::default(),
+            badges: Default::default(),
+            upvotes: 0,
+            streak: (0, 0),
+            webhook: None,
+            post_limit: Default::default(),
+            comment_limit: Default::default(),
+            notification_settings: Default::default(),
+            referral_pending: false,
+            linked_principals: Default::default(),
+            pending_principal: None,
+            guardians: Default::default(),
+            guardian_quorum: 0,
+            recovery: None,
+            feed_mode: Default::default(),
+            onboarding: Default::default(),
         }
     }
 
@@ -99,6 +281,136 @@ impl User {
         true
     }
 
+    pub fn create_collection(&mut self, title: String, public: bool) -> Result<usize, String> {
+        if self.collections.len() >= CONFIG.max_collections {
+            return Err(format!(
+                "not more than {} collections are allowed",
+                CONFIG.max_collections
+            ));
+        }
+        if title.is_empty() || title.chars().count() > CONFIG.max_collection_title_length {
+            return Err("invalid collection title".into());
+        }
+        self.collections.push(Collection {
+            title,
+            public,
+            posts: Default::default(),
+        });
+        Ok(self.collections.len() - 1)
+    }
+
+    // Creates a new draft or updates the given one and returns its id.
+    pub fn save_draft(
+        &mut self,
+        id: Option<u64>,
+        body: String,
+        realm: Option<String>,
+        timestamp: u64,
+    ) -> Result<u64, String> {
+        if body.is_empty() || body.len() > CONFIG.max_post_length {
+            return Err("invalid draft".into());
+        }
+        let other_drafts_size: usize = self
+            .drafts
+            .iter()
+            .filter(|draft| Some(draft.id) != id)
+            .map(|draft| draft.body.len())
+            .sum();
+        if other_drafts_size + body.len() > CONFIG.max_drafts_size_bytes {
+            return Err("drafts storage quota exceeded".into());
+        }
+        let draft = Draft {
+            id: id.unwrap_or_else(|| {
+                self.drafts
+                    .iter()
+                    .map(|draft| draft.id + 1)
+                    .max()
+                    .unwrap_or_default()
+            }),
+            body,
+            realm,
+            timestamp,
+        };
+        match id {
+            Some(id) => {
+                *self
+                    .drafts
+                    .iter_mut()
+                    .find(|draft| draft.id == id)
+                    .ok_or("no draft found")? = draft;
+            }
+            None if self.drafts.len() >= CONFIG.max_drafts => {
+                return Err(format!(
+                    "not more than {} drafts are allowed",
+                    CONFIG.max_drafts
+                ));
+            }
+            None => self.drafts.push(draft),
+        }
+        Ok(id.unwrap_or_else(|| self.drafts.last().expect("no draft").id))
+    }
+
+    pub fn delete_draft(&mut self, id: u64) -> Result<Draft, String> {
+        let index = self
+            .drafts
+            .iter()
+            .position(|draft| draft.id == id)
+            .ok_or("no draft found")?;
+        Ok(self.drafts.remove(index))
+    }
+
+    pub fn delete_collection(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.collections.len() {
+            return Err("no collection found".into());
+        }
+        self.collections.remove(index);
+        Ok(())
+    }
+
+    pub fn add_to_collection(&mut self, index: usize, post_id: PostId) -> Result<(), String> {
+        let collection = self
+            .collections
+            .get_mut(index)
+            .ok_or("no collection found")?;
+        if collection.posts.contains(&post_id) {
+            return Err("post already in collection".into());
+        }
+        if collection.posts.len() >= CONFIG.max_collection_size {
+            return Err(format!(
+                "not more than {} posts per collection are allowed",
+                CONFIG.max_collection_size
+            ));
+        }
+        collection.posts.push(post_id);
+        Ok(())
+    }
+
+    pub fn remove_from_collection(&mut self, index: usize, post_id: PostId) -> Result<(), String> {
+        let collection = self
+            .collections
+            .get_mut(index)
+            .ok_or("no collection found")?;
+        collection.posts.retain(|id| id != &post_id);
+        Ok(())
+    }
+
+    // Accepts a new order of the collection posts, which must be a permutation of the current one.
+    pub fn reorder_collection(&mut self, index: usize, posts: Vec<PostId>) -> Result<(), String> {
+        let collection = self
+            .collections
+            .get_mut(index)
+            .ok_or("no collection found")?;
+        let mut current = collection.posts.clone();
+        let mut new = posts.clone();
+        current.sort_unstable();
+        new.sort_unstable();
+        if current != new {
+            return Err("new order doesn't match the collection".into());
+        }
+        collection.posts = posts;
+        Ok(())
+    }
+
     pub fn active_within_weeks(&self, now: u64, n: u64) -> bool {
         self.last_activity + n * WEEK > now
     }
@@ -132,19 +444,32 @@ impl User {
         true
     }
 
+    pub fn toggle_following_tag(&mut self, tag: &str) -> bool {
+        let tag = tag.trim_start_matches('#').to_lowercase();
+        if tag.is_empty() {
+            return false;
+        }
+        if self.followed_tags.remove(&tag) {
+            return false;
+        }
+        self.followed_tags.insert(tag);
+        true
+    }
+
     pub fn personal_feed<'a>(
         &'a self,
         principal: Principal,
         state: &'a State,
-        page: usize,
         with_comments: bool,
     ) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
+        let caller_id = state.principal_to_user(principal).map(|user| user.id);
         let posts_by_tags = Box::new(
             state
                 .last_posts(principal, with_comments)
                 .filter(move |post| {
                     let lc_tags: BTreeSet<_> = post.tags.iter().map(|t| t.to_lowercase()).collect();
                     covered_by_feeds(&self.feeds, &lc_tags, false).is_some()
+                        || !self.followed_tags.is_disjoint(&lc_tags)
                 })
                 .map(|post| &post.id),
         );
@@ -168,6 +493,7 @@ impl User {
             }
             .filter_map(move |id| state.posts.get(&id))
             .filter(move |post| with_comments || post.parent.is_none())
+            .filter(move |post| state.visible(caller_id, post))
             .filter(move |post| {
                 // Either  the user is in no realm or in the realm of the post
                 (self.current_realm.is_none() || post.realm == self.current_realm)
@@ -177,12 +503,30 @@ impl User {
                         .as_ref()
                         .map(|id| self.realms.contains(id))
                         .unwrap_or(true)
-            })
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size),
+            }),
         )
     }
 
+    // Returns a page of the personal feed in the order of the feed mode.
+    pub fn feed_page(
+        &self,
+        principal: Principal,
+        state: &State,
+        with_comments: bool,
+        cursor: Option<String>,
+        now: u64,
+    ) -> Result<FeedPage, String> {
+        let feed = self.personal_feed(principal, state, with_comments);
+        match self.feed_mode {
+            FeedMode::Chronological => FeedPage::new(feed, cursor, CONFIG.feed_page_size),
+            FeedMode::Ranked => FeedPage::ranked(feed, cursor, CONFIG.feed_page_size, now),
+        }
+    }
+
+    pub fn ignores(&self, user_id: UserId) -> bool {
+        self.muted.contains(&user_id) || self.blocked.contains(&user_id)
+    }
+
     pub fn notify_with_params<T: AsRef<str>>(&mut self, message: T, predicate: Option<Predicate>) {
         self.messages += 1;
         let id = self.messages;
@@ -198,6 +542,35 @@ impl User {
         };
     }
 
+    // Awards the badge unless the user has it already.
+    pub fn award(&mut self, badge: Badge) -> bool {
+        self.badges.insert(badge)
+    }
+
+    // Updates the posting streak and awards the posting badges.
+    pub fn record_post(&mut self, timestamp: u64) {
+        let day = timestamp / (24 * HOUR);
+        let (last_day, days) = self.streak;
+        self.streak = if days > 0 && day == last_day {
+            (day, days)
+        } else if days > 0 && day == last_day + 1 {
+            (day, days + 1)
+        } else {
+            (day, 1)
+        };
+        self.award(Badge::FirstPost);
+        if self.streak.1 >= CONFIG.badge_streak_days {
+            self.award(Badge::Streak);
+        }
+    }
+
+    pub fn record_upvote(&mut self) {
+        self.upvotes += 1;
+        if self.upvotes >= CONFIG.badge_upvotes {
+            self.award(Badge::Upvotes);
+        }
+    }
+
     pub fn notify<T: AsRef<str>>(&mut self, message: T) {
         self.notify_with_params(message, None)
     }
@@ -222,6 +595,26 @@ impl User {
         }
     }
 
+    pub fn update_interests(&mut self, tags: &BTreeSet<String>, delta: i64) {
+        for tag in tags {
+            let weight = self.interests.entry(tag.to_lowercase()).or_default();
+            *weight += delta;
+            if *weight == 0 {
+                self.interests.remove(&tag.to_lowercase());
+            }
+        }
+        // drop the weakest interests
+        while self.interests.len() > CONFIG.max_user_interests {
+            let weakest = self
+                .interests
+                .iter()
+                .min_by_key(|(_, weight)| weight.abs())
+                .map(|(tag, _)| tag.clone())
+                .expect("no interests");
+            self.interests.remove(&weakest);
+        }
+    }
+
     pub fn is_bot(&self) -> bool {
         self.controllers.iter().any(|p| p.len() == 27)
     }
@@ -237,6 +630,7 @@ impl User {
     }
 
     pub fn change_karma<T: ToString>(&mut self, delta: Karma, log: T) {
+        let total = self.total_karma();
         if delta > 0 {
             if self.karma >= 0 {
                 // if total karma is positivie and the delta is positive, increase rewards
@@ -258,10 +652,19 @@ impl User {
         if self.karma < 0 {
             self.rewarded_karma = 0;
         }
+        let change = self.total_karma() - total;
+        if let Some(other_karma) = self.other_karma.as_mut() {
+            *other_karma += change;
+        }
         self.ledger
             .push_front(("KRM".to_string(), delta, log.to_string()));
     }
 
+    // Karma including the rewards not applied yet.
+    pub fn total_karma(&self) -> Karma {
+        self.karma + self.rewarded_karma
+    }
+
     pub fn karma_to_reward(&self) -> Karma {
         self.rewarded_karma
     }
@@ -321,6 +724,41 @@ mod tests {
         assert_eq!(u.karma_to_reward(), 0);
     }
 
+    #[test]
+    fn test_badges() {
+        let mut u = User::new(pr(0), 66, 0, Default::default());
+        let day = 24 * HOUR;
+
+        // badges are awarded once
+        u.record_post(0);
+        u.record_post(HOUR);
+        assert_eq!(u.badges, vec![Badge::FirstPost].into_iter().collect());
+        assert!(!u.award(Badge::FirstPost));
+        for _ in 0..CONFIG.badge_upvotes - 1 {
+            u.record_upvote();
+        }
+        assert!(!u.badges.contains(&Badge::Upvotes));
+        u.record_upvote();
+        u.record_upvote();
+        assert_eq!(u.badges.len(), 2);
+
+        // posts on the same day don't extend the streak, a skipped day resets it
+        assert_eq!(u.streak, (0, 1));
+        u.record_post(day);
+        u.record_post(day + HOUR);
+        assert_eq!(u.streak, (1, 2));
+        u.record_post(3 * day);
+        assert_eq!(u.streak, (3, 1));
+        let last_day = 3 + CONFIG.badge_streak_days as u64 - 1;
+        for i in 4..last_day {
+            u.record_post(i * day);
+            assert!(!u.badges.contains(&Badge::Streak));
+        }
+        u.record_post(last_day * day);
+        assert_eq!(u.streak.1, CONFIG.badge_streak_days);
+        assert!(u.badges.contains(&Badge::Streak));
+    }
+
     #[test]
     fn test_change_cycles() {
         let mut u = User::new(pr(1), 66, 0, Default::default());
@@ -332,6 +770,57 @@ mod tests {
         assert_eq!(u.cycles(), 0);
     }
 
+    #[test]
+    fn test_drafts() {
+        let mut u = User::new(pr(1), 66, 0, Default::default());
+        assert_eq!(
+            u.save_draft(None, "".into(), None, 0),
+            Err("invalid draft".into())
+        );
+        for i in 0..CONFIG.max_drafts {
+            assert_eq!(
+                u.save_draft(None, format!("draft {}", i), None, 0),
+                Ok(i as u64)
+            );
+        }
+        assert_eq!(
+            u.save_draft(None, "one more".into(), None, 0),
+            Err(format!(
+                "not more than {} drafts are allowed",
+                CONFIG.max_drafts
+            ))
+        );
+
+        // existing drafts can still be updated
+        assert_eq!(u.save_draft(Some(3), "updated".into(), None, 5), Ok(3));
+        assert_eq!(u.drafts[3].body, "updated");
+        assert_eq!(u.drafts[3].timestamp, 5);
+        assert_eq!(
+            u.save_draft(Some(42), "missing".into(), None, 0),
+            Err("no draft found".into())
+        );
+
+        // deleting frees a slot and ids aren't reused
+        assert!(u.delete_draft(9).is_ok());
+        assert_eq!(u.delete_draft(9).err(), Some("no draft found".into()));
+        assert_eq!(u.save_draft(None, "new".into(), None, 0), Ok(9));
+        assert!(u.delete_draft(0).is_ok());
+        assert_eq!(u.save_draft(None, "newer".into(), None, 0), Ok(10));
+
+        // the storage quota applies to all drafts
+        let mut u = User::new(pr(2), 67, 0, Default::default());
+        let body = "x".repeat(CONFIG.max_post_length);
+        let fitting = CONFIG.max_drafts_size_bytes / CONFIG.max_post_length;
+        for i in 0..fitting {
+            assert_eq!(u.save_draft(None, body.clone(), None, 0), Ok(i as u64));
+        }
+        assert_eq!(
+            u.save_draft(None, body, None, 0),
+            Err("drafts storage quota exceeded".into())
+        );
+        assert_eq!(u.save_draft(Some(0), "x".into(), None, 0), Ok(0));
+    }
+
     #[test]
     fn test_change_karma() {
         let mut u = User::new(pr(1), 66, 0, Default::default());
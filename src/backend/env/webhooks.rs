@@ -0,0 +1,314 @@
+use super::config::CONFIG;
+use super::user::UserId;
+use super::State;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::export::Principal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EventKind {
+    Mention,
+    Reply,
+    Reward,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    // unique across all webhooks, so that receivers can drop duplicate deliveries
+    #[serde(default)]
+    pub id: u64,
+    pub event: EventKind,
+    pub actor: UserId,
+    // the post the event refers to
+    pub target: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    // consecutive failed deliveries
+    pub failures: u32,
+    pub disabled: bool,
+    // no delivery attempts before this time
+    pub retry_at: u64,
+    pub queue: Vec<WebhookEvent>,
+}
+
+pub fn set(state: &mut State, principal: Principal, url: String) -> Result<(), String> {
+    let user = state
+        .principal_to_user_mut(principal)
+        .ok_or("no user found")?;
+    if url.is_empty() {
+        user.webhook = None;
+        return Ok(());
+    }
+    if !user.trusted() {
+        return Err("only trusted users can set webhooks".into());
+    }
+    if !url.starts_with("https://") || url.len() > CONFIG.max_webhook_url_length {
+        return Err("invalid webhook url".into());
+    }
+    user.webhook = Some(Webhook {
+        url,
+        ..Default::default()
+    });
+    Ok(())
+}
+
+// Queues an event for the user's webhook if it is active; the oldest events are dropped on overflow.
+pub fn emit(
+    state: &mut State,
+    user_id: UserId,
+    event: EventKind,
+    actor: UserId,
+    target: u64,
+    timestamp: u64,
+) {
+    if let Some(webhook) = state
+        .users
+        .get_mut(&user_id)
+        .and_then(|user| user.webhook.as_mut())
+        .filter(|webhook| !webhook.disabled)
+    {
+        webhook.queue.push(WebhookEvent {
+            id: state.next_webhook_event,
+            event,
+            actor,
+            target,
+            timestamp,
+        });
+        let overflow = webhook.queue.len().saturating_sub(CONFIG.webhook_max_queue);
+        webhook.queue.drain(..overflow);
+        state.next_webhook_event += 1;
+    }
+}
+
+// Takes one batch of queued events from every webhook due for a delivery and charges its
+// owner for it; events of users without enough credits stay queued.
+pub fn batches(state: &mut State, now: u64) -> Vec<(UserId, String, Vec<WebhookEvent>)> {
+    let due = state
+        .users
+        .values()
+        .filter(|user| {
+            user.webhook
+                .as_ref()
+                .map(|webhook| {
+                    !webhook.disabled && !webhook.queue.is_empty() && now >= webhook.retry_at
+                })
+                .unwrap_or_default()
+        })
+        .map(|user| user.id)
+        .collect::<Vec<_>>();
+    due.into_iter()
+        .filter_map(|user_id| {
+            state
+                .charge(user_id, CONFIG.webhook_delivery_cost, "webhook delivery")
+                .ok()?;
+            let webhook = state.users.get_mut(&user_id)?.webhook.as_mut()?;
+            let size = webhook.queue.len().min(CONFIG.webhook_batch_size);
+            Some((
+                user_id,
+                webhook.url.clone(),
+                webhook.queue.drain(..size).collect(),
+            ))
+        })
+        .collect()
+}
+
+// Records the outcome of a delivery: failed batches are re-queued and retried with an
+// exponential backoff until the webhook gets disabled.
+pub fn delivered(
+    state: &mut State,
+    user_id: UserId,
+    url: &str,
+    events: Vec<WebhookEvent>,
+    result: Result<(), String>,
+    now: u64,
+) {
+    let user = match state.users.get_mut(&user_id) {
+        Some(user) => user,
+        None => return,
+    };
+    let webhook = match user.webhook.as_mut() {
+        // the webhook was changed or removed in the meantime
+        Some(webhook) if webhook.url == url => webhook,
+        _ => return,
+    };
+    let err = match result {
+        Ok(()) => {
+            webhook.failures = 0;
+            webhook.retry_at = 0;
+            return;
+        }
+        Err(err) => err,
+    };
+    webhook.failures += 1;
+    webhook.queue.splice(..0, events);
+    webhook.retry_at = now + CONFIG.webhook_backoff * (1 << webhook.failures.min(16));
+    if webhook.failures < CONFIG.webhook_max_failures {
+        return;
+    }
+    webhook.disabled = true;
+    webhook.queue.clear();
+    user.notify(format!(
+        "Your webhook was disabled after {} failed deliveries; the last error: {}",
+        CONFIG.webhook_max_failures, err
+    ));
+}
+
+pub async fn deliver(state: &mut State, now: u64) {
+    for (user_id, url, events) in batches(state, now) {
+        let result = post(&url, &events).await;
+        delivered(state, user_id, &url, events, result, now);
+    }
+}
+
+async fn post(url: &str, events: &[WebhookEvent]) -> Result<(), String> {
+    let body = serde_json::to_vec(events).map_err(|err| err.to_string())?;
+    let (response,) = http_request(CanisterHttpRequestArgument {
+        url: url.into(),
+        max_response_bytes: Some(CONFIG.webhook_max_response_bytes),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".into(),
+            value: "application/json".into(),
+        }],
+        body: Some(body),
+        transform: Some(TransformContext::new(webhook_transform, vec![])),
+    })
+    .await
+    .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+    if response.status.clone() / 100_u32 != 2_u32 {
+        return Err(format!("status code {}", response.status));
+    }
+    Ok(())
+}
+
+// Strips everything but the status code so that all replicas agree on the response.
+#[ic_cdk_macros::query]
+fn webhook_transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::tests::{create_untrusted_user, create_user, pr};
+
+    #[test]
+    fn test_webhook_batching() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        create_untrusted_user(&mut state, pr(1));
+        assert_eq!(
+            set(&mut state, pr(1), "https://example.com".into()),
+            Err("only trusted users can set webhooks".into())
+        );
+        assert_eq!(
+            set(&mut state, pr(0), "http://example.com".into()),
+            Err("invalid webhook url".into())
+        );
+        assert_eq!(set(&mut state, pr(0), "https://example.com".into()), Ok(()));
+        let credits = state.users.get(&0).unwrap().cycles();
+
+        for i in 0..(CONFIG.webhook_batch_size + 5) as u64 {
+            emit(&mut state, 0, EventKind::Mention, 1, i, 0);
+        }
+        let batches = batches(&mut state, 0);
+        assert_eq!(batches.len(), 1);
+        let (user_id, url, events) = &batches[0];
+        assert_eq!((*user_id, url.as_str()), (0, "https://example.com"));
+        assert_eq!(events.len(), CONFIG.webhook_batch_size);
+        assert_eq!(events[0].target, 0);
+        assert_eq!(events[0].event, EventKind::Mention);
+        // every event has a unique id
+        assert!(events.windows(2).all(|pair| pair[0].id + 1 == pair[1].id));
+        // every batch is charged
+        assert_eq!(
+            state.users.get(&0).unwrap().cycles(),
+            credits - CONFIG.webhook_delivery_cost
+        );
+
+        // the rest goes with the next batch
+        let webhook = state.users.get(&0).unwrap().webhook.as_ref().unwrap();
+        assert_eq!(webhook.queue.len(), 5);
+        assert_eq!(webhook.queue[0].target, CONFIG.webhook_batch_size as u64);
+
+        // the queue is capped
+        for i in 0..2 * CONFIG.webhook_max_queue as u64 {
+            emit(&mut state, 0, EventKind::Reply, 1, i, 0);
+        }
+        let webhook = state.users.get(&0).unwrap().webhook.as_ref().unwrap();
+        assert_eq!(webhook.queue.len(), CONFIG.webhook_max_queue);
+        assert_eq!(
+            webhook.queue.last().unwrap().target,
+            2 * CONFIG.webhook_max_queue as u64 - 1
+        );
+
+        // without credits, the events stay queued
+        let user = state.users.get_mut(&0).unwrap();
+        let credits = user.cycles();
+        user.change_cycles(-credits, "test").unwrap();
+        assert!(super::batches(&mut state, 0).is_empty());
+        let webhook = state.users.get(&0).unwrap().webhook.as_ref().unwrap();
+        assert_eq!(webhook.queue.len(), CONFIG.webhook_max_queue);
+    }
+
+    #[test]
+    fn test_webhook_auto_disable() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let url = "https://example.com".to_string();
+        set(&mut state, pr(0), url.clone()).unwrap();
+        emit(&mut state, 0, EventKind::Reward, 1, 7, 0);
+        let inbox = state.users.get(&0).unwrap().inbox.len();
+
+        let mut now = 0;
+        for i in 1..=CONFIG.webhook_max_failures {
+            let mut batch = batches(&mut state, now);
+            assert_eq!(batch.len(), 1);
+            let (_, _, events) = batch.pop().unwrap();
+            // no retries during the backoff
+            assert!(batches(&mut state, now + 1).is_empty());
+            delivered(&mut state, 0, &url, events, Err("timeout".into()), now);
+            let webhook = state.users.get(&0).unwrap().webhook.as_ref().unwrap();
+            assert_eq!(webhook.failures, i);
+            let retry_at = webhook.retry_at;
+            assert!(batches(&mut state, now).is_empty());
+            now = retry_at;
+        }
+
+        let user = state.users.get(&0).unwrap();
+        let webhook = user.webhook.as_ref().unwrap();
+        assert!(webhook.disabled);
+        assert!(webhook.queue.is_empty());
+        assert_eq!(user.inbox.len(), inbox + 1);
+        emit(&mut state, 0, EventKind::Reward, 1, 8, now);
+        assert!(batches(&mut state, now).is_empty());
+
+        // a successful delivery resets the failures
+        set(&mut state, pr(0), url.clone()).unwrap();
+        emit(&mut state, 0, EventKind::Reply, 1, 9, now);
+        let (_, _, events) = batches(&mut state, now).pop().unwrap();
+        delivered(&mut state, 0, &url, events, Err("timeout".into()), now);
+        let retry_at = state
+            .users
+            .get(&0)
+            .unwrap()
+            .webhook
+            .as_ref()
+            .unwrap()
+            .retry_at;
+        let (_, _, events) = batches(&mut state, retry_at).pop().unwrap();
+        delivered(&mut state, 0, &url, events, Ok(()), retry_at);
+        let webhook = state.users.get(&0).unwrap().webhook.as_ref().unwrap();
+        assert_eq!((webhook.failures, webhook.queue.len()), (0, 0));
+    }
+}
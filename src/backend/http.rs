@@ -1,5 +1,6 @@
 use super::{assets, state};
 use crate::config::CONFIG;
+use crate::env::{RealmVisibility, State};
 use ic_cdk::export::candid::CandidType;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
@@ -22,12 +23,44 @@ pub struct HttpResponse {
 #[ic_cdk_macros::query]
 fn http_request(req: HttpRequest) -> HttpResponse {
     let path = req.url.split('?').next().expect("no path in url");
+    if let Some(xml) = sitemap_page(path).and_then(|page| {
+        sitemap(
+            state(),
+            CONFIG.domains.first().expect("no domains"),
+            page,
+            CONFIG.sitemap_max_urls,
+        )
+    }) {
+        return HttpResponse {
+            status_code: 200,
+            headers: vec![(
+                "Content-Type".to_string(),
+                "application/xml; charset=utf-8".to_string(),
+            )],
+            body: ByteBuf::from(xml.into_bytes()),
+        };
+    }
+    let mut parts = path.split('/').skip(1);
+    if let (Some("feed"), Some(kind), Some(arg)) = (parts.next(), parts.next(), parts.next()) {
+        let domain = CONFIG.domains.first().expect("no domains");
+        if let Some(xml) = atom_feed(state(), domain, kind, arg) {
+            return HttpResponse {
+                status_code: 200,
+                headers: vec![(
+                    "Content-Type".to_string(),
+                    "application/atom+xml; charset=utf-8".to_string(),
+                )],
+                body: ByteBuf::from(xml.into_bytes()),
+            };
+        }
+    }
     let raw = req
         .headers
-        .into_iter()
+        .iter()
         .find_map(|(key, host)| (key.to_lowercase() == "host").then(|| host.contains(".raw")))
         .unwrap_or(false);
-    if !raw {
+    // crawlers get the pages with the meta tags for link previews
+    if !raw && !is_crawler(&req.headers) {
         let (headers, body) = assets::asset(path, !raw).expect("not found");
         return HttpResponse {
             status_code: 200,
@@ -57,52 +90,34 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
     };
     let mut parts = path.split('/').skip(1);
     match (parts.next(), parts.next()) {
-        (None, _) | (Some(""), _) => index(domain, "", CONFIG.name, "Web3 Social Network"),
-        (Some("post"), Some(id)) | (Some("thread"), Some(id)) => {
-            if let Some(post) = state
-                .posts
-                .get(&id.parse::<u64>().expect("couldn't parse post id"))
-            {
-                return index(
-                    domain,
-                    &format!(
-                        "{}/{}",
-                        match post.parent {
-                            None => "post",
-                            _ => "thread",
-                        },
-                        post.id
-                    ),
-                    &format!(
-                        "{} #{} by @{}",
-                        match post.parent {
-                            None => "Post",
-                            _ => "Reply",
-                        },
-                        post.id,
-                        state.users.get(&post.user)?.name
-                    ),
-                    &filter(&post.body),
-                );
-            }
-            None
-        }
+        (None, _) | (Some(""), _) => index(
+            domain,
+            &Preview::new("", CONFIG.name, "Web3 Social Network"),
+        ),
+        (Some("post"), Some(id)) | (Some("thread"), Some(id)) => index(
+            domain,
+            &post_preview(state, id.parse::<u64>().expect("couldn't parse post id"))?,
+        ),
         (Some("journal"), Some(handle)) => {
             let user = state.user(handle)?;
             index(
                 domain,
-                &format!("journal/{}", user.name),
-                &format!("@{}'s journal", user.name),
-                &filter(&user.about),
+                &Preview::new(
+                    &format!("journal/{}", user.name),
+                    &format!("@{}'s journal", user.name),
+                    &filter(&user.about),
+                ),
             )
         }
         (Some("user"), Some(handle)) => {
             let user = state.user(handle)?;
             index(
                 domain,
-                &format!("user/{}", user.name),
-                &format!("User @{}", user.name),
-                &filter(&user.about),
+                &Preview::new(
+                    &format!("user/{}", user.name),
+                    &format!("User @{}", user.name),
+                    &filter(&user.about),
+                ),
             )
         }
         (Some("realm"), Some(arg)) => {
@@ -110,22 +125,384 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
             let realm = state.realms.get(&id)?;
             index(
                 domain,
-                &format!("realm/{}", id),
-                &format!("Realm {}", id),
-                &filter(&realm.description),
+                &Preview::new(
+                    &format!("realm/{}", id),
+                    &format!("Realm {}", id),
+                    &filter(&realm.description),
+                ),
             )
         }
         (Some("feed"), Some(filter)) => index(
             domain,
-            &format!("feed/{}", filter),
-            filter,
-            &format!("Latest posts on {}", filter),
+            &Preview::new(
+                &format!("feed/{}", filter),
+                filter,
+                &format!("Latest posts on {}", filter),
+            ),
+        ),
+        _ => None,
+    }
+}
+
+// Renders an Atom feed with the latest public posts of a user or a realm.
+fn atom_feed(state: &State, domain: &str, kind: &str, arg: &str) -> Option<String> {
+    let (path, title, post_ids) = match kind {
+        "user" => {
+            let user = state.user(arg)?;
+            (
+                format!("user/{}", user.name),
+                format!("@{} on {}", user.name, CONFIG.name),
+                &user.posts,
+            )
+        }
+        "realm" => {
+            let id = arg.to_uppercase();
+            let realm = state.realms.get(&id)?;
+            if realm.visibility == RealmVisibility::Private {
+                return None;
+            }
+            (
+                format!("realm/{}", id),
+                format!("Realm {} on {}", id, CONFIG.name),
+                &realm.posts,
+            )
+        }
+        _ => return None,
+    };
+    let posts = post_ids
+        .iter()
+        .rev()
+        .filter_map(|id| state.posts.get(id))
+        .filter(|post| post.parent.is_none() && !post.deleted() && state.visible(None, post))
+        .take(CONFIG.atom_feed_size)
+        .collect::<Vec<_>>();
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<title>{}</title>
+<id>https://{}/#/{}</id>
+<link href="https://{1}/#/{2}"/>
+<updated>{}</updated>
+"#,
+        escape_xml(&title),
+        domain,
+        escape_xml(&path),
+        rfc3339(posts.first().map(|post| post.timestamp).unwrap_or_default())
+    );
+    for post in posts {
+        let author = state
+            .users
+            .get(&post.user)
+            .map(|user| user.name.as_str())
+            .unwrap_or_default();
+        let headline = post
+            .body
+            .lines()
+            .map(|line| line.trim_start_matches('#').trim())
+            .find(|line| !line.is_empty())
+            .map(|line| line.chars().take(100).collect::<String>())
+            .unwrap_or_else(|| format!("Post #{}", post.id));
+        xml.push_str(&format!(
+            r#"<entry>
+<title>{}</title>
+<id>https://{}/#/post/{}</id>
+<link href="https://{1}/#/post/{2}"/>
+<updated>{}</updated>
+<author><name>{}</name></author>
+<content type="text">{}</content>
+</entry>
+"#,
+            escape_xml(&headline),
+            domain,
+            post.id,
+            rfc3339(post.timestamp),
+            escape_xml(author),
+            escape_xml(&post.body)
+        ));
+    }
+    xml.push_str("</feed>\n");
+    Some(xml)
+}
+
+// Link preview of a page.
+struct Preview {
+    path: String,
+    title: String,
+    description: String,
+    image: Option<String>,
+}
+
+impl Preview {
+    fn new(path: &str, title: &str, description: &str) -> Self {
+        Self {
+            path: path.into(),
+            title: title.into(),
+            description: description.into(),
+            image: None,
+        }
+    }
+}
+
+const CRAWLERS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "facebookexternalhit",
+    "whatsapp",
+    "embedly",
+    "skypeuripreview",
+];
+
+fn is_crawler(headers: &Headers) -> bool {
+    headers.iter().any(|(key, value)| {
+        let agent = value.to_lowercase();
+        key.to_lowercase() == "user-agent" && CRAWLERS.iter().any(|name| agent.contains(name))
+    })
+}
+
+// Builds the preview of a public post with an excerpt of the body and its first image.
+fn post_preview(state: &State, id: u64) -> Option<Preview> {
+    let post = state
+        .posts
+        .get(&id)
+        .filter(|post| !post.deleted() && state.visible(None, post))?;
+    let words = post.body.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut description = words.chars().take(200).collect::<String>();
+    if description.len() < words.len() {
+        description.push('…');
+    }
+    let image = post.files.iter().next().and_then(|(key, (offset, _))| {
+        let (_, bucket_id) = key.split_once('@')?;
+        Some(format!("https://{}.ic0.app/blob/{}", bucket_id, offset))
+    });
+    Some(Preview {
+        path: format!(
+            "{}/{}",
+            match post.parent {
+                None => "post",
+                _ => "thread",
+            },
+            post.id
+        ),
+        title: format!(
+            "{} #{} by @{}",
+            match post.parent {
+                None => "Post",
+                _ => "Reply",
+            },
+            post.id,
+            state.users.get(&post.user)?.name
+        ),
+        description,
+        image,
+    })
+}
+
+// Renders the OpenGraph and Twitter card tags of a preview.
+fn meta_tags(host: &str, preview: &Preview) -> String {
+    let mut tags = vec![
+        (
+            "property",
+            "og:url",
+            format!("https://{}/#/{}", host, preview.path),
+        ),
+        ("property", "og:title", preview.title.clone()),
+        ("property", "og:description", preview.description.clone()),
+        (
+            "name",
+            "twitter:card",
+            match preview.image {
+                Some(_) => "summary_large_image",
+                None => "summary",
+            }
+            .into(),
         ),
+        ("name", "twitter:title", preview.title.clone()),
+        ("name", "twitter:description", preview.description.clone()),
+    ];
+    if let Some(image) = &preview.image {
+        tags.push(("property", "og:image", image.clone()));
+        tags.push(("name", "twitter:image", image.clone()));
+    }
+    tags.into_iter()
+        .map(|(attribute, key, content)| {
+            format!(
+                r#"<meta {}="{}" content="{}" />"#,
+                attribute,
+                key,
+                escape_xml(&content)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Maps `/sitemap.xml` to the root sitemap and `/sitemap/<n>.xml` to the n-th part of the index.
+fn sitemap_page(path: &str) -> Option<Option<usize>> {
+    if path == "/sitemap.xml" {
+        return Some(None);
+    }
+    path.strip_prefix("/sitemap/")?
+        .strip_suffix(".xml")?
+        .parse()
+        .ok()
+        .map(Some)
+}
+
+enum SitemapEntry<'a> {
+    Post(u64),
+    User(&'a str),
+    Realm(&'a str),
+}
+
+impl SitemapEntry<'_> {
+    fn path(&self) -> String {
+        match self {
+            SitemapEntry::Post(id) => format!("post/{}", id),
+            SitemapEntry::User(name) => format!("user/{}", name),
+            SitemapEntry::Realm(id) => format!("realm/{}", id),
+        }
+    }
+}
+
+// Lists all public posts, users and realms with their last modification times. Post and user
+// ids are sequential, so the entries come in a stable order without collecting and sorting.
+fn sitemap_urls(state: &State) -> impl Iterator<Item = (SitemapEntry<'_>, Option<u64>)> {
+    let last_post = move |ids: &[u64]| {
+        ids.last()
+            .and_then(|id| state.posts.get(id))
+            .map(|post| post.timestamp)
+    };
+    (0..state.next_post_id)
+        .filter_map(move |id| state.posts.get(&id))
+        .filter(move |post| !post.deleted() && state.visible(None, post))
+        .map(|post| (SitemapEntry::Post(post.id), Some(post.timestamp)))
+        .chain(
+            (0..state.next_user_id)
+                .filter_map(move |id| state.users.get(&id))
+                .map(move |user| {
+                    (
+                        SitemapEntry::User(&user.name),
+                        last_post(&user.posts).or(Some(user.timestamp)),
+                    )
+                }),
+        )
+        .chain(
+            state
+                .realms
+                .iter()
+                .filter(|(_, realm)| realm.visibility != RealmVisibility::Private)
+                .map(move |(id, realm)| (SitemapEntry::Realm(id), last_post(&realm.posts))),
+        )
+}
+
+// Renders the sitemap; if it has more URLs than the limit, the root sitemap becomes an
+// index of sitemap parts. Parts only render their own URLs.
+fn sitemap(state: &State, domain: &str, page: Option<usize>, limit: usize) -> Option<String> {
+    let limit = limit.max(1);
+    let lastmod = |timestamp: Option<u64>| {
+        timestamp
+            .map(|timestamp| format!("<lastmod>{}</lastmod>", rfc3339(timestamp)))
+            .unwrap_or_default()
+    };
+    let urlset = |urls: &mut dyn Iterator<Item = (SitemapEntry, Option<u64>)>| {
+        let mut xml = String::from(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+"#,
+        );
+        for (entry, timestamp) in urls {
+            xml.push_str(&format!(
+                "<url><loc>https://{}/#/{}</loc>{}</url>\n",
+                domain,
+                escape_xml(&entry.path()),
+                lastmod(timestamp)
+            ));
+        }
+        xml.push_str("</urlset>\n");
+        xml
+    };
+    // the last modification time of every part
+    let mut parts = Vec::new();
+    for (i, (_, timestamp)) in sitemap_urls(state).enumerate() {
+        if i % limit == 0 {
+            parts.push(None);
+        }
+        if let Some(last) = parts.last_mut() {
+            *last = (*last).max(timestamp);
+        }
+    }
+    match page {
+        None if parts.len() <= 1 => Some(urlset(&mut sitemap_urls(state))),
+        None => {
+            let mut xml = String::from(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+"#,
+            );
+            for (i, timestamp) in parts.iter().enumerate() {
+                xml.push_str(&format!(
+                    "<sitemap><loc>https://{}/sitemap/{}.xml</loc>{}</sitemap>\n",
+                    domain,
+                    i,
+                    lastmod(*timestamp)
+                ));
+            }
+            xml.push_str("</sitemapindex>\n");
+            Some(xml)
+        }
+        // parts only exist if the sitemap is split
+        Some(i) if parts.len() > 1 && i < parts.len() => {
+            Some(urlset(&mut sitemap_urls(state).skip(i * limit).take(limit)))
+        }
         _ => None,
     }
 }
 
-fn index(host: &str, path: &str, title: &str, desc: &str) -> Option<(Headers, ByteBuf)> {
+// Escapes XML special characters and drops control characters not allowed in XML.
+fn escape_xml(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            '\n' | '\r' | '\t' => result.push(c),
+            c if c.is_control() => {}
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Formats a timestamp in nanoseconds as an RFC 3339 UTC date.
+fn rfc3339(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn index(host: &str, preview: &Preview) -> Option<(Headers, ByteBuf)> {
     assets::asset("/", false).map(|(headers, body)| {
         (
             headers,
@@ -133,12 +510,7 @@ fn index(host: &str, path: &str, title: &str, desc: &str) -> Option<(Headers, By
                 String::from_utf8_lossy(&body)
                     .replace(
                         r#"<meta name="mark" content="OG">"#,
-                        &format!(
-                            r#"<meta content="https://{}/#/{}" property="og:url" />
-                               <meta content="{}" property="og:title" />
-                               <meta content="{}" property="og:description" />"#,
-                            host, path, title, &desc
-                        ),
+                        &meta_tags(host, preview),
                     )
                     .as_bytes()
                     .to_vec(),
@@ -146,3 +518,339 @@ fn index(host: &str, path: &str, title: &str, desc: &str) -> Option<(Headers, By
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{
+        post,
+        tests::{create_user, pr},
+    };
+
+    // Checks that all tags are balanced and no raw special characters leak into text.
+    fn well_formed(xml: &str) -> bool {
+        let mut stack = Vec::new();
+        let mut rest = xml;
+        while let Some(start) = rest.find('<') {
+            let text = &rest[..start];
+            if text.contains('>') || text.replace("&amp;", "").contains("&amp") {
+                return false;
+            }
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end,
+                None => return false,
+            };
+            let tag = &rest[start + 1..end];
+            if let Some(name) = tag.strip_prefix('/') {
+                if stack.pop() != Some(name.to_string()) {
+                    return false;
+                }
+            } else if !tag.starts_with('?') && !tag.ends_with('/') {
+                stack.push(tag.split(' ').next().unwrap_or_default().to_string());
+            }
+            rest = &rest[end + 1..];
+        }
+        stack.is_empty() && !rest.contains('<')
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            rfc3339(1_700_000_000 * 1_000_000_000),
+            "2023-11-14T22:13:20Z"
+        );
+        assert_eq!(rfc3339(951_782_400 * 1_000_000_000), "2000-02-29T00:00:00Z");
+    }
+
+    #[actix_rt::test]
+    async fn test_atom_feeds() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let realm = "SECRET".to_string();
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert!(state.toggle_realm_membership(pr(0), realm.clone()));
+
+        for i in 0..3 {
+            post::add(
+                &mut state,
+                format!("# Post {} <b>&\"'</b>\u{1}\nbody", i),
+                vec![],
+                pr(0),
+                i * CONFIG.rate_limit_window,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let realm_post = post::add(
+            &mut state,
+            "hidden realm post".to_string(),
+            vec![],
+            pr(0),
+            3 * CONFIG.rate_limit_window,
+            None,
+            Some(realm.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let xml = atom_feed(&state, "taggr.link", "user", "0").unwrap();
+        assert!(xml.starts_with(r#"<?xml version="1.0" encoding="utf-8"?>"#));
+        assert!(well_formed(&xml));
+        assert_eq!(xml.matches("<entry>").count(), 4);
+        assert!(xml.contains("<title>Post 2 &lt;b&gt;&amp;&quot;&apos;&lt;/b&gt;</title>"));
+        assert!(!xml.contains('\u{1}'));
+        assert!(xml.contains("<id>https://taggr.link/#/post/0</id>"));
+
+        // public realm feeds are served
+        let xml = atom_feed(&state, "taggr.link", "realm", "secret").unwrap();
+        assert!(well_formed(&xml));
+        assert!(xml.contains(&format!("/#/post/{}</id>", realm_post)));
+
+        // private realms and their posts are excluded
+        state.realms.get_mut(&realm).unwrap().visibility = RealmVisibility::Private;
+        assert!(atom_feed(&state, "taggr.link", "realm", "secret").is_none());
+        let xml = atom_feed(&state, "taggr.link", "user", "0").unwrap();
+        assert_eq!(xml.matches("<entry>").count(), 3);
+        assert!(!xml.contains("hidden realm post"));
+
+        assert!(atom_feed(&state, "taggr.link", "user", "unknown").is_none());
+        assert!(atom_feed(&state, "taggr.link", "tag", "0").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_sitemap_index_splitting() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        create_user(&mut state, pr(1));
+        for i in 0..3 {
+            post::add(
+                &mut state,
+                format!("Post {}", i),
+                vec![],
+                pr(0),
+                i * CONFIG.rate_limit_window,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        // 3 posts and 2 users fit into one sitemap
+        let xml = sitemap(&state, "taggr.link", None, 5).unwrap();
+        assert!(well_formed(&xml));
+        assert!(xml.contains("<urlset"));
+        assert_eq!(xml.matches("<url>").count(), 5);
+        assert!(xml.contains("<loc>https://taggr.link/#/post/0</loc>"));
+        let name = state.users.get(&1).unwrap().name.clone();
+        assert!(xml.contains(&format!("<loc>https://taggr.link/#/user/{}</loc>", name)));
+        assert!(sitemap(&state, "taggr.link", Some(0), 5).is_none());
+
+        // otherwise the root sitemap is an index of parts
+        let xml = sitemap(&state, "taggr.link", None, 2).unwrap();
+        assert!(well_formed(&xml));
+        assert!(xml.contains("<sitemapindex"));
+        assert_eq!(xml.matches("<sitemap>").count(), 3);
+        assert!(xml.contains("<loc>https://taggr.link/sitemap/2.xml</loc>"));
+        let parts = (0..3)
+            .map(|i| sitemap(&state, "taggr.link", Some(i), 2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts
+                .iter()
+                .map(|xml| xml.matches("<url>").count())
+                .collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert!(parts[0].contains("/#/post/1</loc>"));
+        assert!(parts[2].contains(&format!("/#/user/{}</loc>", name)));
+        assert!(sitemap(&state, "taggr.link", Some(3), 2).is_none());
+
+        // edits update the last modification time
+        let edited = 10 * CONFIG.rate_limit_window;
+        post::edit(
+            &mut state,
+            1,
+            "Edited".into(),
+            vec![],
+            "".into(),
+            None,
+            pr(0),
+            edited,
+        )
+        .await
+        .unwrap();
+        assert!(sitemap(&state, "taggr.link", Some(0), 2)
+            .unwrap()
+            .contains(&format!(
+                "/#/post/1</loc><lastmod>{}</lastmod>",
+                rfc3339(edited)
+            )));
+
+        assert_eq!(sitemap_page("/sitemap.xml"), Some(None));
+        assert_eq!(sitemap_page("/sitemap/12.xml"), Some(Some(12)));
+        assert_eq!(sitemap_page("/sitemap/x.xml"), None);
+        assert_eq!(sitemap_page("/post/1"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_sitemap_excludes_private_content() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        state
+            .principal_to_user_mut(pr(0))
+            .unwrap()
+            .change_cycles(CONFIG.realm_cost, "")
+            .unwrap();
+        let realm = "SECRET".to_string();
+        state
+            .create_realm(
+                pr(0),
+                realm.clone(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                vec![0],
+            )
+            .unwrap();
+        assert!(state.toggle_realm_membership(pr(0), realm.clone()));
+        let public = post::add(
+            &mut state,
+            "public post".into(),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let hidden = post::add(
+            &mut state,
+            "realm post".into(),
+            vec![],
+            pr(0),
+            CONFIG.rate_limit_window,
+            None,
+            Some(realm.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let xml = sitemap(&state, "taggr.link", None, 100).unwrap();
+        assert!(xml.contains(&format!("/#/post/{}</loc>", hidden)));
+        assert!(xml.contains("/#/realm/SECRET</loc>"));
+
+        state.realms.get_mut(&realm).unwrap().visibility = RealmVisibility::Private;
+        let xml = sitemap(&state, "taggr.link", None, 100).unwrap();
+        assert!(xml.contains(&format!("/#/post/{}</loc>", public)));
+        assert!(!xml.contains(&format!("/#/post/{}</loc>", hidden)));
+        assert!(!xml.contains("/#/realm/SECRET"));
+        let name = &state.users.get(&0).unwrap().name;
+        assert!(xml.contains(&format!("/#/user/{}</loc>", name)));
+    }
+
+    #[actix_rt::test]
+    async fn test_post_meta_tags() {
+        let mut state = State::default();
+        create_user(&mut state, pr(0));
+        let id = post::add(
+            &mut state,
+            format!("Hello   world\n{}", "word ".repeat(100)),
+            vec![],
+            pr(0),
+            0,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let bucket = "e3mmv-5qaaa-aaaah-aadma-cai";
+        state
+            .posts
+            .get_mut(&id)
+            .unwrap()
+            .files
+            .insert(format!("pic@{}", bucket), (108, 1024));
+
+        let preview = post_preview(&state, id).unwrap();
+        let name = &state.users.get(&0).unwrap().name;
+        assert_eq!(preview.title, format!("Post #{} by @{}", id, name));
+        assert!(preview.description.starts_with("Hello world word word"));
+        assert_eq!(preview.description.chars().count(), 201);
+        assert!(preview.description.ends_with('…'));
+
+        let tags = meta_tags("taggr.link", &preview);
+        assert!(tags.contains(&format!(
+            r#"<meta property="og:url" content="https://taggr.link/#/post/{}" />"#,
+            id
+        )));
+        assert!(tags.contains(&format!(
+            r#"<meta name="twitter:title" content="Post #{} by @{}" />"#,
+            id, name
+        )));
+        assert!(tags.contains(&format!(
+            r#"<meta property="og:image" content="https://{}.ic0.app/blob/108" />"#,
+            bucket
+        )));
+        assert!(tags.contains(r#"<meta name="twitter:card" content="summary_large_image" />"#));
+
+        // deleted posts have no previews
+        state.posts.get_mut(&id).unwrap().hashes = vec!["hash".into()];
+        assert!(post_preview(&state, id).is_none());
+
+        assert!(is_crawler(&vec![(
+            "User-Agent".into(),
+            "Mozilla/5.0 (compatible; Twitterbot/1.0)".into()
+        )]));
+        assert!(!is_crawler(&vec![(
+            "user-agent".into(),
+            "Mozilla/5.0 (X11; Linux x86_64) Firefox/115.0".into()
+        )]));
+    }
+
+    #[test]
+    fn test_meta_tags_escaping() {
+        let preview = Preview::new(
+            "post/1",
+            r#"Post "quoted" <b>"#,
+            r#"1 < 2 && 'x' > "y"" /><script>alert(1)</script>"#,
+        );
+        let tags = meta_tags("taggr.link", &preview);
+        assert!(well_formed(&tags));
+        assert!(!tags.contains("<script>"));
+        assert!(tags.contains(
+            r#"<meta property="og:title" content="Post &quot;quoted&quot; &lt;b&gt;" />"#
+        ));
+        assert!(tags.contains(
+            r#"content="1 &lt; 2 &amp;&amp; &apos;x&apos; &gt; &quot;y&quot;&quot; /&gt;&lt;script&gt;alert(1)&lt;/script&gt;" />"#
+        ));
+        // no image tags without an image
+        assert!(tags.contains(r#"<meta name="twitter:card" content="summary" />"#));
+        assert!(!tags.contains("og:image"));
+        assert_eq!(tags.matches("<meta ").count(), 6);
+    }
+}
@@ -4,15 +4,16 @@ use env::{
     canisters::upgrade_main_canister,
     config::CONFIG,
     memory,
-    post::{Extension, Post, PostId},
+    post::{CommentSort, Extension, Post, PostId, PostStatus},
     proposals::{Payload, Release, Status},
-    user::{User, UserId},
+    user::{FeedMode, NotificationSettings, OnboardingStep, User, UserId},
     State, *,
 };
 use ic_cdk::{
     api::{
         self,
         call::{arg_data_raw, reply_raw},
+        management_canister::main::raw_rand,
     },
     caller, id, spawn, timer,
 };
@@ -54,7 +55,10 @@ fn init() {
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    env::memory::heap_to_stable(state_mut());
+    // trapping aborts the upgrade and keeps the running version
+    if let Err(err) = env::memory::dump_heap(state_mut()) {
+        panic!("couldn't dump the heap: {}", err)
+    }
 }
 
 #[post_upgrade]
@@ -73,8 +77,17 @@ fn post_upgrade() {
 
     // temporary post upgrade logic goes here
     let s = state_mut();
-    for realm in s.realms.values_mut() {
-        realm.posts.sort_unstable_by(|a, b| a.cmp(&b));
+    // users without tracked other karma get it initialized once
+    let user_ids = s
+        .users
+        .values()
+        .filter(|user| user.other_karma.is_none())
+        .map(|user| user.id)
+        .collect::<Vec<_>>();
+    for id in user_ids {
+        let reaction_karma = env::audit::reaction_karma(s, id);
+        let user = s.users.get_mut(&id).expect("no user found");
+        user.other_karma = Some(user.total_karma() - reaction_karma);
     }
 }
 
@@ -116,7 +129,7 @@ fn add_bucket(id: String) {
     state_mut()
         .storage
         .buckets
-        .insert(Principal::from_text(id).unwrap(), 0);
+        .insert(Principal::from_text(id).unwrap(), Default::default());
 }
 
 #[cfg(feature = "dev")]
@@ -157,15 +170,34 @@ fn heap_to_stable() {
         .expect("no user found")
         .clone();
     if user.stalwart {
-        env::memory::heap_to_stable(s);
+        // trapping rolls back the partially dumped heap
+        if let Err(err) = env::memory::heap_to_stable(s) {
+            panic!("couldn't dump the heap: {}", err)
+        }
         s.logger.info(format!(
-            "@{} dumped heap to stable memory for backup purposes.",
+            "@{} dumped heap to stable memory for backup purposes; the next upgrade dumps the full heap too.",
             user.name
         ));
     }
     reply_raw(&[]);
 }
 
+#[export_name = "canister_update reconcile_buckets"]
+fn reconcile_buckets() {
+    spawn(async {
+        let canisters: Vec<String> = parse(&arg_data_raw());
+        reply(state_mut().reconcile_buckets(caller(), canisters).await)
+    });
+}
+
+#[export_name = "canister_update decommission_bucket"]
+fn decommission_bucket() {
+    spawn(async {
+        let id: String = parse(&arg_data_raw());
+        reply(state_mut().decommission_bucket(caller(), id).await)
+    });
+}
+
 #[export_name = "canister_update execute_upgrade"]
 fn execute_upgrade() {
     let state = state_mut();
@@ -237,16 +269,46 @@ fn vote_on_report() {
 
 #[export_name = "canister_update vote_on_poll"]
 fn vote_on_poll() {
-    let (post_id, vote): (PostId, u16) = parse(&arg_data_raw());
+    let (post_id, vote): (PostId, Vec<u16>) = parse(&arg_data_raw());
     reply(state_mut().vote_on_poll(caller(), api::time(), post_id, vote));
 }
 
+#[export_name = "canister_update release_held_post"]
+fn release_held_post() {
+    let post_id: PostId = parse(&arg_data_raw());
+    reply(env::coordination::release(state_mut(), caller(), post_id));
+}
+
 #[export_name = "canister_update report"]
 fn report() {
     let (post_id, reason): (PostId, String) = parse(&arg_data_raw());
     reply(state_mut().report(caller(), post_id, reason));
 }
 
+#[export_name = "canister_update report_post"]
+fn report_post() {
+    let (post_id, reason): (PostId, String) = parse(&arg_data_raw());
+    reply(env::reports::report_to_realm(
+        state_mut(),
+        caller(),
+        post_id,
+        reason,
+        api::time(),
+    ));
+}
+
+#[export_name = "canister_update resolve_realm_report"]
+fn resolve_realm_report() {
+    let (post_id, resolution): (PostId, env::reports::Resolution) = parse(&arg_data_raw());
+    reply(env::reports::resolve_realm_report(
+        state_mut(),
+        caller(),
+        post_id,
+        resolution,
+        api::time(),
+    ));
+}
+
 #[export_name = "canister_update clear_notifications"]
 fn clear_notifications() {
     let ids: Vec<String> = parse(&arg_data_raw());
@@ -254,18 +316,98 @@ fn clear_notifications() {
     reply_raw(&[]);
 }
 
+#[export_name = "canister_update tip_tokens"]
+fn tip_tokens() {
+    let (post_id, amount): (PostId, token::Token) = parse(&arg_data_raw());
+    reply(state_mut().tip_tokens(caller(), post_id, amount, time()));
+}
+
 #[export_name = "canister_update tip"]
 fn tip() {
     let (post_id, tip): (PostId, Cycles) = parse(&arg_data_raw());
     reply(state_mut().tip(caller(), post_id, tip));
 }
 
+#[export_name = "canister_update create_bounty"]
+fn create_bounty() {
+    let (post_id, amount, duration_hours): (PostId, token::Token, u64) = parse(&arg_data_raw());
+    reply(env::bounties::create(
+        state_mut(),
+        caller(),
+        post_id,
+        amount,
+        duration_hours,
+        time(),
+    ));
+}
+
+#[export_name = "canister_update accept_answer"]
+fn accept_answer() {
+    let (post_id, answer_id): (PostId, PostId) = parse(&arg_data_raw());
+    reply(env::bounties::accept(
+        state_mut(),
+        caller(),
+        post_id,
+        answer_id,
+        time(),
+    ));
+}
+
+#[export_name = "canister_update tip_ckbtc"]
+fn tip_ckbtc() {
+    let (post_id, amount): (PostId, u64) = parse(&arg_data_raw());
+    reply(env::ckbtc::tip(state_mut(), caller(), post_id, amount));
+}
+
+#[export_name = "canister_update deposit_ckbtc"]
+fn deposit_ckbtc() {
+    spawn(async {
+        reply(env::ckbtc::deposit(state_mut(), caller()).await);
+    });
+}
+
+#[export_name = "canister_update withdraw_ckbtc"]
+fn withdraw_ckbtc() {
+    spawn(async {
+        let (recipient, amount): (String, u64) = parse(&arg_data_raw());
+        reply(env::ckbtc::withdraw(state_mut(), caller(), recipient, amount, time()).await);
+    });
+}
+
+#[export_name = "canister_update canister_preview"]
+fn canister_preview() {
+    spawn(async {
+        let id: String = parse(&arg_data_raw());
+        reply(env::previews::resolve(state_mut(), id, api::time()).await);
+    });
+}
+
+// Returns the hex-encoded deposit subaccount of the caller and the credited balance.
+#[export_name = "canister_query ckbtc_wallet"]
+fn ckbtc_wallet() {
+    let s = state();
+    let balance = s
+        .principal_to_user(caller())
+        .map(|user| s.ckbtc.balance(user.id))
+        .unwrap_or_default();
+    reply((
+        hex::encode(env::ckbtc::deposit_subaccount(caller())),
+        balance,
+    ));
+}
+
 #[export_name = "canister_update react"]
 fn react() {
     let (post_id, reaction): (PostId, u16) = parse(&arg_data_raw());
     reply(state_mut().react(caller(), post_id, reaction, api::time()));
 }
 
+#[export_name = "canister_update react_many"]
+fn react_many() {
+    let reactions: Vec<(PostId, u16)> = parse(&arg_data_raw());
+    reply(state_mut().react_many(caller(), reactions, api::time()));
+}
+
 #[export_name = "canister_update update_last_activity"]
 fn update_last_activity() {
     if let Some(user) = state_mut().principal_to_user_mut(caller()) {
@@ -280,6 +422,41 @@ fn change_principal() {
     reply(state_mut().change_principal(caller(), principal));
 }
 
+#[export_name = "canister_update link_principal"]
+fn link_principal() {
+    let principal: String = parse(&arg_data_raw());
+    reply(state_mut().link_principal(caller(), principal));
+}
+
+#[export_name = "canister_update confirm_principal_link"]
+fn confirm_principal_link() {
+    let user_id: UserId = parse(&arg_data_raw());
+    reply(state_mut().confirm_principal_link(caller(), user_id));
+}
+
+#[export_name = "canister_update unlink_principal"]
+fn unlink_principal() {
+    let principal: String = parse(&arg_data_raw());
+    reply(state_mut().unlink_principal(caller(), principal));
+}
+
+#[export_name = "canister_update set_guardians"]
+fn set_guardians() {
+    let (guardians, quorum): (Vec<String>, usize) = parse(&arg_data_raw());
+    reply(state_mut().set_guardians(caller(), guardians, quorum));
+}
+
+#[export_name = "canister_update approve_recovery"]
+fn approve_recovery() {
+    let (user_id, principal): (UserId, String) = parse(&arg_data_raw());
+    reply(state_mut().approve_recovery(caller(), user_id, principal, api::time()));
+}
+
+#[export_name = "canister_update contest_recovery"]
+fn contest_recovery() {
+    reply(state_mut().contest_recovery(caller()));
+}
+
 #[export_name = "canister_update update_user"]
 fn update_user() {
     let (about, principals, settings): (String, Vec<String>, String) = parse(&arg_data_raw());
@@ -291,13 +468,24 @@ fn update_user() {
         return;
     }
     if let Some(user) = state.principal_to_user_mut(caller()) {
+        // only filling an empty profile completes the onboarding step
+        let completed = user.about.is_empty() && !about.is_empty();
         user.update(about, principals, settings);
+        let user_id = user.id;
+        if completed {
+            state.complete_onboarding_step(user_id, OnboardingStep::Profile);
+        }
     } else {
         response = Err("no user found".into());
     }
     reply(response);
 }
 
+#[export_name = "canister_update claim_onboarding_rewards"]
+fn claim_onboarding_rewards() {
+    reply(state_mut().claim_onboarding_rewards(caller()));
+}
+
 #[export_name = "canister_update create_user"]
 fn create_user() {
     let (name, invite): (String, Option<String>) = parse(&arg_data_raw());
@@ -382,6 +570,127 @@ fn propose_funding() {
     });
 }
 
+#[export_name = "canister_update propose_realm_spending"]
+fn propose_realm_spending() {
+    spawn(async {
+        let (description, realm, receiver, tokens): (String, String, String, u64) =
+            parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::RealmSpend(realm, receiver, tokens),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update propose_vesting"]
+fn propose_vesting() {
+    spawn(async {
+        let (description, receiver, tokens, cliff_days, duration_days): (
+            String,
+            String,
+            u64,
+            u64,
+            u64,
+        ) = parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::Vest(receiver, tokens, cliff_days, duration_days),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update propose_vesting_revocation"]
+fn propose_vesting_revocation() {
+    spawn(async {
+        let (description, id): (String, u64) = parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::RevokeVesting(id),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update claim_vested"]
+fn claim_vested() {
+    let id: usize = parse(&arg_data_raw());
+    reply(token::claim_vested(state_mut(), caller(), id, time()));
+}
+
+#[export_name = "canister_update propose_thresholds"]
+fn propose_thresholds() {
+    spawn(async {
+        let (description, quorum, approval): (String, u16, u16) = parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::SetThresholds(proposals::Thresholds { quorum, approval }),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update propose_coordination_settings"]
+fn propose_coordination_settings() {
+    spawn(async {
+        let (description, settings): (String, env::coordination::Settings) = parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::SetCoordinationSettings(settings),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update propose_max_bucket_size"]
+fn propose_max_bucket_size() {
+    spawn(async {
+        let (description, size): (String, u64) = parse(&arg_data_raw());
+        reply(
+            proposals::propose(
+                state_mut(),
+                caller(),
+                description,
+                proposals::Payload::SetMaxBucketSize(size),
+                time(),
+            )
+            .await,
+        )
+    });
+}
+
+#[export_name = "canister_update delegate_votes"]
+fn delegate_votes() {
+    let (topic, delegate): (Option<String>, Option<UserId>) = parse(&arg_data_raw());
+    reply(proposals::delegate(state_mut(), caller(), topic, delegate))
+}
+
 #[export_name = "canister_update vote_on_proposal"]
 fn vote_on_proposal() {
     spawn(async {
@@ -406,8 +715,24 @@ async fn add_post(
     parent: Option<PostId>,
     realm: Option<String>,
     extension: Option<ByteBuf>,
+    scheduled_at: Option<u64>,
 ) -> Result<PostId, String> {
+    state().health.check()?;
     let extension: Option<Extension> = extension.map(|bytes| parse(&bytes));
+    // scheduled posts return their schedule id
+    if let Some(scheduled_at) = scheduled_at {
+        return post::schedule(
+            state_mut(),
+            body,
+            blobs,
+            caller(),
+            api::time(),
+            scheduled_at,
+            parent,
+            realm,
+            extension,
+        );
+    }
     post::add(
         state_mut(),
         body,
@@ -421,6 +746,26 @@ async fn add_post(
     .await
 }
 
+#[update]
+async fn quote_post(
+    body: String,
+    blobs: Vec<(String, Blob)>,
+    quoted: PostId,
+    realm: Option<String>,
+) -> Result<PostId, String> {
+    state().health.check()?;
+    post::quote(
+        state_mut(),
+        body,
+        blobs,
+        caller(),
+        api::time(),
+        quoted,
+        realm,
+    )
+    .await
+}
+
 #[update]
 async fn edit_post(
     id: PostId,
@@ -429,6 +774,7 @@ async fn edit_post(
     patch: String,
     realm: Option<String>,
 ) -> Result<(), String> {
+    state().health.check()?;
     post::edit(
         state_mut(),
         id,
@@ -442,10 +788,55 @@ async fn edit_post(
     .await
 }
 
+#[update]
+fn edit_scheduled_post(
+    id: u64,
+    body: String,
+    blobs: Vec<(String, Blob)>,
+    scheduled_at: u64,
+) -> Result<(), String> {
+    state().health.check()?;
+    post::edit_scheduled(
+        state_mut(),
+        caller(),
+        id,
+        body,
+        blobs,
+        api::time(),
+        scheduled_at,
+    )
+}
+
+#[export_name = "canister_update cancel_scheduled_post"]
+fn cancel_scheduled_post() {
+    let id: u64 = parse(&arg_data_raw());
+    reply(post::cancel_scheduled(state_mut(), caller(), id));
+}
+
+#[export_name = "canister_query scheduled_posts"]
+fn scheduled_posts() {
+    let state = state();
+    let user_id = state.principal_to_user(caller()).map(|user| user.id);
+    reply(
+        state
+            .scheduled_posts
+            .iter()
+            .filter(|(_, post)| Some(post.user) == user_id)
+            .map(|((scheduled_at, id), post)| (*id, *scheduled_at, post.body.clone(), post.parent))
+            .collect::<Vec<_>>(),
+    );
+}
+
 #[export_name = "canister_update delete_post"]
 fn delete_post() {
     let (post_id, versions): (PostId, Vec<String>) = parse(&arg_data_raw());
-    reply(state_mut().delete_post(caller(), post_id, versions));
+    reply(state_mut().delete_post(caller(), post_id, versions, api::time()));
+}
+
+#[export_name = "canister_update restore_post"]
+fn restore_post() {
+    let post_id: PostId = parse(&arg_data_raw());
+    reply(state_mut().restore_post(caller(), post_id, api::time()));
 }
 
 #[export_name = "canister_update toggle_bookmark"]
@@ -457,6 +848,127 @@ fn toggle_bookmark() {
     reply(true);
 }
 
+#[export_name = "canister_update save_draft"]
+fn save_draft() {
+    let (id, body, realm): (Option<u64>, String, Option<String>) = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .principal_to_user_mut(caller())
+            .ok_or_else(|| "no user found".to_string())
+            .and_then(|user| user.save_draft(id, body, realm, api::time())),
+    )
+}
+
+#[export_name = "canister_update delete_draft"]
+fn delete_draft() {
+    let id: u64 = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .principal_to_user_mut(caller())
+            .ok_or_else(|| "no user found".to_string())
+            .and_then(|user| user.delete_draft(id).map(|_| ())),
+    )
+}
+
+#[export_name = "canister_query drafts"]
+fn drafts() {
+    reply(
+        state()
+            .principal_to_user(caller())
+            .map(|user| user.drafts.clone())
+            .unwrap_or_default(),
+    )
+}
+
+#[export_name = "canister_query export"]
+fn export() {
+    let cursor: Option<String> = parse(&arg_data_raw());
+    reply(env::export::export(
+        state(),
+        caller(),
+        cursor,
+        CONFIG.max_export_page_bytes,
+    ))
+}
+
+#[update]
+async fn publish_draft(id: u64) -> Result<PostId, String> {
+    state().health.check()?;
+    post::publish_draft(state_mut(), caller(), id, api::time()).await
+}
+
+#[export_name = "canister_update create_collection"]
+fn create_collection() {
+    let (title, public): (String, bool) = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .principal_to_user_mut(caller())
+            .ok_or_else(|| "no user found".to_string())
+            .and_then(|user| user.create_collection(title, public)),
+    )
+}
+
+#[export_name = "canister_update delete_collection"]
+fn delete_collection() {
+    let index: usize = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .principal_to_user_mut(caller())
+            .ok_or_else(|| "no user found".to_string())
+            .and_then(|user| user.delete_collection(index)),
+    )
+}
+
+#[export_name = "canister_update add_to_collection"]
+fn add_to_collection() {
+    let (index, post_id): (usize, PostId) = parse(&arg_data_raw());
+    reply(state_mut().add_to_collection(caller(), index, post_id))
+}
+
+#[export_name = "canister_update remove_from_collection"]
+fn remove_from_collection() {
+    let (index, post_id): (usize, PostId) = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .prune_collections(caller())
+            .and_then(|user| user.remove_from_collection(index, post_id)),
+    )
+}
+
+#[export_name = "canister_update reorder_collection"]
+fn reorder_collection() {
+    let (index, posts): (usize, Vec<PostId>) = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .prune_collections(caller())
+            .and_then(|user| user.reorder_collection(index, posts)),
+    )
+}
+
+#[export_name = "canister_update mute_user"]
+fn mute_user() {
+    let (user_id, muted): (UserId, bool) = parse(&arg_data_raw());
+    reply(state_mut().mute_user(caller(), user_id, muted))
+}
+
+#[export_name = "canister_update set_feed_mode"]
+fn set_feed_mode() {
+    let mode: FeedMode = parse(&arg_data_raw());
+    reply(state_mut().set_feed_mode(caller(), mode))
+}
+
+#[export_name = "canister_update set_notification_settings"]
+fn set_notification_settings() {
+    let settings: NotificationSettings = parse(&arg_data_raw());
+    reply(state_mut().set_notification_settings(caller(), settings))
+}
+
+#[export_name = "canister_update block_user"]
+fn block_user() {
+    let (user_id, blocked): (UserId, bool) = parse(&arg_data_raw());
+    reply(state_mut().block_user(caller(), user_id, blocked))
+}
+
 #[export_name = "canister_update toggle_following_post"]
 fn toggle_following_post() {
     let post_id: PostId = parse(&arg_data_raw());
@@ -480,6 +992,17 @@ fn toggle_following_feed() {
     )
 }
 
+#[export_name = "canister_update toggle_following_tag"]
+fn toggle_following_tag() {
+    let tag: String = parse(&arg_data_raw());
+    reply(
+        state_mut()
+            .principal_to_user_mut(caller())
+            .map(|user| user.toggle_following_tag(&tag))
+            .unwrap_or_default(),
+    )
+}
+
 #[export_name = "canister_update edit_realm"]
 fn edit_realm() {
     let (name, logo, label_color, description, controllers): (
@@ -492,6 +1015,153 @@ fn edit_realm() {
     reply(state_mut().edit_realm(caller(), name, logo, label_color, description, controllers))
 }
 
+#[export_name = "canister_update set_webhook"]
+fn set_webhook() {
+    let url: String = parse(&arg_data_raw());
+    reply(webhooks::set(state_mut(), caller(), url))
+}
+
+#[export_name = "canister_update merge_realms"]
+fn merge_realms() {
+    let (source, target): (String, String) = parse(&arg_data_raw());
+    reply(state_mut().merge_realms(caller(), source, target))
+}
+
+#[export_name = "canister_update set_realm_tiers"]
+fn set_realm_tiers() {
+    let (name, tiers): (String, Vec<Tier>) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_tiers(caller(), name, tiers))
+}
+
+#[export_name = "canister_update donate_to_realm"]
+fn donate_to_realm() {
+    let (name, amount): (String, u64) = parse(&arg_data_raw());
+    reply(state_mut().donate_to_realm(caller(), name, amount, time()))
+}
+
+#[export_name = "canister_update set_realm_visibility"]
+fn set_realm_visibility() {
+    let (name, visibility): (String, RealmVisibility) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_visibility(caller(), name, visibility))
+}
+
+#[export_name = "canister_update resolve_join_request"]
+fn resolve_join_request() {
+    let (name, user_id, approve): (String, UserId, bool) = parse(&arg_data_raw());
+    reply(state_mut().resolve_join_request(caller(), name, user_id, approve))
+}
+
+#[export_name = "canister_update create_realm_invite"]
+fn create_realm_invite() {
+    spawn(async {
+        let (name, max_uses, expires_in_hours): (String, u32, Option<u64>) = parse(&arg_data_raw());
+        let principal = caller();
+        // invite codes must not be guessable, so they're derived from the canister randomness
+        let seed = match raw_rand().await {
+            Ok((seed,)) => seed,
+            Err(err) => {
+                return reply(Err::<String, String>(format!(
+                    "couldn't get randomness: {:?}",
+                    err
+                )))
+            }
+        };
+        reply(state_mut().create_realm_invite(
+            principal,
+            name,
+            max_uses,
+            expires_in_hours,
+            &seed,
+            time(),
+        ))
+    });
+}
+
+#[export_name = "canister_update redeem_realm_invite"]
+fn redeem_realm_invite() {
+    let code: String = parse(&arg_data_raw());
+    reply(state_mut().redeem_realm_invite(caller(), code, time()))
+}
+
+#[export_name = "canister_update pin_post"]
+fn pin_post() {
+    let (name, post_id): (String, PostId) = parse(&arg_data_raw());
+    reply(state_mut().pin_post(caller(), name, post_id))
+}
+
+#[export_name = "canister_update unpin_post"]
+fn unpin_post() {
+    let (name, post_id): (String, PostId) = parse(&arg_data_raw());
+    reply(state_mut().unpin_post(caller(), name, post_id))
+}
+
+#[export_name = "canister_update set_realm_archival"]
+fn set_realm_archival() {
+    let (name, delay_hours, cold_storage): (String, Option<u64>, bool) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_archival(caller(), name, delay_hours, cold_storage))
+}
+
+#[export_name = "canister_update set_realm_rewards"]
+fn set_realm_rewards() {
+    let (name, amount, period_hours): (String, token::Token, u64) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_rewards(caller(), name, amount, period_hours, api::time()))
+}
+
+#[export_name = "canister_update set_realm_post_limits"]
+fn set_realm_post_limits() {
+    let (name, max_length, max_attachments): (String, Option<usize>, Option<usize>) =
+        parse(&arg_data_raw());
+    reply(state_mut().set_realm_post_limits(caller(), name, max_length, max_attachments));
+}
+
+#[export_name = "canister_update set_realm_blocked_patterns"]
+fn set_realm_blocked_patterns() {
+    let (name, patterns): (String, Vec<String>) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_blocked_patterns(caller(), name, patterns))
+}
+
+#[export_name = "canister_update set_realm_reward_multiplier"]
+fn set_realm_reward_multiplier() {
+    let (name, percentage): (String, u32) = parse(&arg_data_raw());
+    reply(state_mut().set_realm_reward_multiplier(caller(), name, percentage))
+}
+
+#[export_name = "canister_update set_post_status"]
+fn set_post_status() {
+    let (post_id, status): (PostId, PostStatus) = parse(&arg_data_raw());
+    reply(post::set_status(
+        state_mut(),
+        caller(),
+        post_id,
+        status,
+        api::time(),
+    ))
+}
+
+#[export_name = "canister_update transfer_post"]
+fn transfer_post() {
+    let (post_id, new_owner): (PostId, UserId) = parse(&arg_data_raw());
+    reply(post::transfer(
+        state_mut(),
+        caller(),
+        post_id,
+        new_owner,
+        api::time(),
+    ))
+}
+
+#[export_name = "canister_update cross_post"]
+fn cross_post() {
+    let (post_id, realm): (PostId, String) = parse(&arg_data_raw());
+    reply(post::cross_post(
+        state_mut(),
+        caller(),
+        post_id,
+        realm,
+        api::time(),
+    ))
+}
+
 #[export_name = "canister_update enter_realm"]
 fn enter_realm() {
     let name: String = parse(&arg_data_raw());
@@ -508,7 +1178,9 @@ fn create_realm() {
         String,
         Vec<UserId>,
     ) = parse(&arg_data_raw());
-    reply(state_mut().create_realm(caller(), name, logo, label_color, description, controllers))
+    reply(state().health.check().and_then(|_| {
+        state_mut().create_realm(caller(), name, logo, label_color, description, controllers)
+    }))
 }
 
 #[export_name = "canister_update toggle_realm_membership"]
@@ -583,6 +1255,19 @@ fn proposal() {
     )
 }
 
+#[export_name = "canister_query vestings"]
+fn vestings() {
+    let now = time();
+    reply(
+        state()
+            .vestings
+            .iter()
+            .enumerate()
+            .map(|(id, vesting)| (id, vesting, vesting.claimable(now)))
+            .collect::<Vec<_>>(),
+    );
+}
+
 #[export_name = "canister_query proposals"]
 fn proposals() {
     let page_size = 10;
@@ -596,86 +1281,137 @@ fn proposals() {
             .skip(page * page_size)
             .take(page_size)
             .filter_map(|proposal| state.posts.get(&proposal.post_id))
+            .map(Post::restored)
             .collect::<Vec<_>>(),
     )
 }
 
 #[export_name = "canister_query realm_posts"]
 fn realm_posts() {
-    let (name, page, with_comments): (String, usize, bool) = parse(&arg_data_raw());
+    let (name, cursor, with_comments): (String, Option<String>, bool) = parse(&arg_data_raw());
+    let state = state();
+    reply(state.realm_posts(
+        caller(),
+        &state.resolve_realm_name(&name),
+        cursor,
+        with_comments,
+    ));
+}
+
+#[export_name = "canister_query realm_archive"]
+fn realm_archive() {
+    let (name, page): (String, usize) = parse(&arg_data_raw());
     let state = state();
-    match state.realms.get(&name) {
+    let user_id = state.principal_to_user(caller()).map(|user| user.id);
+    match state.realms.get(&state.resolve_realm_name(&name)) {
         None => reply_raw(&[]),
         Some(realm) => reply(
             realm
-                .posts
+                .archived
                 .iter()
                 .rev()
                 .filter_map(|id| state.posts.get(id))
-                .filter(move |post| with_comments || post.parent.is_none())
+                .filter(|post| state.visible(user_id, post))
                 .skip(page * CONFIG.feed_page_size)
                 .take(CONFIG.feed_page_size)
-                .cloned()
+                .map(Post::restored)
                 .collect::<Vec<Post>>(),
         ),
     }
 }
 
+// Private realms are only listed to their members.
 fn sorted_realms<'a>() -> Vec<(&'a String, &'a Realm)> {
-    let mut realms = state().realms.iter().collect::<Vec<_>>();
+    let state = state();
+    let user_id = state.principal_to_user(caller()).map(|user| user.id);
+    let mut realms = state
+        .realms
+        .iter()
+        .filter(|(_, realm)| {
+            realm.visibility != RealmVisibility::Private
+                || user_id
+                    .map(|id| realm.members.contains(&id))
+                    .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
     realms.sort_unstable_by(|(_, b), (_, a)| {
         (a.posts.len() * a.members.len()).cmp(&(b.posts.len() * b.members.len()))
     });
     realms
 }
 
+#[export_name = "canister_query coordination_settings"]
+fn coordination_settings() {
+    reply(&state().detector.settings);
+}
+
+#[export_name = "canister_query proposal_thresholds"]
+fn proposal_thresholds() {
+    reply(&state().proposal_thresholds);
+}
+
 #[export_name = "canister_query realms_data"]
 fn realms_data() {
     reply(
         sorted_realms()
             .iter()
-            .map(|(name, realm)| (name, &realm.label_color))
+            .map(|(name, realm)| (name, &realm.label_color, realm.flairs()))
             .collect::<Vec<_>>(),
     );
 }
 
+#[export_name = "canister_query realm_reports"]
+fn realm_reports() {
+    let name: String = parse(&arg_data_raw());
+    reply(env::reports::realm_reports(state(), caller(), &name));
+}
+
 #[export_name = "canister_query realm"]
 fn realm() {
     let name: String = parse(&arg_data_raw());
-    reply(state().realms.get(&name).ok_or("no realm found"));
+    let state = state();
+    let user_id = state.principal_to_user(caller()).map(|user| user.id);
+    reply(
+        state
+            .realms
+            .get(&state.resolve_realm_name(&name))
+            .map(|realm| realm.view(user_id))
+            .ok_or("no realm found"),
+    );
 }
 
 #[export_name = "canister_query realms"]
 fn realms() {
     let page_size = 8;
     let page: usize = parse(&arg_data_raw());
+    let user_id = state().principal_to_user(caller()).map(|user| user.id);
     reply(
         sorted_realms()
-            .iter()
+            .into_iter()
             .skip(page * page_size)
             .take(page_size)
+            .map(|(name, realm)| (name, realm.view(user_id)))
             .collect::<Vec<_>>(),
     );
 }
 
 #[export_name = "canister_query tree"]
 fn tree() {
-    let post_id: PostId = parse(&arg_data_raw());
-    reply(state().tree(post_id));
+    let (post_id, sort, collapse_below): (PostId, CommentSort, Option<i64>) =
+        parse(&arg_data_raw());
+    reply(state().tree(caller(), post_id, sort, collapse_below));
 }
 
 #[export_name = "canister_query user"]
 fn user() {
     let input: Vec<String> = parse(&arg_data_raw());
-    reply(resolve_handle(input.into_iter().next()).map(|mut user| {
-        let state = state();
-        user.balance = state
-            .balances
-            .get(&token::account(user.principal))
-            .copied()
-            .unwrap_or_default();
-        user
-    }));
+    reply(state().user_view(caller(), input.first().map(String::as_str)));
+}
+
+#[export_name = "canister_query collection"]
+fn collection() {
+    let (handle, index): (String, usize) = parse(&arg_data_raw());
+    reply(state().collection(caller(), &handle, index));
 }
 
 #[export_name = "canister_query invites"]
@@ -687,91 +1423,120 @@ fn invites() {
 fn posts() {
     let ids: Vec<PostId> = parse(&arg_data_raw());
     let state = state();
-    reply(state.posts(ids).into_iter().collect::<Vec<Post>>());
+    reply(
+        state
+            .posts(caller(), ids)
+            .into_iter()
+            .collect::<Vec<Post>>(),
+    );
 }
 
-#[export_name = "canister_query journal"]
-fn journal() {
-    let (handle, page): (String, usize) = parse(&arg_data_raw());
+#[export_name = "canister_query runoff_rounds"]
+fn runoff_rounds() {
+    let id: PostId = parse(&arg_data_raw());
+    reply(
+        match state()
+            .posts
+            .get(&id)
+            .and_then(|post| post.extension.as_ref())
+        {
+            Some(Extension::Poll(poll)) => Some((poll.runoff_rounds(), poll.runoff_winner())),
+            _ => None,
+        },
+    );
+}
+
+#[export_name = "canister_query quoted_post"]
+fn quoted_post() {
+    let id: PostId = parse(&arg_data_raw());
+    reply(post::resolve_quote(state(), caller(), id));
+}
+
+#[export_name = "canister_query post_history"]
+fn post_history() {
+    let id: PostId = parse(&arg_data_raw());
     let state = state();
+    let user_id = state.principal_to_user(caller()).map(|user| user.id);
     reply(
         state
-            .user(&handle)
-            .map(|user| {
-                user.posts
-                    .iter()
-                    .rev()
-                    .filter_map(|id| state.posts.get(id))
-                    // we filter out responses and root posts starting with tagging another user
-                    .filter(|post| post.parent.is_none() && !post.body.starts_with('@'))
-                    .skip(page * CONFIG.feed_page_size)
-                    .take(CONFIG.feed_page_size)
-                    .cloned()
-                    .collect::<Vec<Post>>()
-            })
+            .posts
+            .get(&id)
+            .filter(|post| state.accessible(user_id, post))
+            .map(|post| post.edits.clone())
             .unwrap_or_default(),
     );
 }
 
+#[export_name = "canister_query journal"]
+fn journal() {
+    let (handle, page): (String, usize) = parse(&arg_data_raw());
+    reply(state().journal(caller(), &handle, page));
+}
+
 #[export_name = "canister_query hot_posts"]
 fn hot_posts() {
     let page: usize = parse(&arg_data_raw());
     reply(state().hot_posts(caller(), page));
 }
 
+#[export_name = "canister_query featured_post"]
+fn featured_post() {
+    reply(state().featured_post(api::time()).map(Post::restored));
+}
+
+#[export_name = "canister_query trending_posts"]
+fn trending_posts() {
+    let page: usize = parse(&arg_data_raw());
+    reply(state().trending_posts(caller(), page, api::time()));
+}
+
+#[export_name = "canister_query recommended_posts"]
+fn recommended_posts() {
+    let page: usize = parse(&arg_data_raw());
+    reply(state().recommended_posts(caller(), page));
+}
+
 #[export_name = "canister_query last_posts"]
 fn last_posts() {
-    let (page, with_comments): (usize, bool) = parse(&arg_data_raw());
-    let state = state();
-    reply(
-        state
+    let (cursor, with_comments): (Option<String>, bool) = parse(&arg_data_raw());
+    reply(FeedPage::new(
+        state()
             .last_posts(caller(), with_comments)
             .filter(|post| match post.extension {
                 Some(Extension::Proposal(id)) => id > 50,
                 _ => true,
-            })
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size)
-            .cloned()
-            .collect::<Vec<Post>>(),
-    );
+            }),
+        cursor,
+        CONFIG.feed_page_size,
+    ));
 }
 
 #[export_name = "canister_query posts_by_tags"]
 fn posts_by_tags() {
-    let (tags, users, page): (Vec<String>, Vec<UserId>, usize) = parse(&arg_data_raw());
-    reply(
-        state()
-            .posts_by_tags(caller(), tags, users, page)
-            .into_iter()
-            .collect::<Vec<Post>>(),
-    );
+    let (tags, users, cursor): (Vec<String>, Vec<UserId>, Option<String>) = parse(&arg_data_raw());
+    reply(state().posts_by_tags(caller(), tags, users, cursor));
+}
+
+#[export_name = "canister_query mentions"]
+fn mentions() {
+    let cursor: Option<String> = parse(&arg_data_raw());
+    reply(state().mentions(caller(), cursor));
 }
 
 #[export_name = "canister_query personal_feed"]
 fn personal_feed() {
-    let (id, page, with_comments): (UserId, usize, bool) = parse(&arg_data_raw());
+    let (id, cursor, with_comments): (UserId, Option<String>, bool) = parse(&arg_data_raw());
     let state = state();
     reply(match state.user(id.to_string().as_str()) {
-        None => Default::default(),
-        Some(user) => user
-            .personal_feed(caller(), state, page, with_comments)
-            .cloned()
-            .collect::<Vec<Post>>(),
+        None => Ok(Default::default()),
+        Some(user) => user.feed_page(caller(), state, with_comments, cursor, api::time()),
     });
 }
 
 #[export_name = "canister_query thread"]
 fn thread() {
     let id: PostId = parse(&arg_data_raw());
-    let state = state();
-    reply(
-        state
-            .thread(id)
-            .filter_map(|id| state.posts.get(&id))
-            .cloned()
-            .collect::<Vec<Post>>(),
-    );
+    reply(state().thread_posts(caller(), id));
 }
 
 #[export_name = "canister_query validate_username"]
@@ -797,6 +1562,17 @@ fn users() {
     );
 }
 
+#[export_name = "canister_query search_users"]
+fn search_users() {
+    let (prefix, limit): (String, usize) = parse(&arg_data_raw());
+    reply(state().search_users(caller(), &prefix, limit));
+}
+
+#[export_name = "canister_query follow_suggestions"]
+fn follow_suggestions() {
+    reply(state().follow_suggestions(caller()));
+}
+
 #[export_name = "canister_query config"]
 fn config() {
     reply(CONFIG);
@@ -812,30 +1588,94 @@ fn stats() {
     reply(state().stats(api::time()));
 }
 
+#[export_name = "canister_query bucket_report"]
+fn bucket_report() {
+    let storage = &state().storage;
+    reply((
+        storage
+            .bucket_report()
+            .into_iter()
+            .map(|(id, offset, max_size)| (id.to_string(), offset, max_size))
+            .collect::<Vec<_>>(),
+        storage.bucket_summary(),
+    ));
+}
+
 #[export_name = "canister_query search"]
 fn search() {
-    let term: String = parse(&arg_data_raw());
-    reply(state().search(caller(), term));
+    let (term, realm): (String, Option<String>) = parse(&arg_data_raw());
+    reply(state().search(caller(), term, realm));
 }
 
 #[query]
 fn stable_mem_read(page: u64) -> Vec<(u64, Blob)> {
     let offset = page * BACKUP_PAGE_SIZE as u64;
-    let (heap_off, heap_size) = memory::heap_address();
-    let memory_end = heap_off + heap_size;
+    let memory_end = match memory::heap_end() {
+        Ok(end) => end,
+        Err(_) => return Default::default(),
+    };
     if offset > memory_end {
         return Default::default();
     }
     let chunk_size = (BACKUP_PAGE_SIZE as u64).min(memory_end - offset) as usize;
-    let mut buf = Vec::with_capacity(chunk_size);
-    buf.spare_capacity_mut();
-    unsafe {
-        buf.set_len(chunk_size);
-    }
+    let mut buf = vec![0; chunk_size];
     api::stable::stable64_read(offset, &mut buf);
     vec![(page, ByteBuf::from(buf))]
 }
 
+// Returns a chunk of the serialized heap, the heap length and the checksum of the chunk.
+#[query]
+fn heap_backup(index: u64) -> Result<(ByteBuf, u64, u32), String> {
+    if !env::canisters::is_controller(&caller()) {
+        return Err("only controllers can back up the heap".into());
+    }
+    memory::heap_backup_chunk(index, BACKUP_PAGE_SIZE as u64)
+        .map(|(bytes, len, checksum)| (ByteBuf::from(bytes), len, checksum))
+}
+
+#[update]
+fn heap_restore(index: u64, chunk: ByteBuf, checksum: u32, len: u64) -> Result<(), String> {
+    if !env::canisters::is_controller(&caller()) {
+        return Err("only controllers can restore the heap".into());
+    }
+    memory::restore_heap_chunk(
+        &mut state_mut().memory,
+        index,
+        &chunk,
+        checksum,
+        len,
+        BACKUP_PAGE_SIZE as u64,
+    )
+}
+
+// Returns a page of the bodies of cold-stored posts, which heap backups don't contain.
+#[query]
+fn cold_bodies_backup(page: u64) -> Result<Vec<(PostId, String)>, String> {
+    if !env::canisters::is_controller(&caller()) {
+        return Err("only controllers can back up the heap".into());
+    }
+    Ok(memory::cold_bodies_backup(state(), page as usize, 100))
+}
+
+#[update]
+fn heap_restore_cold_bodies(bodies: Vec<(PostId, String)>) -> Result<(), String> {
+    if !env::canisters::is_controller(&caller()) {
+        return Err("only controllers can restore the heap".into());
+    }
+    state_mut().memory.restore_cold_bodies(bodies)
+}
+
+// Loads the restored heap once all chunks and cold-stored bodies were received and verified.
+#[update]
+fn heap_restore_commit() -> Result<(), String> {
+    if !env::canisters::is_controller(&caller()) {
+        return Err("only controllers can restore the heap".into());
+    }
+    memory::commit_heap_restore(state_mut())?;
+    state_mut().load();
+    Ok(())
+}
+
 fn parse<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
     serde_json::from_slice(bytes).expect("couldn't parse the input")
 }
@@ -843,10 +1683,3 @@ fn parse<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
 fn reply<T: serde::Serialize>(data: T) {
     reply_raw(serde_json::json!(data).to_string().as_bytes());
 }
-
-fn resolve_handle(handle: Option<String>) -> Option<User> {
-    match handle {
-        Some(handle) => state().user(&handle).cloned(),
-        None => Some(state().principal_to_user(caller())?.clone()),
-    }
-}
@@ -0,0 +1,168 @@
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub type Headers = Vec<(String, String)>;
+
+const LABEL: &[u8] = b"http_assets";
+
+// The header byte of blobs written by the main canister.
+const RAW_BLOB: u8 = 0;
+const GZIP_BLOB: u8 = 1;
+
+// Certified blobs with their lengths and hashes of their response bodies by offset.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Blobs {
+    blobs: BTreeMap<u64, (u64, Hash)>,
+    #[serde(skip)]
+    hashes: RbTree<Vec<u8>, Hash>,
+}
+
+pub fn path(offset: u64) -> String {
+    format!("/blob/{}", offset)
+}
+
+pub fn parse_path(path: &str) -> Option<u64> {
+    path.strip_prefix("/blob/")?.parse().ok()
+}
+
+// Returns the response headers and body of a stored blob.
+pub fn response(mut blob: Vec<u8>) -> (Headers, Vec<u8>) {
+    let mut headers = vec![(
+        "Cache-Control".to_string(),
+        "public, max-age=31536000, immutable".to_string(),
+    )];
+    let content_type = match blob.first() {
+        Some(&GZIP_BLOB) => {
+            blob.remove(0);
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+            "application/octet-stream"
+        }
+        Some(&RAW_BLOB) => {
+            blob.remove(0);
+            content_type(&blob)
+        }
+        _ => content_type(&blob),
+    };
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
+    (headers, blob)
+}
+
+fn content_type(body: &[u8]) -> &'static str {
+    match body {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Blobs {
+    // Registers the hash of the blob's response body and returns the new certified data.
+    pub fn certify(&mut self, offset: u64, blob: Vec<u8>) -> Hash {
+        let len = blob.len() as u64;
+        let (_, body) = response(blob);
+        let mut hasher = Sha256::new();
+        hasher.update(&body);
+        let hash = hasher.finalize().into();
+        self.blobs.insert(offset, (len, hash));
+        self.hashes.insert(path(offset).into_bytes(), hash);
+        self.root_hash()
+    }
+
+    // Rebuilds the hash tree after an upgrade and returns the certified data.
+    pub fn restore(&mut self) -> Hash {
+        for (offset, (_, hash)) in self.blobs.iter() {
+            self.hashes.insert(path(*offset).into_bytes(), *hash);
+        }
+        self.root_hash()
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        labeled_hash(LABEL, &self.hashes.root_hash())
+    }
+
+    pub fn len(&self, offset: u64) -> Option<u64> {
+        self.blobs.get(&offset).map(|(len, _)| *len)
+    }
+
+    fn witness(&self, offset: u64) -> HashTree<'_> {
+        labeled(LABEL, self.hashes.witness(path(offset).as_bytes()))
+    }
+
+    // Returns the `IC-Certificate` header for the given certificate.
+    pub fn certificate_header(&self, offset: u64, certificate: &[u8]) -> (String, String) {
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe().expect("tagging failed");
+        self.witness(offset)
+            .serialize(&mut serializer)
+            .expect("couldn't serialize");
+        (
+            "IC-Certificate".to_string(),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                base64::encode(certificate),
+                base64::encode(serializer.into_inner())
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the leaf under the given label path.
+    fn lookup<'a>(tree: &'a HashTree<'a>, path: &[&[u8]]) -> Option<&'a [u8]> {
+        match (tree, path.split_first()) {
+            (HashTree::Leaf(data), None) => Some(data),
+            (HashTree::Fork(forks), Some(_)) => {
+                lookup(&forks.0, path).or_else(|| lookup(&forks.1, path))
+            }
+            (HashTree::Labeled(label, subtree), Some((first, rest))) if label == first => {
+                lookup(subtree, rest)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_certified_blobs() {
+        let png = [RAW_BLOB, 0x89, b'P', b'N', b'G', 1, 2, 3].to_vec();
+        let mut blobs = Blobs::default();
+        blobs.certify(8, png.clone());
+        let root_hash = blobs.certify(16, vec![GZIP_BLOB, 31, 139, 8]);
+        assert_eq!(blobs.len(8), Some(8));
+        assert_eq!(blobs.len(9), None);
+
+        // the witness reconstructs the certified data and contains the body hash
+        let witness = blobs.witness(8);
+        assert_eq!(witness.reconstruct(), root_hash);
+        let (headers, body) = response(png);
+        assert_eq!(body, vec![0x89, b'P', b'N', b'G', 1, 2, 3]);
+        assert!(headers.contains(&("Content-Type".to_string(), "image/png".to_string())));
+        let mut hasher = Sha256::new();
+        hasher.update(&body);
+        let hash: Hash = hasher.finalize().into();
+        assert_eq!(
+            lookup(&witness, &[LABEL, path(8).as_bytes()]),
+            Some(&hash[..])
+        );
+        assert_eq!(lookup(&witness, &[LABEL, path(16).as_bytes()]), None);
+
+        // compressed blobs are served with the gzip encoding
+        let (headers, body) = response(vec![GZIP_BLOB, 31, 139, 8]);
+        assert_eq!(body, vec![31, 139, 8]);
+        assert!(headers.contains(&("Content-Encoding".to_string(), "gzip".to_string())));
+
+        // the hash tree is restored after an upgrade
+        let mut restored: Blobs =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&blobs).unwrap()).unwrap();
+        assert_eq!(restored.restore(), root_hash);
+
+        assert_eq!(parse_path(&path(16)), Some(16));
+        assert_eq!(parse_path("/blob/abc"), None);
+    }
+}
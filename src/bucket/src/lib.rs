@@ -1,15 +1,45 @@
+use candid::CandidType;
+use certified::{Blobs, Headers};
 use ic_cdk::{
     api::{
         self,
-        call::{arg_data_raw, reply_raw},
+        call::{arg_data, arg_data_raw, call_with_payment, reject, reply, reply_raw},
         canister_balance,
         stable::*,
     },
     export::Principal,
 };
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+mod certified;
+
+// cycles kept by the bucket to pay for the withdrawal itself
+const WITHDRAWAL_RESERVE: u64 = 100_000_000;
 
 static mut CONTROLLER: Option<Principal> = None;
 
+thread_local! {
+    static BLOBS: RefCell<Blobs> = Default::default();
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    url: String,
+}
+
+#[derive(CandidType)]
+pub struct HttpResponse {
+    status_code: u16,
+    headers: Headers,
+    body: ByteBuf,
+}
+
+fn with_blobs<T>(f: impl FnOnce(&mut Blobs) -> T) -> T {
+    BLOBS.with(|blobs| f(&mut blobs.borrow_mut()))
+}
+
 fn set_controller() {
     unsafe {
         CONTROLLER = Some(Principal::from_slice(&arg_data_raw()));
@@ -23,14 +53,40 @@ fn assert_controller() {
 #[export_name = "canister_init"]
 fn init() {
     let initial_offset: u64 = 8;
-    grow_to_fit(initial_offset);
+    grow_to_fit(initial_offset).expect("couldn't initialize memory");
     api::stable::stable64_write(0, &initial_offset.to_be_bytes());
     set_controller();
 }
 
+// Dumps the certified blob hashes behind the last written blob.
+#[export_name = "canister_pre_upgrade"]
+fn pre_upgrade() {
+    let bytes = with_blobs(|blobs| serde_cbor::to_vec(blobs)).expect("couldn't serialize blobs");
+    let offset = pointer();
+    grow_to_fit(offset + 8 + bytes.len() as u64).expect("couldn't dump blobs");
+    stable64_write(offset, &(bytes.len() as u64).to_be_bytes());
+    stable64_write(offset + 8, &bytes);
+}
+
 #[export_name = "canister_post_upgrade"]
 fn post_upgrade() {
     set_controller();
+    // buckets upgraded from a version without certification have no dump
+    let offset = pointer();
+    let stable_size = stable64_size() << 16;
+    if offset + 8 <= stable_size {
+        let mut len_bytes: [u8; 8] = Default::default();
+        stable64_read(offset, &mut len_bytes);
+        let len = u64::from_be_bytes(len_bytes);
+        if len > 0 && offset + 8 + len <= stable_size {
+            let mut bytes = vec![0; len as usize];
+            stable64_read(offset + 8, &mut bytes);
+            if let Ok(restored) = serde_cbor::from_slice(&bytes) {
+                with_blobs(|blobs| *blobs = restored);
+            }
+        }
+    }
+    api::set_certified_data(&with_blobs(|blobs| blobs.restore()));
 }
 
 #[export_name = "canister_query balance"]
@@ -38,18 +94,46 @@ fn balance() {
     reply_raw(&canister_balance().to_be_bytes())
 }
 
+// Sends all cycles except the reserve back to the controller before the bucket gets deleted.
+#[export_name = "canister_update withdraw_cycles"]
+fn withdraw_cycles() {
+    assert_controller();
+    ic_cdk::spawn(async {
+        #[derive(CandidType)]
+        struct Args {
+            canister_id: Principal,
+        }
+        let controller = unsafe { CONTROLLER.expect("uninitialized") };
+        let cycles = canister_balance().saturating_sub(WITHDRAWAL_RESERVE);
+        let result: Result<((),), _> = call_with_payment(
+            Principal::management_canister(),
+            "deposit_cycles",
+            (Args {
+                canister_id: controller,
+            },),
+            cycles,
+        )
+        .await;
+        match result {
+            Ok(_) => reply_raw(&cycles.to_be_bytes()),
+            Err(err) => reject(&format!("couldn't withdraw cycles: {:?}", err)),
+        }
+    })
+}
+
 #[export_name = "canister_query read"]
 fn read() {
     let args = &arg_data_raw();
     let offset = bytes_to_u64(args, 0);
     let len = bytes_to_u64(args, 8);
-    let mut buf = Vec::with_capacity(len as usize);
-    buf.spare_capacity_mut();
-    unsafe {
-        buf.set_len(len as usize);
+    match offset.checked_add(len) {
+        Some(end) if end <= stable64_size() << 16 => {
+            let mut buf = vec![0; len as usize];
+            stable64_read(offset, &mut buf);
+            reply_raw(&buf);
+        }
+        _ => reject("reading beyond the memory size"),
     }
-    stable64_read(offset, &mut buf);
-    reply_raw(&buf);
 }
 
 #[export_name = "canister_update update_pointer"]
@@ -63,10 +147,25 @@ fn update_pointer() {
 #[export_name = "canister_update write"]
 fn write() {
     assert_controller();
-    let mut offset_bytes: [u8; 8] = Default::default();
-    api::stable::stable64_read(0, &mut offset_bytes);
     let blob = arg_data_raw();
-    write_at(u64::from_be_bytes(offset_bytes), &blob, true);
+    match write_at(pointer(), &blob, true) {
+        Ok(offset) => reply_raw(&offset.to_be_bytes()),
+        Err(err) => reject(&err),
+    }
+}
+
+// Writes the blob and certifies it for serving over HTTP.
+#[export_name = "canister_update write_certified"]
+fn write_certified() {
+    assert_controller();
+    let blob = arg_data_raw();
+    match write_at(pointer(), &blob, true) {
+        Ok(offset) => {
+            api::set_certified_data(&with_blobs(|blobs| blobs.certify(offset, blob)));
+            reply_raw(&offset.to_be_bytes())
+        }
+        Err(err) => reject(&err),
+    }
 }
 
 #[export_name = "canister_update write_at_offset"]
@@ -74,23 +173,62 @@ fn write_at_offset() {
     assert_controller();
     let params = &arg_data_raw();
     let offset = bytes_to_u64(params, 0);
-    write_at(offset, &params[8..], false);
+    match write_at(offset, &params[8..], false) {
+        Ok(offset) => reply_raw(&offset.to_be_bytes()),
+        Err(err) => reject(&err),
+    }
+}
+
+#[export_name = "canister_query http_request"]
+fn http_request() {
+    let (req,): (HttpRequest,) = arg_data();
+    let path = req.url.split('?').next().unwrap_or_default();
+    let response = certified::parse_path(path)
+        .and_then(|offset| Some((offset, with_blobs(|blobs| blobs.len(offset))?)))
+        .map(|(offset, len)| {
+            let mut blob = vec![0; len as usize];
+            stable64_read(offset, &mut blob);
+            let (mut headers, body) = certified::response(blob);
+            let certificate = api::data_certificate().expect("no certificate");
+            headers.push(with_blobs(|blobs| {
+                blobs.certificate_header(offset, &certificate)
+            }));
+            HttpResponse {
+                status_code: 200,
+                headers,
+                body: ByteBuf::from(body),
+            }
+        })
+        .unwrap_or_else(|| HttpResponse {
+            status_code: 404,
+            headers: Default::default(),
+            body: ByteBuf::from(b"not found".to_vec()),
+        });
+    reply((response,));
+}
+
+fn pointer() -> u64 {
+    let mut offset_bytes: [u8; 8] = Default::default();
+    api::stable::stable64_read(0, &mut offset_bytes);
+    u64::from_be_bytes(offset_bytes)
 }
 
-fn write_at(offset: u64, blob: &[u8], update_pointer: bool) {
-    grow_to_fit(offset + blob.len() as u64);
+fn write_at(offset: u64, blob: &[u8], update_pointer: bool) -> Result<u64, String> {
+    grow_to_fit(offset + blob.len() as u64)?;
     stable64_write(offset, blob);
     if update_pointer {
         let new_offset = offset + blob.len() as u64;
         api::stable::stable64_write(0, &new_offset.to_be_bytes());
     }
-    reply_raw(&offset.to_be_bytes());
+    Ok(offset)
 }
 
-fn grow_to_fit(len: u64) {
-    if len > (stable64_size() << 16) && stable64_grow((len >> 16) + 1).is_err() {
-        panic!("couldn't grow stable memory");
+// Returns an error instead of trapping, so that the caller can pick another bucket.
+fn grow_to_fit(len: u64) -> Result<(), String> {
+    if len > (stable64_size() << 16) {
+        stable64_grow((len >> 16) + 1).map_err(|err| format!("out of stable memory: {:?}", err))?;
     }
+    Ok(())
 }
 
 fn bytes_to_u64(bytes: &[u8], offset: usize) -> u64 {
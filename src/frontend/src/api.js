@@ -53,13 +53,20 @@ export const Api = (defaultCanisterId, identity, mainnetMode) => {
             const arg = IDL.encode([IDL.Text, IDL.Text, IDL.Vec(IDL.Nat8)], [text, commit, blob]);
             return IDL.decode([IDL.Variant({ "Ok": IDL.Nat32, "Err": IDL.Text})], await call_raw(undefined, "propose_release", arg))[0];
         },
-        add_post: async (text, blobs, parent, realm, extension) => {
+        add_post: async (text, blobs, parent, realm, extension, scheduledAt = []) => {
             const arg = IDL.encode(
-                [IDL.Text, IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(IDL.Nat8))), IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Text), IDL.Opt(IDL.Vec(IDL.Nat8))],
-                [text, blobs, parent, realm, extension]
+                [IDL.Text, IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(IDL.Nat8))), IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Text), IDL.Opt(IDL.Vec(IDL.Nat8)), IDL.Opt(IDL.Nat64)],
+                [text, blobs, parent, realm, extension, scheduledAt]
             );
             return IDL.decode([IDL.Variant({ "Ok": IDL.Nat64, "Err": IDL.Text})], await call_raw(undefined, "add_post", arg))[0];
         },
+        quote_post: async (text, blobs, quoted, realm) => {
+            const arg = IDL.encode(
+                [IDL.Text, IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(IDL.Nat8))), IDL.Nat64, IDL.Opt(IDL.Text)],
+                [text, blobs, quoted, realm]
+            );
+            return IDL.decode([IDL.Variant({ "Ok": IDL.Nat64, "Err": IDL.Text})], await call_raw(undefined, "quote_post", arg))[0];
+        },
         edit_post: async (id, text, blobs, patch, realm) => {
             const arg = IDL.encode(
                 [ IDL.Nat64, IDL.Text, IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(IDL.Nat8))), IDL.Text, IDL.Opt(IDL.Text)],
@@ -67,6 +74,10 @@ export const Api = (defaultCanisterId, identity, mainnetMode) => {
             );
             return IDL.decode([IDL.Variant({ "Ok": IDL.Null, "Err": IDL.Text})], await call_raw(undefined, "edit_post", arg))[0];
         },
+        publish_draft: async id => {
+            const arg = IDL.encode([IDL.Nat64], [id]);
+            return IDL.decode([IDL.Variant({ "Ok": IDL.Nat64, "Err": IDL.Text})], await call_raw(undefined, "publish_draft", arg))[0];
+        },
         account_balance: async address => {
             const arg = IDL.encode([IDL.Record({ "account": IDL.Vec(IDL.Nat8) })], 
                 [{ "account": hexToBytes(address) }]);
@@ -55,6 +55,8 @@ export const realmColors = (name, col) => {
     return [effCol, light(effCol) ? "black" : "white"];
 };
 
+export const realmFlair = (name, userId) => ((backendCache.realms.find(([n]) => n == name) || [])[2] || {})[userId];
+
 export const RealmSpan = ({col, name, classNameArg, onClick}) => {
     if (!name) return null;
     const [background, color] = realmColors(name, col);
@@ -184,7 +186,7 @@ export const loadPost = async (api, id) => {
 export const postUserToPost = post => {
     const id = post.user;
     const { users, karma } = window.backendCache;
-    post.user = { id, name: users[id], karma: karma[id] };
+    post.user = { id, name: users[id], karma: karma[id], flair: realmFlair(post.realm, id) };
     return post;
 };
 
@@ -206,6 +208,18 @@ export const ReactionToggleButton = ({icon, onClick, pressed, classNameArg}) =>
 
 export const BurgerButton = ({onClick, pressed}) => <ReactionToggleButton onClick={onClick} pressed={pressed} icon={<Menu />} />
 
+// Blobs start with a header byte: 0 for raw and 1 for gzipped blobs.
+// Blobs without a header are images stored before the header was introduced.
+const decodeBlob = async blob => {
+    const bytes = new Uint8Array(blob);
+    if (bytes[0] == 0) return bytes.slice(1);
+    if (bytes[0] == 1) {
+        const stream = new Blob([bytes.slice(1)]).stream().pipeThrough(new DecompressionStream("gzip"));
+        return new Uint8Array(await new Response(stream).arrayBuffer());
+    }
+    return blob;
+};
+
 export const loadPostBlobs = async (files) => {
     const ids = Object.keys(files);
     const blobs = await Promise.all(ids.map(async id => {
@@ -214,7 +228,7 @@ export const loadPostBlobs = async (files) => {
         const arg = Buffer.from(intToBEBytes(offset).concat(intToBEBytes(len)));
         // This allows us to see the bucket pics in dev mode.
         const api = backendCache.stats.buckets.every(([id, ]) => id != bucket_id) ? window.mainnet_api : window.api;
-        return api.query_raw(bucket_id, "read", arg).then(blob => [blobId, blob]);
+        return api.query_raw(bucket_id, "read", arg).then(async blob => [blobId, await decodeBlob(blob)]);
     }));
     return blobs.reduce((acc, [blobId, blob]) => {
         acc[blobId] = blob;
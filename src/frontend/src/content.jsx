@@ -30,6 +30,8 @@ const linkTagsAndUsers = value => {
         r.replaceAll("#"+tag, `[&#x23;${tag}](#/feed/${tag})`), value);
     value = users.reduce((r, handle) => 
         r.replaceAll("@"+handle, `[&commat;${handle}](#/user/${handle})`), value);
+    value = value.replace(/(^|[^\w])canister:([a-z0-9]{5}(-[a-z0-9]{1,5})*)/g,
+        (_, prefix, id) => `${prefix}[canister:${id}](#/canister/${id})`);
     return value;
 };
 
@@ -82,6 +84,10 @@ const markdownizer = (value, urls, setUrls, blobs, primeMode) =>
                         return <YouTube id={id} />;
                     }
 
+                    // Canister references
+                    const canisterId = canisterReference(props.href);
+                    if (canisterId) return <CanisterPreview id={canisterId} />;
+
                     // Reposts
                     const id = repost(props.href);
                     if(id != null && primeMode && props["data-repost"]) { 
@@ -168,6 +174,33 @@ const YouTube = ({id}) => {
     </span>;
 }
 
+// Resolved canister references shared by all posts; the oldest resolution gets evicted.
+const canisterPreviews = new Map();
+const MAX_CANISTER_PREVIEWS = 100;
+
+const CanisterPreview = ({id}) => {
+    const [preview, setPreview] = React.useState(canisterPreviews.get(id));
+    React.useEffect(() => {
+        if (preview) return;
+        api.call("canister_preview", id).then(response => {
+            if (!response || "Err" in response) return;
+            if (canisterPreviews.size >= MAX_CANISTER_PREVIEWS)
+                canisterPreviews.delete(canisterPreviews.keys().next().value);
+            canisterPreviews.set(id, response.Ok);
+            setPreview(response.Ok);
+        });
+    }, [id]);
+    // unresolved references stay plain text
+    if (!preview) return <>canister:{id}</>;
+    return <span data-meta="skipClicks" className="canister_preview">
+        <a target="_blank" href={`https://dashboard.internetcomputer.org/canister/${id}`}>{id}</a>
+        <br />
+        Controllers: {preview.controllers.map(controller => <code key={controller}>{controller}</code>)}
+        <br />
+        Module hash: <code>{preview.module_hash || "none"}</code>
+    </span>;
+};
+
 const ArrowDown =  () => <div className="text_centered bottom_spaced top_spaced"><CarretDown classNameArg="action" /></div>;
 
 const setDimensions = props => {
@@ -179,6 +212,11 @@ const setDimensions = props => {
 
 const fillerImg = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAQAAAADCAQAAAAe/WZNAAAAEElEQVR42mNkMGYAA0YMBgAJ4QCdD/t7zAAAAABJRU5ErkJggg==";
 
+const canisterReference = link => {
+    const matches = (link || "").match(/^\#\/canister\/([a-z0-9-]+)$/);
+    return matches ? matches.pop() : null;
+};
+
 const repost = link => {
     const matches = link.match(/^\#\/post\/(\d+)$/);
     if (!matches) return null;
@@ -8,12 +8,17 @@ const show = (number, unit = null) => <code>{number.toLocaleString()}{unit}</cod
 export const Dashboard = ({fullMode}) => {
     const stats = window.backendCache.stats;
     const [logs, setLogs] = React.useState([]);
+    const [capacity, setCapacity] = React.useState({});
 
     React.useEffect(() => { api.query("logs").then(logs => {
         logs.reverse();
         setLogs(logs);
     });}, []);
 
+    React.useEffect(() => { api.query("bucket_report").then(([buckets]) =>
+        setCapacity(buckets.reduce((acc, [id, offset, max_size]) => ({...acc, [id]: offset / max_size}), {})));
+    }, []);
+
     const { config: {distribution_interval_hours}, stats: {last_distribution}} = backendCache;
     return <>
         {fullMode && <HeadBar title="Dashboard" shareLink="dashboard" />}
@@ -63,15 +68,20 @@ export const Dashboard = ({fullMode}) => {
                             <div className="db_cell top_spaced bottom_spaced">
                                 <label><Box /> STATE</label> {sizeMb(stats.state_size)}
                             </div>
+                            <div className="db_cell bottom_spaced">
+                                <label><Box /> FREE</label> {sizeMb(stats.stable_memory_fragmentation[0])}
+                            </div>
                             <div className="db_cell">
                                 <label><Cycles /> IC-CYCLES</label> {show(stats.canister_cycle_balance / 10**12, "T")}
                             </div>
                         </div>
-                        {stats.buckets.map(([bucket_id, size], i) => <div key={bucket_id} className="db_cell">
+                        {stats.buckets.map(([bucket_id, size, live], i) => <div key={bucket_id} className="db_cell">
                             <a href={`https://dashboard.internetcomputer.org/canister/${bucket_id}`}>
                                 <StorageCanister /> STORAGE {i}
                             </a>
                             <div className="db_cell top_spaced bottom_spaced"><label><Box /> STATE</label> {sizeMb(size)}</div>
+                            <div className="db_cell bottom_spaced"><label><Box /> LIVE</label> {sizeMb(live)}</div>
+                            {bucket_id in capacity && <div className="db_cell bottom_spaced"><label><Box /> CAPACITY</label> {show(Math.round(capacity[bucket_id] * 100), "%")}</div>}
                             <div className="db_cell"><label><Cycles /> IC-CYCLES</label> <CycleBalance id={bucket_id}/></div>
                         </div>)}
                         <div className="db_cell bottom_spaced">
@@ -133,6 +143,8 @@ const level2icon = level => {
             return "⚠️";
         case "CRITICAL":
             return "❌";
+        case "METRICS":
+            return "📊";
         default:
             return "❓";
     }
@@ -16,13 +16,13 @@ export const Feed = ({params, heartbeat}) => {
     React.useEffect(() => setFilter(params), [params]);
     return <div className="column_container">
         <FeedBar params={params} callback={setFilter} />
-        <PostFeed feedLoader={async page => {
+        <PostFeed feedLoader={async (page, _, cursor) => {
             const tags = [], users = [];
             filter.forEach(token => {
                 if (token.startsWith("@")) users.push(userId(token));
                 else tags.push(token);
             });
-            return await api.query("posts_by_tags", tags, users, page);
+            return await api.query("posts_by_tags", tags, users, cursor);
         }}
             heartbeat={heartbeat + filter + params} />
     </div>;
@@ -18,13 +18,13 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
     const [tmpBlobs, setTmpBlobs] = React.useState([]);
     const [busy, setBusy] = React.useState(false);
     const [poll, setPoll] = React.useState(null);
+    const [scheduledAt, setScheduledAt] = React.useState("");
     const [showTextField, setShowTextField] = React.useState(!!localStorage.getItem(draftKey) || expanded);
     const [suggestedTags, setSuggestedTags] = React.useState([]);
     const [suggestedUsers, setSuggestedUsers] = React.useState([]);
     const [choresTimer, setChoresTimer] = React.useState(null);
     const focus = React.useRef();
     const tags = window.backendCache.recent_tags;
-    const users = Object.values(window.backendCache.users); 
     const { max_post_length, max_blob_size_bytes } = backendCache.config;
 
     const handleSubmit = async () => {
@@ -49,7 +49,8 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
         } else if ((value.match(/!\[.*?\]\(\/blob\/.*?\)/g) || []).length != blobArrays.length) {
             alert("You're referencing pictures that are not attached anymore. Please re-upload.");
         } else {
-            await submitCallback(value, blobArrays, poll, realm);
+            await submitCallback(value, blobArrays, poll, realm, scheduledAt ? Number(new Date(scheduledAt)) * 1000000 : null);
+            setScheduledAt("");
             setValue("");
             localStorage.removeItem(draftKey); 
         }
@@ -111,8 +112,10 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
         const cursor = focus.current?.selectionStart-1;
         const suggestedTags = suggestTokens(cursor, value, tags, "#");
         setSuggestedTags(suggestedTags);
-        const suggestedUsers = suggestTokens(cursor, value, users, "@");
-        setSuggestedUsers(suggestedUsers);
+        const handle = currentToken(cursor, value, "@");
+        if (handle == null) setSuggestedUsers([]);
+        else api.query("search_users", handle, MAX_SUGGESTED_TAGS).then(users =>
+            setSuggestedUsers(users.map(([_, name]) => handle + name.slice(handle.length))));
         setChoresTimer(setTimeout(() => localStorage.setItem(draftKey, value), 1500));
         writingCallback(value);
     };
@@ -121,7 +124,6 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
         if (event.charCode == 13) {
             const cursor = focus.current?.selectionStart-1;
             const suggestedTags = suggestTokens(cursor, value, tags, "#");
-            const suggestedUsers = suggestTokens(cursor, value, users, "@");
             if (suggestedTags.length) {
                 insertSuggestion(event, "#", suggestedTags[0]);
             } else if (suggestedUsers.length) {
@@ -201,7 +203,9 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
                             <option value="">{backendCache.config.name.toUpperCase()}</option>
                             {api._user.realms.map(name => <option key={name} value={name}>{name}</option>)}
                         </select>}
-                        <ButtonWithLoading classNameArg="active left_spaced" label="SEND" onClick={handleSubmit} />
+                        {!comment && postId == null && <input type="datetime-local" className="small_text left_spaced" title="Publication time"
+                            value={scheduledAt} onChange={event => setScheduledAt(event.target.value)} />}
+                        <ButtonWithLoading classNameArg="active left_spaced" label={scheduledAt ? "SCHEDULE" : "SEND"} onClick={handleSubmit} />
                     </div>
                 </div>
             </form>}
@@ -214,6 +218,18 @@ export const Form = ({postId = null, comment, realmArg = "", expanded, submitCal
             <select value={poll.deadline} onChange={e => setPoll({...poll, deadline: parseInt(e.target.value) })}>
                 {[1,2,3,4,5,6,7].map(d => <option key={d} value={`${d * 24}`}>{`${d} DAY${d == 1 ? "" : "S"}`}</option>)}
             </select>
+            MODE:
+            <select value={poll.mode?.MultiSelect ? "MultiSelect" : poll.mode || "Single"}
+                onChange={e => setPoll({...poll, mode: e.target.value == "MultiSelect" ? { MultiSelect: 2 } : e.target.value })}>
+                <option value="Single">SINGLE CHOICE</option>
+                <option value="MultiSelect">MULTI-SELECT</option>
+                <option value="RankedChoice">RANKED CHOICE</option>
+            </select>
+            {poll.mode?.MultiSelect && <>
+                MAX CHOICES:
+                <input type="number" min="1" max={poll.options.length} value={poll.mode.MultiSelect}
+                    onChange={e => setPoll({...poll, mode: { MultiSelect: parseInt(e.target.value) }})} />
+            </>}
         </div>}
         {value && <article ref={ref} className={`bottom_spaced ${postId == null ? "prime" : ""}`}>
             <Content post={true} blobs={tmpBlobs} value={value} preview={true} primeMode={postId == null} />
@@ -270,13 +286,19 @@ const resize = async (blob, scale) => {
     return await canvasToBlob(canvas);
 };
 
-const suggestTokens = (cursor, value, tokens, trigger) => {
-    let currentTag = ""
+// returns the token being typed after the trigger or null
+const currentToken = (cursor, value, trigger) => {
+    let token = "";
     let i;
     for (i = cursor; i >= 0 && value[i].match(/(\p{L}|-|\d)/gu); i--) {
-        currentTag = value[i] + currentTag;
+        token = value[i] + token;
     }
-    if (value[i] == trigger) {
+    return value[i] == trigger ? token : null;
+};
+
+const suggestTokens = (cursor, value, tokens, trigger) => {
+    const currentTag = currentToken(cursor, value, trigger);
+    if (currentTag != null) {
         const result = tokens.filter(tag => tag.toLowerCase().startsWith(currentTag.toLowerCase()))
             .map(tag => currentTag + tag.slice(currentTag.length, tag.length));
         result.sort((a, b) => { if (a.length != b.length) { return a.length - b.length} else { return a < b } });
@@ -10,7 +10,7 @@ import { Thread } from "./thread";
 import { Invites } from "./invites";
 import { Inbox } from "./inbox";
 import { Journal } from "./journal";
-import { RealmForm, Realms, RealmPage } from "./realms";
+import { RealmForm, Realms, RealmPage, RealmInvite } from "./realms";
 import { Dashboard } from "./dashboard";
 import { PostSubmissionForm } from "./new";
 import { Profile } from './profile';
@@ -114,6 +114,8 @@ const App = () => {
         if (action) content = auth(<RealmForm existingName={name.toUpperCase()} />);
         else if (!name) content = <Realms />;
         else content = <RealmPage name={decodeURI(name.toUpperCase())} />;
+    } else if (handler == "realm_invite") {
+        content = auth(<RealmInvite code={param} />);
     } else if (handler == "inbox") {
         content = auth(<Inbox />);
     } else if (handler == "transaction") {
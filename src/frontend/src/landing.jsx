@@ -29,11 +29,11 @@ export const Landing = ({heartbeat}) => {
         </>}
         <TagCloud size={bigScreen() ? 60 : 30} heartbeat={heartbeat} />
         <PostFeed heartbeat={heartbeat + feed} title={title} grid={true} feedLoader={
-            async page => {
+            async (page, _, cursor) => {
                 setTitle(feed);
-                if (feed == "FOLLOWED") return await api.query("personal_feed", user.id, page, false);
+                if (feed == "FOLLOWED") return await api.query("personal_feed", user.id, cursor, false);
                 if (feed == "HOT") return await api.query("hot_posts", page);
-                else return await api.query("last_posts", page, false);
+                else return await api.query("last_posts", cursor, false);
             }} />
     </>;
 }; 
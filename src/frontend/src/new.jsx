@@ -5,6 +5,9 @@ import { getPatch, loadPostBlobs, loadPost, isRoot } from './common';
 export const PostSubmissionForm = ({id, repost}) => {
     const [post, setPost] = React.useState({});
     const [blobs, setBlobs] = React.useState({});
+    const [scheduledPosts, setScheduledPosts] = React.useState([]);
+
+    const loadScheduledPosts = async () => setScheduledPosts(await api.query("scheduled_posts") || []);
 
     const load = async () => {
         if (!id) return;
@@ -13,9 +16,9 @@ export const PostSubmissionForm = ({id, repost}) => {
         setBlobs(await loadPostBlobs(post.files));
     };
 
-    React.useEffect(() => { load(); }, []);
+    React.useEffect(() => { load(); loadScheduledPosts(); }, []);
 
-    const callback = async (text, blobs, poll, realm) => {
+    const callback = async (text, blobs, poll, realm, scheduledAt) => {
         let postId;
         const optionalRealm = realm ? [realm] : [];
         if (post.id) {
@@ -26,18 +29,26 @@ export const PostSubmissionForm = ({id, repost}) => {
                 return
             }
             postId = post.id;
+        } else if (repost) {
+            const result = await api.quote_post(text, blobs, parseInt(repost), optionalRealm);
+            if ("Err" in result) {
+                return alert(`Error: ${result.Err}`);
+            }
+            postId = result.Ok;
         } else {
-            const result = await api.add_post(text, blobs, [], optionalRealm, encodePoll(poll));
+            const result = await api.add_post(text, blobs, [], optionalRealm, encodePoll(poll), scheduledAt ? [scheduledAt] : []);
             if ("Err" in result) {
                 return alert(`Error: ${result.Err}`);
             }
+            if (scheduledAt) {
+                await loadScheduledPosts();
+                return;
+            }
             postId = result.Ok;
         }
         location.href = `#/post/${postId}`;
     };
 
-    const content = repost ? `\n\n[repost:${repost}](#/post/${repost})` : "";
-
     return <div className="spaced">
         <ul>
             <li>Use <a target="_blank" href="https://commonmark.org/help/">Markdown</a> for formatting.</li>
@@ -46,7 +57,20 @@ export const PostSubmissionForm = ({id, repost}) => {
             <li>You can drag and drop images into the text area.</li>
             <li>Group images together and separate from the rest by new lines to create galleries.</li>
         </ul>
-        <Form submitCallback={callback} postId={id} content={post.body || content} blobs={blobs} expanded={true}
+        {scheduledPosts.length > 0 && <div className="monospace bottom_spaced">
+            <h2>Scheduled posts</h2>
+            {scheduledPosts.map(([scheduledId, scheduledAt, body]) => <div key={scheduledId} className="vcentered bottom_half_spaced">
+                <code>{new Date(scheduledAt / 1000000).toLocaleString()}</code>
+                <span className="left_spaced max_width_col">{body.slice(0, 64)}</span>
+                <button className="left_spaced" onClick={async () => {
+                    const response = await api.call("cancel_scheduled_post", scheduledId);
+                    if ("Err" in response) alert(`Error: ${response.Err}`);
+                    await loadScheduledPosts();
+                }}>CANCEL</button>
+            </div>)}
+        </div>}
+        {repost && <h2>Quoting post #{repost}</h2>}
+        <Form submitCallback={callback} postId={id} content={post.body} blobs={blobs} expanded={true}
             comment={!isRoot(post)} realmArg={post.realm || api._user.current_realm}/>
     </div>;
 }
@@ -4,35 +4,68 @@ import { Content } from './content';
 
 export const Poll = ({poll, post_id, created}) => {
     const [data, setData] = React.useState(poll);
+    const [selection, setSelection] = React.useState([]);
+    const [revoting, setRevoting] = React.useState(false);
+    const [runoff, setRunoff] = React.useState(null);
 
     React.useEffect(() => setData(poll), [poll]);
 
     const user_id = api._user?.id;
+    const mode = data.mode || "Single";
+    const maxChoices = mode == "Single" ? 1 : mode == "RankedChoice" ? data.options.length : mode.MultiSelect;
+    const ranked = mode == "RankedChoice";
     const voted = Object.values(data.votes).flat().includes(user_id);
     const totalVotes = Object.values(data.votes).map(votes => votes.length).reduce((acc, e) => acc + e ,0);
     const createdHoursAgo = Math.floor((Number(new Date()) - parseInt(created) / 1000000) / 1000 / 3600);
-    const expired = createdHoursAgo > poll.deadline;
-    const showVoting = !isNaN(user_id) && !voted && !expired;
+    const expired = data.closed || (data.closes_at
+        ? Number(new Date()) * 1000000 >= Number(data.closes_at)
+        : createdHoursAgo > poll.deadline);
+    const showVoting = !isNaN(user_id) && (!voted || revoting) && !expired;
+
+    React.useEffect(() => {
+        if (ranked && !showVoting && !isNaN(post_id))
+            api.query("runoff_rounds", post_id).then(setRunoff);
+    }, [data, showVoting]);
+
+    const vote = choices => {
+        if (isNaN(post_id) || !api._user) return;
+        api.call("vote_on_poll", post_id, choices).then(response => {
+            if (response.Err) {
+                alert(`Error: ${response.Err}!`);
+                return;
+            }});
+        Object.values(poll.votes).forEach(list => list.splice(0, list.length, ...list.filter(id => id != user_id)));
+        (ranked ? choices.slice(0, 1) : choices).forEach(id => {
+            const list = poll.votes[id] || [];
+            list.push(user_id);
+            poll.votes[id] = list;
+        });
+        setSelection([]);
+        setRevoting(false);
+        setData({...poll});
+    };
+
+    const toggle = id => {
+        if (selection.includes(id)) setSelection(selection.filter(choice => choice != id));
+        else if (selection.length < maxChoices) setSelection(selection.concat(id));
+    };
+
+    // final instant-runoff round for ranked-choice polls
+    const [rounds, winner] = runoff || [[], null];
+    const lastRound = rounds[rounds.length - 1] || {};
+    const runoffTotal = Object.values(lastRound).reduce((acc, e) => acc + e, 0);
 
     return <div className="column_container post_extension" data-meta="skipClicks">
         {data.options.map((option, id) => {
-            const votes = (data.votes[id] || []).length;
-            const pc = totalVotes > 0 ? Math.ceil(votes / totalVotes * 100) : 0;
+            const votes = ranked ? (lastRound[id] || 0) : (data.votes[id] || []).length;
+            const total = ranked ? runoffTotal : totalVotes;
+            const pc = total > 0 ? Math.ceil(votes / total * 100) : 0;
             return <label key={id} className={showVoting ? "vcentered" : null} style={{display: "flex", flexDirection: showVoting ? "row" : "column"}}>
-                {showVoting && <input type="radio" value={id} name={id} className="right_spaced" style={{marginTop: 0}}
-                    onChange={e => {
-                        if (isNaN(post_id) || !api._user) return;
-                        let vote = e.target.value;
-                        api.call("vote_on_poll", post_id, parseInt(vote)).then(response => {
-                            if (response.Err) {
-                                alert(`Error: ${response.Err}!`);
-                                return;
-                            }});
-                        const list = poll.votes[vote] || [];
-                        list.push(user_id);
-                        poll.votes[vote] = list;
-                        setData({...poll});
-                    }} />}
+                {showVoting && mode == "Single" && <input type="radio" value={id} name={id} className="right_spaced" style={{marginTop: 0}}
+                    onChange={e => vote([parseInt(e.target.value)])} />}
+                {showVoting && mode != "Single" && <input type="checkbox" className="right_spaced" style={{marginTop: 0}}
+                    checked={selection.includes(id)} onChange={() => toggle(id)} />}
+                {showVoting && ranked && selection.includes(id) && <code className="right_spaced">{selection.indexOf(id) + 1}</code>}
                 <Content classNameArg="max_width_col clickable" value={option} />
                 {!showVoting && <div className="column_container" style={{margin: "0.5em", width: "96%"}}>
                     <div className="vcentered">
@@ -45,6 +78,13 @@ export const Poll = ({poll, post_id, created}) => {
                     </div>
                 </div>}
             </label>})}
+        {showVoting && mode != "Single" && <button className="active top_spaced" disabled={selection.length == 0}
+            onClick={() => vote(selection)}>{ranked ? "VOTE (IN THE SELECTED ORDER)" : `VOTE (UP TO ${maxChoices})`}</button>}
+        {!showVoting && ranked && runoff && <span className="top_spaced small_text text_centered inactive">
+            {`${rounds.length} RUNOFF ROUND${rounds.length == 1 ? "" : "S"}: `}
+            {winner == null ? "NO MAJORITY" : "MAJORITY FOR OPTION " + (winner + 1)}
+        </span>}
+        {voted && !expired && !revoting && <button className="top_spaced" onClick={() => setRevoting(true)}>CHANGE VOTE</button>}
         {!expired && <span className="top_spaced small_text text_centered inactive">EXPIRES IN {printDelta(data.deadline - createdHoursAgo)}</span>}
     </div>;
 }
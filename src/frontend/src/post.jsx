@@ -16,7 +16,7 @@ export const postDataProvider = (id, preloadedData = null, rootOnly = false) =>
             else provider.source[id] = post;
             return
         }
-        const tree = await api.query("tree", id);
+        const {posts: tree} = await api.query("tree", id, "Oldest", null);
         if (Object.keys(tree).length == 0) {
             provider.notFound = true;
             return;
@@ -143,6 +143,7 @@ export const Post = ({id, data, version, isFeedItem, repost, classNameArg, isCom
         <div className={`post_box ${sum < 0 ? "inactive" : ""} ${cls}`} style={{position: "relative"}}>
             {showReport && <ReportBanner post={post} />}
             {isNSFW && <div className="post_head banner2 x_large_text" onClick={() => setSafeToOpen(true)}>#NSFW</div>}
+            {post.deletion && <div className="post_head banner3 small_text monospace"><h3>Post deleted: it can be restored until {new Date((post.deletion[0] + backendCache.config.post_deletion_grace_period) / 1000000).toLocaleString()}</h3></div>}
             {deleted && <div className="post_head banner3 small_text monospace"><h3>Post deleted</h3>
                 <ol>{post.hashes.map(hash => <li key={hash}><code>{bigScreen() ? hash : hash.slice(0,16)}</code></li>)}</ol>
             </div>}
@@ -154,6 +155,7 @@ export const Post = ({id, data, version, isFeedItem, repost, classNameArg, isCom
                 {/* The key is needed to render different content for different versions to avoid running into diffrrent
                  number of memorized pieces inside content */}
                 <Content key={post.effBody} post={true} value={post.effBody} blobs={blobs} collapse={!expanded} primeMode={isRoot(post) && !repost} />
+                {post.quoted != null && !repost && <QuotedPost id={post.quoted} />}
                 {post.extension && post.extension.Poll && <Poll poll={post.extension.Poll} post_id={post.id} created={postCreated} />}
                 {post.extension && post.extension.Proposal && <Proposal id={post.extension.Proposal} />}
             </article>}
@@ -187,6 +189,14 @@ export const Post = ({id, data, version, isFeedItem, repost, classNameArg, isCom
     </div>;
 };
 
+const QuotedPost = ({id}) => {
+    const [quote, setQuote] = React.useState(null);
+    React.useEffect(() => { api.query("quoted_post", id).then(setQuote); }, [id]);
+    if (!quote) return <Loading />;
+    if ("Tombstone" in quote) return <div className="repost banner3 small_text monospace">QUOTED POST WAS DELETED</div>;
+    return <Post id={id} data={postDataProvider(id, postUserToPost(quote.Post), "post_only")} repost={true} classNameArg="repost" />;
+};
+
 const PostInfo = ({post, version, postCreated, callback}) => {
     const postAuthor = api._user?.id == post.user.id;
     return <>
@@ -220,8 +230,26 @@ const PostInfo = ({post, version, postCreated, callback}) => {
                     alert(`Error: ${response.Err}`);
                 } else await callback();
             }} label={<Coin />} />
+            {!postAuthor && <ButtonWithLoading classNameArg="max_width_col" onClick={async () => {
+                const { token_symbol, token_decimals, minimal_token_tip } = backendCache.config;
+                const base = Math.pow(10, token_decimals);
+                const amount = prompt(`Tip @${post.user.name} with $${token_symbol} (minimum: ${minimal_token_tip / base}):`, 1);
+                if(amount == null) return;
+                const tip = Math.round(parseFloat(amount) * base);
+                if(isNaN(tip)) return alert("Couldn't parse the number of tokens.");
+                let response = await api.call("tip_tokens", post.id, tip);
+                if ("Err" in response) {
+                    alert(`Error: ${response.Err}`);
+                } else await callback();
+            }} label={`$${backendCache.config.token_symbol}`} />}
             {postAuthor && <>
-                {post.hashes.length == 0 && <ButtonWithLoading classNameArg="max_width_col" onClick={async () => {
+                {post.deletion && <ButtonWithLoading classNameArg="max_width_col" onClick={async () => {
+                    let response = await api.call("restore_post", post.id);
+                    if ("Err" in response) {
+                        alert(`Error: ${response.Err}`);
+                    } else await callback();
+                }} label="RESTORE" />}
+                {post.hashes.length == 0 && !post.deletion && <ButtonWithLoading classNameArg="max_width_col" onClick={async () => {
                     const { post_cost, post_deletion_penalty_factor } = backendCache.config;
                     const cost = objectReduce(post.reactions, (acc, id, users) => {
                         const costTable = reactionCosts();
@@ -257,6 +285,9 @@ const PostInfo = ({post, version, postCreated, callback}) => {
             {post.tips.length > 0 && <>
                 <b>TIPS</b>: {commaSeparated(post.tips.map(([id, tip]) => <span key={id + tip}><code>{tip}</code> from {linkToProfile(id)}</span>))}
             </>}
+            {post.token_tips.length > 0 && <div>
+                <b>TOKEN TIPS</b>: <code>{post.token_tips.reduce((acc, [_, amount]) => acc + amount, 0) / Math.pow(10, backendCache.config.token_decimals)}</code> ${backendCache.config.token_symbol} from {post.token_tips.length} tip{post.token_tips.length == 1 ? "" : "s"}
+            </div>}
             {Object.keys(post.reactions).length > 0 && <div className="top_spaced">
                 {Object.keys(post.reactions).map(id => {
                     let users = post.reactions[id];
@@ -283,6 +314,7 @@ const PostBar = ({post, react, highlighted, highlightOp, repost, showInfo, toggl
         <div className="vcentered">
             <a className={`right_spaced ${highlightOp ? "accent" : ""}`}
                 href={`#/user/${post.user.id}`}>{`${post.user.name}`}</a>
+            {post.user.flair && <span className="right_spaced">{post.user.flair}</span>}
             <div className="right_spaced no_wrap vcentered">
                 {time}
                 {newPost && <New classNameArg="left_half_spaced accent" /> }
@@ -9,11 +9,22 @@ export const PostFeed = ({data, classNameArg = null, focusedPost, feedLoader, he
     const [init, setInit] = React.useState(false);
     const [loading, setLoading] = React.useState(false);
     const [noMoreData, setNoMoreData] = React.useState(false);
+    const [cursor, setCursor] = React.useState(null);
 
     const loadPage = async page => {
         setLoading(true);
-        let next_posts = (await feedLoader(page, includeComments)).map(postUserToPost);
-        if (next_posts.length < backendCache.config.feed_page_size) setNoMoreData(true);
+        let result = await feedLoader(page, includeComments, page == 0 ? null : cursor);
+        if (result.Err) {
+            alert(`Error: ${result.Err}`);
+            result = [];
+        }
+        // cursor-based feeds return the cursor of the next page, which is missing at the end
+        if (result.Ok) {
+            setCursor(result.Ok.cursor);
+            if (!result.Ok.cursor) setNoMoreData(true);
+            result = result.Ok.posts;
+        } else if (result.length < backendCache.config.feed_page_size) setNoMoreData(true);
+        let next_posts = result.map(postUserToPost);
         const loaded = new Set(posts.map(post => post.id));
         setPosts(page == 0 ? next_posts : posts.concat(next_posts.filter(post => !loaded.has(post.id))));
         setLoading(false);
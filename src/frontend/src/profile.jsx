@@ -46,6 +46,14 @@ export const Profile = ({handle}) => {
                 {api._user && <ToggleButton classNameArg="left_half_spaced max_width_col"
                     currState={() => api._user.followees.includes(profile.id)}
                     toggler={() => api.call("toggle_following_user", profile.id).then(api._reloadUser)} />}
+                {api._user && api._user.id != profile.id && <ToggleButton classNameArg="left_half_spaced max_width_col"
+                    offLabel="MUTE" onLabel="UNMUTE"
+                    currState={() => api._user.muted.includes(profile.id)}
+                    toggler={() => api.call("mute_user", profile.id, !api._user.muted.includes(profile.id)).then(api._reloadUser)} />}
+                {api._user && api._user.id != profile.id && <ToggleButton classNameArg="left_half_spaced max_width_col"
+                    offLabel="BLOCK" onLabel="UNBLOCK"
+                    currState={() => api._user.blocked.includes(profile.id)}
+                    toggler={() => api.call("block_user", profile.id, !api._user.blocked.includes(profile.id)).then(api._reloadUser)} />}
             </div>} />
         <UserInfo profile={profile} />
         {trusted(profile) && !stalwart(profile) && !isBot(profile) && <>
@@ -191,6 +199,14 @@ export const UserInfo = ({profile}) => {
                 <span><a href={`/#/user/${inviter}`}>{`${backendCache.users[inviter]}`}</a></span>
             </div>}
         </div>
+        {profile.badges.length > 0 && <>
+            <hr />
+            <h2>BADGES</h2>
+            <div className="row_container">
+                {profile.badges.map(badge => <span key={badge} className="padded_rounded right_half_spaced top_half_spaced">
+                    🏅 {badgeName(badge)}</span>)}
+            </div>
+        </>}
         <hr />
         <h2>INTERESTS</h2>
         {feeds}
@@ -198,6 +214,12 @@ export const UserInfo = ({profile}) => {
     </div>;
 };
 
+const badgeName = badge => ({
+    FirstPost: "FIRST POST",
+    Upvotes: `${backendCache.config.badge_upvotes} UPVOTES`,
+    Streak: `${backendCache.config.badge_streak_days}-DAY STREAK`,
+})[badge] || badge.toUpperCase();
+
 const day = 24 * 3600 * 1000;
 
 const trusted = profile => profile.karma >= backendCache.config.trusted_user_min_karma &&
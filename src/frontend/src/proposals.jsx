@@ -39,6 +39,15 @@ export const Proposals = () => {
                     }
                     setProposal(response.Ok);
                 }} />
+                <ButtonWithLoading classNameArg="max_width_col" label="BUCKET SIZE" onClick={async () => {
+                    let size = parseInt(prompt("Enter the bucket size limit in bytes."));
+                    let description = prompt("Enter the proposal description.");
+                    let response = await api.call("propose_max_bucket_size", description, size);
+                    if ("Err" in response) {
+                        alert(`Error: ${response.Err}`);
+                    }
+                    setProposal(response.Ok);
+                }} />
                 <button className="max_width_col active" onClick={() => toggleMask(!showMask)}>RELEASE</button>
             </div>} />
         <div className="vertically_spaced">
@@ -94,7 +103,7 @@ export const Proposal = ({id}) => {
     if (!proposal) return <Loading />;
     if (proposal == 404) return <NotFound />;
 
-    const statusEmoji = status => { return {"OPEN": "✨", "REJECTED": "🟥", "CANCELLED": "❌", "EXECUTED": "✅" }[status] || <HourGlass /> };
+    const statusEmoji = status => { return {"OPEN": "✨", "REJECTED": "🟥", "CANCELLED": "❌", "EXECUTED": "✅", "ADOPTED": "⏳", "NOQUORUM": "⬜", "FAILED": "⚠️" }[status] || <HourGlass /> };
 
     const vote = async (proposal_id, adopted) => {
         const prevStatus = proposal.status;
@@ -122,6 +131,9 @@ export const Proposal = ({id}) => {
     const adopted = proposal.bulletins.reduce((acc, [_, adopted, votes]) => adopted ? acc + votes : acc, 0);
     const rejected = proposal.bulletins.reduce((acc, [_, adopted, votes]) => !adopted ? acc + votes : acc, 0);
     const open = proposal.status == "Open";
+    // adopted proposals can still be vetoed during the timelock
+    const adoptedStatus = proposal.status == "Adopted";
+    const vetoed = !api._user || proposal.bulletins.some(vote => api._user.id == vote[0] && !vote[1]);
     const commit = proposal.payload.Release ? chunks(proposal.payload.Release.commit).join(" ") : null;
     const hash = proposal.payload.Release ? chunks(proposal.payload.Release.hash).join(" ") : null;
     const dailyDrop = proposal.voting_power / 100;
@@ -134,15 +146,38 @@ export const Proposal = ({id}) => {
         <div className="monospace bottom_half_spaced">PROPOSER: <a href={`#/user/${proposal.proposer}`}>{`@${users[proposal.proposer]}`}</a></div>
         <div className="monospace bottom_half_spaced">DATE: {timeAgo(proposal.timestamp)}</div>
         <div className="monospace bottom_spaced">STATUS: {statusEmoji(propStatus)} <span className={open ? "accent" : null}>{propStatus}</span></div>
+        {proposal.error && <div className="monospace bottom_spaced">ERROR: <code>{proposal.error}</code></div>}
         {"Release" in proposal.payload && <div className="monospace bottom_spaced">
             {commit && <div className="row_container bottom_half_spaced">COMMIT:<a className="monospace left_spaced" href={`${REPO}/${proposal.payload.Release.commit}`}>{commit}</a></div>}
             <div className="row_container"><span>HASH:</span><code className="left_spaced monospace">{hash}</code></div>
         </div>}
         {"SetController" in proposal.payload && <div className="monospace bottom_spaced">PRINCIPAL: <code>{proposal.payload.SetController}</code></div>}
+        {"SetMaxBucketSize" in proposal.payload && <div className="monospace bottom_spaced">SIZE: <code>{proposal.payload.SetMaxBucketSize.toLocaleString()}</code> BYTES</div>}
+        {"KarmaAudit" in proposal.payload && <div className="monospace bottom_spaced">CORRECTIONS: <code>{proposal.payload.KarmaAudit ? "YES" : "NO"}</code></div>}
         {"Fund" in proposal.payload && <>
             <div className="monospace bottom_half_spaced">RECEIVER: <code>{proposal.payload.Fund[0]}</code></div>
             <div className="monospace bottom_spaced">AMOUNT: <code>{proposal.payload.Fund[1].toLocaleString()}</code></div>
         </>}
+        {"SetThresholds" in proposal.payload && <>
+            <div className="monospace bottom_half_spaced">QUORUM: <code>{proposal.payload.SetThresholds.quorum}%</code></div>
+            <div className="monospace bottom_spaced">APPROVAL: <code>{proposal.payload.SetThresholds.approval}%</code></div>
+        </>}
+        {"SetCoordinationSettings" in proposal.payload && <div className="monospace bottom_spaced">
+            {Object.entries(proposal.payload.SetCoordinationSettings).map(([key, value]) =>
+                <div key={key} className="bottom_half_spaced">{key.replaceAll("_", " ").toUpperCase()}: <code>{value.toString()}</code></div>)}
+        </div>}
+        {"RealmSpend" in proposal.payload && <>
+            <div className="monospace bottom_half_spaced">REALM: <a href={`#/realm/${proposal.payload.RealmSpend[0]}`}>{proposal.payload.RealmSpend[0]}</a></div>
+            <div className="monospace bottom_half_spaced">RECEIVER: <code>{proposal.payload.RealmSpend[1]}</code></div>
+            <div className="monospace bottom_spaced">AMOUNT: <code>{proposal.payload.RealmSpend[2].toLocaleString()}</code></div>
+        </>}
+        {"Vest" in proposal.payload && <>
+            <div className="monospace bottom_half_spaced">RECEIVER: <code>{proposal.payload.Vest[0]}</code></div>
+            <div className="monospace bottom_half_spaced">AMOUNT: <code>{proposal.payload.Vest[1].toLocaleString()}</code></div>
+            <div className="monospace bottom_half_spaced">CLIFF: <code>{proposal.payload.Vest[2]}</code> DAYS</div>
+            <div className="monospace bottom_spaced">DURATION: <code>{proposal.payload.Vest[3]}</code> DAYS</div>
+        </>}
+        {"RevokeVesting" in proposal.payload && <div className="monospace bottom_spaced">VESTING ID: <code>{proposal.payload.RevokeVesting}</code></div>}
         <div className="monospace bottom_spaced">
             EFFECTIVE VOTING POWER: <code>{token(proposal.voting_power)}</code>
         </div>
@@ -157,7 +192,7 @@ export const Proposal = ({id}) => {
             <div className="bottom_half_spaced">REJECTED: <b className={adopted < rejected && open ? "accent" : null}>{token(rejected)}</b> ({percentage(rejected, proposal.voting_power)})</div>
             <div className="small_text">{users && userList(proposal.bulletins.filter(vote => !vote[1]).map(vote => vote[0]))}</div>
         </div>
-        {api._user && open && !voted && <>
+        {api._user && (open && !voted || adoptedStatus && !vetoed) && <>
             <div className="row_container">
                 <ButtonWithLoading onClick={() => vote(proposal.id, false)} classNameArg="max_width_col large_text" label="REJECT" />
                 <ButtonWithLoading onClick={() => vote(proposal.id, true)} classNameArg="max_width_col large_text" label="ADOPT" />
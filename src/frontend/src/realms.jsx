@@ -3,6 +3,7 @@ import {PostFeed} from "./post_feed";
 import {loadFile} from "./form";
 import {bigScreen, ButtonWithLoading, HeadBar, Loading, NotFound, RealmRibbon, setTitle, userList, } from "./common";
 import { Content } from './content';
+import {Search} from "./search";
 import {Edit} from "./icons";
 
 export const RealmForm = ({existingName}) => {
@@ -20,6 +21,7 @@ export const RealmForm = ({existingName}) => {
     const [description, setDescription] = React.useState("");
     const [controllersString, setControllersString] = React.useState(users[userId]);
     const [controllers, setControllers] = React.useState([userId]);
+    const [visibility, setVisibility] = React.useState("Public");
     const [loading, setLoading] = React.useState(false);
 
     const loadRealm = async () => {
@@ -33,6 +35,7 @@ export const RealmForm = ({existingName}) => {
         setDescription(realm.description);
         setControllers(realm.controllers);
         setLabelColor(realm.label_color || "#ffffff");
+        setVisibility(realm.visibility);
         setControllersString(realm.controllers.map(id => users[id]).join(", "));
     };
     React.useEffect(() => { if (editing) loadRealm() }, []);
@@ -93,6 +96,14 @@ export const RealmForm = ({existingName}) => {
                         setControllers(ids);
                     }} />
             </div>
+            {editing && <div className="column_container bottom_spaced monospace">
+                <div className="bottom_half_spaced">VISIBILITY</div>
+                <select value={visibility} onChange={event => setVisibility(event.target.value)}>
+                    <option value="Public">PUBLIC</option>
+                    <option value="Restricted">RESTRICTED (JOINING NEEDS APPROVAL)</option>
+                    <option value="Private">PRIVATE (HIDDEN FROM NON-MEMBERS)</option>
+                </select>
+            </div>}
             {controllers.length > 0 &&
             <div className="column_container bottom_spaced monospace">
                 <div className="bottom_half_spaced">VALID CONTROLLERS: {userList(controllers)}</div>
@@ -101,8 +112,10 @@ export const RealmForm = ({existingName}) => {
             {!loading && <button className={valid ? "active" : "inactive"} onClick={async () => {
                 if (!valid) return;
                 setLoading(true);
-                const response = await api.call(editing ? "edit_realm" : "create_realm",
+                let response = await api.call(editing ? "edit_realm" : "create_realm",
                     name, logo, labelColor, description, controllers.map(id => parseInt(id)));
+                if (editing && !("Err" in response))
+                    response = await api.call("set_realm_visibility", name, visibility);
                 await window.reloadCache();
                 setLoading(false);
                 if ("Err" in response) {
@@ -139,8 +152,21 @@ export const RealmPage = ({name}) => {
             content={<>
                 {user && realm.controllers.includes(user.id) && 
                 <button className="right_half_spaced" onClick={() => location.href = `/#/realm/${name}/edit`}><Edit /></button>}
-                {user && !user.realms.includes(name) && <ButtonWithLoading
-                    label="JOIN" classNameArg="active right_half_spaced"
+                {user && realm.controllers.includes(user.id) && <ButtonWithLoading classNameArg="right_half_spaced" label="INVITE"
+                    onClick={async () => {
+                        const uses = parseInt(prompt("Maximal number of uses:", 10));
+                        if (isNaN(uses)) return;
+                        const hours = parseInt(prompt("Expires in hours (leave empty for no expiry):", ""));
+                        const response = await api.call("create_realm_invite", name, uses, isNaN(hours) ? null : hours);
+                        if ("Err" in response) return alert(`Error: ${response.Err}`);
+                        prompt("Share this invite link:", `${location.origin}/#/realm_invite/${response.Ok}`);
+                        await loadRealm();
+                    }} />}
+                {user && !user.realms.includes(name) && realm.join_requests.includes(user.id) &&
+                    <button className="inactive right_half_spaced" disabled={true}>REQUESTED</button>}
+                {user && !user.realms.includes(name) && !realm.join_requests.includes(user.id) && <ButtonWithLoading
+                    label={realm.visibility == "Public" || realm.controllers.includes(user.id) ? "JOIN" : "REQUEST TO JOIN"}
+                    classNameArg="active right_half_spaced"
                     onClick={async () => {
                         if (!confirm(`By joining the realm ${name} you confirm that you understand its description and agree with all terms and conditions mentioned there. Any rule violation can lead to moderation by stalwarts.`))
                             return false;
@@ -151,14 +177,40 @@ export const RealmPage = ({name}) => {
             </>} />
         <div className="spaced">
             <Content value={realm.description} />
+            {user && realm.controllers.includes(user.id) && realm.join_requests.length > 0 && <div className="bottom_spaced">
+                <h3>Join requests</h3>
+                {realm.join_requests.map(id => <div key={id} className="vcentered bottom_half_spaced">
+                    <span className="max_width_col">{userList([id])}</span>
+                    {[["APPROVE", true], ["REJECT", false]].map(([label, approve]) =>
+                        <ButtonWithLoading key={label} label={label} classNameArg="left_half_spaced"
+                            onClick={async () => {
+                                const response = await api.call("resolve_join_request", name, id, approve);
+                                if ("Err" in response) alert(`Error: ${response.Err}`);
+                                await loadRealm();
+                            }} />)}
+                </div>)}
+            </div>}
             <p>Members: {showMembers ? userList(realm.members) : <a href="" onClick={e => {e.preventDefault(); setShowMembers(true)}}>{realm.members.length}</a>}</p>
         </div>
         <hr />
+        <Search realm={name} />
         <PostFeed title={<h2 className="spaced">Latest Posts</h2>}
-            grid={true} feedLoader={async page => await api.query("realm_posts", name, page, false)} />
+            grid={true} feedLoader={async (page, _, cursor) => await api.query("realm_posts", name, cursor, false)} />
     </>;
 }
 
+export const RealmInvite = ({code}) => {
+    const [error, setError] = React.useState(null);
+    React.useEffect(() => {
+        api.call("redeem_realm_invite", code).then(async response => {
+            if ("Err" in response) return setError(response.Err);
+            await api._reloadUser();
+            location.href = `/#/realm/${response.Ok}`;
+        });
+    }, []);
+    return error ? <div className="spaced text_centered">Error: {error}</div> : <Loading />;
+}
+
 export const Realms = () => {
     const [realms, setRealms] = React.useState([]);
     const [page, setPage] = React.useState(0);
@@ -1,7 +1,7 @@
 import * as React from "react";
 import {Loading} from "./common";
 
-export const Search = () => {
+export const Search = ({realm}) => {
     const [term, setTerm] = React.useState("");
     const [results, setResults] = React.useState([]);
     const [timer, setTimer] = React.useState(null);
@@ -9,7 +9,7 @@ export const Search = () => {
 
     return <div className="column_container spaced top_spaced bottom_spaced">
         <input id="search_field" className="monospace larger_text" type="search"
-            placeholder={`Search #${backendCache.config.name}`} value={term}
+            placeholder={`Search ${realm ? "/" + realm : "#" + backendCache.config.name}`} value={term}
             onChange={event => {
                 clearTimeout(timer);
                 const term = event.target.value;
@@ -20,7 +20,7 @@ export const Search = () => {
                         return;
                     }
                     setSearching(true);
-                    setResults(await api.query("search", term));
+                    setResults(await api.query("search", term, realm || null));
                     setSearching(false);
                 }, 300))
             }} />
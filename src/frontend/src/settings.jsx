@@ -11,12 +11,18 @@ export const Settings = ({invite = null}) => {
     const [label, setLabel] = React.useState(null);
     const [timer, setTimer] = React.useState(null);
     const [uiRefresh, setUIRefresh] = React.useState(false);
+    const [webhook, setWebhook] = React.useState("");
+    const [notifications, setNotifications] = React.useState({});
+    const [feedMode, setFeedMode] = React.useState("Chronological");
 
     React.useEffect(() => {
         if (!user) return;
         setAbout(user.about);
         setControllers(user.controllers.join("\n"));
         setSettings(user.settings);
+        setWebhook(user.webhook ? user.webhook.url : "");
+        setNotifications(user.notification_settings);
+        setFeedMode(user.feed_mode);
     }, [user])
 
     const setSetting = (key, e) => {
@@ -98,7 +104,76 @@ export const Settings = ({invite = null}) => {
                 }
                 localStorage.clear();
                 location.href = "/";
-            }} label="CHANGE PRINCIPAL" />}</>}
+            }} label="CHANGE PRINCIPAL" />}
+            <hr />
+            <div className="column_container top_spaced">
+                <div className="bottom_half_spaced">WEBHOOK URL (mentions, replies and rewards are posted as JSON)</div>
+                <input placeholder="https://..." className="monospace small_text" type="text" value={webhook} onChange={event => setWebhook(event.target.value)} />
+            </div>
+            {user.webhook && user.webhook.disabled && <div className="small_text vertically_spaced">⚠️ The webhook was disabled after repeated delivery failures; save it again to re-enable.</div>}
+            <ButtonWithLoading classNameArg="top_spaced" onClick={async () => {
+                let response = await api.call("set_webhook", webhook.trim());
+                if ("Err" in response) {
+                    alert(`Error: ${response.Err}`);
+                    return;
+                }
+                await api._reloadUser();
+            }} label="SAVE WEBHOOK" />
+            <hr />
+            <div className="column_container top_spaced">
+                <div className="bottom_half_spaced">NOTIFY ME ABOUT</div>
+                {["mentions", "replies", "followers", "rewards", "realms"].map(key =>
+                    <label key={key} className="vcentered">
+                        <input type="checkbox" checked={!!notifications[key]} className="right_spaced"
+                            onChange={() => setNotifications({...notifications, [key]: !notifications[key]})} />
+                        {key.toUpperCase()}
+                    </label>)}
+            </div>
+            <ButtonWithLoading classNameArg="top_spaced" onClick={async () => {
+                let response = await api.call("set_notification_settings", notifications);
+                if ("Err" in response) {
+                    alert(`Error: ${response.Err}`);
+                    return;
+                }
+                await api._reloadUser();
+            }} label="SAVE NOTIFICATIONS" />
+            <hr />
+            <div className="column_container top_spaced">
+                <div className="bottom_half_spaced">FEED ORDER</div>
+                <select value={feedMode} onChange={event => setFeedMode(event.target.value)}>
+                    <option value="Chronological">CHRONOLOGICAL</option>
+                    <option value="Ranked">RANKED</option>
+                </select>
+            </div>
+            <ButtonWithLoading classNameArg="top_spaced" onClick={async () => {
+                let response = await api.call("set_feed_mode", feedMode);
+                if ("Err" in response) {
+                    alert(`Error: ${response.Err}`);
+                    return;
+                }
+                await api._reloadUser();
+            }} label="SAVE FEED ORDER" />
+            <hr />
+            <ButtonWithLoading classNameArg="top_spaced" onClick={async () => {
+                const archive = {posts: [], comments: []};
+                let cursor = null;
+                do {
+                    const response = await api.query("export", cursor);
+                    if ("Err" in response) {
+                        alert(`Error: ${response.Err}`);
+                        return;
+                    }
+                    const page = response.Ok;
+                    if (page.profile) archive.profile = page.profile;
+                    archive.posts.push(...page.posts);
+                    archive.comments.push(...page.comments);
+                    cursor = page.cursor;
+                } while (cursor);
+                const link = document.createElement("a");
+                link.href = URL.createObjectURL(new Blob([JSON.stringify(archive)], {type: "application/json"}));
+                link.download = `${user.name}.json`;
+                link.click();
+            }} label="EXPORT MY DATA" /></>}
         </div>
     </>;
 }